@@ -0,0 +1,393 @@
+//! Optional machine-readable audit log of tool calls.
+//!
+//! When `AIMCP_AUDIT_LOG` names a file, every tool call appends one JSON line with
+//! the tool name, its arguments with prompts hashed and secrets removed, the outcome,
+//! the duration and the security flags in effect. Writes happen on a background
+//! thread and failures are only reported on stderr, so auditing never slows down or
+//! fails a tool call.
+
+use futures::FutureExt;
+use rmcp::{
+    handler::server::tool::{DynCallToolHandler, ToolCallContext},
+    model::{CallToolResult, JsonObject},
+    ErrorData as McpError,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use crate::tools::codex;
+
+/// Env var naming the file audit records are appended to
+pub const ENV_AUDIT_LOG: &str = "AIMCP_AUDIT_LOG";
+
+/// Argument names whose value is replaced by its length and hash
+const HASHED_ARGS: &[&str] = &["prompt"];
+/// Argument names whose value is dropped entirely
+const SECRET_ARGS: &[&str] = &["api_key"];
+
+/// Server-wide security settings in effect for a call, from the `CODEX_ALLOW_*` variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SecurityFlags {
+    pub allow_dangerous: bool,
+    pub allow_yolo: bool,
+    pub allow_skip_git_check: bool,
+    pub allow_approval_never: bool,
+}
+
+impl SecurityFlags {
+    /// Flags read from the environment; parse warnings are already reported to the tools
+    pub fn from_env() -> Self {
+        let security = codex::get_security_config(&mut Vec::new());
+        Self {
+            allow_dangerous: security.allow_danger_full_access,
+            allow_yolo: security.allow_yolo,
+            allow_skip_git_check: security.allow_skip_git_check,
+            allow_approval_never: security.allow_approval_never,
+        }
+    }
+}
+
+/// How a tool call ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    /// The tool ran and returned a result flagged `isError`
+    ToolError,
+    /// The call failed with a protocol error, e.g. invalid parameters
+    Error,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// RFC 3339 UTC time the call started
+    pub timestamp: String,
+    pub tool: String,
+    pub args: Value,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    pub security: SecurityFlags,
+}
+
+impl AuditRecord {
+    /// Build the record for a finished call, sanitizing `args`
+    pub fn new(
+        started_at: chrono::DateTime<chrono::Utc>,
+        tool: &str,
+        args: Option<&JsonObject>,
+        result: &Result<CallToolResult, McpError>,
+        duration: Duration,
+        security: SecurityFlags,
+    ) -> Self {
+        let (outcome, error) = match result {
+            Ok(result) if result.is_error == Some(true) => (Outcome::ToolError, None),
+            Ok(_) => (Outcome::Success, None),
+            Err(e) => (Outcome::Error, Some(e.message.to_string())),
+        };
+        Self {
+            timestamp: started_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            tool: tool.to_string(),
+            args: args
+                .map(sanitize_object)
+                .unwrap_or_else(|| Value::Object(Map::new())),
+            outcome,
+            error,
+            duration_ms: u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+            security,
+        }
+    }
+}
+
+/// Copy of `args` with prompts replaced by `{"redacted", "length", "fnv1a64"}` and
+/// secrets by `"[redacted]"`, at any depth (e.g. `codex_batch` tasks)
+pub fn sanitize_args(args: &Value) -> Value {
+    match args {
+        Value::Object(map) => sanitize_object(map),
+        Value::Array(items) => Value::Array(items.iter().map(sanitize_args).collect()),
+        other => other.clone(),
+    }
+}
+
+fn sanitize_object(map: &JsonObject) -> Value {
+    let sanitized = map
+        .iter()
+        .map(|(key, value)| {
+            let name = key.to_ascii_lowercase();
+            let value = if SECRET_ARGS.contains(&name.as_str()) {
+                Value::String("[redacted]".to_string())
+            } else if HASHED_ARGS.contains(&name.as_str()) {
+                match value {
+                    Value::String(text) => redact_text(text),
+                    other => sanitize_args(other),
+                }
+            } else {
+                sanitize_args(value)
+            };
+            (key.clone(), value)
+        })
+        .collect();
+    Value::Object(sanitized)
+}
+
+/// Stand-in for a prompt: enough to correlate identical prompts without storing them
+fn redact_text(text: &str) -> Value {
+    json!({
+        "redacted": true,
+        "length": text.chars().count(),
+        "fnv1a64": format!("{:016x}", fnv1a64(text.as_bytes())),
+    })
+}
+
+/// 64-bit FNV-1a, stable across builds unlike `DefaultHasher`
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Handle to the audit log file; records are written in order by a background thread
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    sender: mpsc::Sender<String>,
+}
+
+impl AuditLog {
+    /// Open `path` for appending, creating it if needed
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let (sender, receiver) = mpsc::channel::<String>();
+        let path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("aimcp-audit-log".to_string())
+            .spawn(move || {
+                let mut failed = false;
+                for line in receiver {
+                    let written = writeln!(file, "{}", line).and_then(|_| file.flush());
+                    if let Err(e) = written {
+                        // Report the first failure only so a full disk doesn't flood stderr
+                        if !failed {
+                            eprintln!(
+                                "ikuncode-aimcp: warning: failed to write audit log {}: {}",
+                                path.display(),
+                                e
+                            );
+                        }
+                        failed = true;
+                    }
+                }
+            })?;
+        Ok(Self { sender })
+    }
+
+    /// Log opened from `AIMCP_AUDIT_LOG`; `None` when unset or when the file cannot
+    /// be opened, which is reported on stderr
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var(ENV_AUDIT_LOG)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)?;
+        match Self::open(&path) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                eprintln!(
+                    "ikuncode-aimcp: warning: audit log disabled, cannot open {}: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Queue `record` for writing without waiting for it
+    pub fn record(&self, record: &AuditRecord) {
+        if let Ok(line) = serde_json::to_string(record) {
+            // The writer thread only stops if it panicked; the call must not fail then
+            let _ = self.sender.send(line);
+        }
+    }
+}
+
+/// Wrap a tool handler so each call through it is recorded in `log`
+pub fn audited<S: Send + Sync + 'static>(
+    inner: Arc<DynCallToolHandler<S>>,
+    log: AuditLog,
+) -> Arc<DynCallToolHandler<S>> {
+    Arc::new(move |context: ToolCallContext<'_, S>| {
+        let inner = inner.clone();
+        let log = log.clone();
+        let tool = context.name().to_string();
+        let args = context.arguments.clone();
+        let started_at = chrono::Utc::now();
+        let started = Instant::now();
+        async move {
+            let result = inner(context).await;
+            log.record(&AuditRecord::new(
+                started_at,
+                &tool,
+                args.as_ref(),
+                &result,
+                started.elapsed(),
+                SecurityFlags::from_env(),
+            ));
+            result
+        }
+        .boxed()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::Content;
+
+    fn flags() -> SecurityFlags {
+        SecurityFlags {
+            allow_dangerous: false,
+            allow_yolo: true,
+            allow_skip_git_check: false,
+            allow_approval_never: false,
+        }
+    }
+
+    fn started_at() -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339("2026-01-02T03:04:05.678Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc)
+    }
+
+    #[test]
+    fn record_captures_call_details() {
+        let args = json!({"PROMPT": "fix the bug", "cd": "/repo", "yolo": true});
+        let result = Ok(CallToolResult::success(vec![Content::text("done")]));
+        let record = AuditRecord::new(
+            started_at(),
+            "codex",
+            args.as_object(),
+            &result,
+            Duration::from_millis(1500),
+            flags(),
+        );
+        let value = serde_json::to_value(&record).unwrap();
+
+        assert_eq!(value["timestamp"], "2026-01-02T03:04:05.678Z");
+        assert_eq!(value["tool"], "codex");
+        assert_eq!(value["outcome"], "success");
+        assert!(value.get("error").is_none());
+        assert_eq!(value["duration_ms"], 1500);
+        assert_eq!(value["security"]["allow_yolo"], true);
+        assert_eq!(value["security"]["allow_dangerous"], false);
+        assert_eq!(value["args"]["cd"], "/repo");
+        assert_eq!(value["args"]["yolo"], true);
+    }
+
+    #[test]
+    fn record_distinguishes_tool_errors_from_call_errors() {
+        let tool_error = Ok(CallToolResult::error(vec![Content::text("boom")]));
+        let record = AuditRecord::new(
+            started_at(),
+            "gemini",
+            None,
+            &tool_error,
+            Duration::ZERO,
+            flags(),
+        );
+        assert_eq!(record.outcome, Outcome::ToolError);
+        assert_eq!(record.args, json!({}));
+
+        let call_error = Err(McpError::invalid_params("PROMPT is required", None));
+        let record = AuditRecord::new(
+            started_at(),
+            "gemini",
+            None,
+            &call_error,
+            Duration::ZERO,
+            flags(),
+        );
+        assert_eq!(record.outcome, Outcome::Error);
+        assert_eq!(record.error.as_deref(), Some("PROMPT is required"));
+    }
+
+    #[test]
+    fn sanitize_args_redacts_prompts_and_secrets() {
+        let args = json!({
+            "PROMPT": "secret plan",
+            "api_key": "xai-123",
+            "query": "rust async",
+            "tasks": [{"PROMPT": "secret plan", "cd": "/a"}, {"prompt": "other"}],
+        });
+        let sanitized = sanitize_args(&args);
+        let text = sanitized.to_string();
+
+        assert!(!text.contains("secret plan"));
+        assert!(!text.contains("xai-123"));
+        assert!(!text.contains("other"));
+        assert_eq!(sanitized["api_key"], "[redacted]");
+        assert_eq!(sanitized["query"], "rust async");
+        assert_eq!(sanitized["PROMPT"]["redacted"], true);
+        assert_eq!(sanitized["PROMPT"]["length"], 11);
+        // Identical prompts hash alike, so calls can be correlated
+        assert_eq!(sanitized["PROMPT"], sanitized["tasks"][0]["PROMPT"]);
+        assert_ne!(
+            sanitized["PROMPT"]["fnv1a64"],
+            sanitized["tasks"][1]["prompt"]["fnv1a64"]
+        );
+        assert_eq!(sanitized["tasks"][0]["cd"], "/a");
+    }
+
+    #[test]
+    fn fnv1a64_matches_reference_values() {
+        assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn audit_log_appends_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        std::fs::write(&path, "existing\n").unwrap();
+        let log = AuditLog::open(&path).unwrap();
+        let record = AuditRecord::new(
+            started_at(),
+            "web_search",
+            json!({"query": "q"}).as_object(),
+            &Ok(CallToolResult::success(vec![])),
+            Duration::from_millis(7),
+            flags(),
+        );
+        log.record(&record);
+        log.record(&record);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let contents = loop {
+            let contents = std::fs::read_to_string(&path).unwrap();
+            if contents.lines().count() == 3 || Instant::now() > deadline {
+                break contents;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "existing");
+        let logged: Value = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(logged["tool"], "web_search");
+        assert_eq!(logged["duration_ms"], 7);
+    }
+
+    #[test]
+    fn audit_log_open_fails_for_missing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(AuditLog::open(&dir.path().join("missing/audit.jsonl")).is_err());
+    }
+}
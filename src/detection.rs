@@ -1,37 +1,69 @@
-use crate::shared::find_binary;
+use crate::shared::{locate_binary_with_prepend, BinaryLookup};
 use std::path::PathBuf;
 
 #[derive(Clone)]
 pub struct Capabilities {
     pub gemini_available: bool,
+    /// Executable path when available; the offending file when found but not executable
     pub gemini_path: Option<PathBuf>,
+    /// GEMINI_BIN points at a file that exists but cannot be executed
+    pub gemini_found_not_executable: bool,
     pub codex_available: bool,
+    /// Executable path when available; the offending file when found but not executable
     pub codex_path: Option<PathBuf>,
+    /// CODEX_BIN points at a file that exists but cannot be executed
+    pub codex_found_not_executable: bool,
     pub grok_available: bool,
 }
 
+/// Availability, path and not-executable flag for a binary lookup
+fn lookup_state(lookup: BinaryLookup) -> (bool, Option<PathBuf>, bool) {
+    match lookup {
+        BinaryLookup::Found(path) => (true, Some(path), false),
+        BinaryLookup::NotExecutable(path) => (false, Some(path), true),
+        BinaryLookup::NotFound => (false, None, false),
+    }
+}
+
+/// Detection log line for a CLI backend
+fn cli_status(available: bool, path: Option<&PathBuf>, env_override: &str) -> String {
+    match (available, path) {
+        (true, Some(path)) => format!("✓ ({})", path.display()),
+        (false, Some(path)) => format!(
+            "✗ ({} found at {} but not executable)",
+            env_override,
+            path.display()
+        ),
+        _ => "✗ (not found)".to_string(),
+    }
+}
+
 pub fn detect() -> Capabilities {
-    let gemini_path = find_binary("gemini", "GEMINI_BIN");
-    let codex_path = find_binary("codex", "CODEX_BIN");
+    let (gemini_available, gemini_path, gemini_found_not_executable) = lookup_state(
+        locate_binary_with_prepend("gemini", "GEMINI_BIN", Some("GEMINI_PATH_PREPEND")),
+    );
+    let (codex_available, codex_path, codex_found_not_executable) = lookup_state(
+        locate_binary_with_prepend("codex", "CODEX_BIN", Some("CODEX_PATH_PREPEND")),
+    );
     let grok_available =
         std::env::var("GROK_API_URL").is_ok() && std::env::var("GROK_API_KEY").is_ok();
 
     let caps = Capabilities {
-        gemini_available: gemini_path.is_some(),
+        gemini_available,
         gemini_path,
-        codex_available: codex_path.is_some(),
+        gemini_found_not_executable,
+        codex_available,
         codex_path,
+        codex_found_not_executable,
         grok_available,
     };
 
-    let gemini_status = match &caps.gemini_path {
-        Some(path) => format!("✓ ({})", path.display()),
-        None => "✗ (not found)".to_string(),
-    };
-    let codex_status = match &caps.codex_path {
-        Some(path) => format!("✓ ({})", path.display()),
-        None => "✗ (not found)".to_string(),
-    };
+    let gemini_status = cli_status(
+        caps.gemini_available,
+        caps.gemini_path.as_ref(),
+        "GEMINI_BIN",
+    );
+    let codex_status = cli_status(caps.codex_available, caps.codex_path.as_ref(), "CODEX_BIN");
     let grok_status = if caps.grok_available {
         "✓ (API key configured)".to_string()
     } else {
@@ -56,12 +88,39 @@ mod tests {
         let caps = Capabilities {
             gemini_available: false,
             gemini_path: None,
+            gemini_found_not_executable: false,
             codex_available: false,
             codex_path: None,
+            codex_found_not_executable: false,
             grok_available: false,
         };
         assert!(!caps.gemini_available);
         assert!(!caps.codex_available);
         assert!(!caps.grok_available);
     }
+
+    #[test]
+    fn test_lookup_state() {
+        let path = PathBuf::from("/opt/codex");
+        assert_eq!(
+            lookup_state(BinaryLookup::Found(path.clone())),
+            (true, Some(path.clone()), false)
+        );
+        assert_eq!(
+            lookup_state(BinaryLookup::NotExecutable(path.clone())),
+            (false, Some(path.clone()), true)
+        );
+        assert_eq!(lookup_state(BinaryLookup::NotFound), (false, None, false));
+    }
+
+    #[test]
+    fn test_cli_status_reports_not_executable() {
+        let path = PathBuf::from("/opt/codex");
+        assert_eq!(cli_status(true, Some(&path), "CODEX_BIN"), "✓ (/opt/codex)");
+        assert_eq!(
+            cli_status(false, Some(&path), "CODEX_BIN"),
+            "✗ (CODEX_BIN found at /opt/codex but not executable)"
+        );
+        assert_eq!(cli_status(false, None, "CODEX_BIN"), "✗ (not found)");
+    }
 }
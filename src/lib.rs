@@ -1,3 +1,4 @@
+pub mod audit;
 pub mod detection;
 pub mod server;
 pub mod shared;
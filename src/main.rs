@@ -3,7 +3,7 @@ use clap::Parser;
 
 use ikuncode_aimcp::detection;
 use ikuncode_aimcp::server::UnifiedServer;
-use ikuncode_aimcp::transport::AdaptiveStdio;
+use ikuncode_aimcp::transport::{AdaptiveStdio, SharedFramingFormat};
 use rmcp::ServiceExt;
 
 #[derive(Parser)]
@@ -18,8 +18,10 @@ async fn main() -> Result<()> {
 
     let capabilities = detection::detect();
 
+    let framing_format = SharedFramingFormat::default();
     let service = UnifiedServer::new(capabilities)
-        .serve(AdaptiveStdio::new())
+        .with_framing_format(framing_format.clone())
+        .serve(AdaptiveStdio::new().with_shared_format(framing_format))
         .await
         .inspect_err(|e| eprintln!("[ikuncode-aimcp] serving error: {:?}", e))?;
 
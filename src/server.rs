@@ -1,14 +1,17 @@
-use crate::detection::Capabilities;
+use crate::audit;
+use crate::detection::{self, Capabilities};
 use crate::tools::codex::{self, SandboxPolicy};
 use crate::tools::gemini;
 use crate::tools::gemini_image_api;
 use crate::tools::grok;
+use crate::tools::smoke;
+use crate::transport;
 use rmcp::{
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
     model::*,
     schemars,
-    service::NotificationContext,
-    tool, tool_handler, tool_router, ErrorData as McpError, RoleServer, ServerHandler,
+    service::{NotificationContext, RequestContext},
+    tool, tool_handler, tool_router, ErrorData as McpError, Peer, RoleServer, ServerHandler,
 };
 use serde::Deserialize;
 use std::path::PathBuf;
@@ -83,6 +86,7 @@ mod serialize_as_os_string_vec {
 pub struct GeminiArgs {
     /// Instruction for the task to send to gemini
     #[serde(rename = "PROMPT")]
+    #[schemars(example = "Summarize the architecture of this repository")]
     pub prompt: String,
     /// Run in sandbox mode. Defaults to `False`
     #[serde(default)]
@@ -99,7 +103,17 @@ pub struct GeminiArgs {
     /// Timeout in seconds for gemini execution (1-3600). If not specified, uses GEMINI_DEFAULT_TIMEOUT
     /// environment variable or falls back to 600 seconds (10 minutes).
     #[serde(default)]
+    #[schemars(range(min = MIN_TIMEOUT_SECS, max = MAX_TIMEOUT_SECS), example = 600)]
     pub timeout_secs: Option<u64>,
+    /// How to render the event log when return_all_messages is true: "pretty" (default),
+    /// "ndjson" (one event per line), or "compact" (single-line JSON array)
+    #[serde(default)]
+    pub return_format: gemini::EventLogFormat,
+    /// Stream assistant content to the client as progress notifications while gemini runs.
+    /// Requires the request to carry a progressToken. The final agent_messages then keeps only
+    /// its last 2000 characters and agent_messages_truncated is reported.
+    #[serde(default)]
+    pub stream_agent_messages: bool,
 }
 
 /// Input parameters for gemini_image tool (image generation via Gemini CLI)
@@ -107,6 +121,7 @@ pub struct GeminiArgs {
 pub struct GeminiImageArgs {
     /// Instruction for the image generation task to send to gemini
     #[serde(rename = "PROMPT")]
+    #[schemars(example = "A watercolor lighthouse at dusk")]
     pub prompt: String,
     /// Run in sandbox mode. Defaults to `False`
     #[serde(default)]
@@ -124,15 +139,18 @@ pub struct GeminiImageArgs {
     /// Timeout in seconds for gemini execution (1-3600). If not specified, uses GEMINI_DEFAULT_TIMEOUT
     /// environment variable or falls back to 600 seconds (10 minutes).
     #[serde(default)]
+    #[schemars(range(min = MIN_TIMEOUT_SECS, max = MAX_TIMEOUT_SECS), example = 600)]
     pub timeout_secs: Option<u64>,
-    /// Directory to save the generated image. If not specified, uses the first MCP workspace root or current working directory.
+    /// Directory to save the generated image. If not specified, uses AIMCP_IMAGE_DIR, the first MCP workspace root or the current working directory (order configurable via GEMINI_IMAGE_DIR_PRIORITY).
     #[serde(default)]
     pub output_dir: Option<String>,
     /// Aspect ratio of the generated image. Supported values: "1:1", "2:3", "3:2", "3:4", "4:3", "4:5", "5:4", "9:16", "16:9", "21:9". If not specified, the model chooses automatically.
     #[serde(default)]
+    #[schemars(example = "16:9")]
     pub aspect_ratio: Option<String>,
     /// Resolution/size of the generated image. Supported values: "1K", "2K", "4K". 4K is only available for certain models. If not specified, defaults to model default.
     #[serde(default)]
+    #[schemars(example = "2K")]
     pub image_size: Option<String>,
 }
 
@@ -141,16 +159,19 @@ pub struct GeminiImageArgs {
 pub struct CodexArgs {
     /// Instruction for task to send to codex
     #[serde(rename = "PROMPT")]
+    #[schemars(example = "Add unit tests for the config parser")]
     pub prompt: String,
     /// Set the workspace root for codex before executing the task
     #[serde(
         serialize_with = "serialize_as_os_string::serialize",
         deserialize_with = "serialize_as_os_string::deserialize"
     )]
+    #[schemars(example = "/home/user/project")]
     pub cd: PathBuf,
-    /// Sandbox policy for model-generated commands. Defaults to 'read-only'
+    /// Sandbox policy for model-generated commands. Defaults to CODEX_DEFAULT_SANDBOX when set,
+    /// otherwise 'read-only'
     #[serde(default)]
-    pub sandbox: SandboxPolicy,
+    pub sandbox: Option<SandboxPolicy>,
     /// Resume the specified session of the codex. Defaults to None, start a new session
     #[serde(rename = "SESSION_ID", default)]
     pub session_id: Option<String>,
@@ -160,16 +181,27 @@ pub struct CodexArgs {
     /// Return all messages (e.g. reasoning, tool calls, etc.) from the codex session
     #[serde(default)]
     pub return_all_messages: bool,
-    /// Maximum number of messages to keep when return_all_messages is true (default: 10000)
+    /// Maximum number of messages to keep when return_all_messages is true (default: 10000).
+    /// Larger values are capped to the server's maximum (CODEX_MAX_MESSAGE_LIMIT, default 50000)
+    /// with a warning
     #[serde(default)]
+    #[schemars(range(min = 1), example = 10000)]
     pub return_all_messages_limit: Option<usize>,
+    /// Also return agent_messages split by turn as agent_turns: [{turn_index, text}], where a new
+    /// turn starts with the first agent message after tool activity (commands, file changes, tool calls)
+    #[serde(default)]
+    pub return_turns: bool,
+    /// Also return the model's reasoning summaries as reasoning_summary, without the full event
+    /// trace of return_all_messages
+    #[serde(default)]
+    pub include_reasoning_summary: bool,
     /// Attach one or more image files to the initial prompt
     #[serde(
         serialize_with = "serialize_as_os_string_vec::serialize",
         deserialize_with = "serialize_as_os_string_vec::deserialize"
     )]
     pub image: Vec<PathBuf>,
-    /// The model to use for the codex session
+    /// The model to use for the codex session. Defaults to CODEX_DEFAULT_MODEL when set, otherwise the Codex CLI default
     #[serde(default)]
     pub model: Option<String>,
     /// Run every command without approvals or sandboxing
@@ -181,13 +213,74 @@ pub struct CodexArgs {
     /// Timeout in seconds for codex execution. If not specified, uses CODEX_DEFAULT_TIMEOUT
     /// environment variable or falls back to 600 seconds (10 minutes). Max: 3600 seconds.
     #[serde(default)]
+    #[schemars(range(min = MIN_TIMEOUT_SECS, max = MAX_TIMEOUT_SECS), example = 600)]
     pub timeout_secs: Option<u64>,
     /// Force using stdin to pass the prompt to the codex process, bypassing the auto-detection.
     /// Default: false. When true, the prompt is always piped via stdin regardless of content.
     #[serde(default)]
     pub force_stdin: bool,
+    /// Append every raw codex JSON event line to this file as it is read, independent of
+    /// return_all_messages. Relative paths are resolved against `cd`; the file must be inside
+    /// `cd` or an MCP workspace root.
+    #[serde(default)]
+    pub log_file: Option<PathBuf>,
+    /// Reasoning effort for the model: "minimal", "low", "medium" or "high".
+    /// Passed to codex as the model_reasoning_effort config override.
+    #[serde(default)]
+    #[schemars(example = &"high")]
+    pub reasoning_effort: Option<String>,
+    /// Output verbosity for the model: "low", "medium" or "high".
+    /// Passed to codex as the model_verbosity config override.
+    #[serde(default)]
+    #[schemars(example = &"low")]
+    pub verbosity: Option<String>,
+    /// When codex asks for approval before running commands: "untrusted", "on-failure",
+    /// "on-request" or "never". Passed to codex as the approval_policy config override.
+    /// "never" is downgraded to "on-request" unless CODEX_ALLOW_APPROVAL_NEVER is set.
+    #[serde(default)]
+    #[schemars(example = &"on-request")]
+    pub approval_policy: Option<String>,
+    /// Developer instructions for the model, kept separate from PROMPT so persistent behavior
+    /// need not be repeated in every task. Passed to codex as the developer_instructions
+    /// config override. Must be non-empty when provided. On Windows, where it goes on the
+    /// cmd.exe command line, it is limited to 800 bytes without newlines, quotes, backslashes
+    /// or cmd metacharacters such as & | < > ^ % !.
+    #[serde(default)]
+    #[schemars(example = &"Follow the existing code style.")]
+    pub instructions: Option<String>,
+}
+
+/// Input parameters for codex_kill tool
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CodexKillArgs {
+    /// SESSION_ID of the running codex session to terminate
+    #[serde(rename = "SESSION_ID")]
+    pub session_id: String,
+}
+
+/// Upper bound on the number of tasks in one codex_batch call
+const MAX_CODEX_BATCH_TASKS: usize = 16;
+
+/// Input parameters for codex_batch tool
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CodexBatchArgs {
+    /// Independent codex tasks, each taking the same parameters as the codex tool.
+    /// Run one after another; at most 16 tasks per call.
+    pub tasks: Vec<CodexArgs>,
 }
 
+/// Input parameters for smoke_test tool
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SmokeTestArgs {
+    /// Hard timeout in seconds for each backend (1-3600). Defaults to 30 seconds.
+    #[serde(default)]
+    #[schemars(range(min = MIN_TIMEOUT_SECS, max = MAX_TIMEOUT_SECS), example = 30)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// Upper bound on `min_results`/`max_results` advertised in the web_search schema
+const MAX_SEARCH_RESULTS: i32 = 50;
+
 fn default_min_results() -> i32 {
     3
 }
@@ -199,29 +292,89 @@ fn default_max_results() -> i32 {
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct WebSearchArgs {
     /// Clear, self-contained natural-language search query. When helpful, include constraints such as topic, time range, language, or domain.
+    #[schemars(example = "Latest stable Rust release and its highlights")]
     pub query: String,
     /// Platforms to focus on searching, such as "Twitter", "GitHub", "Reddit", etc.
+    /// Accepts a comma-separated list ("GitHub,Reddit"), or "all" to explicitly search every platform.
     #[serde(default)]
+    #[schemars(example = "GitHub,Reddit")]
     pub platform: Option<String>,
-    /// Minimum number of results to return
+    /// Minimum number of results to return (relaxed when `platform` restricts the search)
     #[serde(default = "default_min_results")]
+    #[schemars(range(min = 0, max = MAX_SEARCH_RESULTS), example = 3)]
     pub min_results: i32,
     /// Maximum number of results to return
     #[serde(default = "default_max_results")]
+    #[schemars(range(min = 1, max = MAX_SEARCH_RESULTS), example = 10)]
     pub max_results: i32,
     /// The Grok model to use for this search. If not specified, uses GROK_MODEL environment variable or defaults to grok-4.20-beta.
     #[serde(default)]
     pub model: Option<String>,
+    /// Total timeout in seconds for this search, including retries. Overrides GROK_TOTAL_TIMEOUT (default: 300); capped at 3600.
+    #[serde(default)]
+    #[schemars(range(min = MIN_TIMEOUT_SECS, max = MAX_TIMEOUT_SECS), example = 60)]
+    pub timeout_secs: Option<u64>,
+    /// Maximum retry attempts for this search. Overrides GROK_RETRY_MAX_ATTEMPTS (default: 3); capped at 10.
+    #[serde(default)]
+    #[schemars(range(max = grok::provider::MAX_RETRIES_LIMIT), example = 1)]
+    pub max_retries: Option<u32>,
+    /// Force the current date/time to be added to the search request (true) or left out (false).
+    /// When omitted, it is added only if the query contains time-related words like "latest" or "today".
+    #[serde(default)]
+    pub time_context: Option<bool>,
+    /// Send each result as a progress notification (a JSON object) as soon as it has been received in full.
+    /// Requires the request to carry a progressToken. The final result still contains every result.
+    #[serde(default)]
+    pub stream_results: bool,
+    /// Grok-compatible API base URL for this call only, overriding GROK_API_URL. Requires api_key
+    #[serde(default)]
+    pub api_url: Option<String>,
+    /// API key for this call only, overriding GROK_API_KEY. Never logged
+    #[serde(default)]
+    pub api_key: Option<grok::tools::ApiKey>,
 }
 
 /// Input parameters for web_fetch tool
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct WebFetchArgs {
     /// A valid HTTP/HTTPS web address pointing to the target page
+    #[schemars(example = "https://www.rust-lang.org/learn")]
     pub url: String,
     /// The Grok model to use for this fetch. If not specified, uses GROK_MODEL environment variable or defaults to grok-4.20-beta.
     #[serde(default)]
     pub model: Option<String>,
+    /// Output mode: "markdown" (default) extracts the page via Grok; "raw" fetches the URL directly and returns the unmodified HTML/text; "extract" fetches directly and returns the page's main text with navigation, ads and scripts removed, without a model call. Raw and extract modes refuse local and private addresses.
+    #[serde(default)]
+    #[schemars(example = &"extract")]
+    pub mode: Option<String>,
+    /// Send a HEAD request first and refuse to fetch when the URL serves a binary content type (images, PDFs, archives, ...). Defaults to false.
+    #[serde(default)]
+    pub reject_binary: bool,
+    /// Return a JSON object {"metadata": {...}, "content": "..."} with the page's front-matter (source, title, fetched_at) parsed out of the Markdown. Markdown mode only. Defaults to false.
+    #[serde(default)]
+    pub include_metadata: bool,
+    /// Ask only for the page's metadata (title, description, author, published_date, canonical_url) as a JSON object, skipping full-content extraction. Markdown mode only; cannot be combined with include_metadata. Defaults to false.
+    #[serde(default)]
+    pub metadata_only: bool,
+    /// Maximum retry attempts for this fetch (markdown mode). Overrides GROK_RETRY_MAX_ATTEMPTS (default: 3); capped at 10.
+    #[serde(default)]
+    #[schemars(range(max = grok::provider::MAX_RETRIES_LIMIT), example = 1)]
+    pub max_retries: Option<u32>,
+    /// Grok-compatible API base URL for this fetch only (markdown mode), overriding GROK_API_URL.
+    /// Requires api_key
+    #[serde(default)]
+    pub api_url: Option<String>,
+    /// API key for this fetch only (markdown mode), overriding GROK_API_KEY. Never logged
+    #[serde(default)]
+    pub api_key: Option<grok::tools::ApiKey>,
+}
+
+/// Input parameters for web_fetch_head tool
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct WebFetchHeadArgs {
+    /// A valid HTTP/HTTPS web address to check
+    #[schemars(example = "https://www.rust-lang.org/learn")]
+    pub url: String,
 }
 
 // ---------------------------------------------------------------------------
@@ -261,30 +414,205 @@ fn attach_warnings(error_msg: String, warnings: Option<String>) -> String {
 fn build_codex_output(
     result: &codex::CodexResult,
     return_all_messages: bool,
-    warnings: Option<String>,
+    warnings: Option<Vec<String>>,
 ) -> CodexOutput {
     codex::build_codex_output(result, return_all_messages, warnings)
 }
 
+/// Output for a batch task that failed before codex produced a result
+fn failed_codex_output(error: String) -> CodexOutput {
+    let result = codex::CodexResult {
+        error: Some(error),
+        ..codex::CodexResult::default()
+    };
+    build_codex_output(&result, false, None)
+}
+
+/// Runs `tasks` one after another through `run_task`, returning one output per
+/// task in order. A task that fails is reported in its own output and does not
+/// stop the rest of the batch.
+async fn run_codex_batch<F, Fut>(tasks: Vec<CodexArgs>, mut run_task: F) -> Vec<CodexOutput>
+where
+    F: FnMut(CodexArgs) -> Fut,
+    Fut: std::future::Future<Output = Result<CodexOutput, McpError>>,
+{
+    let mut outputs = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let output = match run_task(task).await {
+            Ok(output) => output,
+            Err(e) => failed_codex_output(e.message.into_owned()),
+        };
+        outputs.push(output);
+    }
+    outputs
+}
+
+/// Tool result carrying `value` as structured content, with `text` kept as the
+/// `content` fallback for clients that do not read structured content
+fn structured_result(text: String, value: serde_json::Value) -> CallToolResult {
+    let mut result = CallToolResult::success(vec![Content::text(text)]);
+    result.structured_content = Some(value);
+    result
+}
+
+/// Serialize `output` both as the JSON text fallback and as structured content
+fn json_result<T: serde::Serialize>(output: &T) -> Result<CallToolResult, McpError> {
+    let serialize_error = |e: serde_json::Error| {
+        McpError::internal_error(format!("Failed to serialize output: {}", e), None)
+    };
+    let text = serde_json::to_string(output).map_err(serialize_error)?;
+    let value = serde_json::to_value(output).map_err(serialize_error)?;
+    Ok(structured_result(text, value))
+}
+
+/// Text result that also carries the text as structured content when it is a JSON object
+fn text_or_structured_result(text: String) -> CallToolResult {
+    match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(value @ serde_json::Value::Object(_)) => structured_result(text, value),
+        _ => CallToolResult::success(vec![Content::text(text)]),
+    }
+}
+
+/// Structured content for a successful gemini run, mirroring the fields of the text output
+fn gemini_structured_content(
+    result: &gemini::GeminiResult,
+    timeout_secs: u64,
+    return_all_messages: bool,
+) -> serde_json::Value {
+    let mut value = serde_json::json!({
+        "success": true,
+        "SESSION_ID": result.session_id,
+        "agent_messages": result.agent_messages,
+        "timeout_secs": timeout_secs,
+    });
+    if result.agent_messages_truncated {
+        value["agent_messages_truncated"] = true.into();
+    }
+    if let Some(ref warning) = result.warning {
+        value["warning"] = warning.clone().into();
+    }
+    if let Some(ref warnings) = result.warnings {
+        value["warnings"] = warnings.clone().into();
+    }
+    if return_all_messages && !result.all_messages.is_empty() {
+        value["all_messages"] = result.all_messages.clone().into();
+    }
+    value
+}
+
 fn apply_security_restrictions(
     mut args: CodexArgs,
     security: &SecurityConfig,
 ) -> (CodexArgs, Vec<String>) {
-    let mut sandbox = args.sandbox.clone();
+    let mut sandbox = args.sandbox.clone().unwrap_or_default();
     let mut yolo = args.yolo;
     let mut skip_git_repo_check = args.skip_git_repo_check;
+    let mut approval_policy = args.approval_policy.take();
     let warnings = codex::apply_security_restrictions(
         &mut sandbox,
         &mut yolo,
         &mut skip_git_repo_check,
+        &mut approval_policy,
         security,
     );
-    args.sandbox = sandbox;
+    args.sandbox = Some(sandbox);
     args.yolo = yolo;
     args.skip_git_repo_check = skip_git_repo_check;
+    args.approval_policy = approval_policy;
     (args, warnings)
 }
 
+// ---------------------------------------------------------------------------
+// Progress notifications
+// ---------------------------------------------------------------------------
+
+/// Build a gemini content sink that forwards each chunk to the client as a progress
+/// notification. The returned task finishes once every clone of the sink is dropped.
+fn progress_sink(
+    peer: Peer<RoleServer>,
+    progress_token: ProgressToken,
+) -> (gemini::ContentSink, tokio::task::JoinHandle<()>) {
+    let (tx, forwarder) = progress_channel(peer, progress_token);
+    let sink = gemini::ContentSink::new(move |chunk: &str| {
+        tx.send(chunk.to_string());
+    });
+    (sink, forwarder)
+}
+
+/// Build a search result sink that sends each result to the client as a progress
+/// notification. The returned task finishes once the sink is dropped.
+fn search_result_sink(
+    peer: Peer<RoleServer>,
+    progress_token: ProgressToken,
+) -> (grok::tools::SearchResultSink, tokio::task::JoinHandle<()>) {
+    let (tx, forwarder) = progress_channel(peer, progress_token);
+    let sink: grok::tools::SearchResultSink = Arc::new(move |result: &serde_json::Value| {
+        tx.send(result.to_string());
+    });
+    (sink, forwarder)
+}
+
+/// Progress messages buffered for a slow client before new ones are dropped
+const PROGRESS_QUEUE_CAPACITY: usize = 256;
+
+/// Sending half of a progress channel. Never blocks the producer: when the queue
+/// is full the message is dropped, since progress is advisory and the tool result
+/// still carries the complete output.
+#[derive(Clone)]
+struct ProgressSender {
+    tx: tokio::sync::mpsc::Sender<String>,
+    dropped: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl ProgressSender {
+    fn send(&self, message: String) {
+        if let Err(tokio::sync::mpsc::error::TrySendError::Full(_)) = self.tx.try_send(message) {
+            self.dropped
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+/// Spawn a task sending each message on the returned channel as a progress
+/// notification, finishing once every sender is dropped.
+fn progress_channel(
+    peer: Peer<RoleServer>,
+    progress_token: ProgressToken,
+) -> (ProgressSender, tokio::task::JoinHandle<()>) {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<String>(PROGRESS_QUEUE_CAPACITY);
+    let dropped = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let sender = ProgressSender {
+        tx,
+        dropped: dropped.clone(),
+    };
+    let forwarder = tokio::spawn(async move {
+        let mut progress = 0.0;
+        while let Some(chunk) = rx.recv().await {
+            progress += 1.0;
+            let param = ProgressNotificationParam {
+                progress_token: progress_token.clone(),
+                progress,
+                total: None,
+                message: Some(chunk),
+            };
+            if let Err(e) = peer.notify_progress(param).await {
+                eprintln!(
+                    "ikuncode-aimcp: failed to send progress notification: {}",
+                    e
+                );
+            }
+        }
+        let dropped = dropped.load(std::sync::atomic::Ordering::Relaxed);
+        if dropped > 0 {
+            eprintln!(
+                "ikuncode-aimcp: dropped {} progress notification(s) the client was too slow to receive",
+                dropped
+            );
+        }
+    });
+    (sender, forwarder)
+}
+
 // ---------------------------------------------------------------------------
 // URI helpers
 // ---------------------------------------------------------------------------
@@ -292,9 +620,12 @@ fn apply_security_restrictions(
 /// Convert a `file://` URI to a local [`PathBuf`].
 ///
 /// Handles both Unix (`file:///home/user`) and Windows (`file:///D:/path`)
-/// forms.  Returns `None` for non-file URIs or malformed strings.
-fn file_uri_to_path(uri: &str) -> Option<PathBuf> {
-    let path_str = uri.strip_prefix("file://")?;
+/// forms.  Returns an error describing the problem for non-file URIs or
+/// malformed strings.
+fn file_uri_to_path(uri: &str) -> Result<PathBuf, String> {
+    let path_str = uri
+        .strip_prefix("file://")
+        .ok_or_else(|| format!("not a file:// URI: {:?}", uri))?;
     // On Windows, file:///D:/foo → strip the leading '/' before the drive letter
     #[cfg(windows)]
     let path_str = path_str
@@ -302,9 +633,265 @@ fn file_uri_to_path(uri: &str) -> Option<PathBuf> {
         .filter(|s| s.chars().nth(1) == Some(':'))
         .unwrap_or(path_str);
     if path_str.is_empty() {
-        return None;
+        return Err(format!("file URI has an empty path: {:?}", uri));
+    }
+    Ok(PathBuf::from(path_str))
+}
+
+/// Resolve root URIs to paths, keeping the error for each root that failed.
+fn resolve_root_uris<'a>(uris: impl IntoIterator<Item = &'a str>) -> (Vec<PathBuf>, Vec<String>) {
+    let mut dirs = Vec::new();
+    let mut errors = Vec::new();
+    for uri in uris {
+        match file_uri_to_path(uri) {
+            Ok(path) => dirs.push(path),
+            Err(e) => errors.push(e),
+        }
+    }
+    (dirs, errors)
+}
+
+/// Apply a roots refresh. A refresh with at least as many roots as are held replaces
+/// them; a smaller one is merged with them (resolved roots first), so roots whose
+/// URIs failed to resolve this time stay usable. When every URI failed to resolve
+/// the current roots are kept as they are.
+fn apply_roots_refresh(roots: &mut Vec<PathBuf>, dirs: Vec<PathBuf>, errors: &[String]) {
+    for e in errors {
+        eprintln!("ikuncode-aimcp: ignoring workspace root: {}", e);
+    }
+    if dirs.is_empty() {
+        if !errors.is_empty() {
+            eprintln!(
+                "ikuncode-aimcp: warning: all {} workspace root(s) from MCP client failed to resolve; keeping existing roots",
+                errors.len()
+            );
+        }
+        return;
+    }
+    if !errors.is_empty() {
+        eprintln!(
+            "ikuncode-aimcp: dropped {} of {} workspace root(s) from MCP client",
+            errors.len(),
+            dirs.len() + errors.len()
+        );
+    }
+    eprintln!(
+        "ikuncode-aimcp: received {} workspace root(s) from MCP client",
+        dirs.len()
+    );
+    if dirs.len() >= roots.len() {
+        *roots = dirs;
+        return;
+    }
+    let mut merged = dirs;
+    for root in roots.drain(..) {
+        if !merged.contains(&root) {
+            merged.push(root);
+        }
+    }
+    eprintln!(
+        "ikuncode-aimcp: refresh returned fewer roots than before; merged into {} root(s)",
+        merged.len()
+    );
+    *roots = merged;
+}
+
+// ---------------------------------------------------------------------------
+// Tool schema draft
+// ---------------------------------------------------------------------------
+
+/// Selects the JSON Schema draft tool input schemas are presented in
+const ENV_SCHEMA_DRAFT: &str = "AIMCP_SCHEMA_DRAFT";
+
+const DRAFT_07_URI: &str = "http://json-schema.org/draft-07/schema#";
+
+/// JSON Schema draft of the tool schemas sent to MCP clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchemaDraft {
+    /// schemars output as is, with `$defs` and `$ref`s
+    #[default]
+    Draft2020_12,
+    /// Local `$ref`s inlined, for clients that don't resolve references
+    Draft07,
+}
+
+impl SchemaDraft {
+    /// Parse `2020-12` or `draft-07` (also `7` / `07`), case-insensitively
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "2020-12" | "draft-2020-12" => Some(Self::Draft2020_12),
+            "7" | "07" | "draft-07" | "draft7" => Some(Self::Draft07),
+            _ => None,
+        }
+    }
+
+    /// Read `AIMCP_SCHEMA_DRAFT`, warning about and ignoring unknown values
+    pub fn from_env() -> Self {
+        let Some(value) = env_override(ENV_SCHEMA_DRAFT) else {
+            return Self::default();
+        };
+        Self::parse(&value).unwrap_or_else(|| {
+            eprintln!(
+                "ikuncode-aimcp: ignoring {}={}; expected 2020-12 or draft-07",
+                ENV_SCHEMA_DRAFT, value
+            );
+            Self::default()
+        })
+    }
+}
+
+/// Rewrite a schemars-generated schema for `draft`.
+///
+/// For draft-07 every `#/$defs/...` reference is replaced by a copy of its
+/// definition (keys next to the `$ref`, such as `description`, win). Recursive
+/// references can't be inlined; they are pointed at a draft-07 `definitions`
+/// section instead, which is only kept when such a reference remains.
+pub fn convert_schema(schema: &JsonObject, draft: SchemaDraft) -> JsonObject {
+    if draft == SchemaDraft::Draft2020_12 {
+        return schema.clone();
+    }
+
+    let mut schema = schema.clone();
+    let defs = match schema.remove("$defs") {
+        Some(serde_json::Value::Object(defs)) => defs,
+        _ => JsonObject::new(),
+    };
+    let mut root = serde_json::Value::Object(schema);
+    let mut kept_recursive = false;
+    inline_refs(&mut root, &defs, &mut Vec::new(), &mut kept_recursive);
+
+    let serde_json::Value::Object(mut schema) = root else {
+        unreachable!("schema root stays an object");
+    };
+    if kept_recursive {
+        let mut definitions = serde_json::Value::Object(defs);
+        retarget_refs(&mut definitions);
+        schema.insert("definitions".to_string(), definitions);
+    }
+    schema.insert("$schema".to_string(), DRAFT_07_URI.into());
+    schema
+}
+
+fn inline_refs(
+    value: &mut serde_json::Value,
+    defs: &JsonObject,
+    stack: &mut Vec<String>,
+    kept_recursive: &mut bool,
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let name = map
+                .get("$ref")
+                .and_then(|r| r.as_str())
+                .and_then(|r| r.strip_prefix("#/$defs/"))
+                .map(str::to_string);
+            if let Some(name) = name {
+                match defs.get(&name) {
+                    Some(def) if !stack.contains(&name) => {
+                        let mut inlined = def.clone();
+                        stack.push(name);
+                        inline_refs(&mut inlined, defs, stack, kept_recursive);
+                        stack.pop();
+                        map.remove("$ref");
+                        if let serde_json::Value::Object(def_map) = inlined {
+                            for (key, def_value) in def_map {
+                                map.entry(key).or_insert(def_value);
+                            }
+                        }
+                    }
+                    Some(_) => {
+                        *kept_recursive = true;
+                        map.insert("$ref".to_string(), format!("#/definitions/{}", name).into());
+                    }
+                    None => {}
+                }
+            }
+            for (key, child) in map.iter_mut() {
+                if key != "$ref" {
+                    inline_refs(child, defs, stack, kept_recursive);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                inline_refs(item, defs, stack, kept_recursive);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Point `#/$defs/` references at `#/definitions/`
+fn retarget_refs(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                match (key.as_str(), child.as_str()) {
+                    ("$ref", Some(r)) if r.starts_with("#/$defs/") => {
+                        *child = r.replacen("#/$defs/", "#/definitions/", 1).into();
+                    }
+                    _ => retarget_refs(child),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(retarget_refs),
+        _ => {}
     }
-    Some(PathBuf::from(path_str))
+}
+
+// ---------------------------------------------------------------------------
+// Server identity
+// ---------------------------------------------------------------------------
+
+/// Overrides the server name presented to MCP clients
+const ENV_SERVER_NAME: &str = "AIMCP_SERVER_NAME";
+/// Overrides the instructions presented to MCP clients
+const ENV_INSTRUCTIONS: &str = "AIMCP_INSTRUCTIONS";
+
+const DEFAULT_INSTRUCTIONS: &str = "Unified AI MCP server providing gemini, codex, and grok search tools. \
+     Use 'gemini' for AI-driven tasks via Gemini CLI, 'codex' for AI-assisted coding \
+     via Codex CLI, 'codex_kill' to stop a running codex session, 'web_search' for web searches, 'web_fetch' for fetching web content, \
+     and 'get_config_info' for configuration status.";
+
+/// Skips the roots/list request sent to the client after initialization
+const ENV_DISABLE_ROOTS: &str = "AIMCP_DISABLE_ROOTS";
+/// How long to wait for the client's roots/list response, in milliseconds
+const ENV_ROOTS_TIMEOUT_MS: &str = "AIMCP_ROOTS_TIMEOUT_MS";
+const DEFAULT_ROOTS_TIMEOUT_MS: u64 = 3000;
+
+/// Whether and how long to wait for workspace roots after initialization
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RootsFetch {
+    enabled: bool,
+    timeout: std::time::Duration,
+}
+
+impl RootsFetch {
+    fn from_env() -> Self {
+        let disabled = codex::resolve_env_bool(
+            ENV_DISABLE_ROOTS,
+            std::env::var(ENV_DISABLE_ROOTS).ok(),
+            &mut Vec::new(),
+        )
+        .unwrap_or(false);
+        let timeout_ms = std::env::var(ENV_ROOTS_TIMEOUT_MS)
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(DEFAULT_ROOTS_TIMEOUT_MS);
+        Self {
+            enabled: !disabled,
+            timeout: std::time::Duration::from_millis(timeout_ms),
+        }
+    }
+}
+
+/// Non-empty value of an identity override env var
+fn env_override(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
 }
 
 // ---------------------------------------------------------------------------
@@ -314,347 +901,129 @@ fn file_uri_to_path(uri: &str) -> Option<PathBuf> {
 #[derive(Clone)]
 pub struct UnifiedServer {
     tool_router: ToolRouter<UnifiedServer>,
-    capabilities: Capabilities,
+    /// Detected backends, swapped in place by the `redetect` tool.
+    capabilities: Arc<std::sync::RwLock<Capabilities>>,
     /// MCP client workspace roots, populated during on_initialized via roots/list request.
     /// Passed to Gemini CLI as --include-directories to allow file access beyond CWD.
     roots: Arc<RwLock<Vec<PathBuf>>>,
+    /// Running codex children keyed by SESSION_ID, used by `codex_kill`.
+    codex_sessions: codex::SessionRegistry,
+    /// Per-SESSION_ID locks that keep concurrent resumes of a session apart.
+    codex_session_locks: codex::SessionLocks,
+    /// Framing format detected by the transport, reported by `get_config_info`.
+    framing_format: transport::SharedFramingFormat,
 }
 
 impl UnifiedServer {
     pub fn new(capabilities: Capabilities) -> Self {
+        let mut tool_router = Self::tool_router();
+        let draft = SchemaDraft::from_env();
+        if draft != SchemaDraft::default() {
+            for route in tool_router.map.values_mut() {
+                let tool = &mut route.attr;
+                tool.input_schema = Arc::new(convert_schema(&tool.input_schema, draft));
+                if let Some(output) = tool.output_schema.as_mut() {
+                    *output = Arc::new(convert_schema(output, draft));
+                }
+            }
+        }
+        if let Some(log) = audit::AuditLog::from_env() {
+            for route in tool_router.map.values_mut() {
+                route.call = audit::audited(route.call.clone(), log.clone());
+            }
+        }
         Self {
-            tool_router: Self::tool_router(),
-            capabilities,
+            tool_router,
+            capabilities: Arc::new(std::sync::RwLock::new(capabilities)),
             roots: Arc::new(RwLock::new(Vec::new())),
+            codex_sessions: codex::SessionRegistry::new(),
+            codex_session_locks: codex::SessionLocks::new(),
+            framing_format: transport::SharedFramingFormat::default(),
         }
     }
 
-    /// Shared helper for running Gemini CLI and formatting the result.
-    /// Used by both `gemini` and `gemini_image` tools.
-    async fn run_gemini(
-        opts: gemini::Options,
-        return_all_messages: bool,
-    ) -> Result<CallToolResult, McpError> {
-        let result = match gemini::run(opts).await {
-            Ok(r) => r,
-            Err(e) => {
-                return Err(McpError::internal_error(
-                    format!("Failed to execute gemini: {}", e),
-                    None,
-                ));
-            }
-        };
-
-        if result.success {
-            let mut response_text = format!(
-                "success: true\nSESSION_ID: {}\nagent_messages: {}",
-                result.session_id, result.agent_messages
-            );
-
-            if return_all_messages && !result.all_messages.is_empty() {
-                response_text.push_str(&format!(
-                    "\nall_messages: {} events captured",
-                    result.all_messages.len()
-                ));
-                if let Ok(json) = serde_json::to_string_pretty(&result.all_messages) {
-                    response_text.push_str(&format!("\n\nFull event log:\n{}", json));
-                }
-            }
+    /// Report the framing format detected by the transport given the same handle,
+    /// see `AdaptiveStdio::with_shared_format`
+    pub fn with_framing_format(mut self, framing_format: transport::SharedFramingFormat) -> Self {
+        self.framing_format = framing_format;
+        self
+    }
 
-            Ok(CallToolResult::success(vec![Content::text(response_text)]))
-        } else {
-            let mut error_msg = result.error.unwrap_or_else(|| "Unknown error".to_string());
+    /// Snapshot of the live capabilities
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
 
-            if return_all_messages && !result.all_messages.is_empty() {
-                error_msg.push_str(&format!(
-                    "\n\nCaptured {} events before failure:",
-                    result.all_messages.len()
-                ));
-                if let Ok(json) = serde_json::to_string_pretty(&result.all_messages) {
-                    error_msg.push_str(&format!("\n{}", json));
-                }
-            }
+    /// Re-run backend detection and swap the result in for subsequent tool calls
+    fn redetect_capabilities(&self) -> Capabilities {
+        let caps = detection::detect();
+        *self.capabilities.write().unwrap_or_else(|e| e.into_inner()) = caps.clone();
+        caps
+    }
 
-            Err(McpError::internal_error(error_msg, None))
-        }
+    /// Names of the tools whose backend is available in this process
+    fn enabled_tools(&self) -> Vec<&'static str> {
+        let caps = self.capabilities();
+        let gemini_image_configured =
+            gemini::get_api_url().is_some() && gemini::get_image_api_key().is_some();
+        [
+            ("gemini", caps.gemini_available),
+            ("gemini_image", gemini_image_configured),
+            ("codex", caps.codex_available),
+            ("codex_batch", caps.codex_available),
+            ("codex_kill", caps.codex_available),
+            ("web_search", caps.grok_available),
+            ("web_fetch", caps.grok_available),
+            ("web_fetch_head", true),
+            ("list_models", caps.grok_available),
+            ("get_config_info", true),
+            ("smoke_test", true),
+            ("redetect", true),
+            ("describe_tools", true),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
     }
-}
 
-#[tool_router]
-impl UnifiedServer {
-    /// Invokes the Gemini CLI to execute AI-driven tasks, returning structured JSON events and a session identifier for conversation continuity.
-    ///
-    /// **Return structure:**
-    /// - `success`: boolean indicating execution status
-    /// - `SESSION_ID`: unique identifier for resuming this conversation in future calls
-    /// - `agent_messages`: concatenated assistant response text
-    /// - `all_messages`: (optional) complete array of JSON events when `return_all_messages=True`
-    /// - `error`: error description when `success=False`
-    ///
-    /// **Best practices:**
-    /// - Always capture and reuse `SESSION_ID` for multi-turn interactions
-    /// - Enable `sandbox` mode when file modifications should be isolated
-    /// - Use `return_all_messages` only when detailed execution traces are necessary (increases payload size)
-    #[tool(
-        name = "gemini",
-        description = "Invokes the Gemini CLI to execute AI-driven tasks, returning structured JSON events and a session identifier for conversation continuity."
-    )]
-    async fn gemini(
-        &self,
-        Parameters(args): Parameters<GeminiArgs>,
-    ) -> Result<CallToolResult, McpError> {
-        if !self.capabilities.gemini_available {
-            return Err(McpError::internal_error(
-                "Gemini CLI not found in PATH. Install gemini CLI or set GEMINI_BIN env var.",
+    /// Validates one codex task, runs it and builds its output. Shared by
+    /// `codex` and `codex_batch`.
+    async fn run_codex(&self, mut args: CodexArgs) -> Result<CodexOutput, McpError> {
+        let mut security_warnings = Vec::new();
+        let security = get_security_config(&mut security_warnings);
+
+        if args.prompt.is_empty() {
+            return Err(McpError::invalid_params(
+                "PROMPT is required and must be a non-empty string",
                 None,
             ));
         }
 
-        if args.prompt.trim().is_empty() {
+        if args.cd.as_os_str().is_empty() {
             return Err(McpError::invalid_params(
-                "PROMPT is required and must be a non-empty, non-whitespace string",
+                "cd is required and must be a non-empty string",
                 None,
             ));
         }
 
-        if let Some(ref model) = args.model {
-            if model.trim().is_empty() {
-                return Err(McpError::invalid_params(
-                    "Model overrides must be explicitly requested as a non-empty, non-whitespace string",
-                    None,
-                ));
-            }
+        if args.cd.is_relative() {
+            let roots = self.roots.read().await.clone();
+            let policy = codex::get_relative_cd_policy(&mut security_warnings);
+            args.cd = codex::resolve_relative_cd(&args.cd, &roots, policy, &mut security_warnings)
+                .map_err(|e| McpError::invalid_params(e, None))?;
         }
 
-        if let Some(timeout) = args.timeout_secs {
-            if !(MIN_TIMEOUT_SECS..=MAX_TIMEOUT_SECS).contains(&timeout) {
-                return Err(McpError::invalid_params(
-                    format!(
-                        "timeout_secs must be between {} and {} seconds",
-                        MIN_TIMEOUT_SECS, MAX_TIMEOUT_SECS
-                    ),
-                    None,
-                ));
-            }
-        }
-
-        let session_id = args.session_id.filter(|s| !s.is_empty());
-        let model = args.model.filter(|m| !m.trim().is_empty());
-
-        // Read MCP client roots to pass as --include-directories to Gemini CLI
-        let include_directories = self.roots.read().await.clone();
-
-        let opts = gemini::Options {
-            prompt: args.prompt,
-            sandbox: args.sandbox,
-            session_id,
-            return_all_messages: args.return_all_messages,
-            model,
-            timeout_secs: args.timeout_secs,
-            include_directories,
-            api_key: gemini::get_api_key(),
-            api_base_url: gemini::get_api_url(),
-        };
-
-        Self::run_gemini(opts, args.return_all_messages).await
-    }
-
-    /// Generates images using the Gemini API directly (not via CLI).
-    /// Returns the generated image(s) as base64-encoded content along with any text response.
-    ///
-    /// **Return structure:**
-    /// - Image content(s) as base64-encoded data with mime type
-    /// - Text description from the model (if any)
-    ///
-    /// **Required environment variables:**
-    /// - `GEMINI_IMAGE_API_KEY`: API key for image generation
-    /// - `GEMINI_API_URL`: Base URL for the Gemini API
-    /// - `GEMINI_IMAGE_MODEL`: Model name for image generation (e.g. "gemini-3-pro-image-preview")
-    #[tool(
-        name = "gemini_image",
-        description = "Invokes the Gemini CLI for image generation tasks, using a dedicated image generation model configured via GEMINI_IMAGE_MODEL environment variable."
-    )]
-    async fn gemini_image(
-        &self,
-        Parameters(args): Parameters<GeminiImageArgs>,
-    ) -> Result<CallToolResult, McpError> {
-        if args.prompt.trim().is_empty() {
-            return Err(McpError::invalid_params(
-                "PROMPT is required and must be a non-empty, non-whitespace string",
-                None,
-            ));
-        }
-
-        let api_url = gemini::get_api_url().ok_or_else(|| {
-            McpError::internal_error(
-                "GEMINI_API_URL environment variable is not set",
-                None,
-            )
-        })?;
-
-        let api_key = gemini::get_image_api_key().ok_or_else(|| {
-            McpError::internal_error(
-                "GEMINI_IMAGE_API_KEY environment variable is not set",
-                None,
-            )
-        })?;
-
-        let model = args
-            .model
-            .filter(|m| !m.trim().is_empty())
-            .or_else(|| gemini::get_image_model())
-            .ok_or_else(|| {
-                McpError::internal_error(
-                    "No model specified and GEMINI_IMAGE_MODEL environment variable is not set",
-                    None,
-                )
-            })?;
-
-        match gemini_image_api::generate_image(
-            &api_url,
-            &api_key,
-            &model,
-            &args.prompt,
-            args.aspect_ratio.as_deref(),
-            args.image_size.as_deref(),
-        )
-        .await
-        {
-            Ok(result) => {
-                let mut contents: Vec<Content> = Vec::new();
-                let mut saved_paths: Vec<String> = Vec::new();
-
-                for (idx, (data, mime_type)) in result.images.iter().enumerate() {
-                    contents.push(Content::image(data.as_str(), mime_type.as_str()));
-
-                    // Save image to current working directory
-                    let ext = match mime_type.as_str() {
-                        "image/png" => "png",
-                        "image/jpeg" | "image/jpg" => "jpg",
-                        "image/webp" => "webp",
-                        "image/gif" => "gif",
-                        _ => "png",
-                    };
-                    let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
-                    let filename = if result.images.len() == 1 {
-                        format!("gemini_image_{}.{}", timestamp, ext)
-                    } else {
-                        format!("gemini_image_{}_{}.{}", timestamp, idx + 1, ext)
-                    };
-
-                    match base64::Engine::decode(
-                        &base64::engine::general_purpose::STANDARD,
-                        data,
-                    ) {
-                        Ok(bytes) => {
-                            // Save directory priority:
-                            // 1. output_dir parameter (caller specifies)
-                            // 2. First MCP workspace root
-                            // 3. Current working directory
-                            let save_dir = if let Some(dir) = args
-                                .output_dir
-                                .as_ref()
-                                .filter(|s| !s.trim().is_empty())
-                            {
-                                PathBuf::from(dir)
-                            } else {
-                                self.roots
-                                    .read()
-                                    .await
-                                    .first()
-                                    .cloned()
-                                    .unwrap_or_else(|| {
-                                        std::env::current_dir()
-                                            .unwrap_or_else(|_| PathBuf::from("."))
-                                    })
-                            };
-                            let save_path = save_dir.join(&filename);
-                            match std::fs::write(&save_path, &bytes) {
-                                Ok(_) => {
-                                    let abs_path = save_path
-                                        .canonicalize()
-                                        .unwrap_or(save_path)
-                                        .display()
-                                        .to_string();
-                                    saved_paths.push(abs_path);
-                                }
-                                Err(e) => {
-                                    eprintln!("[gemini_image] Failed to save {}: {}", filename, e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("[gemini_image] Failed to decode base64: {}", e);
-                        }
-                    }
-                }
-
-                if let Some(ref text) = result.text {
-                    contents.push(Content::text(text));
-                }
-
-                if !saved_paths.is_empty() {
-                    let paths_text = saved_paths
-                        .iter()
-                        .map(|p| format!("- {}", p))
-                        .collect::<Vec<_>>()
-                        .join("\n");
-                    contents.push(Content::text(format!(
-                        "Saved to:\n{}",
-                        paths_text
-                    )));
-                }
-
-                if contents.is_empty() {
-                    contents.push(Content::text("Image generation completed but no content was returned."));
-                }
-
-                Ok(CallToolResult::success(contents))
-            }
-            Err(e) => Err(McpError::internal_error(
-                format!("Image generation failed: {}", e),
-                None,
-            )),
-        }
-    }
-
-    /// Executes a non-interactive Codex session via CLI to perform AI-assisted coding tasks in a secure workspace.
-    /// This tool wraps the 'codex exec' command, enabling model-driven code generation, debugging, or automation based on natural language prompts.
-    /// It supports resuming ongoing sessions for continuity and enforces sandbox policies to prevent unsafe operations.
-    #[tool(
-        name = "codex",
-        description = "Execute Codex CLI for AI-assisted coding tasks"
-    )]
-    async fn codex(
-        &self,
-        Parameters(args): Parameters<CodexArgs>,
-    ) -> Result<CallToolResult, McpError> {
-        if !self.capabilities.codex_available {
-            return Err(McpError::internal_error(
-                "Codex CLI not found in PATH. Install codex CLI or set CODEX_BIN env var.",
-                None,
-            ));
-        }
-
-        let mut security_warnings = Vec::new();
-        let security = get_security_config(&mut security_warnings);
-
-        if args.prompt.is_empty() {
-            return Err(McpError::invalid_params(
-                "PROMPT is required and must be a non-empty string",
-                None,
-            ));
-        }
-
-        if args.cd.as_os_str().is_empty() {
-            return Err(McpError::invalid_params(
-                "cd is required and must be a non-empty string",
-                None,
-            ));
-        }
-
-        let (mut args, restriction_warnings) = apply_security_restrictions(args, &security);
-        security_warnings.extend(restriction_warnings);
+        args.sandbox = Some(codex::get_sandbox(
+            args.sandbox.take(),
+            &mut security_warnings,
+        ));
+        let skip_git_repo_check_requested = args.skip_git_repo_check;
+        let (mut args, restriction_warnings) = apply_security_restrictions(args, &security);
+        security_warnings.extend(restriction_warnings);
+        let sandbox = args.sandbox.take().unwrap_or_default();
 
         match args.timeout_secs {
             None => {
@@ -685,6 +1054,15 @@ impl UnifiedServer {
             Some(_) => {}
         }
 
+        if args.return_all_messages {
+            let max_message_limit = codex::get_max_message_limit(&mut security_warnings);
+            args.return_all_messages_limit = Some(codex::resolve_message_limit(
+                args.return_all_messages_limit,
+                max_message_limit,
+                &mut security_warnings,
+            ));
+        }
+
         let working_dir = &args.cd;
         let canonical_working_dir = working_dir.canonicalize().map_err(|e| {
             McpError::invalid_params(
@@ -707,6 +1085,11 @@ impl UnifiedServer {
             ));
         }
 
+        let image_limits = codex::ImageLimits::from_env();
+        image_limits
+            .check_count(args.image.len())
+            .map_err(|e| McpError::invalid_params(e, None))?;
+
         let mut canonical_image_paths = Vec::new();
         for img_path in &args.image {
             let resolved_path = if img_path.is_absolute() {
@@ -735,21 +1118,95 @@ impl UnifiedServer {
 
             canonical_image_paths.push(canonical);
         }
+        image_limits
+            .check_sizes(&canonical_image_paths)
+            .map_err(|e| McpError::invalid_params(e, None))?;
+
+        let log_file = match args.log_file.as_deref() {
+            Some(path) if !path.as_os_str().is_empty() => {
+                let roots = self.roots.read().await.clone();
+                Some(
+                    codex::resolve_log_file(path, &canonical_working_dir, &roots)
+                        .map_err(|e| McpError::invalid_params(e, None))?,
+                )
+            }
+            _ => None,
+        };
+
+        let reasoning_effort = args
+            .reasoning_effort
+            .as_deref()
+            .filter(|v| !v.trim().is_empty())
+            .map(codex::validate_reasoning_effort)
+            .transpose()
+            .map_err(|e| McpError::invalid_params(e, None))?;
+        let verbosity = args
+            .verbosity
+            .as_deref()
+            .filter(|v| !v.trim().is_empty())
+            .map(codex::validate_verbosity)
+            .transpose()
+            .map_err(|e| McpError::invalid_params(e, None))?;
+        let approval_policy = args
+            .approval_policy
+            .as_deref()
+            .filter(|v| !v.trim().is_empty())
+            .map(codex::validate_approval_policy)
+            .transpose()
+            .map_err(|e| McpError::invalid_params(e, None))?;
+        let developer_instructions = args
+            .instructions
+            .as_deref()
+            .map(codex::validate_instructions)
+            .transpose()
+            .map_err(|e| McpError::invalid_params(e, None))?;
+
+        if let Some(profile) = args.profile.as_deref().filter(|p| !p.trim().is_empty()) {
+            if codex::get_validate_profile() {
+                codex::validate_profile(profile).map_err(|e| McpError::invalid_params(e, None))?;
+            }
+        }
+
+        let include_diff =
+            codex::get_include_diff() && (args.yolo || sandbox != SandboxPolicy::ReadOnly);
+        let diff_dir = canonical_working_dir.clone();
 
         let opts = codex::Options {
-            prompt: args.prompt,
+            prompt: codex::apply_prompt_affixes(args.prompt),
             working_dir: canonical_working_dir,
-            sandbox: args.sandbox,
+            sandbox,
             session_id: args.session_id,
             skip_git_repo_check: args.skip_git_repo_check,
+            skip_git_repo_check_denied: skip_git_repo_check_requested && !args.skip_git_repo_check,
             return_all_messages: args.return_all_messages,
             return_all_messages_limit: args.return_all_messages_limit,
+            return_turns: args.return_turns,
+            include_reasoning_summary: args.include_reasoning_summary,
             image_paths: canonical_image_paths,
-            model: args.model,
+            model: codex::resolve_model(args.model),
             yolo: args.yolo,
             profile: args.profile,
             timeout_secs: args.timeout_secs,
             force_stdin: args.force_stdin,
+            session_registry: Some(self.codex_sessions.clone()),
+            log_file,
+            reasoning_effort,
+            verbosity,
+            approval_policy,
+            developer_instructions,
+        };
+
+        let _session_lock = match opts.session_id.as_deref().filter(|id| !id.is_empty()) {
+            Some(session_id) => {
+                let policy = codex::get_concurrent_resume_policy(&mut security_warnings);
+                Some(
+                    self.codex_session_locks
+                        .acquire(session_id, policy)
+                        .await
+                        .map_err(|e| McpError::invalid_params(e, None))?,
+                )
+            }
+            None => None,
         };
 
         let result = match codex::run(opts).await {
@@ -762,94 +1219,862 @@ impl UnifiedServer {
             }
         };
 
-        let combined_warnings = merge_warnings(security_warnings.clone(), result.warnings.clone());
-        let output = build_codex_output(&result, args.return_all_messages, combined_warnings);
+        let combined_warnings =
+            codex::merge_warning_list(security_warnings.clone(), result.warnings.clone());
+        let mut output = build_codex_output(&result, args.return_all_messages, combined_warnings);
+        if include_diff && result.success {
+            output.diff_stat = codex::git_diff_stat(&diff_dir).await;
+        }
 
-        let json_output = serde_json::to_string(&output).map_err(|e| {
-            McpError::internal_error(format!("Failed to serialize output: {}", e), None)
-        })?;
+        Ok(output)
+    }
 
-        Ok(CallToolResult::success(vec![Content::text(json_output)]))
+    /// Description and input schema of every registered tool, keyed by name
+    fn tool_descriptions(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.tool_router
+            .list_all()
+            .into_iter()
+            .map(|tool| {
+                let entry = serde_json::json!({
+                    "description": tool.description,
+                    "input_schema": tool.input_schema.as_ref(),
+                });
+                (tool.name.into_owned(), entry)
+            })
+            .collect()
     }
 
-    /// Performs a third-party web search based on the given query and returns the results as a JSON string.
-    #[tool(
-        name = "web_search",
-        description = "Performs a third-party web search based on the given query and returns the results as a JSON string. The query should be a clear, self-contained natural-language search query."
-    )]
-    async fn web_search(
-        &self,
-        Parameters(args): Parameters<WebSearchArgs>,
-    ) -> Result<CallToolResult, McpError> {
-        if !self.capabilities.grok_available {
-            return Err(McpError::internal_error(
-                "GROK_API_URL or GROK_API_KEY not configured. Set both environment variables to enable web search.",
-                None,
-            ));
+    /// Ask the MCP client for its workspace roots via `list_roots` and apply them with
+    /// [`apply_roots_refresh`], unless fetching is disabled.
+    async fn refresh_roots<F, Fut, E>(&self, fetch: RootsFetch, list_roots: F)
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<ListRootsResult, E>>,
+        E: std::fmt::Display,
+    {
+        // Request workspace roots from the MCP client.
+        // These are passed to Gemini CLI as --include-directories so it can
+        // access files outside its inherited CWD (which MCP hosts may set to
+        // an internal directory like F:\Windsurf).
+        // Use a short timeout (AIMCP_ROOTS_TIMEOUT_MS) — some clients don't
+        // support roots/list and the call would block indefinitely without one.
+        if !fetch.enabled {
+            return;
         }
-
-        if args.query.trim().is_empty() {
-            return Err(McpError::invalid_params(
-                "query is required and must be a non-empty string",
-                None,
-            ));
+        match tokio::time::timeout(fetch.timeout, list_roots()).await {
+            Ok(Ok(roots_result)) => {
+                let (dirs, errors) =
+                    resolve_root_uris(roots_result.roots.iter().map(|root| root.uri.as_str()));
+                apply_roots_refresh(&mut *self.roots.write().await, dirs, &errors);
+            }
+            Ok(Err(e)) => {
+                eprintln!(
+                    "ikuncode-aimcp: failed to list roots from MCP client (non-fatal): {}",
+                    e
+                );
+            }
+            Err(_) => {
+                eprintln!(
+                    "ikuncode-aimcp: list_roots timed out (client may not support roots/list, non-fatal)"
+                );
+            }
         }
+    }
 
-        let platform = args.platform.unwrap_or_default();
-
-        match grok::tools::web_search(&args.query, &platform, args.min_results, args.max_results, args.model)
-            .await
-        {
-            Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
-            Err(e) => Err(McpError::internal_error(
-                format!("Web search failed: {}", e),
-                None,
-            )),
-        }
+    /// Server self-report included in `get_config_info`
+    fn server_info_report(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol_version": self.get_info().protocol_version,
+            "enabled_tools": self.enabled_tools(),
+            "framing_format": transport::describe_framing_format(&self.framing_format),
+        })
     }
 
-    /// Fetches and extracts the complete content from a specified URL and returns it as a structured Markdown document.
-    #[tool(
-        name = "web_fetch",
-        description = "Fetches and extracts the complete content from a specified URL and returns it as a structured Markdown document. The URL should be a valid HTTP/HTTPS web address."
-    )]
-    async fn web_fetch(
-        &self,
-        Parameters(args): Parameters<WebFetchArgs>,
+    /// Shared helper for running Gemini CLI and formatting the result.
+    /// Used by both `gemini` and `gemini_image` tools.
+    async fn run_gemini(
+        opts: gemini::Options,
+        return_all_messages: bool,
+        return_format: gemini::EventLogFormat,
     ) -> Result<CallToolResult, McpError> {
-        if !self.capabilities.grok_available {
-            return Err(McpError::internal_error(
-                "GROK_API_URL or GROK_API_KEY not configured. Set both environment variables to enable web fetch.",
-                None,
-            ));
-        }
+        gemini::validate_options(&opts).map_err(|e| McpError::invalid_params(e, None))?;
 
-        if args.url.trim().is_empty() {
-            return Err(McpError::invalid_params(
-                "url is required and must be a non-empty string",
+        let timeout_secs = gemini::effective_timeout_secs(opts.timeout_secs);
+        let result = match gemini::run(opts).await {
+            Ok(r) => r,
+            Err(e) => {
+                return Err(McpError::internal_error(
+                    format!("Failed to execute gemini: {}", e),
+                    None,
+                ));
+            }
+        };
+
+        if result.success {
+            let mut response_text = format!(
+                "success: true\nSESSION_ID: {}\nagent_messages: {}\ntimeout_secs: {}",
+                result.session_id, result.agent_messages, timeout_secs
+            );
+            if result.agent_messages_truncated {
+                response_text.push_str(&format!(
+                    "\nagent_messages_truncated: true (only the last {} characters are included; the full content was sent as progress notifications)",
+                    gemini::STREAMED_TAIL_CHARS
+                ));
+            }
+            if let Some(ref warning) = result.warning {
+                response_text.push_str(&format!("\nwarning: {}", warning));
+            }
+            if let Some(ref warnings) = result.warnings {
+                response_text.push_str(&format!("\nwarnings: {}", warnings));
+            }
+
+            if return_all_messages && !result.all_messages.is_empty() {
+                response_text.push_str(&format!(
+                    "\nall_messages: {} events captured",
+                    result.all_messages.len()
+                ));
+                if let Some(log) = gemini::format_event_log(&result.all_messages, return_format) {
+                    response_text.push_str(&format!("\n\nFull event log:\n{}", log));
+                }
+            }
+
+            let structured = gemini_structured_content(&result, timeout_secs, return_all_messages);
+            Ok(structured_result(response_text, structured))
+        } else {
+            let mut error_msg = result.error.unwrap_or_else(|| "Unknown error".to_string());
+            if let Some(ref code) = result.error_code {
+                error_msg.push_str(&format!("\nerror_code: {}", code));
+            }
+            if let Some(ref kind) = result.error_type {
+                error_msg.push_str(&format!("\nerror_type: {}", kind));
+            }
+
+            if return_all_messages && !result.all_messages.is_empty() {
+                error_msg.push_str(&format!(
+                    "\n\nCaptured {} events before failure:",
+                    result.all_messages.len()
+                ));
+                if let Some(log) = gemini::format_event_log(&result.all_messages, return_format) {
+                    error_msg.push_str(&format!("\n{}", log));
+                }
+            }
+
+            Err(McpError::internal_error(error_msg, None))
+        }
+    }
+}
+
+#[tool_router]
+impl UnifiedServer {
+    /// Invokes the Gemini CLI to execute AI-driven tasks, returning structured JSON events and a session identifier for conversation continuity.
+    ///
+    /// **Return structure:**
+    /// - `success`: boolean indicating execution status
+    /// - `SESSION_ID`: unique identifier for resuming this conversation in future calls
+    /// - `agent_messages`: concatenated assistant response text
+    /// - `all_messages`: (optional) complete array of JSON events when `return_all_messages=True`
+    /// - `error`: error description when `success=False`, followed by `error_code`/`error_type` when the CLI reported them
+    /// - `warnings`: (optional) stderr from a successful run when `GEMINI_INCLUDE_STDERR_WARNINGS` is enabled
+    /// - `timeout_secs`: the timeout the run used, after applying `GEMINI_DEFAULT_TIMEOUT`
+    ///
+    /// **Best practices:**
+    /// - Always capture and reuse `SESSION_ID` for multi-turn interactions
+    /// - Enable `sandbox` mode when file modifications should be isolated
+    /// - Use `return_all_messages` only when detailed execution traces are necessary (increases payload size)
+    #[tool(
+        name = "gemini",
+        description = "Invokes the Gemini CLI to execute AI-driven tasks, returning structured JSON events and a session identifier for conversation continuity."
+    )]
+    async fn gemini(
+        &self,
+        Parameters(args): Parameters<GeminiArgs>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        if !self.capabilities().gemini_available {
+            return Err(McpError::internal_error(
+                "Gemini CLI not found in PATH. Install gemini CLI or set GEMINI_BIN env var.",
+                None,
+            ));
+        }
+
+        if args.prompt.trim().is_empty() {
+            return Err(McpError::invalid_params(
+                "PROMPT is required and must be a non-empty, non-whitespace string",
+                None,
+            ));
+        }
+
+        if let Some(ref model) = args.model {
+            if model.trim().is_empty() {
+                return Err(McpError::invalid_params(
+                    "Model overrides must be explicitly requested as a non-empty, non-whitespace string",
+                    None,
+                ));
+            }
+        }
+
+        let session_id = args.session_id.filter(|s| !s.is_empty());
+        let model = args.model.filter(|m| !m.trim().is_empty());
+
+        // Read MCP client roots to pass as --include-directories to Gemini CLI
+        let include_directories = self.roots.read().await.clone();
+
+        let opts = gemini::Options {
+            prompt: gemini::apply_prompt_affixes(args.prompt),
+            sandbox: args.sandbox,
+            session_id,
+            return_all_messages: args.return_all_messages,
+            model,
+            timeout_secs: args.timeout_secs,
+            include_directories,
+            api_key: gemini::get_api_key(),
+            api_base_url: gemini::get_api_url(),
+            content_sink: None,
+        };
+
+        let progress_token = context
+            .meta
+            .get_progress_token()
+            .filter(|_| args.stream_agent_messages);
+        let Some(progress_token) = progress_token else {
+            return Self::run_gemini(opts, args.return_all_messages, args.return_format).await;
+        };
+
+        let (sink, forwarder) = progress_sink(context.peer, progress_token);
+        let opts = gemini::Options {
+            content_sink: Some(sink),
+            ..opts
+        };
+        let result = Self::run_gemini(opts, args.return_all_messages, args.return_format).await;
+        // All senders are gone once the run finishes; wait for queued notifications to go out
+        let _ = forwarder.await;
+        result
+    }
+
+    /// Generates images using the Gemini API directly (not via CLI).
+    /// Returns the generated image(s) as base64-encoded content along with any text response.
+    ///
+    /// **Return structure:**
+    /// - Image content(s) as base64-encoded data with mime type
+    /// - Text description from the model (if any)
+    ///
+    /// **Required environment variables:**
+    /// - `GEMINI_IMAGE_API_KEY`: API key for image generation
+    /// - `GEMINI_API_URL`: Base URL for the Gemini API
+    /// - `GEMINI_IMAGE_MODEL`: Model name for image generation (e.g. "gemini-3-pro-image-preview")
+    #[tool(
+        name = "gemini_image",
+        description = "Invokes the Gemini CLI for image generation tasks, using a dedicated image generation model configured via GEMINI_IMAGE_MODEL environment variable."
+    )]
+    async fn gemini_image(
+        &self,
+        Parameters(args): Parameters<GeminiImageArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        if args.prompt.trim().is_empty() {
+            return Err(McpError::invalid_params(
+                "PROMPT is required and must be a non-empty, non-whitespace string",
+                None,
+            ));
+        }
+
+        let api_url = gemini::get_api_url().ok_or_else(|| {
+            McpError::internal_error(
+                "GEMINI_API_URL environment variable is not set",
+                None,
+            )
+        })?;
+
+        let api_key = gemini::get_image_api_key().ok_or_else(|| {
+            McpError::internal_error(
+                "GEMINI_IMAGE_API_KEY environment variable is not set",
+                None,
+            )
+        })?;
+
+        let model = args
+            .model
+            .filter(|m| !m.trim().is_empty())
+            .or_else(gemini::get_image_model)
+            .ok_or_else(|| {
+                McpError::internal_error(
+                    "No model specified and GEMINI_IMAGE_MODEL environment variable is not set",
+                    None,
+                )
+            })?;
+
+        match gemini_image_api::generate_image(
+            &api_url,
+            &api_key,
+            &model,
+            &args.prompt,
+            args.aspect_ratio.as_deref(),
+            args.image_size.as_deref(),
+            gemini::get_image_api_style(),
+        )
+        .await
+        {
+            Ok(result) => {
+                let mut contents: Vec<Content> = Vec::new();
+                let mut saved_paths: Vec<String> = Vec::new();
+
+                let limits = gemini_image_api::ImageLimits {
+                    max_count: gemini::get_image_max_count(),
+                    max_total_bytes: gemini::get_image_max_total_bytes(),
+                };
+                let limited = gemini_image_api::apply_image_limits(&result.images, limits);
+
+                for (idx, image) in limited.kept.iter().enumerate() {
+                    contents.push(Content::image(image.data, image.mime_type));
+
+                    // Save image to current working directory
+                    let ext = gemini_image_api::image_extension(&image.bytes, image.mime_type);
+                    let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
+                    let filename = if limited.kept.len() == 1 {
+                        format!("gemini_image_{}.{}", timestamp, ext)
+                    } else {
+                        format!("gemini_image_{}_{}.{}", timestamp, idx + 1, ext)
+                    };
+
+                    // Save directory priority defaults to output_dir, AIMCP_IMAGE_DIR,
+                    // first MCP workspace root, then cwd (see GEMINI_IMAGE_DIR_PRIORITY)
+                    let candidates = gemini::ImageDirCandidates {
+                        arg: args
+                            .output_dir
+                            .as_ref()
+                            .filter(|s| !s.trim().is_empty())
+                            .map(PathBuf::from),
+                        env: gemini::get_image_dir(),
+                        root: self.roots.read().await.first().cloned(),
+                        cwd: std::env::current_dir().ok(),
+                    };
+                    let save_dir =
+                        gemini::resolve_image_dir(&candidates, &gemini::get_image_dir_priority());
+                    let save_path = save_dir.join(&filename);
+                    let saved = match std::fs::write(&save_path, &image.bytes) {
+                        Ok(_) => {
+                            let abs_path = save_path
+                                .canonicalize()
+                                .unwrap_or(save_path)
+                                .display()
+                                .to_string();
+                            saved_paths.push(abs_path);
+                            true
+                        }
+                        Err(e) => {
+                            eprintln!("[gemini_image] Failed to save {}: {}", filename, e);
+                            false
+                        }
+                    };
+
+                    let metadata =
+                        gemini_image_api::ImageMetadata::new(idx, image, saved.then_some(filename));
+                    if let Ok(metadata) = serde_json::to_string(&metadata) {
+                        contents.push(Content::text(metadata));
+                    }
+                }
+
+                if let Some(ref text) = result.text {
+                    contents.push(Content::text(text));
+                }
+
+                if !saved_paths.is_empty() {
+                    let paths_text = saved_paths
+                        .iter()
+                        .map(|p| format!("- {}", p))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    contents.push(Content::text(format!(
+                        "Saved to:\n{}",
+                        paths_text
+                    )));
+                }
+
+                if let Some(ref reason) = limited.limit_reason {
+                    contents.push(Content::text(format!(
+                        "Wrote {} of {} images returned by the API (limit: {})",
+                        saved_paths.len(),
+                        limited.returned,
+                        reason
+                    )));
+                }
+
+                if contents.is_empty() {
+                    contents.push(Content::text("Image generation completed but no content was returned."));
+                }
+
+                Ok(CallToolResult::success(contents))
+            }
+            Err(e) => Err(McpError::internal_error(
+                format!("Image generation failed: {}", e),
+                None,
+            )),
+        }
+    }
+
+    /// Executes a non-interactive Codex session via CLI to perform AI-assisted coding tasks in a secure workspace.
+    /// This tool wraps the 'codex exec' command, enabling model-driven code generation, debugging, or automation based on natural language prompts.
+    /// It supports resuming ongoing sessions for continuity and enforces sandbox policies to prevent unsafe operations.
+    #[tool(
+        name = "codex",
+        description = "Execute Codex CLI for AI-assisted coding tasks"
+    )]
+    async fn codex(
+        &self,
+        Parameters(args): Parameters<CodexArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        if !self.capabilities().codex_available {
+            return Err(McpError::internal_error(
+                "Codex CLI not found in PATH. Install codex CLI or set CODEX_BIN env var.",
+                None,
+            ));
+        }
+
+        let output = self.run_codex(args).await?;
+        json_result(&output)
+    }
+
+    /// Runs several independent codex tasks in sequence and returns their outputs as a JSON array,
+    /// in the order the tasks were given. A failing task does not affect the others.
+    #[tool(
+        name = "codex_batch",
+        description = "Run a list of independent Codex CLI tasks one after another (at most 16). Each task takes the same parameters as the codex tool; returns a JSON array with one codex output per task, in order. A failing task is reported in its own entry without stopping the batch."
+    )]
+    async fn codex_batch(
+        &self,
+        Parameters(args): Parameters<CodexBatchArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        if !self.capabilities().codex_available {
+            return Err(McpError::internal_error(
+                "Codex CLI not found in PATH. Install codex CLI or set CODEX_BIN env var.",
+                None,
+            ));
+        }
+
+        if args.tasks.is_empty() {
+            return Err(McpError::invalid_params(
+                "tasks is required and must contain at least one task",
+                None,
+            ));
+        }
+        if args.tasks.len() > MAX_CODEX_BATCH_TASKS {
+            return Err(McpError::invalid_params(
+                format!(
+                    "tasks contains {} tasks; at most {} are allowed per batch",
+                    args.tasks.len(),
+                    MAX_CODEX_BATCH_TASKS
+                ),
+                None,
+            ));
+        }
+
+        let outputs = run_codex_batch(args.tasks, |task| self.run_codex(task)).await;
+        let json_output = serde_json::to_string(&outputs).map_err(|e| {
+            McpError::internal_error(format!("Failed to serialize output: {}", e), None)
+        })?;
+        // Structured content must be an object, so the array goes under `results`
+        let structured = serde_json::json!({ "results": outputs });
+
+        Ok(structured_result(json_output, structured))
+    }
+
+    /// Terminates a running codex session by SESSION_ID.
+    /// Returns whether a matching running session was found and killed.
+    #[tool(
+        name = "codex_kill",
+        description = "Kill a running codex session by SESSION_ID. Returns whether a matching running session was found and terminated."
+    )]
+    async fn codex_kill(
+        &self,
+        Parameters(args): Parameters<CodexKillArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let session_id = args.session_id.trim();
+        if session_id.is_empty() {
+            return Err(McpError::invalid_params(
+                "SESSION_ID is required and must be a non-empty string",
+                None,
+            ));
+        }
+
+        let killed = self.codex_sessions.kill(session_id);
+        let output = serde_json::json!({
+            "SESSION_ID": session_id,
+            "killed": killed,
+        });
+
+        Ok(CallToolResult::success(vec![Content::text(
+            output.to_string(),
+        )]))
+    }
+
+    /// Performs a third-party web search based on the given query and returns the results as a JSON string.
+    #[tool(
+        name = "web_search",
+        description = "Performs a third-party web search based on the given query and returns the results as a JSON string. The query should be a clear, self-contained natural-language search query."
+    )]
+    async fn web_search(
+        &self,
+        Parameters(args): Parameters<WebSearchArgs>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        if args.query.trim().is_empty() {
+            return Err(McpError::invalid_params(
+                "query is required and must be a non-empty string",
+                None,
+            ));
+        }
+
+        if args.timeout_secs == Some(0) {
+            return Err(McpError::invalid_params(
+                "timeout_secs must be greater than 0",
+                None,
+            ));
+        }
+
+        let api = grok::tools::ApiOverride {
+            api_url: args.api_url,
+            api_key: args.api_key,
+        };
+        api.validate()
+            .map_err(|e| McpError::invalid_params(e, None))?;
+
+        if !api.is_complete() && !self.capabilities().grok_available {
+            return Err(McpError::internal_error(
+                "GROK_API_URL or GROK_API_KEY not configured. Set both environment variables to enable web search.",
+                None,
+            ));
+        }
+
+        let platform = args.platform.unwrap_or_default();
+
+        let progress_token = context
+            .meta
+            .get_progress_token()
+            .filter(|_| args.stream_results);
+        let (on_result, forwarder) = match progress_token {
+            Some(token) => {
+                let (sink, forwarder) = search_result_sink(context.peer, token);
+                (Some(sink), Some(forwarder))
+            }
+            None => (None, None),
+        };
+
+        let result = grok::tools::web_search(
+            &args.query,
+            &platform,
+            args.min_results,
+            args.max_results,
+            args.model,
+            args.timeout_secs,
+            args.max_retries,
+            args.time_context,
+            on_result,
+            &api,
+        )
+        .await;
+        // The sink is dropped with the provider; wait for queued notifications to go out
+        if let Some(forwarder) = forwarder {
+            let _ = forwarder.await;
+        }
+
+        match result {
+            Ok(result) => Ok(text_or_structured_result(result)),
+            Err(e) => Err(McpError::internal_error(
+                format!("Web search failed: {}", e),
+                None,
+            )),
+        }
+    }
+
+    /// Fetches and extracts the complete content from a specified URL and returns it as a structured Markdown document.
+    #[tool(
+        name = "web_fetch",
+        description = "Fetches and extracts the complete content from a specified URL and returns it as a structured Markdown document. The URL should be a valid HTTP/HTTPS web address. Set mode to \"raw\" to skip the model and get the unmodified HTML/text, or to \"extract\" to skip the model and get the main text with boilerplate removed."
+    )]
+    async fn web_fetch(
+        &self,
+        Parameters(args): Parameters<WebFetchArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let mode = grok::tools::FetchMode::parse(args.mode.as_deref()).ok_or_else(|| {
+            McpError::invalid_params("mode must be \"markdown\", \"raw\" or \"extract\"", None)
+        })?;
+
+        if args.include_metadata && mode != grok::tools::FetchMode::Markdown {
+            return Err(McpError::invalid_params(
+                "include_metadata is only supported in markdown mode",
+                None,
+            ));
+        }
+
+        if args.metadata_only && mode != grok::tools::FetchMode::Markdown {
+            return Err(McpError::invalid_params(
+                "metadata_only is only supported in markdown mode",
+                None,
+            ));
+        }
+
+        if args.metadata_only && args.include_metadata {
+            return Err(McpError::invalid_params(
+                "metadata_only cannot be combined with include_metadata",
+                None,
+            ));
+        }
+
+        let api = grok::tools::ApiOverride {
+            api_url: args.api_url,
+            api_key: args.api_key,
+        };
+        if api.is_set() && mode != grok::tools::FetchMode::Markdown {
+            return Err(McpError::invalid_params(
+                "api_url and api_key are only supported in markdown mode",
+                None,
+            ));
+        }
+        api.validate()
+            .map_err(|e| McpError::invalid_params(e, None))?;
+
+        if mode == grok::tools::FetchMode::Markdown
+            && !api.is_complete()
+            && !self.capabilities().grok_available
+        {
+            return Err(McpError::internal_error(
+                "GROK_API_URL or GROK_API_KEY not configured. Set both environment variables to enable web fetch.",
+                None,
+            ));
+        }
+
+        if args.url.trim().is_empty() {
+            return Err(McpError::invalid_params(
+                "url is required and must be a non-empty string",
+                None,
+            ));
+        }
+
+        if args.reject_binary {
+            // Best effort: servers that reject HEAD or omit Content-Type are fetched anyway
+            match grok::tools::web_fetch_head(&args.url).await {
+                Ok(head) if head.binary => {
+                    return Err(McpError::invalid_params(
+                        format!(
+                            "URL serves binary content ({}); not fetching",
+                            head.content_type.unwrap_or_default()
+                        ),
+                        None,
+                    ));
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("[grok] HEAD check failed, fetching anyway: {}", e),
+            }
+        }
+
+        let result = match mode {
+            grok::tools::FetchMode::Markdown => {
+                grok::tools::web_fetch(
+                    &args.url,
+                    args.model,
+                    args.max_retries,
+                    args.metadata_only,
+                    &api,
+                )
+                .await
+            }
+            grok::tools::FetchMode::Raw => grok::tools::web_fetch_raw(&args.url).await,
+            grok::tools::FetchMode::Extract => grok::tools::web_fetch_extract(&args.url).await,
+        };
+
+        let result = match result {
+            Ok(markdown) if args.include_metadata => {
+                serde_json::to_string_pretty(&grok::tools::parse_fetch_output(&markdown))
+                    .map_err(|e| anyhow::anyhow!("Failed to serialize fetch result: {}", e))
+            }
+            other => other,
+        };
+
+        match result {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
+            Err(e) => Err(McpError::internal_error(
+                format!("Web fetch failed: {}", e),
+                None,
+            )),
+        }
+    }
+
+    /// Checks a URL with a HEAD request before fetching it.
+    #[tool(
+        name = "web_fetch_head",
+        description = "Checks a URL with a direct HEAD request (no model call) and returns its HTTP status, content type, content length and whether the content is binary. Use before web_fetch to confirm a page is reachable and not a large binary file. Local and private addresses are refused."
+    )]
+    async fn web_fetch_head(
+        &self,
+        Parameters(args): Parameters<WebFetchHeadArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        if args.url.trim().is_empty() {
+            return Err(McpError::invalid_params(
+                "url is required and must be a non-empty string",
+                None,
+            ));
+        }
+
+        let head = grok::tools::web_fetch_head(&args.url)
+            .await
+            .map_err(|e| McpError::internal_error(format!("HEAD request failed: {}", e), None))?;
+        match serde_json::to_string_pretty(&head) {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
+            Err(e) => Err(McpError::internal_error(
+                format!("Failed to serialize HEAD result: {}", e),
+                None,
+            )),
+        }
+    }
+
+    /// Lists the model ids available from the configured Grok provider.
+    #[tool(
+        name = "list_models",
+        description = "Lists the model ids offered by the configured Grok provider's /models endpoint, returned as JSON. Useful for choosing a value for the web_search and web_fetch model parameter."
+    )]
+    async fn list_models(&self) -> Result<CallToolResult, McpError> {
+        if !self.capabilities().grok_available {
+            return Err(McpError::internal_error(
+                "GROK_API_URL or GROK_API_KEY not configured. Set both environment variables to list models.",
+                None,
+            ));
+        }
+
+        let models = grok::tools::list_models()
+            .await
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        let output = serde_json::json!({ "models": models });
+        match serde_json::to_string_pretty(&output) {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
+            Err(e) => Err(McpError::internal_error(
+                format!("Failed to serialize model list: {}", e),
+                None,
+            )),
+        }
+    }
+
+    /// Returns server information, the current Grok Search configuration, and a connection test.
+    #[tool(
+        name = "get_config_info",
+        description = "Returns server information (version, protocol version, enabled tools, detected message framing) and the current Grok Search configuration, testing the Grok connection when it is configured. Useful for verifying environment variables, testing API connectivity, and debugging configuration issues."
+    )]
+    async fn get_config_info(&self) -> Result<CallToolResult, McpError> {
+        let mut report = grok::tools::config_report().await;
+        report["server_info"] = self.server_info_report();
+        match serde_json::to_string_pretty(&report) {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
+            Err(e) => Err(McpError::internal_error(
+                format!("Failed to get config info: {}", e),
+                None,
+            )),
+        }
+    }
+
+    /// Runs a canned prompt against gemini and codex with a short timeout to validate setup.
+    #[tool(
+        name = "smoke_test",
+        description = "Runs a trivial canned prompt (\"Reply with exactly: OK\") against the gemini and codex CLIs with a short hard timeout (default 30 seconds), reporting per-backend status, latency and first message. Backends that are not available are reported as skipped. Useful for validating setup end-to-end."
+    )]
+    async fn smoke_test(
+        &self,
+        Parameters(args): Parameters<SmokeTestArgs>,
+    ) -> Result<CallToolResult, McpError> {
+        let timeout_secs = args
+            .timeout_secs
+            .unwrap_or(smoke::DEFAULT_SMOKE_TIMEOUT_SECS);
+        if !(MIN_TIMEOUT_SECS..=MAX_TIMEOUT_SECS).contains(&timeout_secs) {
+            return Err(McpError::invalid_params(
+                format!(
+                    "timeout_secs must be between {} and {} seconds",
+                    MIN_TIMEOUT_SECS, MAX_TIMEOUT_SECS
+                ),
+                None,
+            ));
+        }
+
+        let roots = self.roots.read().await.clone();
+        let working_dir = roots
+            .first()
+            .cloned()
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")));
+
+        let caps = self.capabilities();
+        let gemini_run = async {
+            if caps.gemini_available {
+                Some(smoke::run_gemini(timeout_secs, roots.clone()).await)
+            } else {
+                None
+            }
+        };
+        let codex_run = async {
+            if caps.codex_available {
+                Some(smoke::run_codex(timeout_secs, working_dir.clone()).await)
+            } else {
+                None
+            }
+        };
+        let (gemini_run, codex_run) = tokio::join!(gemini_run, codex_run);
+
+        let report = serde_json::json!({
+            "timeout_secs": timeout_secs,
+            "backends": [
+                smoke::assemble_report("gemini", gemini_run),
+                smoke::assemble_report("codex", codex_run),
+            ],
+        });
+        match serde_json::to_string_pretty(&report) {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
+            Err(e) => Err(McpError::internal_error(
+                format!("Failed to serialize smoke test report: {}", e),
                 None,
-            ));
+            )),
         }
+    }
 
-        match grok::tools::web_fetch(&args.url, args.model).await {
+    /// Re-runs CLI and Grok detection so backends installed after startup are picked up.
+    #[tool(
+        name = "redetect",
+        description = "Re-runs backend detection (gemini/codex binaries via PATH or GEMINI_BIN/CODEX_BIN, Grok via GROK_API_URL/GROK_API_KEY) and swaps in the result, so a CLI installed after the server started becomes usable without a restart. Returns the new availability, paths, whether an override points at a file that is not executable, and enabled tools."
+    )]
+    async fn redetect(&self) -> Result<CallToolResult, McpError> {
+        let caps = self.redetect_capabilities();
+        let report = serde_json::json!({
+            "gemini": {
+                "available": caps.gemini_available,
+                "path": caps.gemini_path,
+                "found_not_executable": caps.gemini_found_not_executable,
+            },
+            "codex": {
+                "available": caps.codex_available,
+                "path": caps.codex_path,
+                "found_not_executable": caps.codex_found_not_executable,
+            },
+            "grok": {
+                "available": caps.grok_available,
+            },
+            "enabled_tools": self.enabled_tools(),
+        });
+        match serde_json::to_string_pretty(&report) {
             Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
             Err(e) => Err(McpError::internal_error(
-                format!("Web fetch failed: {}", e),
+                format!("Failed to serialize detection report: {}", e),
                 None,
             )),
         }
     }
 
-    /// Returns the current Grok Search configuration information and tests the connection.
+    /// Returns every tool's description and input schema keyed by tool name, the same data as
+    /// `tools/list` in one payload for clients that only make tool calls.
     #[tool(
-        name = "get_config_info",
-        description = "Returns the current Grok Search MCP server configuration information and tests the connection. Useful for verifying environment variables, testing API connectivity, and debugging configuration issues."
+        name = "describe_tools",
+        description = "Returns a JSON object mapping each tool name to its description and input JSON schema (the same data as tools/list), for clients that cache schemas or build UIs out-of-band."
     )]
-    async fn get_config_info(&self) -> Result<CallToolResult, McpError> {
-        match grok::tools::get_config_info().await {
+    async fn describe_tools(&self) -> Result<CallToolResult, McpError> {
+        match serde_json::to_string_pretty(&self.tool_descriptions()) {
             Ok(result) => Ok(CallToolResult::success(vec![Content::text(result)])),
             Err(e) => Err(McpError::internal_error(
-                format!("Failed to get config info: {}", e),
+                format!("Failed to serialize tool descriptions: {}", e),
                 None,
             )),
         }
@@ -859,53 +2084,21 @@ impl UnifiedServer {
 #[tool_handler]
 impl ServerHandler for UnifiedServer {
     async fn on_initialized(&self, context: NotificationContext<RoleServer>) {
-        // Request workspace roots from the MCP client.
-        // These are passed to Gemini CLI as --include-directories so it can
-        // access files outside its inherited CWD (which MCP hosts may set to
-        // an internal directory like F:\Windsurf).
-        // Use a short timeout — some clients don't support roots/list and
-        // the call would block indefinitely without one.
-        let roots_future = context.peer.list_roots();
-        match tokio::time::timeout(std::time::Duration::from_secs(3), roots_future).await {
-            Ok(Ok(roots_result)) => {
-                let dirs: Vec<PathBuf> = roots_result
-                    .roots
-                    .iter()
-                    .filter_map(|root| file_uri_to_path(&root.uri))
-                    .collect();
-                if !dirs.is_empty() {
-                    eprintln!(
-                        "ikuncode-aimcp: received {} workspace root(s) from MCP client",
-                        dirs.len()
-                    );
-                    *self.roots.write().await = dirs;
-                }
-            }
-            Ok(Err(e)) => {
-                eprintln!(
-                    "ikuncode-aimcp: failed to list roots from MCP client (non-fatal): {}",
-                    e
-                );
-            }
-            Err(_) => {
-                eprintln!(
-                    "ikuncode-aimcp: list_roots timed out (client may not support roots/list, non-fatal)"
-                );
-            }
-        }
+        self.refresh_roots(RootsFetch::from_env(), || context.peer.list_roots())
+            .await;
     }
 
     fn get_info(&self) -> ServerInfo {
+        let mut server_info = Implementation::from_build_env();
+        if let Some(name) = env_override(ENV_SERVER_NAME) {
+            server_info.name = name;
+        }
         ServerInfo {
             protocol_version: ProtocolVersion::V_2024_11_05,
             capabilities: ServerCapabilities::builder().enable_tools().build(),
-            server_info: Implementation::from_build_env(),
+            server_info,
             instructions: Some(
-                "Unified AI MCP server providing gemini, codex, and grok search tools. \
-                 Use 'gemini' for AI-driven tasks via Gemini CLI, 'codex' for AI-assisted coding \
-                 via Codex CLI, 'web_search' for web searches, 'web_fetch' for fetching web content, \
-                 and 'get_config_info' for configuration status."
-                    .to_string(),
+                env_override(ENV_INSTRUCTIONS).unwrap_or_else(|| DEFAULT_INSTRUCTIONS.to_string()),
             ),
         }
     }
@@ -931,6 +2124,7 @@ mod tests {
         assert_eq!(args.session_id, Some("session-123".to_string()));
         assert!(!args.return_all_messages);
         assert_eq!(args.model, Some("gemini-pro".to_string()));
+        assert_eq!(args.return_format, gemini::EventLogFormat::Pretty);
     }
 
     #[test]
@@ -957,7 +2151,7 @@ mod tests {
         let args: CodexArgs = serde_json::from_str(json).unwrap();
         assert_eq!(args.prompt, "fix bug");
         assert_eq!(args.cd, PathBuf::from("/tmp/project"));
-        assert_eq!(args.sandbox, SandboxPolicy::ReadOnly);
+        assert_eq!(args.sandbox, Some(SandboxPolicy::ReadOnly));
         assert!(!args.yolo);
     }
 
@@ -970,6 +2164,137 @@ mod tests {
         assert_eq!(args.platform, None);
         assert_eq!(args.min_results, 3);
         assert_eq!(args.max_results, 10);
+        assert_eq!(args.timeout_secs, None);
+        assert_eq!(args.max_retries, None);
+        assert_eq!(args.time_context, None);
+        assert!(!args.stream_results);
+    }
+
+    /// Collect every `$ref` value in `value`
+    fn collect_refs(value: &serde_json::Value, refs: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                if let Some(r) = map.get("$ref").and_then(|r| r.as_str()) {
+                    refs.push(r.to_string());
+                }
+                map.values().for_each(|v| collect_refs(v, refs));
+            }
+            serde_json::Value::Array(items) => items.iter().for_each(|v| collect_refs(v, refs)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_draft07_schema_inlines_codex_args_refs() {
+        let schema = rmcp::handler::server::common::schema_for_type::<Parameters<CodexArgs>>();
+        let mut original_refs = Vec::new();
+        collect_refs(
+            &serde_json::Value::Object((*schema).clone()),
+            &mut original_refs,
+        );
+        assert!(
+            !original_refs.is_empty(),
+            "CodexArgs schema has no $ref to inline"
+        );
+
+        let converted = convert_schema(&schema, SchemaDraft::Draft07);
+        let converted = serde_json::Value::Object(converted);
+        let mut refs = Vec::new();
+        collect_refs(&converted, &mut refs);
+        assert!(refs.is_empty(), "unresolved refs: {:?}", refs);
+        assert!(converted.get("$defs").is_none());
+        assert!(converted.get("definitions").is_none());
+        assert_eq!(converted["$schema"], DRAFT_07_URI);
+        assert_eq!(
+            converted["properties"]["PROMPT"],
+            schema["properties"]["PROMPT"]
+        );
+        assert!(converted["properties"]["sandbox"]
+            .to_string()
+            .contains("read-only"));
+    }
+
+    #[test]
+    fn test_convert_schema_keeps_recursive_refs_as_definitions() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "node": { "$ref": "#/$defs/Node", "description": "root node" }
+            },
+            "$defs": {
+                "Node": {
+                    "type": "object",
+                    "description": "a node",
+                    "properties": { "child": { "$ref": "#/$defs/Node" } }
+                }
+            }
+        });
+        let schema = schema.as_object().unwrap();
+
+        assert_eq!(&convert_schema(schema, SchemaDraft::Draft2020_12), schema);
+
+        let converted = convert_schema(schema, SchemaDraft::Draft07);
+        let node = &converted["properties"]["node"];
+        assert_eq!(node["description"], "root node");
+        assert_eq!(node["properties"]["child"]["$ref"], "#/definitions/Node");
+        assert_eq!(
+            converted["definitions"]["Node"]["properties"]["child"]["$ref"],
+            "#/definitions/Node"
+        );
+        assert!(converted.get("$defs").is_none());
+    }
+
+    #[test]
+    fn test_schema_draft_parse() {
+        assert_eq!(SchemaDraft::parse("Draft-07"), Some(SchemaDraft::Draft07));
+        assert_eq!(SchemaDraft::parse("7"), Some(SchemaDraft::Draft07));
+        assert_eq!(
+            SchemaDraft::parse(" 2020-12 "),
+            Some(SchemaDraft::Draft2020_12)
+        );
+        assert_eq!(SchemaDraft::parse("draft-04"), None);
+    }
+
+    #[test]
+    fn test_codex_args_schema_leaves_message_limit_cap_to_server() {
+        let schema = serde_json::to_value(schemars::schema_for!(CodexArgs)).unwrap();
+        let limit = &schema["properties"]["return_all_messages_limit"];
+
+        assert_eq!(limit["minimum"], 1);
+        assert!(limit.get("maximum").is_none());
+        assert_eq!(limit["examples"], serde_json::json!([10000]));
+    }
+
+    #[test]
+    fn test_web_search_args_schema_bounds_and_examples() {
+        let schema = serde_json::to_value(schemars::schema_for!(WebSearchArgs)).unwrap();
+        let props = &schema["properties"];
+
+        assert_eq!(props["min_results"]["minimum"], 0);
+        assert_eq!(props["min_results"]["maximum"], MAX_SEARCH_RESULTS);
+        assert_eq!(props["min_results"]["examples"], serde_json::json!([3]));
+        assert_eq!(props["max_results"]["minimum"], 1);
+        assert_eq!(props["max_results"]["maximum"], MAX_SEARCH_RESULTS);
+        assert_eq!(props["max_results"]["examples"], serde_json::json!([10]));
+        assert_eq!(props["timeout_secs"]["minimum"], 1);
+        assert_eq!(
+            props["platform"]["examples"],
+            serde_json::json!(["GitHub,Reddit"])
+        );
+        assert!(props["query"]["examples"][0].is_string());
+    }
+
+    #[test]
+    fn test_web_search_args_timeout_and_retries() {
+        let json = r#"{"query": "rust", "timeout_secs": 60, "max_retries": 1}"#;
+
+        let args: WebSearchArgs = serde_json::from_str(json).unwrap();
+        assert_eq!(args.timeout_secs, Some(60));
+        assert_eq!(args.max_retries, Some(1));
+
+        let args: WebSearchArgs =
+            serde_json::from_str(r#"{"query": "The Latest Show", "time_context": false}"#).unwrap();
+        assert_eq!(args.time_context, Some(false));
     }
 
     #[test]
@@ -978,6 +2303,64 @@ mod tests {
 
         let args: WebFetchArgs = serde_json::from_str(json).unwrap();
         assert_eq!(args.url, "https://example.com");
+        assert_eq!(
+            grok::tools::FetchMode::parse(args.mode.as_deref()),
+            Some(grok::tools::FetchMode::Markdown)
+        );
+
+        let json = r#"{"url": "https://example.com", "mode": "raw"}"#;
+        let args: WebFetchArgs = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            grok::tools::FetchMode::parse(args.mode.as_deref()),
+            Some(grok::tools::FetchMode::Raw)
+        );
+        assert!(!args.metadata_only);
+
+        let json = r#"{"url": "https://example.com", "metadata_only": true}"#;
+        let args: WebFetchArgs = serde_json::from_str(json).unwrap();
+        assert!(args.metadata_only);
+        assert!(!args.include_metadata);
+    }
+
+    #[test]
+    fn test_web_fetch_override_skips_grok_config_gate() {
+        let server = UnifiedServer::new(Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        });
+        let fetch = |json: serde_json::Value| {
+            let args: WebFetchArgs = serde_json::from_value(json).unwrap();
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(server.web_fetch(Parameters(args))).unwrap_err()
+        };
+
+        let err = fetch(serde_json::json!({"url": "https://example.com"}));
+        assert!(err.message.contains("not configured"), "{}", err.message);
+
+        // Both overrides reach the (unreachable) gateway instead of the config check
+        let err = fetch(serde_json::json!({
+            "url": "https://example.com",
+            "max_retries": 0,
+            "api_url": "http://127.0.0.1:1/v1",
+            "api_key": "tenant-key",
+        }));
+        assert!(
+            err.message.starts_with("Web fetch failed"),
+            "{}",
+            err.message
+        );
+
+        let err = fetch(serde_json::json!({
+            "url": "https://example.com",
+            "api_url": "http://127.0.0.1:1/v1",
+        }));
+        assert_eq!(err.code, rmcp::model::ErrorCode::INVALID_PARAMS);
+        assert!(err.message.contains("api_url requires api_key"));
     }
 
     #[test]
@@ -1070,29 +2453,68 @@ mod tests {
         let args = CodexArgs {
             prompt: "test".to_string(),
             cd: PathBuf::from("/tmp"),
-            sandbox: SandboxPolicy::DangerFullAccess,
+            sandbox: Some(SandboxPolicy::DangerFullAccess),
             session_id: None,
             skip_git_repo_check: true,
             return_all_messages: false,
             return_all_messages_limit: None,
+            return_turns: false,
+            include_reasoning_summary: false,
             image: vec![],
             model: None,
             yolo: true,
             profile: None,
             timeout_secs: None,
             force_stdin: false,
+            log_file: None,
+            reasoning_effort: None,
+            verbosity: None,
+            approval_policy: Some("Never".to_string()),
+            instructions: None,
         };
         let security = SecurityConfig {
             allow_danger_full_access: false,
             allow_yolo: false,
             allow_skip_git_check: false,
+            allow_approval_never: false,
         };
 
         let (updated, warnings) = apply_security_restrictions(args, &security);
-        assert_eq!(warnings.len(), 3);
-        assert_eq!(updated.sandbox, SandboxPolicy::ReadOnly);
+        assert_eq!(warnings.len(), 4);
+        assert_eq!(updated.sandbox, Some(SandboxPolicy::ReadOnly));
         assert!(!updated.yolo);
         assert!(!updated.skip_git_repo_check);
+        assert_eq!(updated.approval_policy.as_deref(), Some("on-request"));
+    }
+
+    #[test]
+    fn test_env_default_sandbox_is_still_downgraded() {
+        let mut args: CodexArgs = serde_json::from_value(serde_json::json!({
+            "PROMPT": "test",
+            "cd": "/tmp",
+            "image": [],
+        }))
+        .unwrap();
+        assert_eq!(args.sandbox, None);
+
+        let mut warnings = Vec::new();
+        args.sandbox = Some(codex::resolve_sandbox(
+            args.sandbox.take(),
+            Some("danger-full-access".to_string()),
+            &mut warnings,
+        ));
+        assert_eq!(args.sandbox, Some(SandboxPolicy::DangerFullAccess));
+
+        let security = SecurityConfig {
+            allow_danger_full_access: false,
+            allow_yolo: false,
+            allow_skip_git_check: false,
+            allow_approval_never: false,
+        };
+        let (updated, restriction_warnings) = apply_security_restrictions(args, &security);
+        assert_eq!(updated.sandbox, Some(SandboxPolicy::ReadOnly));
+        assert_eq!(restriction_warnings.len(), 1);
+        assert!(restriction_warnings[0].contains("danger-full-access"));
     }
 
     #[test]
@@ -1145,10 +2567,15 @@ mod tests {
             session_id: "sess-1".into(),
             agent_messages: "done".into(),
             agent_messages_truncated: false,
+            agent_turns: None,
+            reasoning_summary: None,
             all_messages: vec![],
             all_messages_truncated: false,
             error: None,
             warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
         };
         let output = build_codex_output(&result, false, None);
         assert!(output.success);
@@ -1160,7 +2587,7 @@ mod tests {
     fn test_file_uri_to_path_windows() {
         // Windows-style file URI
         let path = file_uri_to_path("file:///D:/Desk/ai-tools/aimcp");
-        assert!(path.is_some());
+        assert!(path.is_ok());
         #[cfg(windows)]
         assert_eq!(path.unwrap(), PathBuf::from("D:/Desk/ai-tools/aimcp"));
         #[cfg(not(windows))]
@@ -1170,7 +2597,7 @@ mod tests {
     #[test]
     fn test_file_uri_to_path_unix() {
         let path = file_uri_to_path("file:///home/user/project");
-        assert!(path.is_some());
+        assert!(path.is_ok());
         // On all platforms, /home/user/project is preserved
         let p = path.unwrap();
         assert!(p.to_string_lossy().contains("home"));
@@ -1178,14 +2605,233 @@ mod tests {
 
     #[test]
     fn test_file_uri_to_path_non_file_uri() {
-        assert!(file_uri_to_path("https://example.com").is_none());
-        assert!(file_uri_to_path("").is_none());
-        assert!(file_uri_to_path("not-a-uri").is_none());
+        assert!(file_uri_to_path("https://example.com").is_err());
+        assert!(file_uri_to_path("").is_err());
+        assert!(file_uri_to_path("not-a-uri").is_err());
     }
 
     #[test]
     fn test_file_uri_to_path_empty_path() {
-        assert!(file_uri_to_path("file://").is_none());
+        assert!(file_uri_to_path("file://").is_err());
+    }
+
+    #[test]
+    fn test_resolve_root_uris_mixed() {
+        let (dirs, errors) = resolve_root_uris([
+            "file:///home/user/a",
+            "https://example.com/repo",
+            "file://",
+            "file:///home/user/b",
+        ]);
+        assert_eq!(dirs.len(), 2);
+        assert!(dirs[0].to_string_lossy().ends_with("a"));
+        assert!(dirs[1].to_string_lossy().ends_with("b"));
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("https://example.com/repo"));
+        assert!(errors[1].contains("empty path"));
+    }
+
+    #[test]
+    fn test_resolve_root_uris_all_invalid() {
+        let (dirs, errors) = resolve_root_uris(["not-a-uri", "file://"]);
+        assert!(dirs.is_empty());
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_roots_refresh_replaces_when_not_smaller() {
+        let mut roots = vec![PathBuf::from("/a")];
+        apply_roots_refresh(&mut roots, vec![PathBuf::from("/b")], &[]);
+        assert_eq!(roots, vec![PathBuf::from("/b")]);
+
+        apply_roots_refresh(
+            &mut roots,
+            vec![PathBuf::from("/c"), PathBuf::from("/d")],
+            &[],
+        );
+        assert_eq!(roots, vec![PathBuf::from("/c"), PathBuf::from("/d")]);
+    }
+
+    #[test]
+    fn test_roots_refresh_merges_when_smaller() {
+        let mut roots = vec![PathBuf::from("/a"), PathBuf::from("/b")];
+        let errors = vec!["not a file:// URI".to_string()];
+        apply_roots_refresh(
+            &mut roots,
+            vec![PathBuf::from("/b"), PathBuf::from("/c")],
+            &[],
+        );
+        assert_eq!(roots, vec![PathBuf::from("/b"), PathBuf::from("/c")]);
+
+        // Mixed valid and invalid URIs: the resolved root comes first, the rest are kept
+        apply_roots_refresh(&mut roots, vec![PathBuf::from("/d")], &errors);
+        assert_eq!(
+            roots,
+            vec![
+                PathBuf::from("/d"),
+                PathBuf::from("/b"),
+                PathBuf::from("/c")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_roots_refresh_keeps_existing_when_all_fail() {
+        let mut roots = vec![PathBuf::from("/a")];
+        let errors = vec!["not a file:// URI".to_string()];
+        apply_roots_refresh(&mut roots, Vec::new(), &errors);
+        assert_eq!(roots, vec![PathBuf::from("/a")]);
+    }
+
+    #[test]
+    fn test_codex_batch_preserves_order_and_isolates_failures() {
+        let tasks: CodexBatchArgs = serde_json::from_value(serde_json::json!({
+            "tasks": [
+                {"PROMPT": "first", "cd": "/tmp", "image": []},
+                {"PROMPT": "", "cd": "/tmp", "image": []},
+                {"PROMPT": "third", "cd": "/tmp", "image": []},
+            ]
+        }))
+        .unwrap();
+
+        let mut seen = Vec::new();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let outputs = rt.block_on(run_codex_batch(tasks.tasks, |task| {
+            seen.push(task.prompt.clone());
+            async move {
+                if task.prompt.is_empty() {
+                    return Err(McpError::invalid_params("PROMPT is required", None));
+                }
+                let result = codex::CodexResult {
+                    success: true,
+                    session_id: format!("session-{}", task.prompt),
+                    agent_messages: task.prompt,
+                    ..codex::CodexResult::default()
+                };
+                Ok(build_codex_output(&result, false, None))
+            }
+        }));
+
+        assert_eq!(seen, vec!["first", "", "third"]);
+        assert_eq!(outputs.len(), 3);
+        assert!(outputs[0].success);
+        assert_eq!(outputs[0].session_id, "session-first");
+        assert!(!outputs[1].success);
+        assert_eq!(outputs[1].error.as_deref(), Some("PROMPT is required"));
+        assert!(outputs[2].success);
+        assert_eq!(outputs[2].agent_messages, "third");
+    }
+
+    fn result_text(result: &CallToolResult) -> &str {
+        result.content[0].as_text().unwrap().text.as_str()
+    }
+
+    #[test]
+    fn test_codex_output_returned_as_structured_content() {
+        let result = codex::CodexResult {
+            success: true,
+            session_id: "sess-1".to_string(),
+            agent_messages: "done".to_string(),
+            timeout_secs: Some(600),
+            ..codex::CodexResult::default()
+        };
+        let output = build_codex_output(&result, false, None);
+        let tool_result = json_result(&output).unwrap();
+
+        assert_eq!(tool_result.is_error, Some(false));
+        assert_eq!(
+            tool_result.structured_content,
+            Some(serde_json::json!({
+                "success": true,
+                "SESSION_ID": "sess-1",
+                "agent_messages": "done",
+                "timeout_secs": 600,
+            }))
+        );
+        // The text fallback is the same JSON object
+        let text: serde_json::Value = serde_json::from_str(result_text(&tool_result)).unwrap();
+        assert_eq!(Some(text), tool_result.structured_content);
+    }
+
+    #[test]
+    fn test_gemini_structured_content() {
+        let mut result = gemini::GeminiResult {
+            success: true,
+            session_id: "gem-1".to_string(),
+            agent_messages: "hello".to_string(),
+            agent_messages_truncated: false,
+            all_messages: vec![serde_json::json!({"type": "init"})],
+            return_all_messages: true,
+            error: None,
+            error_code: None,
+            error_type: None,
+            warning: None,
+            warnings: Some("stderr line".to_string()),
+        };
+        assert_eq!(
+            gemini_structured_content(&result, 300, false),
+            serde_json::json!({
+                "success": true,
+                "SESSION_ID": "gem-1",
+                "agent_messages": "hello",
+                "timeout_secs": 300,
+                "warnings": "stderr line",
+            })
+        );
+
+        result.agent_messages_truncated = true;
+        let value = gemini_structured_content(&result, 300, true);
+        assert_eq!(value["agent_messages_truncated"], true);
+        assert_eq!(value["all_messages"], serde_json::json!([{"type": "init"}]));
+    }
+
+    #[test]
+    fn test_text_or_structured_result() {
+        let json = "{\n  \"results\": [{\"title\": \"a\"}]\n}";
+        let result = text_or_structured_result(json.to_string());
+        assert_eq!(result_text(&result), json);
+        assert_eq!(
+            result.structured_content,
+            Some(serde_json::json!({"results": [{"title": "a"}]}))
+        );
+
+        for text in [
+            "[1, 2]",
+            "plain text",
+            "{\"a\": 1}\n\n---\n[debug] HTTP status: 200",
+        ] {
+            let result = text_or_structured_result(text.to_string());
+            assert_eq!(result_text(&result), text);
+            assert!(result.structured_content.is_none());
+        }
+    }
+
+    #[test]
+    fn test_progress_sender_drops_messages_when_queue_is_full() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<String>(2);
+        let sender = ProgressSender {
+            tx,
+            dropped: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+        };
+        for i in 0..5 {
+            sender.send(i.to_string());
+        }
+        assert_eq!(sender.dropped.load(std::sync::atomic::Ordering::Relaxed), 3);
+        assert_eq!(rx.try_recv().unwrap(), "0");
+        assert_eq!(rx.try_recv().unwrap(), "1");
+        assert!(rx.try_recv().is_err());
+
+        // Once the client catches up, new messages get through again
+        sender.send("5".to_string());
+        assert_eq!(rx.try_recv().unwrap(), "5");
+    }
+
+    #[test]
+    fn test_codex_kill_args_deserialization() {
+        let json = r#"{"SESSION_ID": "sess-1"}"#;
+
+        let args: CodexKillArgs = serde_json::from_str(json).unwrap();
+        assert_eq!(args.session_id, "sess-1");
     }
 
     #[test]
@@ -1193,13 +2839,348 @@ mod tests {
         let caps = Capabilities {
             gemini_available: true,
             gemini_path: Some(PathBuf::from("/usr/bin/gemini")),
+            gemini_found_not_executable: false,
             codex_available: false,
             codex_path: None,
+            codex_found_not_executable: false,
             grok_available: true,
         };
         let server = UnifiedServer::new(caps);
-        assert!(server.capabilities.gemini_available);
-        assert!(!server.capabilities.codex_available);
-        assert!(server.capabilities.grok_available);
+        assert!(server.capabilities().gemini_available);
+        assert!(!server.capabilities().codex_available);
+        assert!(server.capabilities().grok_available);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_redetect_picks_up_gemini_bin_changes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("gemini");
+        std::fs::write(&bin, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let missing = dir.path().join("missing-gemini");
+        let _bin = gemini::tests::GEMINI_BIN_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let _guard = gemini::tests::EnvVarGuard::new("GEMINI_BIN");
+
+        let server = UnifiedServer::new(Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        });
+        assert!(!server.enabled_tools().contains(&"gemini"));
+
+        // An override that is not executable is reported, not treated as available
+        std::env::set_var("GEMINI_BIN", &bin);
+        server.redetect_capabilities();
+        let caps = server.capabilities();
+        assert!(!caps.gemini_available);
+        assert!(caps.gemini_found_not_executable);
+        assert_eq!(caps.gemini_path.as_deref(), Some(bin.as_path()));
+        assert!(!server.enabled_tools().contains(&"gemini"));
+
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        server.redetect_capabilities();
+        let caps = server.capabilities();
+        std::env::set_var("GEMINI_BIN", &missing);
+        assert!(!caps.gemini_found_not_executable);
+        assert!(caps.gemini_available);
+        assert_eq!(caps.gemini_path.as_deref(), Some(bin.as_path()));
+        assert!(server.enabled_tools().contains(&"gemini"));
+
+        server.redetect_capabilities();
+        std::env::remove_var("GEMINI_BIN");
+        // Falls back to PATH lookup, which must not resolve to the stale override
+        let caps = server.capabilities();
+        assert_ne!(caps.gemini_path.as_deref(), Some(bin.as_path()));
+        assert_eq!(caps.gemini_available, caps.gemini_path.is_some());
+    }
+
+    #[test]
+    fn test_get_config_info_without_grok() {
+        std::env::remove_var("GROK_API_URL");
+        std::env::remove_var("GROK_API_KEY");
+        let caps = Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: true,
+            codex_path: Some(PathBuf::from("/usr/bin/codex")),
+            codex_found_not_executable: false,
+            grok_available: false,
+        };
+        let server = UnifiedServer::new(caps);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(server.get_config_info()).unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        let report: serde_json::Value = serde_json::from_str(text).unwrap();
+
+        let server_info = &report["server_info"];
+        assert_eq!(server_info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            server_info["protocol_version"],
+            serde_json::to_value(server.get_info().protocol_version).unwrap()
+        );
+        let tools = server_info["enabled_tools"].as_array().unwrap();
+        assert!(tools.contains(&serde_json::json!("codex")));
+        assert!(tools.contains(&serde_json::json!("get_config_info")));
+        assert!(!tools.contains(&serde_json::json!("web_search")));
+        assert!(!tools.contains(&serde_json::json!("list_models")));
+        assert_eq!(server_info["framing_format"], "not yet detected");
+
+        assert_eq!(report["connection_test"]["status"], "not configured");
+    }
+
+    #[test]
+    fn test_server_info_reports_detected_framing_format() {
+        use tokio_util::codec::Decoder;
+
+        let framing_format = transport::SharedFramingFormat::default();
+        let server = UnifiedServer::new(Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        })
+        .with_framing_format(framing_format.clone());
+        assert_eq!(
+            server.server_info_report()["framing_format"],
+            "not yet detected"
+        );
+
+        let mut codec =
+            transport::AdaptiveCodec::<serde_json::Value>::with_shared_format(framing_format);
+        let mut buf = tokio_util::bytes::BytesMut::from(&b"{\"jsonrpc\":\"2.0\",\"id\":1}\n"[..]);
+        codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(server.server_info_report()["framing_format"], "JsonLines");
+    }
+
+    #[test]
+    fn test_describe_tools_lists_every_tool_schema() {
+        let server = UnifiedServer::new(Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        });
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(server.describe_tools()).unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        let report: serde_json::Value = serde_json::from_str(text).unwrap();
+
+        for name in [
+            "codex",
+            "codex_batch",
+            "gemini",
+            "web_search",
+            "web_fetch",
+            "describe_tools",
+        ] {
+            let entry = &report[name];
+            assert!(
+                !entry["description"].as_str().unwrap_or_default().is_empty(),
+                "{}",
+                name
+            );
+            assert_eq!(entry["input_schema"]["type"], "object", "{}", name);
+        }
+        assert!(report["codex"]["input_schema"]["properties"]
+            .get("PROMPT")
+            .is_some());
+        assert!(report["web_search"]["input_schema"]["properties"]
+            .get("query")
+            .is_some());
+    }
+
+    fn roots_test_server() -> UnifiedServer {
+        UnifiedServer::new(Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        })
+    }
+
+    #[test]
+    fn test_refresh_roots_skips_request_when_disabled() {
+        let server = roots_test_server();
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let fetch = RootsFetch {
+            enabled: false,
+            timeout: std::time::Duration::from_secs(3),
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(server.refresh_roots(fetch, || {
+            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { Ok::<_, String>(ListRootsResult::default()) }
+        }));
+
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert!(rt.block_on(server.roots.read()).is_empty());
+    }
+
+    #[test]
+    fn test_refresh_roots_records_client_roots() {
+        let server = roots_test_server();
+        let dir = tempfile::tempdir().unwrap();
+        let uri = reqwest::Url::from_directory_path(dir.path())
+            .unwrap()
+            .to_string();
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let fetch = RootsFetch {
+            enabled: true,
+            timeout: std::time::Duration::from_secs(3),
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(server.refresh_roots(fetch, || {
+            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                Ok::<_, String>(ListRootsResult {
+                    roots: vec![Root { uri, name: None }],
+                })
+            }
+        }));
+
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(rt.block_on(server.roots.read()).len(), 1);
+    }
+
+    #[test]
+    fn test_roots_fetch_from_env() {
+        std::env::set_var(ENV_DISABLE_ROOTS, "1");
+        std::env::set_var(ENV_ROOTS_TIMEOUT_MS, "250");
+        let fetch = RootsFetch::from_env();
+        std::env::remove_var(ENV_DISABLE_ROOTS);
+        std::env::remove_var(ENV_ROOTS_TIMEOUT_MS);
+        assert!(!fetch.enabled);
+        assert_eq!(fetch.timeout, std::time::Duration::from_millis(250));
+
+        let fetch = RootsFetch::from_env();
+        assert!(fetch.enabled);
+        assert_eq!(
+            fetch.timeout,
+            std::time::Duration::from_millis(DEFAULT_ROOTS_TIMEOUT_MS)
+        );
+    }
+
+    #[test]
+    fn test_list_models_requires_grok() {
+        let caps = Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        };
+        let server = UnifiedServer::new(caps);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let err = rt.block_on(server.list_models()).unwrap_err();
+        assert!(err.message.contains("GROK_API_URL"));
+    }
+
+    #[test]
+    #[ignore] // Requires gemini and codex CLIs; run manually with: cargo test --lib -- --ignored
+    fn test_smoke_test_against_installed_clis() {
+        let server = UnifiedServer::new(crate::detection::detect());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt
+            .block_on(server.smoke_test(Parameters(SmokeTestArgs {
+                timeout_secs: Some(60),
+            })))
+            .unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        let report: serde_json::Value = serde_json::from_str(text).unwrap();
+
+        for backend in report["backends"].as_array().unwrap() {
+            assert_ne!(backend["status"], "failed", "{}", backend);
+        }
+    }
+
+    #[test]
+    fn test_smoke_test_skips_unavailable_backends() {
+        let caps = Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        };
+        let server = UnifiedServer::new(caps);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let result = rt
+            .block_on(server.smoke_test(Parameters(SmokeTestArgs { timeout_secs: None })))
+            .unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        let report: serde_json::Value = serde_json::from_str(text).unwrap();
+        assert_eq!(report["timeout_secs"], 30);
+        let backends = report["backends"].as_array().unwrap();
+        assert_eq!(backends.len(), 2);
+        assert!(backends.iter().all(|b| b["status"] == "skipped"));
+
+        let err = rt
+            .block_on(server.smoke_test(Parameters(SmokeTestArgs {
+                timeout_secs: Some(0),
+            })))
+            .unwrap_err();
+        assert!(err.message.contains("timeout_secs"));
+    }
+
+    #[test]
+    fn test_get_info_identity_overrides() {
+        let caps = Capabilities {
+            gemini_available: false,
+            gemini_path: None,
+            gemini_found_not_executable: false,
+            codex_available: false,
+            codex_path: None,
+            codex_found_not_executable: false,
+            grok_available: false,
+        };
+        let server = UnifiedServer::new(caps);
+
+        std::env::remove_var(ENV_SERVER_NAME);
+        std::env::remove_var(ENV_INSTRUCTIONS);
+        let info = server.get_info();
+        assert_eq!(info.server_info.name, Implementation::from_build_env().name);
+        assert_eq!(info.instructions.as_deref(), Some(DEFAULT_INSTRUCTIONS));
+
+        std::env::set_var(ENV_SERVER_NAME, "acme-ai");
+        std::env::set_var(ENV_INSTRUCTIONS, "Acme internal assistant tools.");
+        let info = server.get_info();
+        std::env::remove_var(ENV_SERVER_NAME);
+        std::env::remove_var(ENV_INSTRUCTIONS);
+
+        assert_eq!(info.server_info.name, "acme-ai");
+        assert_eq!(
+            info.instructions.as_deref(),
+            Some("Acme internal assistant tools.")
+        );
     }
 }
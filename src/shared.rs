@@ -1,4 +1,7 @@
-use std::path::PathBuf;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 
 /// Default timeout in seconds (10 minutes)
 pub const DEFAULT_TIMEOUT_SECS: u64 = 600;
@@ -9,15 +12,557 @@ pub const MAX_TIMEOUT_SECS: u64 = 3600;
 /// Minimum allowed timeout in seconds
 pub const MIN_TIMEOUT_SECS: u64 = 1;
 
+/// Outcome of looking up a CLI binary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryLookup {
+    /// An executable binary at this path
+    Found(PathBuf),
+    /// The environment override points at a file that cannot be executed
+    NotExecutable(PathBuf),
+    NotFound,
+}
+
 /// Find a binary by name, checking an environment variable override first.
 pub fn find_binary(name: &str, env_override: &str) -> Option<PathBuf> {
+    match locate_binary(name, env_override) {
+        BinaryLookup::Found(path) => Some(path),
+        BinaryLookup::NotExecutable(_) | BinaryLookup::NotFound => None,
+    }
+}
+
+/// Like [`find_binary`], but reports an override that exists without being executable
+/// instead of treating it as available. `PATH` lookups only ever return executables.
+pub fn locate_binary(name: &str, env_override: &str) -> BinaryLookup {
+    locate_binary_with_prepend(name, env_override, None)
+}
+
+/// Like [`locate_binary`], but searches the directories listed in env var
+/// `path_prepend` (see [`apply_path_prepend`]) ahead of `PATH`, as the child would.
+pub fn locate_binary_with_prepend(
+    name: &str,
+    env_override: &str,
+    path_prepend: Option<&str>,
+) -> BinaryLookup {
     if let Ok(path) = std::env::var(env_override) {
         let p = PathBuf::from(&path);
         if p.exists() {
-            return Some(p);
+            return if is_executable(&p) {
+                BinaryLookup::Found(p)
+            } else {
+                BinaryLookup::NotExecutable(p)
+            };
+        }
+    }
+    let search_path = path_prepend
+        .and_then(std::env::var_os)
+        .and_then(|prepend| prepend_path(&prepend, std::env::var_os("PATH").as_deref()));
+    let found = match search_path {
+        Some(search_path) => std::env::current_dir()
+            .map_err(|_| ())
+            .and_then(|cwd| which::which_in(name, Some(search_path), cwd).map_err(|_| ())),
+        None => which::which(name).map_err(|_| ()),
+    };
+    match found {
+        Ok(path) => BinaryLookup::Found(path),
+        Err(()) => BinaryLookup::NotFound,
+    }
+}
+
+/// Whether `path` is a file the OS can execute: any execute permission bit on Unix,
+/// an executable extension on Windows.
+pub fn is_executable(path: &Path) -> bool {
+    if !path.is_file() {
+        return false;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .map(|m| m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+    #[cfg(windows)]
+    {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| {
+                ["exe", "cmd", "bat", "com"]
+                    .iter()
+                    .any(|x| e.eq_ignore_ascii_case(x))
+            })
+            .unwrap_or(false)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        true
+    }
+}
+
+/// Wrap a prompt with an optional prefix and suffix, each separated from it by a
+/// blank line. Unset or whitespace-only values are ignored.
+pub fn wrap_prompt(prompt: String, prefix: Option<&str>, suffix: Option<&str>) -> String {
+    let prefix = prefix.filter(|p| !p.trim().is_empty());
+    let suffix = suffix.filter(|s| !s.trim().is_empty());
+    if prefix.is_none() && suffix.is_none() {
+        return prompt;
+    }
+
+    let mut wrapped = String::new();
+    if let Some(prefix) = prefix {
+        wrapped.push_str(prefix);
+        wrapped.push_str("\n\n");
+    }
+    wrapped.push_str(&prompt);
+    if let Some(suffix) = suffix {
+        wrapped.push_str("\n\n");
+        wrapped.push_str(suffix);
+    }
+    wrapped
+}
+
+/// Marker appended to output cut short by a size limit
+pub const DEFAULT_TRUNCATION_MARKER: &str = "[... truncated due to size limit ...]";
+
+/// Marker appended to truncated output. Reads `AIMCP_TRUNCATION_MARKER`, falling
+/// back to [`DEFAULT_TRUNCATION_MARKER`] when unset or empty.
+pub fn truncation_marker() -> String {
+    std::env::var("AIMCP_TRUNCATION_MARKER")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| DEFAULT_TRUNCATION_MARKER.to_string())
+}
+
+/// Largest index at or below `index` that falls on a char boundary of `s`
+pub fn floor_char_boundary(s: &str, index: usize) -> usize {
+    if index >= s.len() {
+        return s.len();
+    }
+    (0..=index)
+        .rev()
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+/// Longest prefix of `s` of at most `max` bytes that does not split a char.
+/// Use instead of `&s[..s.len().min(max)]`, which panics mid-char.
+pub fn truncate_str(s: &str, max: usize) -> &str {
+    &s[..floor_char_boundary(s, max)]
+}
+
+/// `s` unchanged when it fits in `max` bytes; otherwise its longest prefix of at
+/// most `max` bytes that does not split a char, followed by `marker`.
+pub fn truncate_with_marker<'a>(s: &'a str, max: usize, marker: &str) -> Cow<'a, str> {
+    if s.len() <= max {
+        return Cow::Borrowed(s);
+    }
+    let mut truncated = truncate_str(s, max).to_string();
+    truncated.push_str(marker);
+    Cow::Owned(truncated)
+}
+
+/// Append `line` to the newline-separated `buf`, keeping `buf` within `max`
+/// bytes. When the line does not fit, the part that does is kept and `marker`
+/// goes on its own line after it; returns true in that case so callers can stop
+/// appending.
+pub fn push_line_with_limit(buf: &mut String, line: &str, max: usize, marker: &str) -> bool {
+    let separator = usize::from(!buf.is_empty());
+    let remaining = max.saturating_sub(buf.len() + separator);
+    let kept = truncate_with_marker(line, remaining, "");
+    if !kept.is_empty() || kept.len() == line.len() {
+        if separator == 1 {
+            buf.push('\n');
+        }
+        buf.push_str(&kept);
+    }
+    if kept.len() == line.len() {
+        return false;
+    }
+    if !buf.is_empty() {
+        buf.push('\n');
+    }
+    buf.push_str(marker);
+    true
+}
+
+/// Optional cleanup applied to `agent_messages` before it is returned. Off by default,
+/// so the text stays exactly as the model produced it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageNormalization {
+    /// Convert CRLF to LF and strip trailing whitespace from every line
+    pub whitespace: bool,
+    /// Collapse runs of two or more blank lines into a single blank line
+    pub collapse_blank_lines: bool,
+}
+
+impl MessageNormalization {
+    /// Read `AIMCP_NORMALIZE_MESSAGES` and `AIMCP_COLLAPSE_BLANK_LINES`
+    pub fn from_env() -> Self {
+        Self {
+            whitespace: env_flag("AIMCP_NORMALIZE_MESSAGES"),
+            collapse_blank_lines: env_flag("AIMCP_COLLAPSE_BLANK_LINES"),
+        }
+    }
+
+    /// Apply the enabled rules to `text`
+    pub fn apply(&self, text: &str) -> String {
+        if !self.whitespace && !self.collapse_blank_lines {
+            return text.to_string();
+        }
+
+        let text = if self.whitespace {
+            text.replace("\r\n", "\n")
+                .split('\n')
+                .map(str::trim_end)
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            text.to_string()
+        };
+        if !self.collapse_blank_lines {
+            return text;
+        }
+
+        let mut collapsed = String::with_capacity(text.len());
+        let mut blank_run = 0;
+        for (i, line) in text.split('\n').enumerate() {
+            if line.trim().is_empty() {
+                blank_run += 1;
+                if blank_run > 1 {
+                    continue;
+                }
+            } else {
+                blank_run = 0;
+            }
+            if i > 0 {
+                collapsed.push('\n');
+            }
+            collapsed.push_str(line);
+        }
+        collapsed
+    }
+}
+
+/// Whether an on/off environment variable is set to a truthy value
+pub(crate) fn env_flag(key: &str) -> bool {
+    std::env::var(key)
+        .map(|v| {
+            matches!(
+                v.trim().to_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Parse one line of CLI JSON output from its raw bytes.
+///
+/// The bytes are parsed as-is first, so valid output never goes through a lossy
+/// conversion. Only when that fails on a line that is not valid UTF-8 is it
+/// re-parsed with invalid bytes replaced by U+FFFD; the flag reports whether
+/// that replacement was needed. On failure the error from the raw parse is returned.
+pub fn parse_json_bytes(raw: &[u8]) -> Result<(Value, bool), serde_json::Error> {
+    let raw_error = match serde_json::from_slice::<Value>(raw) {
+        Ok(value) => return Ok((value, false)),
+        Err(e) => e,
+    };
+    if std::str::from_utf8(raw).is_ok() {
+        return Err(raw_error);
+    }
+    serde_json::from_str::<Value>(&String::from_utf8_lossy(raw))
+        .map(|value| (value, true))
+        .map_err(|_| raw_error)
+}
+
+/// `PATH` for a child process: the directories of `prepend` (a `PATH`-style list)
+/// in front of `current`. `None` when `prepend` names no directory or the result
+/// cannot be joined (a directory containing the path separator).
+pub fn prepend_path(prepend: &OsStr, current: Option<&OsStr>) -> Option<OsString> {
+    let mut dirs: Vec<PathBuf> = std::env::split_paths(prepend)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+    if dirs.is_empty() {
+        return None;
+    }
+    if let Some(current) = current {
+        dirs.extend(std::env::split_paths(current));
+    }
+    std::env::join_paths(dirs).ok()
+}
+
+/// Put the directories listed in env var `key` (e.g. `CODEX_PATH_PREPEND`) in front
+/// of the child's `PATH`, so both the CLI and the helper tools it runs resolve there first.
+pub fn apply_path_prepend(cmd: &mut tokio::process::Command, key: &str) {
+    let Some(prepend) = std::env::var_os(key) else {
+        return;
+    };
+    match prepend_path(&prepend, std::env::var_os("PATH").as_deref()) {
+        Some(path) => {
+            cmd.env("PATH", path);
+        }
+        None if !prepend.is_empty() => {
+            eprintln!("Warning: ignoring {}: not a usable PATH list", key);
+        }
+        None => {}
+    }
+}
+
+/// Default seconds to wait for a prompt write to a child's stdin before
+/// starting to read its output
+pub const DEFAULT_STDIN_WRITE_TIMEOUT_SECS: u64 = 5;
+
+/// How long a prompt write to a child's stdin may block before the child's
+/// output is read alongside it. Reads `AIMCP_STDIN_WRITE_TIMEOUT` (seconds).
+pub fn stdin_write_timeout() -> std::time::Duration {
+    let secs = std::env::var("AIMCP_STDIN_WRITE_TIMEOUT")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(DEFAULT_STDIN_WRITE_TIMEOUT_SECS);
+    std::time::Duration::from_secs(secs)
+}
+
+/// Outcome of [`write_stdin`]
+#[derive(Debug)]
+pub enum StdinWrite {
+    /// Everything was written and stdin closed
+    Done,
+    Failed(std::io::Error),
+    /// The write is still blocked after the timeout, typically because the child
+    /// fills its stdout before reading stdin. It keeps running in the background;
+    /// the caller must drain the child's output, then call [`finish_stdin`].
+    Pending(tokio::task::JoinHandle<std::io::Result<()>>),
+}
+
+/// Write `data` to a child's stdin and close it, giving up waiting after `timeout`.
+///
+/// Writing the whole prompt before reading any output deadlocks once both pipes
+/// are full, so a write that outlives `timeout` is left running instead of
+/// being awaited.
+pub async fn write_stdin(
+    mut stdin: tokio::process::ChildStdin,
+    data: Vec<u8>,
+    timeout: std::time::Duration,
+) -> StdinWrite {
+    use tokio::io::AsyncWriteExt;
+
+    let mut handle = tokio::spawn(async move {
+        stdin.write_all(&data).await?;
+        stdin.shutdown().await
+    });
+    match tokio::time::timeout(timeout, &mut handle).await {
+        Ok(Ok(Ok(()))) => StdinWrite::Done,
+        Ok(Ok(Err(e))) => StdinWrite::Failed(e),
+        Ok(Err(e)) => StdinWrite::Failed(std::io::Error::other(e)),
+        Err(_) => StdinWrite::Pending(handle),
+    }
+}
+
+/// Settle a write left [`StdinWrite::Pending`] once the child's output has been
+/// drained. Returns a warning when the child did not take the whole prompt.
+pub async fn finish_stdin(
+    mut handle: tokio::task::JoinHandle<std::io::Result<()>>,
+    cli: &str,
+) -> Option<String> {
+    match tokio::time::timeout(std::time::Duration::from_secs(1), &mut handle).await {
+        Ok(Ok(Ok(()))) => None,
+        Ok(Ok(Err(e))) => Some(format!(
+            "{} stopped reading the prompt from stdin before it was fully written ({})",
+            cli, e
+        )),
+        Ok(Err(e)) => Some(format!("writing the prompt to {} stdin failed: {}", cli, e)),
+        Err(_) => {
+            handle.abort();
+            Some(format!(
+                "{} exited without reading the full prompt from stdin",
+                cli
+            ))
         }
     }
-    which::which(name).ok()
+}
+
+/// Token bucket spacing out outbound API requests, shared by the Grok and Gemini
+/// image API clients so a burst of tool calls does not trip provider rate limits.
+///
+/// Holds at most `burst` tokens, refilled at `per_second`. A caller that finds the
+/// bucket empty reserves the next token anyway and sleeps until it is due, so
+/// waiting callers are served in arrival order and the lock is never held
+/// across an await.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    state: std::sync::Mutex<RateLimiterState>,
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    /// Available tokens; negative while callers are waiting on reserved tokens
+    tokens: f64,
+    refilled_at: std::time::Instant,
+}
+
+/// Slowest rate a [`RateLimiter`] accepts: one request per hour
+pub const MIN_RATE_LIMIT_PER_SECOND: f64 = 1.0 / 3600.0;
+
+impl RateLimiter {
+    /// A full bucket of `burst` tokens refilled at `per_second` tokens per second,
+    /// raised to at least [`MIN_RATE_LIMIT_PER_SECOND`]
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            per_second: per_second.max(MIN_RATE_LIMIT_PER_SECOND),
+            burst,
+            state: std::sync::Mutex::new(RateLimiterState {
+                tokens: burst,
+                refilled_at: std::time::Instant::now(),
+            }),
+        }
+    }
+
+    /// Process-wide limiter for outbound API requests, allowing
+    /// `AIMCP_GLOBAL_RATE_LIMIT` requests per second with no burst.
+    /// `None` when the variable is unset, not a number, or not positive.
+    pub fn global() -> Option<&'static RateLimiter> {
+        static LIMITER: std::sync::OnceLock<Option<RateLimiter>> = std::sync::OnceLock::new();
+        LIMITER
+            .get_or_init(|| {
+                std::env::var("AIMCP_GLOBAL_RATE_LIMIT")
+                    .ok()
+                    .and_then(|v| v.trim().parse::<f64>().ok())
+                    .filter(|rate| rate.is_finite() && *rate > 0.0)
+                    .map(|rate| RateLimiter::new(rate, 1))
+            })
+            .as_ref()
+    }
+
+    /// Take a token at `now`, returning how long the caller must wait before
+    /// its request may start
+    fn reserve(&self, now: std::time::Instant) -> std::time::Duration {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = now.saturating_duration_since(state.refilled_at);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.per_second).min(self.burst);
+        state.refilled_at = now.max(state.refilled_at);
+        state.tokens -= 1.0;
+        if state.tokens >= 0.0 {
+            std::time::Duration::ZERO
+        } else {
+            std::time::Duration::try_from_secs_f64(-state.tokens / self.per_second)
+                .unwrap_or(std::time::Duration::MAX)
+        }
+    }
+
+    /// Wait until a request may start
+    pub async fn acquire(&self) {
+        let wait = self.reserve(std::time::Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Wait for the global outbound API rate limit, if `AIMCP_GLOBAL_RATE_LIMIT` is set
+pub async fn acquire_api_token() {
+    if let Some(limiter) = RateLimiter::global() {
+        limiter.acquire().await;
+    }
+}
+
+/// Default idle connections kept per host by outbound HTTP clients
+pub const DEFAULT_HTTP_POOL_MAX_IDLE: usize = 32;
+
+/// Default seconds an idle pooled connection is kept open
+pub const DEFAULT_HTTP_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+
+/// Connection pool settings for the outbound HTTP clients (Grok and Gemini image API)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HttpPoolConfig {
+    /// Idle connections kept per host; 0 disables connection reuse
+    pub max_idle_per_host: usize,
+    pub idle_timeout: std::time::Duration,
+}
+
+impl Default for HttpPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: DEFAULT_HTTP_POOL_MAX_IDLE,
+            idle_timeout: std::time::Duration::from_secs(DEFAULT_HTTP_POOL_IDLE_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl HttpPoolConfig {
+    /// Settings from `AIMCP_HTTP_POOL_MAX_IDLE` and `AIMCP_HTTP_POOL_IDLE_TIMEOUT`
+    /// (seconds, positive) values. Unset values keep the defaults; invalid ones
+    /// keep them too and add a warning.
+    pub fn resolve(
+        max_idle: Option<String>,
+        idle_timeout: Option<String>,
+        warnings: &mut Vec<String>,
+    ) -> Self {
+        let mut config = Self::default();
+        if let Some(value) = max_idle.filter(|v| !v.trim().is_empty()) {
+            match value.trim().parse::<usize>() {
+                Ok(n) => config.max_idle_per_host = n,
+                Err(_) => warnings.push(format!(
+                    "AIMCP_HTTP_POOL_MAX_IDLE='{}' is not a non-negative integer; using {}",
+                    value, DEFAULT_HTTP_POOL_MAX_IDLE
+                )),
+            }
+        }
+        if let Some(value) = idle_timeout.filter(|v| !v.trim().is_empty()) {
+            match value.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => config.idle_timeout = std::time::Duration::from_secs(secs),
+                _ => warnings.push(format!(
+                    "AIMCP_HTTP_POOL_IDLE_TIMEOUT='{}' is not a positive number of seconds; using {}",
+                    value, DEFAULT_HTTP_POOL_IDLE_TIMEOUT_SECS
+                )),
+            }
+        }
+        config
+    }
+
+    /// Process-wide settings read from the environment once; warnings go to stderr
+    pub fn global() -> Self {
+        static CONFIG: std::sync::OnceLock<HttpPoolConfig> = std::sync::OnceLock::new();
+        *CONFIG.get_or_init(|| {
+            let mut warnings = Vec::new();
+            let config = Self::resolve(
+                std::env::var("AIMCP_HTTP_POOL_MAX_IDLE").ok(),
+                std::env::var("AIMCP_HTTP_POOL_IDLE_TIMEOUT").ok(),
+                &mut warnings,
+            );
+            for warning in warnings {
+                eprintln!("ikuncode-aimcp: warning: {}", warning);
+            }
+            config
+        })
+    }
+
+    /// Apply the pool settings to `builder`
+    pub fn apply(self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        builder
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .pool_idle_timeout(self.idle_timeout)
+    }
+}
+
+/// Client builder for outbound API requests, with the global pool settings applied
+pub fn http_client_builder() -> reqwest::ClientBuilder {
+    HttpPoolConfig::global().apply(reqwest::Client::builder())
+}
+
+/// The client in `cell`, built by `build` on first use, so every call made with
+/// the same settings shares one connection pool. A build error is returned and
+/// the next call tries again.
+pub fn shared_http_client(
+    cell: &'static std::sync::OnceLock<reqwest::Client>,
+    build: impl FnOnce() -> reqwest::Result<reqwest::Client>,
+) -> reqwest::Result<reqwest::Client> {
+    if let Some(client) = cell.get() {
+        return Ok(client.clone());
+    }
+    let client = build()?;
+    Ok(cell.get_or_init(|| client).clone())
 }
 
 /// Windows Job Object: assigns a child process to a job configured with
@@ -174,10 +719,282 @@ pub mod job_object {
     }
 }
 
+/// Unix counterpart of [`job_object`]: the child is spawned as the leader of its
+/// own process group, so everything it started can be signalled together.
+#[cfg(unix)]
+pub mod process_group {
+    const SIGKILL: i32 = 9;
+
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+
+    /// RAII wrapper for a process group. Kills every process in the group
+    /// when `terminate()` is called or the handle is dropped.
+    pub struct ProcessGroup {
+        pgid: i32,
+    }
+
+    impl ProcessGroup {
+        /// Track the group led by `pid`, which must have been spawned with
+        /// `process_group(0)`.
+        pub fn new(pid: u32) -> Option<Self> {
+            i32::try_from(pid)
+                .ok()
+                .filter(|pgid| *pgid > 0)
+                .map(|pgid| ProcessGroup { pgid })
+        }
+
+        /// Send SIGKILL to every process in the group.
+        pub fn terminate(&self) {
+            unsafe {
+                kill(-self.pgid, SIGKILL);
+            }
+        }
+    }
+
+    impl Drop for ProcessGroup {
+        fn drop(&mut self) {
+            // Like KILL_ON_JOB_CLOSE on Windows: nothing the child started
+            // outlives the handle.
+            self.terminate();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_truncate_with_marker_boundaries() {
+        assert_eq!(truncate_with_marker("hello", 5, "…"), "hello");
+        assert!(matches!(
+            truncate_with_marker("hello", 5, "…"),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(truncate_with_marker("hello!", 5, "…"), "hello…");
+        assert_eq!(truncate_with_marker("hello", 0, "[cut]"), "[cut]");
+        assert_eq!(truncate_with_marker("", 0, "[cut]"), "");
+    }
+
+    #[test]
+    fn test_truncate_with_marker_does_not_split_chars() {
+        // Each of these chars is 3 bytes; cutting at 4 or 5 would land mid-char
+        let s = "中文字符";
+        assert_eq!(truncate_with_marker(s, 4, "…"), "中…");
+        assert_eq!(truncate_with_marker(s, 5, "…"), "中…");
+        assert_eq!(truncate_with_marker(s, 6, "…"), "中文…");
+        assert_eq!(truncate_with_marker(s, 2, "…"), "…");
+        assert_eq!(floor_char_boundary(s, 7), 6);
+        assert_eq!(floor_char_boundary(s, 100), s.len());
+    }
+
+    #[test]
+    fn test_truncate_str_on_chinese_text() {
+        let s = "你好，世界".repeat(20);
+        // A naive `&s[..100]` panics here: byte 100 is inside a 3-byte char
+        assert!(!s.is_char_boundary(100));
+        let prefix = truncate_str(&s, 100);
+        assert_eq!(prefix.len(), 99);
+        assert!(s.starts_with(prefix));
+        assert_eq!(truncate_str(&s, 200).len(), 198);
+        assert_eq!(truncate_str("ab中", 3), "ab");
+        assert_eq!(truncate_str("ab中", 5), "ab中");
+    }
+
+    #[test]
+    fn test_http_pool_config_reads_values() {
+        let mut warnings = Vec::new();
+        let config = HttpPoolConfig::resolve(Some(" 4 ".into()), Some("30".into()), &mut warnings);
+        assert_eq!(config.max_idle_per_host, 4);
+        assert_eq!(config.idle_timeout, std::time::Duration::from_secs(30));
+        assert!(warnings.is_empty());
+        assert!(config.apply(reqwest::Client::builder()).build().is_ok());
+
+        let config = HttpPoolConfig::resolve(Some("0".into()), None, &mut warnings);
+        assert_eq!(config.max_idle_per_host, 0);
+        assert_eq!(
+            HttpPoolConfig::resolve(None, None, &mut warnings),
+            HttpPoolConfig::default()
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_http_pool_config_invalid_values_fall_back() {
+        let mut warnings = Vec::new();
+        let config = HttpPoolConfig::resolve(Some("many".into()), Some("0".into()), &mut warnings);
+        assert_eq!(config, HttpPoolConfig::default());
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("AIMCP_HTTP_POOL_MAX_IDLE"));
+        assert!(warnings[1].contains("AIMCP_HTTP_POOL_IDLE_TIMEOUT"));
+        assert!(config.apply(reqwest::Client::builder()).build().is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_spaces_reservations() {
+        let limiter = RateLimiter::new(4.0, 1);
+        let start = std::time::Instant::now();
+        let ms = |ms| std::time::Duration::from_millis(ms);
+
+        // The first token is free, then each request waits 250ms behind the previous one
+        assert_eq!(limiter.reserve(start), ms(0));
+        assert_eq!(limiter.reserve(start), ms(250));
+        assert_eq!(limiter.reserve(start), ms(500));
+        // Time passing pays back what was reserved
+        assert_eq!(limiter.reserve(start + ms(500)), ms(250));
+        // After an idle period the bucket refills, but only up to the burst size
+        assert_eq!(limiter.reserve(start + ms(5000)), ms(0));
+        assert_eq!(limiter.reserve(start + ms(5000)), ms(250));
+    }
+
+    #[test]
+    fn test_rate_limiter_clamps_tiny_rate() {
+        let limiter = RateLimiter::new(1e-20, 1);
+        let now = std::time::Instant::now();
+        assert!(limiter.reserve(now).is_zero());
+        assert_eq!(limiter.reserve(now), std::time::Duration::from_secs(3600));
+        assert_eq!(limiter.reserve(now), std::time::Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn test_rate_limiter_allows_burst() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let now = std::time::Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.reserve(now).is_zero());
+        }
+        assert_eq!(limiter.reserve(now), std::time::Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_rate_limiter_acquire_waits() {
+        let limiter = RateLimiter::new(20.0, 1);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let start = std::time::Instant::now();
+        rt.block_on(async {
+            for _ in 0..4 {
+                limiter.acquire().await;
+            }
+        });
+        // Three waits of 50ms each after the free first token
+        assert!(start.elapsed() >= std::time::Duration::from_millis(145));
+    }
+
+    #[test]
+    fn test_push_line_with_limit() {
+        let mut buf = String::new();
+        assert!(!push_line_with_limit(&mut buf, "abc", 8, "[cut]"));
+        assert!(!push_line_with_limit(&mut buf, "", 8, "[cut]"));
+        assert_eq!(buf, "abc\n");
+        assert!(push_line_with_limit(&mut buf, "defgh", 8, "[cut]"));
+        assert_eq!(buf, "abc\n\ndef\n[cut]");
+
+        let mut buf = "abcd".to_string();
+        assert!(push_line_with_limit(&mut buf, "中文", 8, "[cut]"));
+        assert_eq!(buf, "abcd\n中\n[cut]");
+    }
+
+    #[test]
+    fn test_prepend_path_puts_dirs_first() {
+        let current = std::env::join_paths(["/usr/bin", "/bin"]).unwrap();
+        let prepend = std::env::join_paths(["/opt/codex-1/bin", "/opt/tools"]).unwrap();
+        let path = prepend_path(&prepend, Some(&current)).unwrap();
+        let dirs: Vec<PathBuf> = std::env::split_paths(&path).collect();
+        assert_eq!(
+            dirs,
+            ["/opt/codex-1/bin", "/opt/tools", "/usr/bin", "/bin"].map(PathBuf::from)
+        );
+
+        let path = prepend_path(OsStr::new("/opt/only"), None).unwrap();
+        assert_eq!(path, OsString::from("/opt/only"));
+        assert!(prepend_path(OsStr::new(""), Some(&current)).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_locate_binary_with_prepend_searches_prepended_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("aimcp_prepend_probe_xyz");
+        std::fs::write(&bin, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let key = "AIMCP_TEST_PATH_PREPEND_PROBE";
+
+        assert_eq!(
+            locate_binary_with_prepend("aimcp_prepend_probe_xyz", "NONEXISTENT_ENV_VAR", Some(key)),
+            BinaryLookup::NotFound
+        );
+        std::env::set_var(key, dir.path());
+        let found =
+            locate_binary_with_prepend("aimcp_prepend_probe_xyz", "NONEXISTENT_ENV_VAR", Some(key));
+        std::env::remove_var(key);
+        assert_eq!(found, BinaryLookup::Found(bin));
+    }
+
+    /// A child that writes `stdout_bytes` to stdout before reading any stdin
+    #[cfg(unix)]
+    fn spawn_stdout_first_child(stdout_bytes: usize) -> tokio::process::Child {
+        tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "head -c {} /dev/zero | tr '\\0' x; wc -c | tr -d ' '",
+                stdout_bytes
+            ))
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_stdin_does_not_deadlock_child_filling_stdout() {
+        use tokio::io::AsyncReadExt;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            // Both payloads exceed any pipe buffer, so a blocking write would deadlock
+            let mut child = spawn_stdout_first_child(1 << 20);
+            let stdin = child.stdin.take().unwrap();
+            let prompt = vec![b'p'; 1 << 20];
+            let write = write_stdin(stdin, prompt, std::time::Duration::from_millis(200)).await;
+            let StdinWrite::Pending(handle) = write else {
+                panic!("expected a pending write, got {:?}", write);
+            };
+
+            let mut stdout = String::new();
+            child
+                .stdout
+                .take()
+                .unwrap()
+                .read_to_string(&mut stdout)
+                .await
+                .unwrap();
+            assert!(finish_stdin(handle, "test").await.is_none());
+            assert!(child.wait().await.unwrap().success());
+            // The child's stdout, then the byte count of the prompt it read
+            assert!(stdout.ends_with(&format!("x{}\n", 1 << 20)));
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_stdin_small_prompt_completes() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut child = spawn_stdout_first_child(10);
+            let stdin = child.stdin.take().unwrap();
+            let write = write_stdin(stdin, b"hi".to_vec(), std::time::Duration::from_secs(5)).await;
+            assert!(matches!(write, StdinWrite::Done), "{:?}", write);
+            let output = child.wait_with_output().await.unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "xxxxxxxxxx2\n");
+        });
+    }
+
     #[test]
     fn test_timeout_constants() {
         assert_eq!(DEFAULT_TIMEOUT_SECS, 600);
@@ -188,5 +1005,137 @@ mod tests {
     #[test]
     fn test_find_binary_nonexistent() {
         assert!(find_binary("this_binary_does_not_exist_xyz", "NONEXISTENT_ENV_VAR").is_none());
+        assert_eq!(
+            locate_binary("this_binary_does_not_exist_xyz", "NONEXISTENT_ENV_VAR"),
+            BinaryLookup::NotFound
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_locate_binary_checks_override_is_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("fake-cli");
+        std::fs::write(&bin, "#!/bin/sh\n").unwrap();
+        std::env::set_var("AIMCP_TEST_LOCATE_BIN", &bin);
+
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(!is_executable(&bin));
+        assert_eq!(
+            locate_binary("this_binary_does_not_exist_xyz", "AIMCP_TEST_LOCATE_BIN"),
+            BinaryLookup::NotExecutable(bin.clone())
+        );
+        assert!(find_binary("this_binary_does_not_exist_xyz", "AIMCP_TEST_LOCATE_BIN").is_none());
+
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(is_executable(&bin));
+        assert_eq!(
+            locate_binary("this_binary_does_not_exist_xyz", "AIMCP_TEST_LOCATE_BIN"),
+            BinaryLookup::Found(bin.clone())
+        );
+
+        std::env::remove_var("AIMCP_TEST_LOCATE_BIN");
+    }
+
+    #[test]
+    fn test_is_executable_rejects_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!is_executable(dir.path()));
+    }
+
+    #[test]
+    fn test_message_normalization_off_keeps_raw() {
+        let raw = "a  \r\nb\n\n\n\nc";
+        assert_eq!(MessageNormalization::default().apply(raw), raw);
+    }
+
+    #[test]
+    fn test_message_normalization_crlf_and_trailing_whitespace() {
+        let norm = MessageNormalization {
+            whitespace: true,
+            collapse_blank_lines: false,
+        };
+        assert_eq!(norm.apply("one \t\r\ntwo\r\n"), "one\ntwo\n");
+        assert_eq!(norm.apply("  indented  \nkept"), "  indented\nkept");
+        // Blank lines are kept as-is without collapsing
+        assert_eq!(norm.apply("a\n\n\n\nb"), "a\n\n\n\nb");
+    }
+
+    #[test]
+    fn test_message_normalization_collapses_blank_lines() {
+        let norm = MessageNormalization {
+            whitespace: false,
+            collapse_blank_lines: true,
+        };
+        assert_eq!(norm.apply("a\n\n\n\nb"), "a\n\nb");
+        assert_eq!(norm.apply("a\n \n\t\nb"), "a\n \nb");
+        assert_eq!(norm.apply("a\n\nb"), "a\n\nb");
+    }
+
+    #[test]
+    fn test_message_normalization_all_rules() {
+        let norm = MessageNormalization {
+            whitespace: true,
+            collapse_blank_lines: true,
+        };
+        assert_eq!(
+            norm.apply("Title  \r\n\r\n  \r\n\r\nBody \r\n"),
+            "Title\n\nBody\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_json_bytes_valid() {
+        let (value, replaced) =
+            parse_json_bytes(b"{\"text\":\"caf\\u00e9 \xe2\x9c\x93\"}").unwrap();
+        assert_eq!(value["text"], "caf\u{e9} \u{2713}");
+        assert!(!replaced);
+    }
+
+    #[test]
+    fn test_parse_json_bytes_invalid_utf8_inside_string() {
+        let (value, replaced) = parse_json_bytes(b"{\"text\":\"bad \xff byte\"}").unwrap();
+        assert_eq!(value["text"], "bad \u{fffd} byte");
+        assert!(replaced);
+    }
+
+    #[test]
+    fn test_parse_json_bytes_invalid_utf8_outside_string() {
+        let err = parse_json_bytes(b"{\"text\":\"ok\"}\xff").unwrap_err();
+        assert!(err.is_syntax());
+        assert!(parse_json_bytes(b"\xff{\"text\":\"ok\"}").is_err());
+    }
+
+    #[test]
+    fn test_wrap_prompt_prefix_and_suffix_order() {
+        let wrapped = wrap_prompt(
+            "fix the bug".to_string(),
+            Some("Always respond in English."),
+            Some("Never delete files."),
+        );
+        assert_eq!(
+            wrapped,
+            "Always respond in English.\n\nfix the bug\n\nNever delete files."
+        );
+
+        assert_eq!(
+            wrap_prompt("task".to_string(), Some("rules"), None),
+            "rules\n\ntask"
+        );
+        assert_eq!(
+            wrap_prompt("task".to_string(), None, Some("rules")),
+            "task\n\nrules"
+        );
+    }
+
+    #[test]
+    fn test_wrap_prompt_empty_is_noop() {
+        assert_eq!(wrap_prompt("task".to_string(), None, None), "task");
+        assert_eq!(
+            wrap_prompt("task".to_string(), Some(""), Some("  \n")),
+            "task"
+        );
     }
 }
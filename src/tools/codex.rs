@@ -3,12 +3,19 @@ use rmcp::schemars;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
 use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
 
-use crate::shared::{DEFAULT_TIMEOUT_SECS, MAX_TIMEOUT_SECS};
+use crate::shared::{
+    apply_path_prepend, finish_stdin, locate_binary_with_prepend, parse_json_bytes,
+    push_line_with_limit, stdin_write_timeout, truncation_marker, wrap_prompt, write_stdin,
+    BinaryLookup, MessageNormalization, StdinWrite, DEFAULT_TIMEOUT_SECS, MAX_TIMEOUT_SECS,
+};
 
 /// Sandbox policy for model-generated commands
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema, Default)]
@@ -33,6 +40,299 @@ impl SandboxPolicy {
     }
 }
 
+/// Tracks running codex children by SESSION_ID so they can be terminated
+/// out-of-band (see the `codex_kill` tool).
+#[derive(Debug, Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<String, (u64, CancellationToken)>>>,
+    next_id: Arc<AtomicU64>,
+    /// Working directory each session was started in, for resume warnings
+    cwds: Arc<Mutex<HashMap<String, PathBuf>>>,
+    /// Runtime accumulated by each session across resumes, see `CODEX_SESSION_MAX_TOTAL_SECS`
+    runtimes: Arc<Mutex<HashMap<String, std::time::Duration>>>,
+}
+
+/// Upper bound on sessions remembered per-session (working directory, runtime)
+const MAX_SESSION_CWDS: usize = 1024;
+
+/// Insert into a per-session map, evicting an arbitrary entry once it is full
+fn insert_bounded<V>(map: &mut HashMap<String, V>, session_id: &str, value: V) {
+    if map.len() >= MAX_SESSION_CWDS && !map.contains_key(session_id) {
+        if let Some(evicted) = map.keys().next().cloned() {
+            map.remove(&evicted);
+        }
+    }
+    map.insert(session_id.to_string(), value);
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a running session. The entry is removed when the returned
+    /// guard is dropped.
+    pub fn register(&self, session_id: &str) -> SessionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session_id.to_string(), (id, token.clone()));
+        SessionGuard {
+            registry: self.clone(),
+            session_id: session_id.to_string(),
+            id,
+            token,
+        }
+    }
+
+    /// Whether a session with this id is currently running.
+    pub fn contains(&self, session_id: &str) -> bool {
+        self.sessions.lock().unwrap().contains_key(session_id)
+    }
+
+    /// Signal the running session to terminate its child process.
+    /// Returns false if no running session matched.
+    pub fn kill(&self, session_id: &str) -> bool {
+        match self.sessions.lock().unwrap().remove(session_id) {
+            Some((_, token)) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remember the working directory a new session was started in.
+    pub fn record_cwd(&self, session_id: &str, dir: &Path) {
+        insert_bounded(
+            &mut self.cwds.lock().unwrap(),
+            session_id,
+            dir.to_path_buf(),
+        );
+    }
+
+    /// The working directory a session was started in, if this process saw it start.
+    pub fn cwd_of(&self, session_id: &str) -> Option<PathBuf> {
+        self.cwds.lock().unwrap().get(session_id).cloned()
+    }
+
+    /// Add the duration of one run to the session's total.
+    pub fn add_runtime(&self, session_id: &str, elapsed: std::time::Duration) {
+        let mut runtimes = self.runtimes.lock().unwrap();
+        let total = runtimes.get(session_id).copied().unwrap_or_default() + elapsed;
+        insert_bounded(&mut runtimes, session_id, total);
+    }
+
+    /// Total runtime of the session's runs seen by this process.
+    pub fn total_runtime(&self, session_id: &str) -> std::time::Duration {
+        self.runtimes
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Ceiling on a session's total runtime across resumes, from
+/// `CODEX_SESSION_MAX_TOTAL_SECS`. Unset or 0 means no ceiling.
+pub fn get_session_max_total_secs() -> Option<u64> {
+    std::env::var("CODEX_SESSION_MAX_TOTAL_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&secs| secs > 0)
+}
+
+/// Timeout for resuming a session that has already run for `used`, so the
+/// session stays within `max_total_secs`. Errors once the budget is spent.
+fn session_budget_timeout(
+    used: std::time::Duration,
+    max_total_secs: u64,
+    timeout_secs: u64,
+) -> std::result::Result<u64, String> {
+    let remaining = max_total_secs.saturating_sub(used.as_secs());
+    if remaining == 0 {
+        return Err(format!(
+            "Session has already run for {}s, reaching CODEX_SESSION_MAX_TOTAL_SECS={}; start a new session instead of resuming this one",
+            used.as_secs(),
+            max_total_secs
+        ));
+    }
+    Ok(timeout_secs.min(remaining))
+}
+
+/// Registration handle for a running session; unregisters on drop.
+pub struct SessionGuard {
+    registry: SessionRegistry,
+    session_id: String,
+    id: u64,
+    token: CancellationToken,
+}
+
+impl SessionGuard {
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut sessions = self.registry.sessions.lock().unwrap();
+        // Only remove our own entry; a later run may have re-registered the id.
+        if sessions.get(&self.session_id).map(|(id, _)| *id) == Some(self.id) {
+            sessions.remove(&self.session_id);
+        }
+    }
+}
+
+/// What a resume does while another call is already resuming the same SESSION_ID,
+/// from `CODEX_CONCURRENT_RESUME`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConcurrentResumePolicy {
+    /// Wait for the other call to finish, so resumes of one session run one at a time
+    #[default]
+    Wait,
+    /// Fail the second call right away
+    Reject,
+}
+
+impl ConcurrentResumePolicy {
+    /// Parse a `CODEX_CONCURRENT_RESUME` value; unknown values fall back to `wait` with a warning
+    pub fn from_env_value(env_val: Option<String>, warnings: &mut Vec<String>) -> Self {
+        let Some(value) = env_val.filter(|v| !v.trim().is_empty()) else {
+            return Self::default();
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "wait" => Self::Wait,
+            "reject" => Self::Reject,
+            _ => {
+                warnings.push(format!(
+                    "Environment variable CODEX_CONCURRENT_RESUME has unrecognized value '{}'; expected wait or reject. Using wait.",
+                    value
+                ));
+                Self::default()
+            }
+        }
+    }
+}
+
+/// [`ConcurrentResumePolicy::from_env_value`] read from `CODEX_CONCURRENT_RESUME`
+pub fn get_concurrent_resume_policy(warnings: &mut Vec<String>) -> ConcurrentResumePolicy {
+    ConcurrentResumePolicy::from_env_value(std::env::var("CODEX_CONCURRENT_RESUME").ok(), warnings)
+}
+
+/// One lock per SESSION_ID being resumed, so concurrent resumes of a session
+/// do not run codex on it at the same time
+#[derive(Debug, Clone, Default)]
+pub struct SessionLocks {
+    locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl SessionLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the lock for `session_id`, waiting for or rejecting a call that holds it
+    /// according to `policy`. The error is a user-facing message.
+    pub async fn acquire(
+        &self,
+        session_id: &str,
+        policy: ConcurrentResumePolicy,
+    ) -> std::result::Result<SessionLockGuard, String> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(session_id.to_string())
+            .or_default()
+            .clone();
+        let held = match policy {
+            ConcurrentResumePolicy::Wait => Ok(lock.lock_owned().await),
+            ConcurrentResumePolicy::Reject => lock.try_lock_owned(),
+        };
+        let guard = SessionLockGuard {
+            locks: self.clone(),
+            session_id: session_id.to_string(),
+            held: held.ok(),
+        };
+        if guard.held.is_none() {
+            return Err(format!(
+                "SESSION_ID {} is already being resumed by another call; retry once it finishes (CODEX_CONCURRENT_RESUME=reject)",
+                session_id
+            ));
+        }
+        Ok(guard)
+    }
+
+    /// Number of sessions with a lock entry, for tests
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+/// Holds a session's resume lock; the map entry is removed once nobody uses it
+pub struct SessionLockGuard {
+    locks: SessionLocks,
+    session_id: String,
+    held: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for SessionLockGuard {
+    fn drop(&mut self) {
+        drop(self.held.take());
+        let mut locks = self.locks.locks.lock().unwrap();
+        // Waiters hold their own clone of the lock, so this only drops an unused entry
+        if locks
+            .get(&self.session_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.session_id);
+        }
+    }
+}
+
+/// Whether two paths name the same directory, comparing canonical forms when possible.
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Warning for resuming a session from a different directory than it ran in.
+fn resume_cwd_warning(session_dir: &Path, working_dir: &Path) -> Option<String> {
+    (!same_dir(session_dir, working_dir)).then(|| {
+        format!(
+            "Session was created in {} but is being resumed with cd={}; codex may read and write files relative to the original directory",
+            session_dir.display(),
+            working_dir.display()
+        )
+    })
+}
+
+/// The working directory a codex event reports, either top-level or in a
+/// legacy `msg` envelope (e.g. `session_configured`).
+fn event_cwd(event: &Value) -> Option<&str> {
+    event
+        .get("cwd")
+        .or_else(|| event.get("msg").and_then(|m| m.get("cwd")))
+        .and_then(|v| v.as_str())
+        .filter(|cwd| !cwd.is_empty())
+}
+
+/// Resolves once the guarded session is killed; pending forever if the
+/// session has not been registered yet.
+async fn session_killed(guard: &Option<SessionGuard>) {
+    match guard {
+        Some(guard) => guard.token().cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Options {
     pub prompt: String,
@@ -40,26 +340,190 @@ pub struct Options {
     pub sandbox: SandboxPolicy,
     pub session_id: Option<String>,
     pub skip_git_repo_check: bool,
+    /// Set when the caller asked for `skip_git_repo_check` but the security policy
+    /// cleared it, so a git-repo-check failure says so instead of suggesting the flag
+    pub skip_git_repo_check_denied: bool,
     pub return_all_messages: bool,
     pub return_all_messages_limit: Option<usize>,
+    /// Collect agent messages per turn into `CodexResult::agent_turns`
+    pub return_turns: bool,
+    /// Collect the text of reasoning items into `CodexResult::reasoning_summary`
+    pub include_reasoning_summary: bool,
     pub image_paths: Vec<PathBuf>,
     pub model: Option<String>,
     pub yolo: bool,
     pub profile: Option<String>,
     pub timeout_secs: Option<u64>,
     pub force_stdin: bool,
+    /// Registry to record the running child in, so `codex_kill` can find it
+    pub session_registry: Option<SessionRegistry>,
+    /// File that every raw stdout line is appended to, see `resolve_log_file`
+    pub log_file: Option<PathBuf>,
+    /// Validated `model_reasoning_effort`, see `validate_reasoning_effort`
+    pub reasoning_effort: Option<String>,
+    /// Validated `model_verbosity`, see `validate_verbosity`
+    pub verbosity: Option<String>,
+    /// Validated `approval_policy`, see `validate_approval_policy`
+    pub approval_policy: Option<String>,
+    /// Developer instructions given to the model separately from the prompt,
+    /// see `validate_instructions`
+    pub developer_instructions: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CodexResult {
     pub success: bool,
     pub session_id: String,
     pub agent_messages: String,
     pub agent_messages_truncated: bool,
+    /// Agent messages split by turn, when `Options::return_turns` is set
+    pub agent_turns: Option<Vec<AgentTurn>>,
+    /// Reasoning summaries joined by newlines, when `Options::include_reasoning_summary` is set
+    pub reasoning_summary: Option<String>,
     pub all_messages: Vec<HashMap<String, Value>>,
     pub all_messages_truncated: bool,
     pub error: Option<String>,
     pub warnings: Option<String>,
+    /// Captured stderr of the codex process, as read (subject to the size limit)
+    pub stderr: Option<String>,
+    /// The run hit its timeout; the other fields hold what was parsed before the deadline
+    pub timed_out: bool,
+    /// Timeout the run actually used, after defaults, capping and the session budget;
+    /// `None` when codex was never started
+    pub timeout_secs: Option<u64>,
+}
+
+/// The agent messages of one turn: those codex sent between two rounds of tool activity
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, schemars::JsonSchema)]
+pub struct AgentTurn {
+    pub turn_index: usize,
+    pub text: String,
+}
+
+/// Item types that mark tool activity; the next agent message starts a new turn
+const TOOL_ITEM_TYPES: &[&str] = &[
+    "command_execution",
+    "file_change",
+    "mcp_tool_call",
+    "web_search",
+];
+
+/// Item types treated as assistant text when `CODEX_AGENT_MESSAGE_TYPES` is unset
+pub const DEFAULT_AGENT_MESSAGE_TYPES: &[&str] = &["agent_message", "assistant_message", "message"];
+
+/// Item types whose text is collected into `agent_messages`, so the extractor
+/// keeps working when codex renames its assistant item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentMessageTypes(Vec<String>);
+
+impl Default for AgentMessageTypes {
+    fn default() -> Self {
+        Self(
+            DEFAULT_AGENT_MESSAGE_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        )
+    }
+}
+
+impl AgentMessageTypes {
+    /// Parse a comma-separated list of item types; `None` when it names none
+    pub fn parse(value: &str) -> Option<Self> {
+        let types: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::to_string)
+            .collect();
+        (!types.is_empty()).then_some(Self(types))
+    }
+
+    /// Read `CODEX_AGENT_MESSAGE_TYPES`, falling back to the defaults when unset or empty
+    pub fn from_env() -> Self {
+        std::env::var("CODEX_AGENT_MESSAGE_TYPES")
+            .ok()
+            .and_then(|v| Self::parse(&v))
+            .unwrap_or_default()
+    }
+
+    /// The assistant text of an event item, or `None` when the item is not an
+    /// agent message. Items carrying a `role` other than "assistant" (e.g. a
+    /// generic `message` echoing the user) are not agent messages. The text is
+    /// read from `text`, or from a `content` string or array of text parts.
+    fn text(&self, item: &serde_json::Map<String, Value>) -> Option<String> {
+        let item_type = item.get("type").and_then(|v| v.as_str())?;
+        if !self.0.iter().any(|t| t == item_type) {
+            return None;
+        }
+        if let Some(role) = item.get("role").and_then(|v| v.as_str()) {
+            if role != "assistant" {
+                return None;
+            }
+        }
+        if let Some(text) = item.get("text").and_then(|v| v.as_str()) {
+            return Some(text.to_string());
+        }
+        match item.get("content")? {
+            Value::String(text) => Some(text.clone()),
+            Value::Array(parts) => {
+                let texts: Vec<&str> = parts
+                    .iter()
+                    .filter_map(|part| part.get("text").and_then(|v| v.as_str()))
+                    .collect();
+                (!texts.is_empty()).then(|| texts.concat())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Text of a `reasoning` item: its `text`, or the `summary` parts when codex
+/// reports the summary as a list
+fn reasoning_summary_text(item: &serde_json::Map<String, Value>) -> Option<String> {
+    if item.get("type").and_then(|v| v.as_str()) != Some("reasoning") {
+        return None;
+    }
+    if let Some(text) = item.get("text").and_then(|v| v.as_str()) {
+        return Some(text.to_string());
+    }
+    let parts = item.get("summary")?.as_array()?;
+    let texts: Vec<&str> = parts
+        .iter()
+        .filter_map(|part| part.as_str().or_else(|| part.get("text")?.as_str()))
+        .collect();
+    (!texts.is_empty()).then(|| texts.join("\n"))
+}
+
+/// Splits agent messages into turns as items stream in
+#[derive(Debug, Default)]
+struct TurnTracker {
+    tool_activity: bool,
+}
+
+impl TurnTracker {
+    /// Record one event item, appending its agent message text (`None` for
+    /// items that are not agent messages) to `turns`
+    fn observe(&mut self, turns: &mut Vec<AgentTurn>, item_type: &str, agent_text: Option<&str>) {
+        if TOOL_ITEM_TYPES.contains(&item_type) {
+            self.tool_activity = true;
+            return;
+        }
+        let Some(text) = agent_text.filter(|t| !t.is_empty()) else {
+            return;
+        };
+        match turns.last_mut() {
+            Some(turn) if !self.tool_activity => {
+                turn.text.push('\n');
+                turn.text.push_str(text);
+            }
+            _ => turns.push(AgentTurn {
+                turn_index: turns.len(),
+                text: text.to_string(),
+            }),
+        }
+        self.tool_activity = false;
+    }
 }
 
 #[derive(Debug)]
@@ -115,6 +579,81 @@ async fn read_line_with_limit<R: AsyncBufReadExt + Unpin>(
     })
 }
 
+const MAX_STDERR_SIZE: usize = 1024 * 1024;
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// How long to wait for stderr to close once codex has exited. A process codex
+/// left behind can keep the pipe open long after codex itself is gone.
+const STDERR_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Background task collecting stderr, up to `MAX_STDERR_SIZE`
+struct StderrReader {
+    handle: tokio::task::JoinHandle<()>,
+    output: Arc<Mutex<String>>,
+}
+
+impl StderrReader {
+    fn spawn<R: AsyncRead + Unpin + Send + 'static>(stderr: R) -> Self {
+        let output = Arc::new(Mutex::new(String::new()));
+        let collected = output.clone();
+        let handle = tokio::spawn(async move {
+            let mut stderr_reader = BufReader::new(stderr);
+            let mut truncated = false;
+            let mut line_buf = Vec::new();
+
+            loop {
+                line_buf.clear();
+                match read_line_with_limit(&mut stderr_reader, &mut line_buf, MAX_LINE_LENGTH).await
+                {
+                    Ok(read_result) => {
+                        if read_result.bytes_read == 0 {
+                            break;
+                        }
+                        let line = String::from_utf8_lossy(&line_buf);
+                        let line = line.trim_end_matches('\n').trim_end_matches('\r');
+                        if !truncated {
+                            truncated = push_line_with_limit(
+                                &mut collected.lock().unwrap(),
+                                line,
+                                MAX_STDERR_SIZE,
+                                &truncation_marker(),
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Warning: Failed to read from stderr: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        Self { handle, output }
+    }
+
+    /// Wait up to `bound` for stderr to reach end of stream, then return what was
+    /// read. The flag is set when the reader was still running and got stopped.
+    async fn finish(self, bound: std::time::Duration) -> (String, bool) {
+        let mut handle = self.handle;
+        let timed_out = match tokio::time::timeout(bound, &mut handle).await {
+            Ok(Ok(())) => false,
+            Ok(Err(e)) => {
+                eprintln!("Warning: Failed to join stderr task: {}", e);
+                false
+            }
+            Err(_) => {
+                eprintln!(
+                    "Warning: codex stderr still open {}s after exit; stopped reading it",
+                    bound.as_secs_f64()
+                );
+                handle.abort();
+                true
+            }
+        };
+        let output = std::mem::take(&mut *self.output.lock().unwrap());
+        (output, timed_out)
+    }
+}
+
 const MAX_CLI_PROMPT_LEN: usize = 800;
 
 const SPECIAL_CHARS: &[char] = &[
@@ -133,7 +672,273 @@ fn normalize_timeout_secs(timeout_secs: Option<u64>) -> u64 {
     }
 }
 
-pub async fn run(opts: Options) -> Result<CodexResult> {
+/// Stderr/error substrings indicating the requested model is unavailable.
+const MODEL_ERROR_SIGNATURES: &[&str] = &[
+    "model not available",
+    "model is not available",
+    "unknown model",
+    "model not found",
+    "model_not_found",
+    "unsupported model",
+    "model is not supported",
+    "invalid model",
+];
+
+/// Whether a codex failure looks like the requested model is unavailable.
+fn is_model_error(error: &str) -> bool {
+    let lower = error.to_ascii_lowercase();
+    MODEL_ERROR_SIGNATURES.iter().any(|sig| lower.contains(sig))
+}
+
+/// Parse a comma-separated model fallback list, skipping empty entries.
+fn parse_model_fallbacks(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Wrap the prompt with `CODEX_PROMPT_PREFIX` / `CODEX_PROMPT_SUFFIX`, if set.
+pub fn apply_prompt_affixes(prompt: String) -> String {
+    let prefix = std::env::var("CODEX_PROMPT_PREFIX").ok();
+    let suffix = std::env::var("CODEX_PROMPT_SUFFIX").ok();
+    wrap_prompt(prompt, prefix.as_deref(), suffix.as_deref())
+}
+
+/// Ordered fallback models from `CODEX_MODEL_FALLBACK`, if set.
+pub fn get_model_fallbacks() -> Vec<String> {
+    std::env::var("CODEX_MODEL_FALLBACK")
+        .map(|v| parse_model_fallbacks(&v))
+        .unwrap_or_default()
+}
+
+/// The per-call model if given, otherwise `CODEX_DEFAULT_MODEL`, if set.
+pub fn resolve_model(requested: Option<String>) -> Option<String> {
+    requested
+        .filter(|m| !m.trim().is_empty())
+        .or_else(|| std::env::var("CODEX_DEFAULT_MODEL").ok())
+        .filter(|m| !m.trim().is_empty())
+}
+
+/// Stderr signatures of a transient codex sandbox setup failure (seccomp/landlock races).
+const SANDBOX_INIT_ERROR_SIGNATURES: &[&str] = &[
+    "seccomp setup error",
+    "failed to install seccomp",
+    "error applying seccomp",
+    "landlockrestrict",
+    "landlock restrict",
+    "error applying landlock",
+    "landlock was not able to fully enforce",
+    "failed to initialize sandbox",
+    "sandbox initialization failed",
+];
+
+/// Whether codex stderr shows the sandbox failed to initialize.
+pub fn is_sandbox_init_error(stderr: &str) -> bool {
+    let lower = stderr.to_ascii_lowercase();
+    SANDBOX_INIT_ERROR_SIGNATURES
+        .iter()
+        .any(|sig| lower.contains(sig))
+}
+
+/// Stderr signatures of codex's own git-repo check. Git's "not a git repository"
+/// is not included: it also appears in output of commands codex ran itself.
+const NOT_GIT_REPO_SIGNATURES: &[&str] = &[
+    "not inside a trusted directory",
+    "--skip-git-repo-check was not specified",
+];
+
+/// Replacement for codex's git-repo-check failure, which doesn't say how to fix it.
+const NOT_GIT_REPO_MESSAGE: &str = "codex refused to run because the working directory (`cd`) is not a git repository. Run `git init` in that directory, or set CODEX_ALLOW_SKIP_GIT_CHECK=true and pass skip_git_repo_check=true.";
+
+/// Replacement for codex's git-repo-check failure when skip_git_repo_check was
+/// requested but cleared by the security policy.
+const NOT_GIT_REPO_DENIED_MESSAGE: &str = "codex refused to run because the working directory (`cd`) is not a git repository. skip_git_repo_check was requested but disabled by the server's security policy (CODEX_ALLOW_SKIP_GIT_CHECK is not set). Run `git init` in that directory, or ask the server operator to set CODEX_ALLOW_SKIP_GIT_CHECK=true.";
+
+/// Whether codex stderr shows its own check refused to run outside a git repository.
+pub fn is_not_git_repo_error(stderr: &str) -> bool {
+    let lower = stderr.to_ascii_lowercase();
+    NOT_GIT_REPO_SIGNATURES
+        .iter()
+        .any(|sig| lower.contains(sig))
+}
+
+/// Stderr signatures of codex failing because it has no valid credentials.
+const AUTH_ERROR_SIGNATURES: &[&str] = &[
+    "not logged in",
+    "please log in",
+    "invalid api key",
+    "incorrect api key",
+];
+
+/// Words that mark a line mentioning HTTP 401 as an auth failure rather than,
+/// say, a ticket number or token count
+const AUTH_CONTEXT_WORDS: &[&str] = &[
+    "unauthorized",
+    "invalid_token",
+    "api key",
+    "authentication",
+    "credentials",
+];
+
+/// Replacement for codex's auth failures, which don't say how to fix them.
+const AUTH_ERROR_MESSAGE: &str = "codex is not authenticated. Run `codex login` (or set OPENAI_API_KEY) in the environment this server runs in, then retry.";
+
+/// Whether a failed codex run was caused by missing or rejected credentials.
+/// A successful exit (`Some(0)`) is never treated as an auth failure.
+pub fn is_auth_error(stderr: &str, code: Option<i32>) -> bool {
+    if code == Some(0) {
+        return false;
+    }
+    let lower = stderr.to_ascii_lowercase();
+    AUTH_ERROR_SIGNATURES.iter().any(|sig| lower.contains(sig))
+        || lower.lines().any(|line| {
+            contains_status_401(line) && AUTH_CONTEXT_WORDS.iter().any(|w| line.contains(w))
+        })
+}
+
+/// Whether `text` contains `401` as a standalone number rather than part of a longer one.
+fn contains_status_401(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    text.match_indices("401").any(|(i, _)| {
+        let before = i.checked_sub(1).map(|j| bytes[j]);
+        let after = bytes.get(i + 3).copied();
+        !before.is_some_and(|b| b.is_ascii_digit()) && !after.is_some_and(|b| b.is_ascii_digit())
+    })
+}
+
+/// Whether a failed run is retried once after a sandbox init error.
+/// Reads `CODEX_SANDBOX_RETRY`; off by default.
+pub fn get_sandbox_retry() -> bool {
+    parse_env_bool("CODEX_SANDBOX_RETRY", &mut Vec::new()).unwrap_or(false)
+}
+
+/// Run codex, retrying once when it fails with a sandbox init error.
+/// Resumed sessions are never retried since the first attempt may have
+/// already appended to the session.
+async fn run_with_sandbox_retry<F, Fut>(
+    opts: Options,
+    enabled: bool,
+    mut runner: F,
+) -> Result<CodexResult>
+where
+    F: FnMut(Options) -> Fut,
+    Fut: std::future::Future<Output = Result<CodexResult>>,
+{
+    let retry = enabled && opts.session_id.is_none();
+    let result = runner(opts.clone()).await?;
+
+    let sandbox_failed = !result.success
+        && [result.stderr.as_deref(), result.error.as_deref()]
+            .into_iter()
+            .flatten()
+            .any(is_sandbox_init_error);
+    if !retry || !sandbox_failed {
+        return Ok(result);
+    }
+
+    let warning = "Codex sandbox failed to initialize; retried once";
+    eprintln!("[codex] {}", warning);
+
+    let mut retried = runner(opts).await?;
+    retried.warnings = push_warning(retried.warnings.take(), warning);
+    Ok(retried)
+}
+
+/// Execute codex, retrying with `CODEX_MODEL_FALLBACK` models when the
+/// requested model is reported unavailable, and once more on a sandbox init
+/// error when `CODEX_SANDBOX_RETRY` is set. Resumed sessions are never
+/// retried since their model is fixed by the session.
+///
+/// Each run's duration is added to its session's total; with
+/// `CODEX_SESSION_MAX_TOTAL_SECS` set, a resume is limited to the remaining
+/// budget and refused once it is used up.
+pub async fn run(mut opts: Options) -> Result<CodexResult> {
+    let registry = opts.session_registry.clone();
+    if let (Some(max_total), Some(registry), Some(session_id)) =
+        (get_session_max_total_secs(), &registry, &opts.session_id)
+    {
+        let used = registry.total_runtime(session_id);
+        match session_budget_timeout(used, max_total, normalize_timeout_secs(opts.timeout_secs)) {
+            Ok(timeout_secs) => opts.timeout_secs = Some(timeout_secs),
+            Err(message) => {
+                let result = CodexResult {
+                    session_id: session_id.clone(),
+                    error: Some(message),
+                    ..CodexResult::default()
+                };
+                return Ok(enforce_required_fields(result, ValidationMode::Skip));
+            }
+        }
+    }
+
+    let fallbacks = if opts.session_id.is_none() {
+        get_model_fallbacks()
+    } else {
+        Vec::new()
+    };
+    let sandbox_retry = get_sandbox_retry();
+    let started = std::time::Instant::now();
+    let result = run_with_model_fallback(opts, &fallbacks, move |opts| {
+        run_with_sandbox_retry(opts, sandbox_retry, run_once)
+    })
+    .await;
+
+    if let (Some(registry), Ok(result)) = (&registry, &result) {
+        if !result.session_id.is_empty() {
+            registry.add_runtime(&result.session_id, started.elapsed());
+        }
+    }
+    result
+}
+
+async fn run_with_model_fallback<F, Fut>(
+    opts: Options,
+    fallbacks: &[String],
+    mut runner: F,
+) -> Result<CodexResult>
+where
+    F: FnMut(Options) -> Fut,
+    Fut: std::future::Future<Output = Result<CodexResult>>,
+{
+    let mut current_model = opts.model.clone();
+    let mut result = runner(opts.clone()).await?;
+
+    for model in fallbacks {
+        if result.success || !result.error.as_deref().is_some_and(is_model_error) {
+            break;
+        }
+        if current_model.as_deref() == Some(model.as_str()) {
+            continue;
+        }
+
+        let warning = format!(
+            "Model '{}' is not available; retrying with fallback model '{}'",
+            current_model.as_deref().unwrap_or("default"),
+            model
+        );
+        eprintln!("[codex] {}", warning);
+
+        let previous_warnings = result.warnings.take();
+        result = runner(Options {
+            model: Some(model.clone()),
+            ..opts.clone()
+        })
+        .await?;
+        let mut warnings = push_warning(previous_warnings, &warning);
+        if let Some(w) = result.warnings.take() {
+            warnings = push_warning(warnings, &w);
+        }
+        result.warnings = warnings;
+        current_model = Some(model.clone());
+    }
+
+    Ok(result)
+}
+
+async fn run_once(opts: Options) -> Result<CodexResult> {
     let timeout_secs = normalize_timeout_secs(opts.timeout_secs);
 
     let opts = Options {
@@ -141,30 +946,30 @@ pub async fn run(opts: Options) -> Result<CodexResult> {
         ..opts
     };
 
+    // `run_internal` fills `partial` as events arrive, so a timeout can still
+    // report the session id and messages seen before the deadline.
+    let mut partial = CodexResult::default();
     let duration = std::time::Duration::from_secs(timeout_secs);
-    match tokio::time::timeout(duration, run_internal(opts)).await {
-        Ok(result) => result,
+    let outcome = tokio::time::timeout(duration, run_internal(opts, &mut partial)).await;
+    let mut result = match outcome {
+        Ok(result) => result?,
         Err(_) => {
-            let result = CodexResult {
-                success: false,
-                session_id: String::new(),
-                agent_messages: String::new(),
-                agent_messages_truncated: false,
-                all_messages: Vec::new(),
-                all_messages_truncated: false,
-                error: Some(format!(
-                    "Codex execution timed out after {} seconds",
-                    timeout_secs
-                )),
-                warnings: None,
-            };
-            Ok(enforce_required_fields(result, ValidationMode::Skip))
+            partial.success = false;
+            partial.timed_out = true;
+            partial.error = Some(format!(
+                "Codex execution timed out after {} seconds",
+                timeout_secs
+            ));
+            enforce_required_fields(partial, ValidationMode::Skip)
         }
-    }
+    };
+    result.timeout_secs = Some(timeout_secs);
+    Ok(result)
 }
 
-async fn run_internal(opts: Options) -> Result<CodexResult> {
+async fn run_internal(opts: Options, result: &mut CodexResult) -> Result<CodexResult> {
     let codex_bin = std::env::var("CODEX_BIN").unwrap_or_else(|_| "codex".to_string());
+    let codex_bin_display = resolved_binary_display(&codex_bin);
 
     #[cfg(windows)]
     let mut cmd = {
@@ -176,6 +981,7 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
     #[cfg(not(windows))]
     let mut cmd = Command::new(codex_bin);
 
+    apply_path_prepend(&mut cmd, "CODEX_PATH_PREPEND");
     cmd.args(["exec", "--sandbox", opts.sandbox.as_str(), "--cd"]);
     cmd.arg(opts.working_dir.as_os_str());
     cmd.arg("--json");
@@ -190,6 +996,7 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
     if let Some(ref profile) = opts.profile {
         cmd.args(["--profile", profile]);
     }
+    cmd.args(config_override_args(&opts));
     if opts.yolo {
         cmd.arg("--yolo");
     }
@@ -219,23 +1026,85 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
     cmd.kill_on_drop(true);
+    // On Unix, give codex its own process group so the shells and commands it
+    // starts are killed with it.
+    #[cfg(unix)]
+    cmd.process_group(0);
+
+    let mut log_file = match opts.log_file {
+        Some(ref path) => Some(
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .with_context(|| format!("Failed to open codex log file {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let mut log_error = None;
 
     let mut child = cmd.spawn().context("Failed to spawn codex command")?;
 
+    // On Windows, assign child to a Job Object so the entire process tree
+    // (cmd.exe + codex) is killed when the session is terminated.
+    #[cfg(windows)]
+    let job = child
+        .id()
+        .and_then(crate::shared::job_object::ProcessJob::assign);
+    #[cfg(unix)]
+    let group = child
+        .id()
+        .and_then(crate::shared::process_group::ProcessGroup::new);
+
+    // Kill codex together with every process it started
+    let kill_tree = |child: &mut tokio::process::Child| {
+        #[cfg(windows)]
+        if let Some(ref j) = job {
+            j.terminate();
+        }
+        #[cfg(unix)]
+        if let Some(ref g) = group {
+            g.terminate();
+        }
+        let _ = child.start_kill();
+    };
+
+    // Resumed sessions are known up front; new sessions register once codex
+    // reports their thread_id.
+    let mut session_guard = match (&opts.session_registry, &opts.session_id) {
+        (Some(registry), Some(session_id)) => Some(registry.register(session_id)),
+        _ => None,
+    };
+
+    let mut pending_stdin = None;
     if use_stdin {
-        if let Some(mut stdin) = child.stdin.take() {
-            if let Err(e) = stdin.write_all(opts.prompt.as_bytes()).await {
-                match e.kind() {
-                    std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::NotConnected => {
-                        eprintln!(
-                            "Warning: codex process closed stdin early ({}); \
-                             continuing to collect exit status and stderr",
-                            e
-                        );
-                    }
-                    _ => {
-                        return Err(e).context("Failed to write prompt to codex stdin");
-                    }
+        if let Some(stdin) = child.stdin.take() {
+            let timeout = stdin_write_timeout();
+            match write_stdin(stdin, opts.prompt.clone().into_bytes(), timeout).await {
+                StdinWrite::Done => {}
+                StdinWrite::Failed(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::NotConnected
+                    ) =>
+                {
+                    eprintln!(
+                        "Warning: codex process closed stdin early ({}); \
+                         continuing to collect exit status and stderr",
+                        e
+                    );
+                }
+                StdinWrite::Failed(e) => {
+                    return Err(e).context("Failed to write prompt to codex stdin");
+                }
+                StdinWrite::Pending(handle) => {
+                    eprintln!(
+                        "Warning: codex has not read the prompt after {}s; \
+                         reading its output while the write continues",
+                        timeout.as_secs()
+                    );
+                    pending_stdin = Some(handle);
                 }
             }
         }
@@ -244,82 +1113,71 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
     let stdout = child.stdout.take().context("Failed to get stdout")?;
     let stderr = child.stderr.take().context("Failed to get stderr")?;
 
-    let mut result = CodexResult {
+    *result = CodexResult {
         success: true,
-        session_id: String::new(),
-        agent_messages: String::new(),
-        agent_messages_truncated: false,
-        all_messages: Vec::new(),
-        all_messages_truncated: false,
-        error: None,
-        warnings: None,
+        ..CodexResult::default()
     };
 
-    const MAX_MESSAGE_LIMIT: usize = 50000;
-    const DEFAULT_MESSAGE_LIMIT: usize = 10000;
+    let mut cwd_warned = false;
+    if let (Some(registry), Some(session_id)) = (&opts.session_registry, &opts.session_id) {
+        if let Some(warning) = registry
+            .cwd_of(session_id)
+            .and_then(|dir| resume_cwd_warning(&dir, &opts.working_dir))
+        {
+            eprintln!("[codex] {}", warning);
+            result.warnings = push_warning(result.warnings.take(), &warning);
+            cwd_warned = true;
+        }
+    }
+
     const MAX_AGENT_MESSAGES_SIZE: usize = 10 * 1024 * 1024;
     const MAX_ALL_MESSAGES_SIZE: usize = 50 * 1024 * 1024;
-    let message_limit = opts
-        .return_all_messages_limit
-        .unwrap_or(DEFAULT_MESSAGE_LIMIT)
-        .min(MAX_MESSAGE_LIMIT);
+    // The server already warned about out-of-range limits; this only enforces the cap
+    let message_limit = resolve_message_limit(
+        opts.return_all_messages_limit,
+        get_max_message_limit(&mut Vec::new()),
+        &mut Vec::new(),
+    );
 
     let mut all_messages_size: usize = 0;
 
-    const MAX_STDERR_SIZE: usize = 1024 * 1024;
-    const MAX_LINE_LENGTH: usize = 1024 * 1024;
-    let stderr_handle = tokio::spawn(async move {
-        let mut stderr_output = String::new();
-        let mut stderr_reader = BufReader::new(stderr);
-        let mut truncated = false;
-        let mut line_buf = Vec::new();
-
-        loop {
-            line_buf.clear();
-            match read_line_with_limit(&mut stderr_reader, &mut line_buf, MAX_LINE_LENGTH).await {
-                Ok(read_result) => {
-                    if read_result.bytes_read == 0 {
-                        break;
-                    }
-                    let line = String::from_utf8_lossy(&line_buf);
-                    let line = line.trim_end_matches('\n').trim_end_matches('\r');
-                    let new_size = stderr_output.len() + line.len() + 1;
-                    if new_size > MAX_STDERR_SIZE {
-                        if !truncated {
-                            if !stderr_output.is_empty() {
-                                stderr_output.push('\n');
-                            }
-                            stderr_output.push_str("[... stderr truncated due to size limit ...]");
-                            truncated = true;
-                        }
-                    } else if !truncated {
-                        if !stderr_output.is_empty() {
-                            stderr_output.push('\n');
-                        }
-                        stderr_output.push_str(line.as_ref());
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Warning: Failed to read from stderr: {}", e);
-                    break;
-                }
-            }
-        }
-
-        stderr_output
-    });
+    let stderr_reader = StderrReader::spawn(stderr);
 
     let mut reader = BufReader::new(stdout);
+    let mut decoder = JsonStreamDecoder::default();
     let mut parse_error_seen = false;
+    let mut stdout_bytes: usize = 0;
+    let mut turn_tracker = TurnTracker::default();
+    let agent_message_types = AgentMessageTypes::from_env();
+    result.agent_turns = opts.return_turns.then(Vec::new);
+    result.reasoning_summary = opts.include_reasoning_summary.then(String::new);
+    let mut reasoning_summary_truncated = false;
     let mut line_buf = Vec::new();
 
     loop {
         line_buf.clear();
-        match read_line_with_limit(&mut reader, &mut line_buf, MAX_LINE_LENGTH).await {
+        let read = tokio::select! {
+            read = read_line_with_limit(&mut reader, &mut line_buf, MAX_LINE_LENGTH) => read,
+            _ = session_killed(&session_guard) => {
+                kill_tree(&mut child);
+                result.success = false;
+                result.error = Some("Codex session was killed via codex_kill".to_string());
+                break;
+            }
+        };
+        match read {
             Ok(read_result) => {
                 if read_result.bytes_read == 0 {
                     break;
                 }
+                stdout_bytes += read_result.bytes_read;
+
+                if let Some(ref mut file) = log_file {
+                    if let Err(e) = append_log_line(file, &line_buf).await {
+                        log_error = Some(e);
+                        log_file = None;
+                    }
+                }
 
                 if read_result.truncated {
                     let error_msg = format!(
@@ -330,13 +1188,13 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                     result.error = Some(error_msg);
                     if !parse_error_seen {
                         parse_error_seen = true;
-                        let _ = child.start_kill();
+                        kill_tree(&mut child);
                     }
                     continue;
                 }
 
-                let line = String::from_utf8_lossy(&line_buf);
-                let line = line.trim_end_matches('\n').trim_end_matches('\r');
+                let line = line_buf.strip_suffix(b"\n").unwrap_or(&line_buf);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
 
                 if line.is_empty() {
                     continue;
@@ -346,120 +1204,563 @@ async fn run_internal(opts: Options) -> Result<CodexResult> {
                     continue;
                 }
 
-                let line_data: Value = match serde_json::from_str(line) {
-                    Ok(data) => data,
+                let events = match decoder.push_line(line) {
+                    Ok(events) => events,
                     Err(e) => {
-                        record_parse_error(&mut result, &e, line);
+                        record_parse_error(result, &e, &String::from_utf8_lossy(line));
                         if !parse_error_seen {
                             parse_error_seen = true;
-                            let _ = child.start_kill();
+                            kill_tree(&mut child);
                         }
                         continue;
                     }
                 };
 
-                if opts.return_all_messages {
-                    if result.all_messages.len() < message_limit {
-                        if let Ok(map) =
-                            serde_json::from_value::<HashMap<String, Value>>(line_data.clone())
-                        {
-                            let message_size =
-                                serde_json::to_string(&map).map(|s| s.len()).unwrap_or(0);
-                            if all_messages_size + message_size <= MAX_ALL_MESSAGES_SIZE {
-                                all_messages_size += message_size;
-                                result.all_messages.push(map);
-                            } else if !result.all_messages_truncated {
-                                result.all_messages_truncated = true;
+                for line_data in events {
+                    if opts.return_all_messages {
+                        if result.all_messages.len() < message_limit {
+                            if let Ok(map) =
+                                serde_json::from_value::<HashMap<String, Value>>(line_data.clone())
+                            {
+                                let message_size =
+                                    serde_json::to_string(&map).map(|s| s.len()).unwrap_or(0);
+                                if all_messages_size + message_size <= MAX_ALL_MESSAGES_SIZE {
+                                    all_messages_size += message_size;
+                                    result.all_messages.push(map);
+                                } else if !result.all_messages_truncated {
+                                    result.all_messages_truncated = true;
+                                }
                             }
+                        } else if !result.all_messages_truncated {
+                            result.all_messages_truncated = true;
                         }
-                    } else if !result.all_messages_truncated {
-                        result.all_messages_truncated = true;
                     }
-                }
 
-                if let Some(thread_id) = line_data.get("thread_id").and_then(|v| v.as_str()) {
-                    if !thread_id.is_empty() {
-                        result.session_id = thread_id.to_string();
+                    if let Some(thread_id) = line_data.get("thread_id").and_then(|v| v.as_str()) {
+                        if !thread_id.is_empty() {
+                            result.session_id = thread_id.to_string();
+                            if session_guard.is_none() {
+                                if let Some(ref registry) = opts.session_registry {
+                                    session_guard = Some(registry.register(thread_id));
+                                    registry.record_cwd(thread_id, &opts.working_dir);
+                                }
+                            }
+                        }
                     }
-                }
 
-                if let Some(item) = line_data.get("item").and_then(|v| v.as_object()) {
-                    if let Some(item_type) = item.get("type").and_then(|v| v.as_str()) {
-                        if item_type == "agent_message" {
-                            if let Some(text) = item.get("text").and_then(|v| v.as_str()) {
-                                let new_size = result.agent_messages.len() + text.len();
-                                if new_size > MAX_AGENT_MESSAGES_SIZE {
-                                    if !result.agent_messages_truncated {
-                                        result.agent_messages.push_str(
-                                    "\n[... Agent messages truncated due to size limit ...]",
-                                );
-                                        result.agent_messages_truncated = true;
-                                    }
-                                } else if !result.agent_messages_truncated {
-                                    if !result.agent_messages.is_empty() && !text.is_empty() {
-                                        result.agent_messages.push('\n');
+                    if opts.session_id.is_some() && !cwd_warned {
+                        if let Some(warning) = event_cwd(&line_data)
+                            .and_then(|cwd| resume_cwd_warning(Path::new(cwd), &opts.working_dir))
+                        {
+                            result.warnings = push_warning(result.warnings.take(), &warning);
+                            cwd_warned = true;
+                        }
+                    }
+
+                    if let Some(item) = line_data.get("item").and_then(|v| v.as_object()) {
+                        if let Some(item_type) = item.get("type").and_then(|v| v.as_str()) {
+                            let agent_text = agent_message_types.text(item);
+                            if let Some(turns) = result.agent_turns.as_mut() {
+                                if !result.agent_messages_truncated {
+                                    turn_tracker.observe(turns, item_type, agent_text.as_deref());
+                                }
+                            }
+                            if let Some(summary) = result.reasoning_summary.as_mut() {
+                                if let Some(text) = reasoning_summary_text(item) {
+                                    if !text.is_empty() && !reasoning_summary_truncated {
+                                        reasoning_summary_truncated = push_line_with_limit(
+                                            summary,
+                                            &text,
+                                            MAX_AGENT_MESSAGES_SIZE,
+                                            &truncation_marker(),
+                                        );
                                     }
-                                    result.agent_messages.push_str(text);
+                                }
+                            }
+                            if let Some(text) = agent_text {
+                                if !text.is_empty() && !result.agent_messages_truncated {
+                                    result.agent_messages_truncated = push_line_with_limit(
+                                        &mut result.agent_messages,
+                                        &text,
+                                        MAX_AGENT_MESSAGES_SIZE,
+                                        &truncation_marker(),
+                                    );
                                 }
                             }
                         }
                     }
-                }
 
-                if let Some(line_type) = line_data.get("type").and_then(|v| v.as_str()) {
-                    if line_type.contains("fail") || line_type.contains("error") {
-                        result.success = false;
-                        if let Some(error_obj) = line_data.get("error").and_then(|v| v.as_object())
-                        {
-                            if let Some(msg) = error_obj.get("message").and_then(|v| v.as_str()) {
+                    if let Some(line_type) = line_data.get("type").and_then(|v| v.as_str()) {
+                        if line_type.contains("fail") || line_type.contains("error") {
+                            result.success = false;
+                            if let Some(error_obj) =
+                                line_data.get("error").and_then(|v| v.as_object())
+                            {
+                                if let Some(msg) = error_obj.get("message").and_then(|v| v.as_str())
+                                {
+                                    result.error = Some(format!("codex error: {}", msg));
+                                }
+                            } else if let Some(msg) =
+                                line_data.get("message").and_then(|v| v.as_str())
+                            {
                                 result.error = Some(format!("codex error: {}", msg));
                             }
-                        } else if let Some(msg) = line_data.get("message").and_then(|v| v.as_str())
-                        {
-                            result.error = Some(format!("codex error: {}", msg));
                         }
                     }
                 }
             }
             Err(e) => {
                 let io_error = std::io::Error::from(e.kind());
-                record_parse_error(&mut result, &serde_json::Error::io(io_error), "");
+                record_parse_error(result, &serde_json::Error::io(io_error), "");
                 break;
             }
         }
     }
 
+    if let Some(document) = decoder.finish() {
+        if !parse_error_seen {
+            let e = serde::de::Error::custom("output ended inside a JSON document");
+            record_parse_error(result, &e, &document);
+        }
+    }
+
+    if let Some(handle) = pending_stdin {
+        if let Some(warning) = finish_stdin(handle, "codex").await {
+            result.warnings = push_warning(result.warnings.take(), &warning);
+        }
+    }
+
     let status = child
         .wait()
         .await
         .context("Failed to wait for codex command")?;
 
-    let stderr_output = match stderr_handle.await {
-        Ok(output) => output,
-        Err(e) => {
-            eprintln!("Warning: Failed to join stderr task: {}", e);
-            String::new()
+    let (stderr_output, stderr_timed_out) = stderr_reader.finish(STDERR_DRAIN_TIMEOUT).await;
+    if stderr_timed_out {
+        result.warnings = push_warning(
+            result.warnings.take(),
+            &format!(
+                "codex stderr stayed open {}s after codex exited (likely held by a process it started); stderr output may be incomplete",
+                STDERR_DRAIN_TIMEOUT.as_secs()
+            ),
+        );
+    }
+
+    if !status.success() && stdout_bytes == 0 && stderr_output.is_empty() && result.error.is_none()
+    {
+        result.error = Some(silent_failure_message(status.code(), &codex_bin_display));
+    }
+
+    apply_exit_status(
+        result,
+        status.success(),
+        status.code(),
+        stderr_output,
+        get_merge_stderr(),
+        opts.skip_git_repo_check_denied,
+    );
+
+    if decoder.replaced_invalid_utf8 {
+        result.warnings = push_warning(
+            result.warnings.take(),
+            "codex output contained invalid UTF-8; invalid bytes were replaced with U+FFFD",
+        );
+    }
+
+    if let Some(ref mut file) = log_file {
+        if let Err(e) = file.flush().await {
+            log_error = Some(e);
+        }
+    }
+    if let Some(e) = log_error {
+        result.warnings = push_warning(
+            result.warnings.take(),
+            &format!(
+                "Failed to write codex log file ({}); the log is incomplete",
+                e
+            ),
+        );
+    }
+
+    Ok(enforce_required_fields(
+        std::mem::take(result),
+        ValidationMode::Full,
+    ))
+}
+
+/// Append one raw stdout line to the log file, terminating it with a newline.
+async fn append_log_line(file: &mut tokio::fs::File, line: &[u8]) -> std::io::Result<()> {
+    file.write_all(line).await?;
+    if !line.ends_with(b"\n") {
+        file.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+/// Resolve `log_file` against the working directory and require the result to stay
+/// inside the working directory or one of `allowed_dirs` (the MCP workspace roots).
+/// The error is a user-facing message.
+pub fn resolve_log_file(
+    log_file: &Path,
+    working_dir: &Path,
+    allowed_dirs: &[PathBuf],
+) -> std::result::Result<PathBuf, String> {
+    let resolved = if log_file.is_absolute() {
+        log_file.to_path_buf()
+    } else {
+        working_dir.join(log_file)
+    };
+    let file_name = resolved
+        .file_name()
+        .ok_or_else(|| format!("log_file must name a file: {}", log_file.display()))?;
+    let parent = resolved.parent().unwrap_or(working_dir);
+    let canonical_parent = parent.canonicalize().map_err(|e| {
+        format!(
+            "log_file directory does not exist or is not accessible: {} ({})",
+            parent.display(),
+            e
+        )
+    })?;
+
+    let mut candidate = canonical_parent.join(file_name);
+    if candidate.exists() {
+        candidate = candidate.canonicalize().map_err(|e| {
+            format!(
+                "log_file is not accessible: {} ({})",
+                candidate.display(),
+                e
+            )
+        })?;
+        if !candidate.is_file() {
+            return Err(format!("log_file is not a file: {}", candidate.display()));
+        }
+    }
+
+    let allowed = std::iter::once(working_dir.to_path_buf())
+        .chain(allowed_dirs.iter().filter_map(|d| d.canonicalize().ok()))
+        .any(|dir| candidate.starts_with(dir));
+    if !allowed {
+        return Err(format!(
+            "log_file must be inside the working directory (cd) or an MCP workspace root: {}",
+            log_file.display()
+        ));
+    }
+    Ok(candidate)
+}
+
+/// How a relative `cd` is resolved (`CODEX_RELATIVE_CD`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelativeCdPolicy {
+    /// Against the server process's working directory (previous behavior)
+    #[default]
+    Cwd,
+    /// Against the first MCP workspace root, falling back to the server's
+    /// working directory with a warning when there are no roots
+    Root,
+    /// Against the first MCP workspace root; rejected when there are no roots
+    Reject,
+}
+
+impl RelativeCdPolicy {
+    /// Parse a `CODEX_RELATIVE_CD` value; unknown values fall back to `cwd` with a warning
+    pub fn from_env_value(env_val: Option<String>, warnings: &mut Vec<String>) -> Self {
+        let Some(value) = env_val.filter(|v| !v.trim().is_empty()) else {
+            return Self::default();
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "cwd" => Self::Cwd,
+            "root" => Self::Root,
+            "reject" => Self::Reject,
+            _ => {
+                warnings.push(format!(
+                    "Environment variable CODEX_RELATIVE_CD has unrecognized value '{}'; expected reject, cwd or root. Using cwd.",
+                    value
+                ));
+                Self::default()
+            }
         }
+    }
+}
+
+/// [`RelativeCdPolicy::from_env_value`] read from `CODEX_RELATIVE_CD`
+pub fn get_relative_cd_policy(warnings: &mut Vec<String>) -> RelativeCdPolicy {
+    RelativeCdPolicy::from_env_value(std::env::var("CODEX_RELATIVE_CD").ok(), warnings)
+}
+
+/// Default upper bound on `return_all_messages_limit`, overridable with `CODEX_MAX_MESSAGE_LIMIT`
+pub const MAX_MESSAGE_LIMIT: usize = 50000;
+
+/// Messages kept when `return_all_messages_limit` is not given
+pub const DEFAULT_MESSAGE_LIMIT: usize = 10000;
+
+/// Parse a `CODEX_MAX_MESSAGE_LIMIT` value; anything but a positive integer falls
+/// back to [`MAX_MESSAGE_LIMIT`] with a warning
+pub fn resolve_max_message_limit(env_val: Option<String>, warnings: &mut Vec<String>) -> usize {
+    let Some(value) = env_val.filter(|v| !v.trim().is_empty()) else {
+        return MAX_MESSAGE_LIMIT;
     };
+    match value.trim().parse::<usize>() {
+        Ok(limit) if limit > 0 => limit,
+        _ => {
+            warnings.push(format!(
+                "Environment variable CODEX_MAX_MESSAGE_LIMIT has invalid value '{}'; expected a positive integer. Using {}.",
+                value, MAX_MESSAGE_LIMIT
+            ));
+            MAX_MESSAGE_LIMIT
+        }
+    }
+}
+
+/// [`resolve_max_message_limit`] read from `CODEX_MAX_MESSAGE_LIMIT`
+pub fn get_max_message_limit(warnings: &mut Vec<String>) -> usize {
+    resolve_max_message_limit(std::env::var("CODEX_MAX_MESSAGE_LIMIT").ok(), warnings)
+}
+
+/// The `return_all_messages_limit` to use: the default when not given, and
+/// capped to `max` with a warning when larger or invalid
+pub fn resolve_message_limit(
+    requested: Option<usize>,
+    max: usize,
+    warnings: &mut Vec<String>,
+) -> usize {
+    match requested {
+        None => DEFAULT_MESSAGE_LIMIT.min(max),
+        Some(0) => {
+            let limit = DEFAULT_MESSAGE_LIMIT.min(max);
+            warnings.push(format!(
+                "return_all_messages_limit of 0 is invalid; using default of {}",
+                limit
+            ));
+            limit
+        }
+        Some(limit) if limit > max => {
+            warnings.push(format!(
+                "return_all_messages_limit of {} exceeds maximum of {}; capping to maximum",
+                limit, max
+            ));
+            max
+        }
+        Some(limit) => limit,
+    }
+}
+
+/// Resolve a relative `cd` according to `policy`, given the MCP workspace roots.
+/// Absolute paths are returned unchanged. The error is a user-facing message.
+pub fn resolve_relative_cd(
+    cd: &Path,
+    roots: &[PathBuf],
+    policy: RelativeCdPolicy,
+    warnings: &mut Vec<String>,
+) -> std::result::Result<PathBuf, String> {
+    if cd.is_absolute() || policy == RelativeCdPolicy::Cwd {
+        return Ok(cd.to_path_buf());
+    }
+    if let Some(root) = roots.first() {
+        return Ok(root.join(cd));
+    }
+    match policy {
+        RelativeCdPolicy::Reject => Err(format!(
+            "cd is relative ({}) and the MCP client provided no workspace roots to resolve it against; pass an absolute path",
+            cd.display()
+        )),
+        _ => {
+            warnings.push(format!(
+                "cd is relative ({}) and no MCP workspace roots are available; resolving it against the server's working directory",
+                cd.display()
+            ));
+            Ok(cd.to_path_buf())
+        }
+    }
+}
 
-    if !status.success() {
+/// Whether stderr is also folded into `error` (on failure) or `warnings` (on success).
+/// Reads `CODEX_MERGE_STDERR`; defaults to true for backward compatibility.
+pub fn get_merge_stderr() -> bool {
+    parse_env_bool("CODEX_MERGE_STDERR", &mut Vec::new()).unwrap_or(true)
+}
+
+/// Record the exit status and captured stderr on the result.
+fn apply_exit_status(
+    result: &mut CodexResult,
+    success: bool,
+    exit_code: Option<i32>,
+    stderr_output: String,
+    merge_stderr: bool,
+    skip_git_repo_check_denied: bool,
+) {
+    let stderr = (!stderr_output.is_empty()).then_some(stderr_output);
+
+    if !success {
         result.success = false;
-        let error_msg = if let Some(ref err) = result.error {
+        let mut error_msg = if let Some(ref err) = result.error {
             err.clone()
         } else {
-            format!("codex command failed with exit code: {:?}", status.code())
+            format!("codex command failed with exit code: {:?}", exit_code)
         };
+        if stderr.as_deref().is_some_and(is_not_git_repo_error) || is_not_git_repo_error(&error_msg)
+        {
+            let hint = if skip_git_repo_check_denied {
+                NOT_GIT_REPO_DENIED_MESSAGE
+            } else {
+                NOT_GIT_REPO_MESSAGE
+            };
+            error_msg = format!("{}\nCodex error: {}", hint, error_msg);
+        } else if stderr
+            .as_deref()
+            .is_some_and(|s| is_auth_error(s, exit_code))
+            || is_auth_error(&error_msg, exit_code)
+        {
+            error_msg = format!("{}\nCodex error: {}", AUTH_ERROR_MESSAGE, error_msg);
+        }
 
-        if !stderr_output.is_empty() {
-            result.error = Some(format!("{}\nStderr: {}", error_msg, stderr_output));
-        } else {
-            result.error = Some(error_msg);
+        match stderr {
+            Some(ref stderr) if merge_stderr => {
+                result.error = Some(format!("{}\nStderr: {}", error_msg, stderr));
+            }
+            _ => result.error = Some(error_msg),
+        }
+    } else if merge_stderr {
+        if let Some(ref stderr) = stderr {
+            result.warnings = push_warning(result.warnings.take(), stderr);
         }
-    } else if !stderr_output.is_empty() {
-        result.warnings = Some(stderr_output);
     }
 
-    Ok(enforce_required_fields(result, ValidationMode::Full))
+    result.stderr = stderr;
+}
+
+/// The codex binary being invoked, with the path it resolves to when it is found
+fn resolved_binary_display(codex_bin: &str) -> String {
+    match locate_binary_with_prepend("codex", "CODEX_BIN", Some("CODEX_PATH_PREPEND")) {
+        BinaryLookup::Found(path) if path.as_os_str() != codex_bin => {
+            format!("{} ({})", codex_bin, path.display())
+        }
+        BinaryLookup::Found(_) => codex_bin.to_string(),
+        BinaryLookup::NotExecutable(path) => {
+            format!("{} ({}, not executable)", codex_bin, path.display())
+        }
+        BinaryLookup::NotFound => format!("{} (not found in PATH)", codex_bin),
+    }
+}
+
+/// Error for a failed run that printed nothing on stdout or stderr, where the
+/// bare exit code gives the caller nothing to go on
+fn silent_failure_message(exit_code: Option<i32>, codex_bin: &str) -> String {
+    format!(
+        "codex command failed with exit code: {:?} without printing anything to stdout or stderr. \
+         Binary invoked: {}. Common causes: a missing or invalid ~/.codex/config.toml, \
+         a PATH in this server's environment that lacks codex or its runtime (e.g. node), \
+         or CODEX_BIN pointing at the wrong executable. Run the smoke_test tool to check the setup.",
+        exit_code, codex_bin
+    )
+}
+
+/// Upper bound on a multi-line JSON document buffered by `JsonStreamDecoder`
+const MAX_JSON_DOCUMENT_SIZE: usize = 50 * 1024 * 1024;
+
+/// Splits codex `--json` output into events.
+///
+/// Codex normally prints one JSON object per line; some versions instead print a
+/// single (pretty-printed) document. Lines are parsed on their own first. Only a
+/// bare `[`/`{` line before any one-line event starts a buffered document, which
+/// is kept until its brackets balance, so a truncated JSONL line is reported as
+/// an error instead of swallowing the events after it. A top-level array is
+/// flattened into its elements.
+#[derive(Debug, Default)]
+struct JsonStreamDecoder {
+    pending: String,
+    depth: usize,
+    /// Set once a line parsed on its own, after which output is treated as JSONL
+    seen_event: bool,
+    /// Set once any line needed invalid UTF-8 replaced to parse
+    replaced_invalid_utf8: bool,
+}
+
+impl JsonStreamDecoder {
+    /// Feed one non-empty output line and return the events it completes.
+    /// Returns no events while a multi-line document is still open.
+    fn push_line(&mut self, raw: &[u8]) -> std::result::Result<Vec<Value>, serde_json::Error> {
+        if self.pending.is_empty() {
+            let parse_error = match parse_json_bytes(raw) {
+                Ok((value, replaced)) => {
+                    self.replaced_invalid_utf8 |= replaced;
+                    self.seen_event = true;
+                    return Ok(flatten_document(value));
+                }
+                Err(e) => e,
+            };
+            let line = String::from_utf8_lossy(raw);
+            let opens_document = !self.seen_event && matches!(line.trim(), "[" | "{");
+            return match json_bracket_depth(&line, 0) {
+                Some(depth) if opens_document && depth > 0 => {
+                    self.replaced_invalid_utf8 |= std::str::from_utf8(raw).is_err();
+                    self.pending.push_str(&line);
+                    self.depth = depth;
+                    Ok(Vec::new())
+                }
+                _ => Err(parse_error),
+            };
+        }
+
+        let line = String::from_utf8_lossy(raw);
+        self.replaced_invalid_utf8 |= std::str::from_utf8(raw).is_err();
+        self.pending.push('\n');
+        self.pending.push_str(&line);
+        if self.pending.len() > MAX_JSON_DOCUMENT_SIZE {
+            self.pending.clear();
+            return Err(serde::de::Error::custom(format!(
+                "JSON document exceeded {} byte limit",
+                MAX_JSON_DOCUMENT_SIZE
+            )));
+        }
+        match json_bracket_depth(&line, self.depth) {
+            Some(0) | None => {
+                self.depth = 0;
+                let document = std::mem::take(&mut self.pending);
+                serde_json::from_str::<Value>(&document).map(flatten_document)
+            }
+            Some(depth) => {
+                self.depth = depth;
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Text of a document that was still open when output ended, if any
+    fn finish(&mut self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+}
+
+fn flatten_document(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array(items) => items,
+        other => vec![other],
+    }
+}
+
+/// Track `[`/`{` nesting across a line of JSON, ignoring brackets inside strings.
+/// Returns None if more brackets are closed than were opened.
+fn json_bracket_depth(line: &str, mut depth: usize) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+    Some(depth)
 }
 
 fn record_parse_error(result: &mut CodexResult, error: &serde_json::Error, line: &str) {
@@ -494,7 +1795,8 @@ fn enforce_required_fields(mut result: CodexResult, mode: ValidationMode) -> Cod
         result.error = Some("Failed to get SESSION_ID from the codex session.".to_string());
     }
 
-    if result.agent_messages.is_empty() {
+    // With return_all_messages the caller already has the full event log
+    if result.agent_messages.is_empty() && result.all_messages.is_empty() {
         let warning_msg = "No agent_messages returned; enable return_all_messages or check codex output for details.";
         result.warnings = push_warning(result.warnings.take(), warning_msg);
     }
@@ -571,6 +1873,7 @@ pub struct SecurityConfig {
     pub allow_danger_full_access: bool,
     pub allow_yolo: bool,
     pub allow_skip_git_check: bool,
+    pub allow_approval_never: bool,
 }
 
 pub fn resolve_env_bool(
@@ -599,6 +1902,43 @@ fn parse_env_bool(key: &str, warnings: &mut Vec<String>) -> Option<bool> {
     resolve_env_bool(key, std::env::var(key).ok(), warnings)
 }
 
+/// The sandbox for a call: `requested` when given, otherwise the policy named by
+/// `env_val` (`CODEX_DEFAULT_SANDBOX`), otherwise read-only. An unknown policy
+/// name falls back to read-only with a warning.
+pub fn resolve_sandbox(
+    requested: Option<SandboxPolicy>,
+    env_val: Option<String>,
+    warnings: &mut Vec<String>,
+) -> SandboxPolicy {
+    if let Some(sandbox) = requested {
+        return sandbox;
+    }
+    let Some(value) = env_val.filter(|v| !v.trim().is_empty()) else {
+        return SandboxPolicy::default();
+    };
+    match value.trim().to_ascii_lowercase().as_str() {
+        "read-only" => SandboxPolicy::ReadOnly,
+        "workspace-write" => SandboxPolicy::WorkspaceWrite,
+        "danger-full-access" => SandboxPolicy::DangerFullAccess,
+        _ => {
+            warnings.push(format!(
+                "Environment variable CODEX_DEFAULT_SANDBOX has unrecognized value '{}'; expected read-only, workspace-write or danger-full-access. Using read-only.",
+                value
+            ));
+            SandboxPolicy::default()
+        }
+    }
+}
+
+/// [`resolve_sandbox`] with the default read from `CODEX_DEFAULT_SANDBOX`
+pub fn get_sandbox(requested: Option<SandboxPolicy>, warnings: &mut Vec<String>) -> SandboxPolicy {
+    resolve_sandbox(
+        requested,
+        std::env::var("CODEX_DEFAULT_SANDBOX").ok(),
+        warnings,
+    )
+}
+
 pub fn get_security_config(warnings: &mut Vec<String>) -> SecurityConfig {
     SecurityConfig {
         allow_danger_full_access: parse_env_bool("CODEX_ALLOW_DANGEROUS", warnings)
@@ -606,20 +1946,339 @@ pub fn get_security_config(warnings: &mut Vec<String>) -> SecurityConfig {
         allow_yolo: parse_env_bool("CODEX_ALLOW_YOLO", warnings).unwrap_or(false),
         allow_skip_git_check: parse_env_bool("CODEX_ALLOW_SKIP_GIT_CHECK", warnings)
             .unwrap_or(false),
+        allow_approval_never: parse_env_bool("CODEX_ALLOW_APPROVAL_NEVER", warnings)
+            .unwrap_or(false),
+    }
+}
+
+// --- Reasoning effort / verbosity / approval policy ---
+
+/// Values codex accepts for `model_reasoning_effort`
+pub const REASONING_EFFORTS: &[&str] = &["minimal", "low", "medium", "high"];
+/// Values codex accepts for `model_verbosity`
+pub const VERBOSITIES: &[&str] = &["low", "medium", "high"];
+/// Values codex accepts for `approval_policy`
+pub const APPROVAL_POLICIES: &[&str] = &["untrusted", "on-failure", "on-request", "never"];
+/// Approval policy that `never` is downgraded to unless `CODEX_ALLOW_APPROVAL_NEVER` is set
+pub const SAFE_APPROVAL_POLICY: &str = "on-request";
+
+/// Normalize `value` case-insensitively against `allowed`.
+/// The error is a user-facing message naming the accepted values.
+fn validate_choice(
+    field: &str,
+    value: &str,
+    allowed: &[&str],
+) -> std::result::Result<String, String> {
+    let normalized = value.trim().to_ascii_lowercase();
+    if allowed.contains(&normalized.as_str()) {
+        Ok(normalized)
+    } else {
+        Err(format!(
+            "{} must be one of {}; got '{}'",
+            field,
+            allowed.join(", "),
+            value
+        ))
+    }
+}
+
+/// Validate a `reasoning_effort` argument against [`REASONING_EFFORTS`]
+pub fn validate_reasoning_effort(value: &str) -> std::result::Result<String, String> {
+    validate_choice("reasoning_effort", value, REASONING_EFFORTS)
+}
+
+/// Validate a `verbosity` argument against [`VERBOSITIES`]
+pub fn validate_verbosity(value: &str) -> std::result::Result<String, String> {
+    validate_choice("verbosity", value, VERBOSITIES)
+}
+
+/// Validate an `approval_policy` argument against [`APPROVAL_POLICIES`]
+pub fn validate_approval_policy(value: &str) -> std::result::Result<String, String> {
+    validate_choice("approval_policy", value, APPROVAL_POLICIES)
+}
+
+/// Validate an `instructions` argument, which must contain more than whitespace
+pub fn validate_instructions(value: &str) -> std::result::Result<String, String> {
+    check_instructions(value, cfg!(windows))
+}
+
+/// On Windows the instructions go on the `cmd.exe` command line, which has no stdin
+/// fallback like the prompt, so cmd metacharacters and long text are rejected there
+fn check_instructions(value: &str, via_cmd: bool) -> std::result::Result<String, String> {
+    if value.trim().is_empty() {
+        return Err("instructions must be a non-empty string when provided".to_string());
+    }
+    if via_cmd && needs_stdin_mode(value) {
+        return Err(format!(
+            "instructions must be at most {} bytes and must not contain newlines or any of {} on Windows",
+            MAX_CLI_PROMPT_LEN,
+            SPECIAL_CHARS
+                .iter()
+                .filter(|c| **c != '\n')
+                .collect::<String>()
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// `s` as a TOML basic string, the form codex parses `-c` values in
+fn toml_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `-c key="value"` overrides for the reasoning effort, verbosity, approval policy
+/// and developer instructions options
+fn config_override_args(opts: &Options) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(ref effort) = opts.reasoning_effort {
+        args.push("-c".to_string());
+        args.push(format!("model_reasoning_effort=\"{}\"", effort));
+    }
+    if let Some(ref verbosity) = opts.verbosity {
+        args.push("-c".to_string());
+        args.push(format!("model_verbosity=\"{}\"", verbosity));
+    }
+    if let Some(ref policy) = opts.approval_policy {
+        args.push("-c".to_string());
+        args.push(format!("approval_policy=\"{}\"", policy));
+    }
+    if let Some(ref instructions) = opts.developer_instructions {
+        args.push("-c".to_string());
+        args.push(format!(
+            "developer_instructions={}",
+            toml_string(instructions)
+        ));
+    }
+    args
+}
+
+// --- Image attachments ---
+
+/// Default maximum number of `--image` attachments per call
+pub const DEFAULT_MAX_IMAGES: usize = 16;
+/// Default maximum size of a single image attachment
+pub const DEFAULT_MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+/// Default maximum combined size of all image attachments
+pub const DEFAULT_MAX_IMAGE_TOTAL_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Caps on codex image attachments, keeping the command line and upload size bounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLimits {
+    pub max_count: usize,
+    pub max_bytes: u64,
+    pub max_total_bytes: u64,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_count: DEFAULT_MAX_IMAGES,
+            max_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            max_total_bytes: DEFAULT_MAX_IMAGE_TOTAL_BYTES,
+        }
+    }
+}
+
+impl ImageLimits {
+    /// Read `CODEX_MAX_IMAGES`, `CODEX_MAX_IMAGE_BYTES` and `CODEX_MAX_IMAGE_TOTAL_BYTES`,
+    /// keeping the default for any that are unset, zero or invalid
+    pub fn from_env() -> Self {
+        fn positive<T: std::str::FromStr + PartialOrd + Default>(key: &str) -> Option<T> {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.trim().parse::<T>().ok())
+                .filter(|n| *n > T::default())
+        }
+
+        let defaults = Self::default();
+        Self {
+            max_count: positive("CODEX_MAX_IMAGES").unwrap_or(defaults.max_count),
+            max_bytes: positive("CODEX_MAX_IMAGE_BYTES").unwrap_or(defaults.max_bytes),
+            max_total_bytes: positive("CODEX_MAX_IMAGE_TOTAL_BYTES")
+                .unwrap_or(defaults.max_total_bytes),
+        }
+    }
+
+    /// Reject more attachments than `max_count`; checked before any path is touched
+    pub fn check_count(&self, count: usize) -> std::result::Result<(), String> {
+        if count > self.max_count {
+            return Err(format!(
+                "too many images: {} attached, at most {} allowed (CODEX_MAX_IMAGES)",
+                count, self.max_count
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reject any image larger than `max_bytes`, or a combined size above `max_total_bytes`
+    pub fn check_sizes(&self, paths: &[PathBuf]) -> std::result::Result<(), String> {
+        let mut total: u64 = 0;
+        for path in paths {
+            let size = std::fs::metadata(path)
+                .map_err(|e| format!("cannot read image file {}: {}", path.display(), e))?
+                .len();
+            if size > self.max_bytes {
+                return Err(format!(
+                    "image file is too large: {} is {} bytes, at most {} allowed (CODEX_MAX_IMAGE_BYTES)",
+                    path.display(),
+                    size,
+                    self.max_bytes
+                ));
+            }
+            total = total.saturating_add(size);
+        }
+        if total > self.max_total_bytes {
+            return Err(format!(
+                "images are too large in total: {} bytes, at most {} allowed (CODEX_MAX_IMAGE_TOTAL_BYTES)",
+                total, self.max_total_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+// --- Git diff summary ---
+
+/// Whether a successful writable run reports `git diff --stat` of its working directory.
+/// Reads `CODEX_INCLUDE_DIFF`; off by default.
+pub fn get_include_diff() -> bool {
+    parse_env_bool("CODEX_INCLUDE_DIFF", &mut Vec::new()).unwrap_or(false)
+}
+
+/// Timeout for the `git diff --stat` run after a codex session
+const GIT_DIFF_TIMEOUT_SECS: u64 = 10;
+
+/// Summary of staged and unstaged changes to tracked files in `dir` relative to HEAD.
+/// This is the state of the worktree, not of the run: changes made before codex started
+/// are included, and untracked files codex created are not.
+/// `None` when `dir` is not a git repository (or has no commits), git is unavailable,
+/// or nothing changed.
+pub async fn git_diff_stat(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["diff", "--stat", "HEAD"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(
+        std::time::Duration::from_secs(GIT_DIFF_TIMEOUT_SECS),
+        output,
+    )
+    .await
+    .ok()?
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stat = String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_string();
+    (!stat.is_empty()).then_some(stat)
+}
+
+// --- Profile validation ---
+
+/// Whether a requested profile is checked against codex's config file before spawning.
+/// Reads `CODEX_VALIDATE_PROFILE`; off by default to avoid filesystem reads.
+pub fn get_validate_profile() -> bool {
+    parse_env_bool("CODEX_VALIDATE_PROFILE", &mut Vec::new()).unwrap_or(false)
+}
+
+/// Location of codex's config file: `$CODEX_HOME/config.toml`, else `~/.codex/config.toml`
+pub fn codex_config_path() -> Option<PathBuf> {
+    if let Some(codex_home) = std::env::var_os("CODEX_HOME").filter(|v| !v.is_empty()) {
+        return Some(PathBuf::from(codex_home).join("config.toml"));
+    }
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .filter(|v| !v.is_empty())
+        .map(|home| PathBuf::from(home).join(".codex").join("config.toml"))
+}
+
+/// Check that `profile` is defined in codex's config file.
+/// The error is a user-facing message listing the available profiles.
+pub fn validate_profile(profile: &str) -> std::result::Result<(), String> {
+    let path = codex_config_path()
+        .ok_or_else(|| "Cannot locate codex config.toml (HOME is not set)".to_string())?;
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    check_profile(profile, &contents).map_err(|e| format!("{} (config: {})", e, path.display()))
+}
+
+fn check_profile(profile: &str, config_contents: &str) -> std::result::Result<(), String> {
+    let names = parse_profile_names(config_contents)
+        .map_err(|e| format!("Failed to parse codex config.toml: {}", e))?;
+    if names.iter().any(|name| name == profile) {
+        return Ok(());
+    }
+    let available = if names.is_empty() {
+        "(none)".to_string()
+    } else {
+        names.join(", ")
+    };
+    Err(format!(
+        "Profile '{}' is not defined in codex config.toml. Available profiles: {}",
+        profile, available
+    ))
+}
+
+/// List the profile names defined in a codex config.toml: the keys of its
+/// `profiles` table, whether written as `[profiles.<name>]` headers, inline
+/// tables or dotted keys. Names come back sorted.
+fn parse_profile_names(contents: &str) -> std::result::Result<Vec<String>, String> {
+    let config: toml::Table = contents
+        .parse()
+        .map_err(|e: toml::de::Error| e.to_string().trim_end().to_string())?;
+    match config.get("profiles") {
+        None => Ok(Vec::new()),
+        Some(toml::Value::Table(profiles)) => Ok(profiles.keys().cloned().collect()),
+        Some(_) => Err("`profiles` is not a table".to_string()),
     }
 }
 
 pub fn merge_warnings(
-    mut security_warnings: Vec<String>,
+    security_warnings: Vec<String>,
     result_warnings: Option<String>,
 ) -> Option<String> {
+    merge_warning_list(security_warnings, result_warnings).map(|w| w.join("\n"))
+}
+
+/// Combine security warnings with the run's newline-joined warnings, one entry per message.
+pub fn merge_warning_list(
+    mut security_warnings: Vec<String>,
+    result_warnings: Option<String>,
+) -> Option<Vec<String>> {
     if let Some(w) = result_warnings {
-        security_warnings.push(w);
+        security_warnings.extend(
+            w.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_string),
+        );
     }
     if security_warnings.is_empty() {
         None
     } else {
-        Some(security_warnings.join("\n"))
+        Some(security_warnings)
     }
 }
 
@@ -640,31 +2299,64 @@ pub struct CodexOutput {
     pub agent_messages: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub agent_messages_truncated: Option<bool>,
+    /// Agent messages split by turn, when `return_turns` was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_turns: Option<Vec<AgentTurn>>,
+    /// Reasoning summaries, when `include_reasoning_summary` was requested and codex sent any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_summary: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub all_messages: Option<Vec<HashMap<String, Value>>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub all_messages_truncated: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    /// All warnings joined by newlines, kept for compatibility
     #[serde(skip_serializing_if = "Option::is_none")]
     pub warnings: Option<String>,
+    /// The same warnings as individual messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warnings_list: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    /// `git diff --stat HEAD` of the working directory after the run, see `git_diff_stat`.
+    /// Also counts changes that were there before the run, and leaves out untracked
+    /// files codex created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff_stat: Option<String>,
+    /// Set when the run hit its timeout; the other fields are partial
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timed_out: Option<bool>,
+    /// Timeout in seconds the run actually used, after defaults and capping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
 }
 
 pub fn build_codex_output(
     result: &CodexResult,
     return_all_messages: bool,
-    warnings: Option<String>,
+    warnings: Option<Vec<String>>,
 ) -> CodexOutput {
     CodexOutput {
         success: result.success,
         session_id: result.session_id.clone(),
-        agent_messages: result.agent_messages.clone(),
+        agent_messages: MessageNormalization::from_env().apply(&result.agent_messages),
         agent_messages_truncated: result.agent_messages_truncated.then_some(true),
+        agent_turns: result.agent_turns.clone(),
+        reasoning_summary: result
+            .reasoning_summary
+            .clone()
+            .filter(|summary| !summary.is_empty()),
         all_messages: return_all_messages.then_some(result.all_messages.clone()),
         all_messages_truncated: (return_all_messages && result.all_messages_truncated)
             .then_some(true),
         error: result.error.clone(),
-        warnings,
+        warnings: warnings.as_ref().map(|w| w.join("\n")),
+        warnings_list: warnings,
+        stderr: result.stderr.clone(),
+        diff_stat: None,
+        timed_out: result.timed_out.then_some(true),
+        timeout_secs: result.timeout_secs,
     }
 }
 
@@ -672,6 +2364,7 @@ pub fn apply_security_restrictions(
     sandbox: &mut SandboxPolicy,
     yolo: &mut bool,
     skip_git_repo_check: &mut bool,
+    approval_policy: &mut Option<String>,
     security: &SecurityConfig,
 ) -> Vec<String> {
     let mut warnings = Vec::new();
@@ -694,6 +2387,17 @@ pub fn apply_security_restrictions(
         *skip_git_repo_check = false;
     }
 
+    let is_never = approval_policy
+        .as_deref()
+        .is_some_and(|p| p.trim().eq_ignore_ascii_case("never"));
+    if !security.allow_approval_never && is_never {
+        warnings.push(format!(
+            "Security warning: approval_policy 'never' was downgraded to '{}'. Set CODEX_ALLOW_APPROVAL_NEVER=true to enable.",
+            SAFE_APPROVAL_POLICY
+        ));
+        *approval_policy = Some(SAFE_APPROVAL_POLICY.to_string());
+    }
+
     warnings
 }
 
@@ -702,6 +2406,9 @@ mod tests {
     use super::*;
     use std::env::VarError;
 
+    /// Serializes tests that point CODEX_BIN at a fake codex script
+    static CODEX_BIN_LOCK: Mutex<()> = Mutex::new(());
+
     #[test]
     fn test_options_creation() {
         let opts = Options {
@@ -710,14 +2417,23 @@ mod tests {
             sandbox: SandboxPolicy::ReadOnly,
             session_id: None,
             skip_git_repo_check: true,
+            skip_git_repo_check_denied: false,
             return_all_messages: false,
             return_all_messages_limit: None,
+            return_turns: false,
+            include_reasoning_summary: false,
             image_paths: vec![],
             model: None,
             yolo: false,
             profile: None,
             timeout_secs: None,
             force_stdin: false,
+            session_registry: None,
+            log_file: None,
+            reasoning_effort: None,
+            verbosity: None,
+            approval_policy: None,
+            developer_instructions: None,
         };
         assert_eq!(opts.prompt, "test prompt");
         assert_eq!(opts.working_dir, PathBuf::from("/tmp"));
@@ -733,14 +2449,23 @@ mod tests {
             sandbox: SandboxPolicy::WorkspaceWrite,
             session_id: Some("test-session-123".to_string()),
             skip_git_repo_check: false,
+            skip_git_repo_check_denied: false,
             return_all_messages: true,
             return_all_messages_limit: Some(5000),
+            return_turns: false,
+            include_reasoning_summary: false,
             image_paths: vec![PathBuf::from("/path/to/image.png")],
             model: Some("claude-3-opus".to_string()),
             yolo: false,
             profile: Some("default".to_string()),
             timeout_secs: Some(600),
             force_stdin: false,
+            session_registry: None,
+            log_file: None,
+            reasoning_effort: None,
+            verbosity: None,
+            approval_policy: None,
+            developer_instructions: None,
         };
         assert_eq!(opts.session_id, Some("test-session-123".to_string()));
         assert_eq!(opts.model, Some("claude-3-opus".to_string()));
@@ -765,36 +2490,374 @@ mod tests {
         assert_eq!(SandboxPolicy::default(), SandboxPolicy::ReadOnly);
     }
 
-    #[test]
-    fn test_record_parse_error_sets_failure_and_appends_message() {
-        let mut result = CodexResult {
-            success: true,
-            session_id: "session".to_string(),
-            agent_messages: "ok".to_string(),
-            agent_messages_truncated: false,
-            all_messages: Vec::new(),
-            all_messages_truncated: false,
-            error: Some("existing".to_string()),
-            warnings: None,
-        };
-        let err = serde_json::from_str::<Value>("not-json").unwrap_err();
-        record_parse_error(&mut result, &err, "not-json");
-        assert!(!result.success);
-        assert!(result.error.as_ref().unwrap().contains("JSON parse error"));
-        assert!(result.error.as_ref().unwrap().contains("existing"));
-    }
+    const PROFILE_FIXTURE: &str = r#"
+model = "gpt-5"  # default model
 
-    #[test]
-    fn test_enforce_required_fields_warns_on_missing_agent_messages() {
+[profiles.fast]
+model = "gpt-5-mini"
+
+[profiles."deep-think"]
+model_reasoning_effort = "high"
+notes = """
+[profiles.not-a-profile]
+"""
+
+[profiles.fast.sandbox]
+mode = "read-only"
+
+[mcp_servers.docs]
+args = [
+  "--port",
+  "8080",
+]
+"#;
+
+    fn decode_lines(output: &str) -> (Vec<Value>, Option<String>) {
+        let mut decoder = JsonStreamDecoder::default();
+        let mut events = Vec::new();
+        for line in output.lines().filter(|l| !l.trim().is_empty()) {
+            events.extend(decoder.push_line(line.as_bytes()).unwrap());
+        }
+        (events, decoder.finish())
+    }
+
+    #[test]
+    fn test_json_stream_decoder_jsonl() {
+        let output = concat!(
+            r#"{"type":"thread.started","thread_id":"t-1"}"#,
+            "\n",
+            r#"{"type":"item.completed","item":{"type":"agent_message","text":"a { brace"}}"#,
+            "\n",
+            r#"{"type":"turn.completed"}"#,
+            "\n"
+        );
+        let (events, pending) = decode_lines(output);
+        assert!(pending.is_none());
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["thread_id"], "t-1");
+        assert_eq!(events[1]["item"]["text"], "a { brace");
+    }
+
+    #[test]
+    fn test_json_stream_decoder_pretty_array() {
+        let events = serde_json::json!([
+            {"type": "thread.started", "thread_id": "t-2"},
+            {"type": "item.completed", "item": {"type": "agent_message", "text": "done ]"}},
+        ]);
+        let output = serde_json::to_string_pretty(&events).unwrap();
+        let (decoded, pending) = decode_lines(&output);
+        assert!(pending.is_none());
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0]["thread_id"], "t-2");
+        assert_eq!(decoded[1]["item"]["text"], "done ]");
+    }
+
+    #[test]
+    fn test_json_stream_decoder_pretty_object_and_truncation() {
+        let output = "{\n  \"type\": \"thread.started\",\n  \"thread_id\": \"t-3\"\n}";
+        let (decoded, pending) = decode_lines(output);
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0]["thread_id"], "t-3");
+        assert!(pending.is_none());
+
+        let (decoded, pending) = decode_lines("[\n  {\"type\": \"x\"},");
+        assert!(decoded.is_empty());
+        assert!(pending.is_some());
+    }
+
+    #[test]
+    fn test_json_stream_decoder_rejects_plain_text() {
+        let mut decoder = JsonStreamDecoder::default();
+        assert!(decoder.push_line(b"not json").is_err());
+        assert!(decoder.push_line(b"{ \"unterminated\": ]").is_err());
+        assert!(decoder.finish().is_none());
+    }
+
+    #[test]
+    fn test_json_stream_decoder_truncated_line_does_not_stall() {
+        let mut decoder = JsonStreamDecoder::default();
+        let first = decoder.push_line(br#"{"type":"thread.started","thread_id":"t-4"}"#);
+        assert_eq!(first.unwrap().len(), 1);
+        assert!(decoder
+            .push_line(br#"{"type":"item.completed","item":{"#)
+            .is_err());
+        let next = decoder.push_line(br#"{"type":"turn.completed"}"#).unwrap();
+        assert_eq!(next[0]["type"], "turn.completed");
+        assert!(decoder.finish().is_none());
+
+        let mut decoder = JsonStreamDecoder::default();
+        assert!(decoder
+            .push_line(br#"{"type":"item.completed","item":{"#)
+            .is_err());
+        assert!(decoder.finish().is_none());
+    }
+
+    #[test]
+    fn test_json_stream_decoder_invalid_utf8() {
+        let mut decoder = JsonStreamDecoder::default();
+        let events = decoder
+            .push_line(b"{\"type\":\"agent_message\",\"text\":\"bad \xff byte\"}")
+            .unwrap();
+        assert_eq!(events[0]["text"], "bad \u{fffd} byte");
+        assert!(decoder.replaced_invalid_utf8);
+
+        let mut decoder = JsonStreamDecoder::default();
+        assert!(decoder
+            .push_line(b"{\"type\":\"turn.completed\"}\xff")
+            .is_err());
+        assert!(!decoder.replaced_invalid_utf8);
+    }
+
+    #[test]
+    fn test_parse_profile_names_tables() {
+        let names = parse_profile_names(PROFILE_FIXTURE).unwrap();
+        assert_eq!(names, vec!["deep-think", "fast"]);
+    }
+
+    #[test]
+    fn test_parse_profile_names_inline_and_dotted() {
+        let contents = "[profiles]\ninline = { model = \"o4\" }\ndotted.model = \"o3\"\n";
+        let names = parse_profile_names(contents).unwrap();
+        assert_eq!(names, vec!["dotted", "inline"]);
+
+        let contents = "profiles.top.model = \"o3\"\n";
+        assert_eq!(parse_profile_names(contents).unwrap(), vec!["top"]);
+    }
+
+    #[test]
+    fn test_parse_profile_names_quoting_and_multiline_arrays() {
+        let contents = concat!(
+            "notes = 'see [profiles.fake] # not a comment'\n",
+            "[profiles.\"with.dot\"]\n",
+            "args = [\n  \"]\",\n  { nested = [1, 2] },\n]\n",
+            "[profiles.'literal']\n",
+            "model = \"x\"\n",
+        );
+        let names = parse_profile_names(contents).unwrap();
+        assert_eq!(names, vec!["literal", "with.dot"]);
+    }
+
+    #[test]
+    fn test_parse_profile_names_malformed() {
+        assert!(parse_profile_names("[profiles.fast\nmodel = \"x\"").is_err());
+        assert!(parse_profile_names("[profiles.fast]\nmodel \"x\"").is_err());
+        assert!(parse_profile_names("args = [\"a\",\n").is_err());
+        assert!(parse_profile_names("x = ]").is_err());
+        assert!(parse_profile_names("profiles = \"fast\"").is_err());
+    }
+
+    #[test]
+    fn test_check_profile_present_and_absent() {
+        assert!(check_profile("fast", PROFILE_FIXTURE).is_ok());
+        assert!(check_profile("deep-think", PROFILE_FIXTURE).is_ok());
+
+        let err = check_profile("missing", PROFILE_FIXTURE).unwrap_err();
+        assert!(err.contains("'missing'"));
+        assert!(err.contains("Available profiles: deep-think, fast"));
+
+        let err = check_profile("fast", "").unwrap_err();
+        assert!(err.contains("Available profiles: (none)"));
+
+        let err = check_profile("fast", "[profiles.fast").unwrap_err();
+        assert!(err.contains("Failed to parse"));
+    }
+
+    #[test]
+    fn test_apply_exit_status_success_populates_stderr() {
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, true, Some(0), "note".to_string(), true, false);
+        assert!(result.success);
+        assert_eq!(result.stderr.as_deref(), Some("note"));
+        assert_eq!(result.warnings.as_deref(), Some("note"));
+
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, true, Some(0), "note".to_string(), false, false);
+        assert_eq!(result.stderr.as_deref(), Some("note"));
+        assert!(result.warnings.is_none());
+    }
+
+    #[test]
+    fn test_apply_exit_status_failure_populates_stderr() {
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, false, Some(2), "boom".to_string(), true, false);
+        assert!(!result.success);
+        assert_eq!(result.stderr.as_deref(), Some("boom"));
+        assert_eq!(
+            result.error.as_deref(),
+            Some("codex command failed with exit code: Some(2)\nStderr: boom")
+        );
+
+        let mut result = model_test_result(true, Some("bad model"));
+        apply_exit_status(
+            &mut result,
+            false,
+            Some(2),
+            "boom".to_string(),
+            false,
+            false,
+        );
+        assert_eq!(result.stderr.as_deref(), Some("boom"));
+        assert_eq!(result.error.as_deref(), Some("bad model"));
+    }
+
+    #[test]
+    fn test_apply_exit_status_rewrites_not_git_repo_error() {
+        let stderr = "Not inside a trusted directory and --skip-git-repo-check was not specified.";
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, false, Some(1), stderr.to_string(), true, false);
+        let error = result.error.unwrap();
+        assert!(error.starts_with(NOT_GIT_REPO_MESSAGE));
+        assert!(error.contains("Codex error: codex command failed with exit code: Some(1)"));
+        assert!(error.ends_with(&format!("Stderr: {}", stderr)));
+        assert_eq!(result.stderr.as_deref(), Some(stderr));
+
+        let mut result = model_test_result(true, None);
+        apply_exit_status(
+            &mut result,
+            false,
+            Some(1),
+            stderr.to_string(),
+            false,
+            false,
+        );
+        assert!(result
+            .error
+            .unwrap()
+            .contains("CODEX_ALLOW_SKIP_GIT_CHECK=true"));
+
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, false, Some(1), stderr.to_string(), false, true);
+        let error = result.error.unwrap();
+        assert!(error.starts_with(NOT_GIT_REPO_DENIED_MESSAGE));
+        assert!(!error.contains("pass skip_git_repo_check=true"));
+
+        // git's own message, e.g. from a command codex ran, is left alone
+        let stderr = "fatal: not a git repository (or any of the parent directories): .git";
+        let mut result = model_test_result(true, None);
+        apply_exit_status(
+            &mut result,
+            false,
+            Some(1),
+            stderr.to_string(),
+            false,
+            false,
+        );
+        assert_eq!(
+            result.error.as_deref(),
+            Some("codex command failed with exit code: Some(1)")
+        );
+    }
+
+    #[test]
+    fn test_is_auth_error_detects_signatures() {
+        assert!(is_auth_error(
+            "Error: Not logged in. Run codex login.",
+            Some(1)
+        ));
+        assert!(is_auth_error(
+            "stream error: authentication failed: invalid api key",
+            Some(1)
+        ));
+        assert!(is_auth_error(
+            "unexpected status 401 Unauthorized: {\"error\":\"invalid_token\"}",
+            None
+        ));
+        assert!(is_auth_error("Please log in with `codex login`", Some(1)));
+        assert!(!is_auth_error("Error: Not logged in.", Some(0)));
+        assert!(!is_auth_error("processed 4012 tokens", Some(1)));
+        // 401 or auth words without an auth failure are not rewritten
+        assert!(!is_auth_error("HTTP 401", Some(1)));
+        assert!(!is_auth_error(
+            "fixing ticket 401\nerror: upstream returned HTTP 500",
+            Some(1)
+        ));
+        assert!(!is_auth_error(
+            "model said: add authentication middleware\nerror: sandbox denied",
+            Some(1)
+        ));
+        assert!(!is_auth_error("codex error: sandbox denied", Some(1)));
+        assert!(!is_auth_error("", Some(1)));
+    }
+
+    #[test]
+    fn test_apply_exit_status_rewrites_auth_error() {
+        let stderr = "Error: unexpected status 401 Unauthorized";
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, false, Some(1), stderr.to_string(), true, false);
+        let error = result.error.unwrap();
+        assert!(error.starts_with(AUTH_ERROR_MESSAGE));
+        assert!(error.contains("codex login"));
+        assert!(error.contains("Codex error: codex command failed with exit code: Some(1)"));
+        assert!(error.ends_with(&format!("Stderr: {}", stderr)));
+
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, false, Some(1), "boom".to_string(), true, false);
+        assert_eq!(
+            result.error.as_deref(),
+            Some("codex command failed with exit code: Some(1)\nStderr: boom")
+        );
+    }
+
+    #[test]
+    fn test_is_not_git_repo_error_detects_signatures() {
+        assert!(is_not_git_repo_error(
+            "Not inside a trusted directory and --skip-git-repo-check was not specified."
+        ));
+        assert!(!is_not_git_repo_error(
+            "fatal: not a git repository (or any of the parent directories): .git"
+        ));
+        assert!(!is_not_git_repo_error("codex error: sandbox denied"));
+        assert!(!is_not_git_repo_error(""));
+    }
+
+    #[test]
+    fn test_apply_exit_status_empty_stderr_is_none() {
+        let mut result = model_test_result(true, None);
+        apply_exit_status(&mut result, true, Some(0), String::new(), true, false);
+        assert!(result.stderr.is_none());
+        assert!(result.warnings.is_none());
+    }
+
+    #[test]
+    fn test_record_parse_error_sets_failure_and_appends_message() {
+        let mut result = CodexResult {
+            success: true,
+            session_id: "session".to_string(),
+            agent_messages: "ok".to_string(),
+            agent_messages_truncated: false,
+            agent_turns: None,
+            reasoning_summary: None,
+            all_messages: Vec::new(),
+            all_messages_truncated: false,
+            error: Some("existing".to_string()),
+            warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
+        };
+        let err = serde_json::from_str::<Value>("not-json").unwrap_err();
+        record_parse_error(&mut result, &err, "not-json");
+        assert!(!result.success);
+        assert!(result.error.as_ref().unwrap().contains("JSON parse error"));
+        assert!(result.error.as_ref().unwrap().contains("existing"));
+    }
+
+    #[test]
+    fn test_enforce_required_fields_warns_on_missing_agent_messages() {
         let result = CodexResult {
             success: true,
             session_id: "session".to_string(),
             agent_messages: String::new(),
             agent_messages_truncated: false,
-            all_messages: vec![HashMap::new()],
+            agent_turns: None,
+            reasoning_summary: None,
+            all_messages: Vec::new(),
             all_messages_truncated: false,
             error: None,
             warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
         };
         let updated = enforce_required_fields(result, ValidationMode::Full);
         assert!(updated.success);
@@ -805,6 +2868,30 @@ mod tests {
             .contains("No agent_messages"));
     }
 
+    #[test]
+    fn test_enforce_required_fields_no_warning_with_all_messages() {
+        let mut event = HashMap::new();
+        event.insert("type".to_string(), Value::from("item.completed"));
+        let result = CodexResult {
+            success: true,
+            session_id: "session".to_string(),
+            agent_messages: String::new(),
+            agent_messages_truncated: false,
+            agent_turns: None,
+            reasoning_summary: None,
+            all_messages: vec![event],
+            all_messages_truncated: false,
+            error: None,
+            warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
+        };
+        let updated = enforce_required_fields(result, ValidationMode::Full);
+        assert!(updated.success);
+        assert!(updated.warnings.is_none());
+    }
+
     #[test]
     fn test_enforce_required_fields_requires_session_id() {
         let result = CodexResult {
@@ -812,10 +2899,15 @@ mod tests {
             session_id: String::new(),
             agent_messages: "msg".to_string(),
             agent_messages_truncated: false,
+            agent_turns: None,
+            reasoning_summary: None,
             all_messages: Vec::new(),
             all_messages_truncated: false,
             error: None,
             warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
         };
         let updated = enforce_required_fields(result, ValidationMode::Full);
         assert!(!updated.success);
@@ -841,10 +2933,15 @@ mod tests {
             session_id: String::new(),
             agent_messages: String::new(),
             agent_messages_truncated: false,
+            agent_turns: None,
+            reasoning_summary: None,
             all_messages: Vec::new(),
             all_messages_truncated: false,
             error: Some("Codex execution timed out after 10 seconds".to_string()),
             warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
         };
         let updated = enforce_required_fields(result, ValidationMode::Skip);
         assert!(!updated.success);
@@ -863,6 +2960,8 @@ mod tests {
             session_id: String::new(),
             agent_messages: String::new(),
             agent_messages_truncated: false,
+            agent_turns: None,
+            reasoning_summary: None,
             all_messages: Vec::new(),
             all_messages_truncated: false,
             error: Some(
@@ -870,6 +2969,9 @@ mod tests {
                     .to_string(),
             ),
             warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
         };
         let updated = enforce_required_fields(result, ValidationMode::Full);
         assert!(!updated.success);
@@ -918,91 +3020,1654 @@ mod tests {
         assert!(needs_stdin_mode("(group)"));
     }
 
-    // --- Security config tests ---
+    #[test]
+    fn test_session_registry_kill_unknown_session() {
+        let registry = SessionRegistry::new();
+        assert!(!registry.kill("missing"));
+    }
 
     #[test]
-    fn resolve_env_bool_accepts_truthy_values() {
-        let mut warnings = Vec::new();
+    fn test_session_guard_unregisters_on_drop() {
+        let registry = SessionRegistry::new();
+        let guard = registry.register("sess");
+        assert!(registry.contains("sess"));
+        drop(guard);
+        assert!(!registry.contains("sess"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_log_file_receives_every_stdout_line() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let lines = [
+            r#"{"type":"thread.started","thread_id":"log-session"}"#,
+            r#"{"type":"item.completed","item":{"type":"reasoning","text":"thinking"}}"#,
+            r#"{"type":"item.completed","item":{"type":"agent_message","text":"done"}}"#,
+            r#"{"type":"turn.completed"}"#,
+        ];
+        let script = dir.path().join("fake-codex");
+        let body: String = lines.iter().map(|l| format!("echo '{}'\n", l)).collect();
+        std::fs::write(&script, format!("#!/bin/sh\n{}", body)).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let log_path = dir.path().join("codex.log");
+        std::fs::write(&log_path, "previous run\n").unwrap();
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        opts.timeout_secs = Some(30);
+        opts.log_file = Some(log_path.clone());
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut partial = CodexResult::default();
+        let result = rt.block_on(run_internal(opts, &mut partial)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(result.success, "{:?}", result.error);
+        assert_eq!(result.agent_messages, "done");
+        let logged = std::fs::read_to_string(&log_path).unwrap();
+        let expected: Vec<&str> = std::iter::once("previous run").chain(lines).collect();
+        assert_eq!(logged.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_validate_reasoning_effort_and_verbosity() {
+        assert_eq!(validate_reasoning_effort(" High ").unwrap(), "high");
+        assert_eq!(validate_reasoning_effort("minimal").unwrap(), "minimal");
+        let err = validate_reasoning_effort("extreme").unwrap_err();
+        assert!(err.contains("reasoning_effort must be one of minimal, low, medium, high"));
+
+        assert_eq!(validate_verbosity("LOW").unwrap(), "low");
+        let err = validate_verbosity("minimal").unwrap_err();
+        assert!(err.contains("verbosity must be one of low, medium, high; got 'minimal'"));
+    }
+
+    /// Run git in `dir` for test setup, panicking on failure
+    fn git(dir: &Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn test_git_diff_stat_reports_staged_change() {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "-q"]);
+        std::fs::write(dir.path().join("notes.txt"), "one\n").unwrap();
+        git(dir.path(), &["add", "notes.txt"]);
+        git(dir.path(), &["commit", "-q", "-m", "init"]);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(rt.block_on(git_diff_stat(dir.path())), None);
+
+        std::fs::write(dir.path().join("notes.txt"), "one\ntwo\nthree\n").unwrap();
+        git(dir.path(), &["add", "notes.txt"]);
+        let stat = rt.block_on(git_diff_stat(dir.path())).unwrap();
+        assert!(stat.contains("notes.txt"), "{}", stat);
+        assert!(stat.contains("1 file changed, 2 insertions(+)"), "{}", stat);
+    }
+
+    #[test]
+    fn test_git_diff_stat_outside_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        assert_eq!(rt.block_on(git_diff_stat(dir.path())), None);
+    }
+
+    #[test]
+    fn test_validate_approval_policy() {
         assert_eq!(
-            resolve_env_bool("K", Some("1".into()), &mut warnings),
-            Some(true)
+            validate_approval_policy(" On-Request ").unwrap(),
+            "on-request"
         );
+        assert_eq!(validate_approval_policy("never").unwrap(), "never");
+        let err = validate_approval_policy("always").unwrap_err();
+        assert!(err.contains(
+            "approval_policy must be one of untrusted, on-failure, on-request, never; got 'always'"
+        ));
+    }
+
+    #[test]
+    fn test_config_override_args() {
+        let mut opts = model_test_options(None);
+        assert!(config_override_args(&opts).is_empty());
+
+        opts.reasoning_effort = Some("high".to_string());
+        opts.verbosity = Some("low".to_string());
         assert_eq!(
-            resolve_env_bool("K", Some("true".into()), &mut warnings),
-            Some(true)
+            config_override_args(&opts),
+            vec![
+                "-c",
+                "model_reasoning_effort=\"high\"",
+                "-c",
+                "model_verbosity=\"low\"",
+            ]
         );
+
+        opts.reasoning_effort = None;
         assert_eq!(
-            resolve_env_bool("K", Some("yes".into()), &mut warnings),
-            Some(true)
+            config_override_args(&opts),
+            vec!["-c", "model_verbosity=\"low\""]
         );
+
+        opts.verbosity = None;
+        opts.approval_policy = Some("on-failure".to_string());
         assert_eq!(
-            resolve_env_bool("K", Some("on".into()), &mut warnings),
-            Some(true)
+            config_override_args(&opts),
+            vec!["-c", "approval_policy=\"on-failure\""]
         );
-        assert!(warnings.is_empty());
     }
 
     #[test]
-    fn resolve_env_bool_accepts_falsy_values() {
-        let mut warnings = Vec::new();
-        assert_eq!(
-            resolve_env_bool("K", Some("0".into()), &mut warnings),
-            Some(false)
-        );
+    fn test_config_override_args_with_instructions() {
+        let mut opts = model_test_options(None);
+        opts.developer_instructions = Some("Answer in English.".to_string());
         assert_eq!(
-            resolve_env_bool("K", Some("false".into()), &mut warnings),
-            Some(false)
+            config_override_args(&opts),
+            vec!["-c", "developer_instructions=\"Answer in English.\""]
         );
+
+        opts.developer_instructions =
+            Some("Use \"snake_case\".\nNever touch C:\\vendor\t\u{7}".to_string());
         assert_eq!(
-            resolve_env_bool("K", Some("off".into()), &mut warnings),
-            Some(false)
+            config_override_args(&opts),
+            vec![
+                "-c",
+                r#"developer_instructions="Use \"snake_case\".\nNever touch C:\\vendor\t\u0007""#,
+            ]
         );
-        assert!(warnings.is_empty());
+
+        opts.developer_instructions = None;
+        assert!(config_override_args(&opts).is_empty());
     }
 
     #[test]
-    fn resolve_env_bool_warns_on_invalid() {
-        let mut warnings = Vec::new();
-        assert_eq!(
-            resolve_env_bool("TEST_KEY", Some("maybe".into()), &mut warnings),
-            None
-        );
-        assert_eq!(warnings.len(), 1);
-        assert!(warnings[0].contains("TEST_KEY"));
-        assert!(warnings[0].contains("maybe"));
+    fn test_validate_instructions() {
+        assert_eq!(validate_instructions("Be terse.").unwrap(), "Be terse.");
+        assert!(validate_instructions("").is_err());
+        assert!(validate_instructions(" \n\t").is_err());
     }
 
     #[test]
-    fn resolve_env_bool_returns_none_for_empty() {
-        let mut warnings = Vec::new();
-        assert_eq!(resolve_env_bool("K", Some("".into()), &mut warnings), None);
-        assert_eq!(resolve_env_bool("K", None, &mut warnings), None);
-        assert!(warnings.is_empty());
+    fn test_check_instructions_rejects_cmd_unsafe_text_on_windows() {
+        let plain = "Be terse and answer in English.";
+        assert_eq!(check_instructions(plain, true).unwrap(), plain);
+
+        for text in [
+            "a & del x",
+            "a | b",
+            "50%",
+            "!x!",
+            "line1\nline2",
+            "say \"hi\"",
+        ] {
+            assert!(check_instructions(text, false).is_ok(), "{text:?}");
+            let err = check_instructions(text, true).unwrap_err();
+            assert!(err.contains("on Windows"), "{text:?}: {err}");
+        }
+
+        let long = "a".repeat(MAX_CLI_PROMPT_LEN + 1);
+        assert!(check_instructions(&long, false).is_ok());
+        assert!(check_instructions(&long, true).is_err());
+        assert!(check_instructions(&long[..MAX_CLI_PROMPT_LEN], true).is_ok());
     }
 
     #[test]
-    fn merge_warnings_combines_security_and_result() {
-        let combined = merge_warnings(vec!["security".into()], Some("result".into())).unwrap();
-        assert!(combined.contains("security"));
-        assert!(combined.contains("result"));
+    fn test_image_limits_check_count() {
+        let limits = ImageLimits {
+            max_count: 2,
+            ..ImageLimits::default()
+        };
+        assert!(limits.check_count(0).is_ok());
+        assert!(limits.check_count(2).is_ok());
+        let err = limits.check_count(3).unwrap_err();
+        assert!(err.contains("3 attached, at most 2 allowed"));
     }
 
     #[test]
-    fn test_apply_security_restrictions_returns_warnings() {
-        let mut sandbox = SandboxPolicy::DangerFullAccess;
-        let mut yolo = true;
-        let mut skip_git = true;
-        let security = SecurityConfig {
-            allow_danger_full_access: false,
-            allow_yolo: false,
-            allow_skip_git_check: false,
+    fn test_image_limits_check_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small.png");
+        let large = dir.path().join("large.png");
+        std::fs::write(&small, vec![0u8; 40]).unwrap();
+        std::fs::write(&large, vec![0u8; 100]).unwrap();
+
+        let limits = ImageLimits {
+            max_count: DEFAULT_MAX_IMAGES,
+            max_bytes: 64,
+            max_total_bytes: 100,
         };
-        let warnings =
-            apply_security_restrictions(&mut sandbox, &mut yolo, &mut skip_git, &security);
-        assert_eq!(warnings.len(), 3);
-        assert_eq!(sandbox, SandboxPolicy::ReadOnly);
-        assert!(!yolo);
-        assert!(!skip_git);
+        assert!(limits.check_sizes(&[small.clone(), small.clone()]).is_ok());
+
+        let err = limits.check_sizes(&[small.clone(), large]).unwrap_err();
+        assert!(err.contains("large.png is 100 bytes, at most 64 allowed"));
+
+        let err = limits
+            .check_sizes(&[small.clone(), small.clone(), small])
+            .unwrap_err();
+        assert!(err.contains("120 bytes, at most 100 allowed"));
+    }
+
+    #[test]
+    fn test_image_limits_from_env() {
+        std::env::set_var("CODEX_MAX_IMAGES", "4");
+        std::env::set_var("CODEX_MAX_IMAGE_BYTES", "0");
+        std::env::set_var("CODEX_MAX_IMAGE_TOTAL_BYTES", "bogus");
+        let limits = ImageLimits::from_env();
+        std::env::remove_var("CODEX_MAX_IMAGES");
+        std::env::remove_var("CODEX_MAX_IMAGE_BYTES");
+        std::env::remove_var("CODEX_MAX_IMAGE_TOTAL_BYTES");
+
+        assert_eq!(limits.max_count, 4);
+        assert_eq!(limits.max_bytes, DEFAULT_MAX_IMAGE_BYTES);
+        assert_eq!(limits.max_total_bytes, DEFAULT_MAX_IMAGE_TOTAL_BYTES);
+    }
+
+    #[test]
+    fn test_resolve_log_file_relative_to_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let working_dir = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(working_dir.join("logs")).unwrap();
+
+        let resolved = resolve_log_file(Path::new("logs/codex.jsonl"), &working_dir, &[]).unwrap();
+        assert_eq!(resolved, working_dir.join("logs").join("codex.jsonl"));
+
+        let err =
+            resolve_log_file(Path::new("missing/codex.jsonl"), &working_dir, &[]).unwrap_err();
+        assert!(err.contains("does not exist"));
+
+        let err = resolve_log_file(Path::new("logs"), &working_dir, &[]).unwrap_err();
+        assert!(err.contains("not a file"));
+    }
+
+    #[test]
+    fn test_resolve_log_file_enforces_allowed_dirs() {
+        let workspace = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let working_dir = workspace.path().canonicalize().unwrap();
+        let outside = root.path().join("codex.log");
+
+        let err = resolve_log_file(Path::new("../escape.log"), &working_dir, &[]).unwrap_err();
+        assert!(err.contains("must be inside"));
+        let err = resolve_log_file(&outside, &working_dir, &[]).unwrap_err();
+        assert!(err.contains("must be inside"));
+
+        let resolved =
+            resolve_log_file(&outside, &working_dir, &[root.path().to_path_buf()]).unwrap();
+        assert_eq!(
+            resolved,
+            root.path().canonicalize().unwrap().join("codex.log")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_codex_kill_reaps_running_child() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        let pid_file = dir.path().join("grandchild.pid");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nsleep 30 &\necho $! > '{}'\necho '{{\"type\":\"thread.started\",\"thread_id\":\"fake-session\"}}'\nexec sleep 30\n",
+                pid_file.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let registry = SessionRegistry::new();
+        let opts = Options {
+            prompt: "long task".to_string(),
+            working_dir: dir.path().to_path_buf(),
+            sandbox: SandboxPolicy::ReadOnly,
+            session_id: None,
+            skip_git_repo_check: false,
+            skip_git_repo_check_denied: false,
+            return_all_messages: false,
+            return_all_messages_limit: None,
+            return_turns: false,
+            include_reasoning_summary: false,
+            image_paths: vec![],
+            model: None,
+            yolo: false,
+            profile: None,
+            timeout_secs: Some(60),
+            force_stdin: false,
+            session_registry: Some(registry.clone()),
+            log_file: None,
+            reasoning_effort: None,
+            verbosity: None,
+            approval_policy: None,
+            developer_instructions: None,
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(async {
+            let handle = tokio::spawn(run(opts));
+            let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+            while !registry.contains("fake-session") {
+                assert!(
+                    tokio::time::Instant::now() < deadline,
+                    "session never registered"
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            assert!(registry.kill("fake-session"));
+            tokio::time::timeout(std::time::Duration::from_secs(10), handle)
+                .await
+                .expect("killed child was not reaped")
+                .unwrap()
+                .unwrap()
+        });
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(!result.success);
+        assert_eq!(result.session_id, "fake-session");
+        assert!(result.error.unwrap().contains("killed"));
+        assert!(!registry.contains("fake-session"));
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while process_alive(pid.trim()) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "grandchild survived codex_kill"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_turn_tracker_splits_on_tool_activity() {
+        let events = [
+            ("reasoning", None),
+            ("agent_message", Some("Looking at the tests")),
+            ("agent_message", Some("Running them now")),
+            ("command_execution", None),
+            ("file_change", None),
+            ("agent_message", Some("Fixed the failing test")),
+            ("mcp_tool_call", None),
+            ("agent_message", Some("")),
+            ("agent_message", Some("All green")),
+        ];
+        let mut tracker = TurnTracker::default();
+        let mut turns = Vec::new();
+        for (item_type, text) in events {
+            tracker.observe(&mut turns, item_type, text);
+        }
+        assert_eq!(
+            turns,
+            vec![
+                AgentTurn {
+                    turn_index: 0,
+                    text: "Looking at the tests\nRunning them now".to_string(),
+                },
+                AgentTurn {
+                    turn_index: 1,
+                    text: "Fixed the failing test".to_string(),
+                },
+                AgentTurn {
+                    turn_index: 2,
+                    text: "All green".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_returns_turns_from_event_stream() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        std::fs::write(
+            &script,
+            concat!(
+                "#!/bin/sh\n",
+                "echo '{\"type\":\"thread.started\",\"thread_id\":\"turns-session\"}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"first\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"command_execution\",\"command\":\"ls\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"second\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"more\"}}'\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        opts.return_turns = true;
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_once(opts.clone())).unwrap();
+        opts.return_turns = false;
+        let without_turns = rt.block_on(run_once(opts)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(result.success, "{:?}", result.error);
+        assert_eq!(result.agent_messages, "first\nsecond\nmore");
+        let turns = result.agent_turns.unwrap();
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[0].text, "first");
+        assert_eq!(turns[1].turn_index, 1);
+        assert_eq!(turns[1].text, "second\nmore");
+        assert!(without_turns.agent_turns.is_none());
+    }
+
+    #[test]
+    fn test_reasoning_summary_text() {
+        let item = |value: Value| value.as_object().unwrap().clone();
+
+        let text =
+            item(serde_json::json!({"type": "reasoning", "text": "**Plan** read the tests"}));
+        assert_eq!(
+            reasoning_summary_text(&text).as_deref(),
+            Some("**Plan** read the tests")
+        );
+        let parts = item(serde_json::json!({
+            "type": "reasoning",
+            "summary": [{"type": "summary_text", "text": "first"}, "second", {"type": "other"}],
+        }));
+        assert_eq!(
+            reasoning_summary_text(&parts).as_deref(),
+            Some("first\nsecond")
+        );
+
+        for other in [
+            serde_json::json!({"type": "agent_message", "text": "answer"}),
+            serde_json::json!({"type": "reasoning", "summary": []}),
+            serde_json::json!({"type": "reasoning"}),
+        ] {
+            assert_eq!(reasoning_summary_text(&item(other)), None);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_returns_reasoning_summary_from_event_stream() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        std::fs::write(
+            &script,
+            concat!(
+                "#!/bin/sh\n",
+                "echo '{\"type\":\"thread.started\",\"thread_id\":\"reasoning-session\"}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"reasoning\",\"text\":\"Looking at the tests\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"command_execution\",\"command\":\"ls\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"reasoning\",\"summary\":[{\"text\":\"Found the bug\"}]}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"done\"}}'\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        opts.include_reasoning_summary = true;
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_once(opts.clone())).unwrap();
+        opts.include_reasoning_summary = false;
+        let without_summary = rt.block_on(run_once(opts)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(result.success, "{:?}", result.error);
+        assert_eq!(result.agent_messages, "done");
+        assert_eq!(
+            result.reasoning_summary.as_deref(),
+            Some("Looking at the tests\nFound the bug")
+        );
+        assert!(result.all_messages.is_empty());
+        let output = build_codex_output(&result, false, None);
+        assert_eq!(
+            output.reasoning_summary.as_deref(),
+            Some("Looking at the tests\nFound the bug")
+        );
+        assert!(output.all_messages.is_none());
+
+        assert!(without_summary.reasoning_summary.is_none());
+        let output = build_codex_output(&without_summary, false, None);
+        assert!(output.reasoning_summary.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_reports_capped_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        std::fs::write(
+            &script,
+            concat!(
+                "#!/bin/sh\n",
+                "echo '{\"type\":\"thread.started\",\"thread_id\":\"timeout-session\"}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"done\"}}'\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        opts.timeout_secs = Some(MAX_TIMEOUT_SECS + 1000);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let capped = rt.block_on(run_once(opts.clone())).unwrap();
+        opts.timeout_secs = None;
+        let defaulted = rt.block_on(run_once(opts)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(capped.success, "{:?}", capped.error);
+        assert_eq!(capped.timeout_secs, Some(MAX_TIMEOUT_SECS));
+        let output = serde_json::to_value(build_codex_output(&capped, false, None)).unwrap();
+        assert_eq!(output["timeout_secs"], MAX_TIMEOUT_SECS);
+        assert_eq!(defaulted.timeout_secs, Some(DEFAULT_TIMEOUT_SECS));
+    }
+
+    #[test]
+    fn test_agent_message_types_parse() {
+        assert_eq!(
+            AgentMessageTypes::parse(" agent_message , reply ,"),
+            Some(AgentMessageTypes(vec![
+                "agent_message".to_string(),
+                "reply".to_string()
+            ]))
+        );
+        assert_eq!(AgentMessageTypes::parse(" , "), None);
+        assert!(AgentMessageTypes::default()
+            .0
+            .iter()
+            .any(|t| t == "assistant_message"));
+    }
+
+    #[test]
+    fn test_agent_message_types_text() {
+        let types = AgentMessageTypes::default();
+        let item = |v: Value| v.as_object().unwrap().clone();
+
+        assert_eq!(
+            types.text(&item(
+                serde_json::json!({"type": "agent_message", "text": "a"})
+            )),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            types.text(&item(serde_json::json!({
+                "type": "message",
+                "role": "assistant",
+                "content": [{"type": "output_text", "text": "b"}, {"type": "output_text", "text": "c"}],
+            }))),
+            Some("bc".to_string())
+        );
+        assert_eq!(
+            types.text(&item(
+                serde_json::json!({"type": "message", "role": "user", "content": "hi"})
+            )),
+            None
+        );
+        assert_eq!(
+            types.text(&item(
+                serde_json::json!({"type": "reasoning", "text": "hmm"})
+            )),
+            None
+        );
+
+        let custom = AgentMessageTypes::parse("reply").unwrap();
+        assert_eq!(
+            custom.text(&item(
+                serde_json::json!({"type": "agent_message", "text": "a"})
+            )),
+            None
+        );
+        assert_eq!(
+            custom.text(&item(serde_json::json!({"type": "reply", "text": "r"}))),
+            Some("r".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_collects_each_agent_message_variant() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        std::fs::write(
+            &script,
+            concat!(
+                "#!/bin/sh\n",
+                "echo '{\"type\":\"thread.started\",\"thread_id\":\"variant-session\"}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"from agent_message\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"assistant_message\",\"text\":\"from assistant_message\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"message\",\"role\":\"user\",\"content\":\"the prompt\"}}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"message\",\"role\":\"assistant\",\"content\":[{\"type\":\"output_text\",\"text\":\"from message\"}]}}'\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_once(opts.clone())).unwrap();
+        std::env::set_var("CODEX_AGENT_MESSAGE_TYPES", "assistant_message");
+        let restricted = rt.block_on(run_once(opts)).unwrap();
+        std::env::remove_var("CODEX_AGENT_MESSAGE_TYPES");
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(result.success, "{:?}", result.error);
+        assert_eq!(
+            result.agent_messages,
+            "from agent_message\nfrom assistant_message\nfrom message"
+        );
+        assert_eq!(restricted.agent_messages, "from assistant_message");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_silent_failure_explains_exit_code() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        std::fs::write(&script, "#!/bin/sh\nexit 3\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_once(opts)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(!result.success);
+        assert!(result.stderr.is_none());
+        let error = result.error.unwrap();
+        assert!(error.contains("exit code: Some(3)"), "{}", error);
+        assert!(error.contains("without printing anything"), "{}", error);
+        assert!(
+            error.contains(&format!("Binary invoked: {}", script.display())),
+            "{}",
+            error
+        );
+        assert!(error.contains("config.toml"), "{}", error);
+        assert!(error.contains("smoke_test"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout_returns_partial_result() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        std::fs::write(
+            &script,
+            concat!(
+                "#!/bin/sh\n",
+                "echo '{\"type\":\"thread.started\",\"thread_id\":\"slow-session\"}'\n",
+                "echo '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"halfway there\"}}'\n",
+                "exec sleep 30\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        opts.timeout_secs = Some(1);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_once(opts)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(!result.success);
+        assert!(result.timed_out);
+        assert_eq!(result.session_id, "slow-session");
+        assert_eq!(result.agent_messages, "halfway there");
+        assert_eq!(
+            result.error.as_deref(),
+            Some("Codex execution timed out after 1 seconds")
+        );
+
+        let output = build_codex_output(&result, false, None);
+        let json = serde_json::to_value(&output).unwrap();
+        assert_eq!(json["timed_out"], true);
+        assert_eq!(json["SESSION_ID"], "slow-session");
+    }
+
+    /// Whether `pid` is still running (zombies count as gone)
+    #[cfg(unix)]
+    fn process_alive(pid: &str) -> bool {
+        let output = std::process::Command::new("ps")
+            .args(["-o", "stat=", "-p", pid])
+            .output()
+            .unwrap();
+        let stat = String::from_utf8_lossy(&output.stdout);
+        !stat.trim().is_empty() && !stat.trim_start().starts_with('Z')
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout_kills_codex_process_group() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        let pid_file = dir.path().join("grandchild.pid");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh
+sleep 30 &
+echo $! > '{}'
+exec sleep 30
+",
+                pid_file.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        opts.timeout_secs = Some(1);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_once(opts)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+        assert!(result.timed_out);
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let pid = pid.trim();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while process_alive(pid) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "grandchild {pid} survived the timeout"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resume_warns_when_codex_reports_other_cwd() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let original = tempfile::tempdir().unwrap();
+        let script = dir.path().join("fake-codex");
+        std::fs::write(
+            &script,
+            format!(
+                concat!(
+                    "#!/bin/sh\n",
+                    "echo '{{\"type\":\"thread.started\",\"thread_id\":\"resumed\",\"cwd\":\"{}\"}}'\n",
+                    "echo '{{\"type\":\"item.completed\",\"item\":{{\"type\":\"agent_message\",\"text\":\"ok\"}}}}'\n",
+                ),
+                original.path().display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::env::set_var("CODEX_BIN", &script);
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = dir.path().to_path_buf();
+        opts.timeout_secs = Some(30);
+        opts.session_id = Some("resumed".to_string());
+        let same_dir_opts = Options {
+            working_dir: original.path().to_path_buf(),
+            ..opts.clone()
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run_once(opts)).unwrap();
+        let unchanged = rt.block_on(run_once(same_dir_opts)).unwrap();
+        std::env::remove_var("CODEX_BIN");
+
+        assert!(result.success, "{:?}", result.error);
+        let warnings = result.warnings.unwrap();
+        assert!(warnings.contains("Session was created in"), "{}", warnings);
+        assert!(warnings.contains(&original.path().display().to_string()));
+        assert!(unchanged.warnings.is_none(), "{:?}", unchanged.warnings);
+    }
+
+    #[test]
+    fn test_registry_remembers_session_cwd_for_resume_warning() {
+        let registry = SessionRegistry::new();
+        let created = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        registry.record_cwd("sess", created.path());
+
+        let dir = registry.cwd_of("sess").unwrap();
+        assert!(resume_cwd_warning(&dir, created.path()).is_none());
+        assert!(resume_cwd_warning(&dir, other.path())
+            .unwrap()
+            .contains("being resumed with cd="));
+        assert!(registry.cwd_of("unknown").is_none());
+    }
+
+    #[test]
+    fn test_event_cwd_reads_top_level_and_msg() {
+        let event = serde_json::json!({"type": "thread.started", "cwd": "/a"});
+        assert_eq!(event_cwd(&event), Some("/a"));
+        let event = serde_json::json!({"msg": {"type": "session_configured", "cwd": "/b"}});
+        assert_eq!(event_cwd(&event), Some("/b"));
+        assert_eq!(
+            event_cwd(&serde_json::json!({"type": "turn.completed"})),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_path_prepend_resolves_codex_and_reaches_child() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _env = CODEX_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let bin_dir = tempfile::tempdir().unwrap();
+        let script = bin_dir.path().join("codex");
+        std::fs::write(
+            &script,
+            concat!(
+                "#!/bin/sh\n",
+                "echo '{\"type\":\"thread.started\",\"thread_id\":\"path-session\"}'\n",
+                "printf '{\"type\":\"item.completed\",\"item\":{\"type\":\"agent_message\",\"text\":\"%s\"}}\\n' \"$PATH\"\n",
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let original_bin = std::env::var_os("CODEX_BIN");
+        std::env::remove_var("CODEX_BIN");
+        std::env::set_var("CODEX_PATH_PREPEND", bin_dir.path());
+
+        let mut opts = model_test_options(None);
+        opts.working_dir = bin_dir.path().to_path_buf();
+        opts.timeout_secs = Some(30);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut partial = CodexResult::default();
+        let result = rt.block_on(run_internal(opts, &mut partial));
+        std::env::remove_var("CODEX_PATH_PREPEND");
+        if let Some(bin) = original_bin {
+            std::env::set_var("CODEX_BIN", bin);
+        }
+
+        let result = result.unwrap();
+        assert!(result.success, "{:?}", result.error);
+        let child_path = std::env::split_paths(&result.agent_messages).next();
+        assert_eq!(child_path.as_deref(), Some(bin_dir.path()));
+    }
+
+    #[test]
+    fn test_registry_accumulates_session_runtime() {
+        let registry = SessionRegistry::new();
+        assert_eq!(registry.total_runtime("s"), std::time::Duration::ZERO);
+        registry.add_runtime("s", std::time::Duration::from_secs(30));
+        registry.add_runtime("s", std::time::Duration::from_millis(1500));
+        registry.add_runtime("other", std::time::Duration::from_secs(5));
+        assert_eq!(
+            registry.total_runtime("s"),
+            std::time::Duration::from_millis(31_500)
+        );
+        assert_eq!(
+            registry.total_runtime("other"),
+            std::time::Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn test_session_budget_timeout() {
+        let secs = std::time::Duration::from_secs;
+        assert_eq!(session_budget_timeout(secs(0), 600, 300), Ok(300));
+        assert_eq!(session_budget_timeout(secs(500), 600, 300), Ok(100));
+        let err = session_budget_timeout(secs(600), 600, 300).unwrap_err();
+        assert!(err.contains("CODEX_SESSION_MAX_TOTAL_SECS=600"), "{}", err);
+        assert!(session_budget_timeout(secs(900), 600, 300).is_err());
+    }
+
+    #[test]
+    fn test_run_refuses_resume_over_session_budget() {
+        let registry = SessionRegistry::new();
+        registry.add_runtime("spent", std::time::Duration::from_secs(120));
+        let mut opts = model_test_options(None);
+        opts.session_id = Some("spent".to_string());
+        opts.session_registry = Some(registry.clone());
+
+        std::env::set_var("CODEX_SESSION_MAX_TOTAL_SECS", "60");
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(run(opts)).unwrap();
+        std::env::remove_var("CODEX_SESSION_MAX_TOTAL_SECS");
+
+        assert!(!result.success);
+        assert_eq!(result.session_id, "spent");
+        assert!(result
+            .error
+            .unwrap()
+            .contains("reaching CODEX_SESSION_MAX_TOTAL_SECS=60"));
+        // A refused resume does not add to the total
+        assert_eq!(
+            registry.total_runtime("spent"),
+            std::time::Duration::from_secs(120)
+        );
+    }
+
+    fn model_test_result(success: bool, error: Option<&str>) -> CodexResult {
+        CodexResult {
+            success,
+            session_id: "session".to_string(),
+            agent_messages: String::new(),
+            agent_messages_truncated: false,
+            agent_turns: None,
+            reasoning_summary: None,
+            all_messages: Vec::new(),
+            all_messages_truncated: false,
+            error: error.map(str::to_string),
+            warnings: None,
+            stderr: None,
+            timed_out: false,
+            timeout_secs: None,
+        }
+    }
+
+    fn model_test_options(model: Option<&str>) -> Options {
+        Options {
+            prompt: "task".to_string(),
+            working_dir: PathBuf::from("/tmp"),
+            sandbox: SandboxPolicy::ReadOnly,
+            session_id: None,
+            skip_git_repo_check: false,
+            skip_git_repo_check_denied: false,
+            return_all_messages: false,
+            return_all_messages_limit: None,
+            return_turns: false,
+            include_reasoning_summary: false,
+            image_paths: vec![],
+            model: model.map(str::to_string),
+            yolo: false,
+            profile: None,
+            timeout_secs: None,
+            force_stdin: false,
+            session_registry: None,
+            log_file: None,
+            reasoning_effort: None,
+            verbosity: None,
+            approval_policy: None,
+            developer_instructions: None,
+        }
+    }
+
+    #[test]
+    fn test_is_model_error_detects_signatures() {
+        assert!(is_model_error(
+            "codex command failed with exit code: Some(1)\nStderr: Error: Unknown model 'gpt-x'"
+        ));
+        assert!(is_model_error("codex error: model_not_found"));
+        assert!(is_model_error(
+            "The model is not available for your account"
+        ));
+        assert!(!is_model_error("codex error: rate limit exceeded"));
+        assert!(!is_model_error(""));
+    }
+
+    #[test]
+    fn test_parse_model_fallbacks() {
+        assert_eq!(
+            parse_model_fallbacks(" gpt-5 , ,o3,"),
+            vec!["gpt-5".to_string(), "o3".to_string()]
+        );
+        assert!(parse_model_fallbacks("").is_empty());
+    }
+
+    #[test]
+    fn test_resolve_model_uses_env_default() {
+        std::env::remove_var("CODEX_DEFAULT_MODEL");
+        assert_eq!(resolve_model(None), None);
+        assert_eq!(
+            resolve_model(Some("o3".to_string())),
+            Some("o3".to_string())
+        );
+
+        std::env::set_var("CODEX_DEFAULT_MODEL", "gpt-5-codex");
+        assert_eq!(resolve_model(None), Some("gpt-5-codex".to_string()));
+        assert_eq!(
+            resolve_model(Some("  ".to_string())),
+            Some("gpt-5-codex".to_string())
+        );
+        assert_eq!(
+            resolve_model(Some("o3".to_string())),
+            Some("o3".to_string())
+        );
+
+        std::env::set_var("CODEX_DEFAULT_MODEL", "");
+        assert_eq!(resolve_model(None), None);
+        std::env::remove_var("CODEX_DEFAULT_MODEL");
+    }
+
+    #[test]
+    fn test_model_fallback_tries_next_model_on_model_error() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let fallbacks = vec!["fallback-a".to_string(), "fallback-b".to_string()];
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt
+            .block_on(run_with_model_fallback(
+                model_test_options(Some("primary")),
+                &fallbacks,
+                move |opts: Options| {
+                    recorder.lock().unwrap().push(opts.model.clone());
+                    let result = match opts.model.as_deref() {
+                        Some("fallback-b") => model_test_result(true, None),
+                        _ => model_test_result(false, Some("Stderr: unknown model")),
+                    };
+                    async move { Ok(result) }
+                },
+            ))
+            .unwrap();
+
+        assert!(result.success);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                Some("primary".to_string()),
+                Some("fallback-a".to_string()),
+                Some("fallback-b".to_string())
+            ]
+        );
+        let warnings = result.warnings.unwrap();
+        assert!(warnings.contains("'primary'"));
+        assert!(warnings.contains("'fallback-b'"));
+    }
+
+    #[test]
+    fn test_model_fallback_skips_non_model_errors() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let fallbacks = vec!["fallback-a".to_string()];
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt
+            .block_on(run_with_model_fallback(
+                model_test_options(None),
+                &fallbacks,
+                move |_opts: Options| {
+                    *counter.lock().unwrap() += 1;
+                    async {
+                        Ok(model_test_result(
+                            false,
+                            Some("codex error: sandbox denied"),
+                        ))
+                    }
+                },
+            ))
+            .unwrap();
+
+        assert!(!result.success);
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_is_sandbox_init_error_detects_signatures() {
+        assert!(is_sandbox_init_error(
+            "Error: Sandbox(SeccompInstall(...)): seccomp setup error: EINVAL"
+        ));
+        assert!(is_sandbox_init_error(
+            "thread 'main' panicked: error applying Landlock rules: Sandbox(LandlockRestrict)"
+        ));
+        assert!(is_sandbox_init_error(
+            "codex command failed with exit code: Some(1)\nStderr: failed to initialize sandbox"
+        ));
+        assert!(!is_sandbox_init_error("codex error: sandbox denied"));
+        assert!(!is_sandbox_init_error("Error: unknown model 'gpt-x'"));
+        assert!(!is_sandbox_init_error(""));
+    }
+
+    fn sandbox_failure() -> CodexResult {
+        CodexResult {
+            stderr: Some("Error: seccomp setup error".to_string()),
+            timed_out: false,
+            ..model_test_result(false, Some("codex command failed with exit code: Some(1)"))
+        }
+    }
+
+    #[test]
+    fn test_sandbox_retry_retries_once_for_new_session() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(run_with_sandbox_retry(
+                model_test_options(None),
+                true,
+                move |_opts: Options| {
+                    let attempt = {
+                        let mut count = counter.lock().unwrap();
+                        *count += 1;
+                        *count
+                    };
+                    async move {
+                        Ok(if attempt == 1 {
+                            sandbox_failure()
+                        } else {
+                            model_test_result(true, None)
+                        })
+                    }
+                },
+            ))
+            .unwrap();
+
+        assert!(result.success);
+        assert_eq!(*calls.lock().unwrap(), 2);
+        assert!(result
+            .warnings
+            .unwrap()
+            .contains("sandbox failed to initialize"));
+    }
+
+    #[test]
+    fn test_sandbox_retry_never_retries_resume() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let opts = Options {
+            session_id: Some("existing-session".to_string()),
+            ..model_test_options(None)
+        };
+
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(run_with_sandbox_retry(opts, true, move |_opts: Options| {
+                *counter.lock().unwrap() += 1;
+                async { Ok(sandbox_failure()) }
+            }))
+            .unwrap();
+
+        assert!(!result.success);
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sandbox_retry_disabled_does_not_retry() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(run_with_sandbox_retry(
+                model_test_options(None),
+                false,
+                move |_opts: Options| {
+                    *counter.lock().unwrap() += 1;
+                    async { Ok(sandbox_failure()) }
+                },
+            ))
+            .unwrap();
+
+        assert!(!result.success);
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    // --- Security config tests ---
+
+    #[test]
+    fn resolve_env_bool_accepts_truthy_values() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            resolve_env_bool("K", Some("1".into()), &mut warnings),
+            Some(true)
+        );
+        assert_eq!(
+            resolve_env_bool("K", Some("true".into()), &mut warnings),
+            Some(true)
+        );
+        assert_eq!(
+            resolve_env_bool("K", Some("yes".into()), &mut warnings),
+            Some(true)
+        );
+        assert_eq!(
+            resolve_env_bool("K", Some("on".into()), &mut warnings),
+            Some(true)
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn resolve_env_bool_accepts_falsy_values() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            resolve_env_bool("K", Some("0".into()), &mut warnings),
+            Some(false)
+        );
+        assert_eq!(
+            resolve_env_bool("K", Some("false".into()), &mut warnings),
+            Some(false)
+        );
+        assert_eq!(
+            resolve_env_bool("K", Some("off".into()), &mut warnings),
+            Some(false)
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn resolve_env_bool_warns_on_invalid() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            resolve_env_bool("TEST_KEY", Some("maybe".into()), &mut warnings),
+            None
+        );
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("TEST_KEY"));
+        assert!(warnings[0].contains("maybe"));
+    }
+
+    #[test]
+    fn resolve_sandbox_uses_env_default_only_when_absent() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            resolve_sandbox(None, None, &mut warnings),
+            SandboxPolicy::ReadOnly
+        );
+        assert_eq!(
+            resolve_sandbox(None, Some(" Workspace-Write ".into()), &mut warnings),
+            SandboxPolicy::WorkspaceWrite
+        );
+        assert_eq!(
+            resolve_sandbox(
+                Some(SandboxPolicy::ReadOnly),
+                Some("workspace-write".into()),
+                &mut warnings
+            ),
+            SandboxPolicy::ReadOnly
+        );
+        assert!(warnings.is_empty());
+
+        assert_eq!(
+            resolve_sandbox(None, Some("full".into()), &mut warnings),
+            SandboxPolicy::ReadOnly
+        );
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("CODEX_DEFAULT_SANDBOX"));
+    }
+
+    #[test]
+    fn relative_cd_policy_parses_env_value() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            RelativeCdPolicy::from_env_value(None, &mut warnings),
+            RelativeCdPolicy::Cwd
+        );
+        assert_eq!(
+            RelativeCdPolicy::from_env_value(Some(" Reject ".into()), &mut warnings),
+            RelativeCdPolicy::Reject
+        );
+        assert_eq!(
+            RelativeCdPolicy::from_env_value(Some("root".into()), &mut warnings),
+            RelativeCdPolicy::Root
+        );
+        assert!(warnings.is_empty());
+
+        assert_eq!(
+            RelativeCdPolicy::from_env_value(Some("home".into()), &mut warnings),
+            RelativeCdPolicy::Cwd
+        );
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("CODEX_RELATIVE_CD"));
+    }
+
+    #[test]
+    fn stderr_reader_returns_output_at_eof() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (output, timed_out) = rt.block_on(async {
+            let (mut writer, reader) = tokio::io::duplex(64);
+            let stderr = StderrReader::spawn(reader);
+            writer.write_all(b"first\r\nsecond\n").await.unwrap();
+            drop(writer);
+            stderr.finish(std::time::Duration::from_secs(5)).await
+        });
+        assert_eq!(output, "first\nsecond");
+        assert!(!timed_out);
+    }
+
+    #[test]
+    fn stderr_reader_stops_when_pipe_stays_open() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (output, timed_out, elapsed) = rt.block_on(async {
+            // The writer stays alive, like a leftover process holding the pipe
+            let (mut writer, reader) = tokio::io::duplex(64);
+            let stderr = StderrReader::spawn(reader);
+            writer.write_all(b"partial\n").await.unwrap();
+            tokio::task::yield_now().await;
+            let start = std::time::Instant::now();
+            let (output, timed_out) = stderr.finish(std::time::Duration::from_millis(200)).await;
+            drop(writer);
+            (output, timed_out, start.elapsed())
+        });
+        assert!(timed_out);
+        assert_eq!(output, "partial");
+        assert!(elapsed < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn concurrent_resume_policy_parses_env_value() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            ConcurrentResumePolicy::from_env_value(None, &mut warnings),
+            ConcurrentResumePolicy::Wait
+        );
+        assert_eq!(
+            ConcurrentResumePolicy::from_env_value(Some(" Reject ".into()), &mut warnings),
+            ConcurrentResumePolicy::Reject
+        );
+        assert!(warnings.is_empty());
+        assert_eq!(
+            ConcurrentResumePolicy::from_env_value(Some("queue".into()), &mut warnings),
+            ConcurrentResumePolicy::Wait
+        );
+        assert!(warnings[0].contains("CODEX_CONCURRENT_RESUME"));
+    }
+
+    #[test]
+    fn session_locks_serialize_concurrent_resumes() {
+        let locks = SessionLocks::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let calls: Vec<_> = (0..2)
+                .map(|call| {
+                    let locks = locks.clone();
+                    let events = events.clone();
+                    tokio::spawn(async move {
+                        let _guard = locks
+                            .acquire("sess-1", ConcurrentResumePolicy::Wait)
+                            .await
+                            .unwrap();
+                        events.lock().unwrap().push(("start", call));
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                        events.lock().unwrap().push(("end", call));
+                    })
+                })
+                .collect();
+            for call in calls {
+                call.await.unwrap();
+            }
+        });
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        // Each resume ends before the other starts
+        assert_eq!(events[0].0, "start");
+        assert_eq!(events[1], ("end", events[0].1));
+        assert_eq!(events[2].0, "start");
+        assert_eq!(events[3], ("end", events[2].1));
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[test]
+    fn session_locks_reject_second_concurrent_resume() {
+        let locks = SessionLocks::new();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let first = locks
+                .acquire("sess-1", ConcurrentResumePolicy::Reject)
+                .await
+                .unwrap();
+            let err = locks
+                .acquire("sess-1", ConcurrentResumePolicy::Reject)
+                .await
+                .err()
+                .unwrap();
+            assert!(err.contains("sess-1"));
+            assert!(err.contains("already being resumed"));
+
+            // Other sessions are unaffected
+            let other = locks
+                .acquire("sess-2", ConcurrentResumePolicy::Reject)
+                .await;
+            assert!(other.is_ok());
+            drop(other);
+
+            drop(first);
+            assert_eq!(locks.len(), 0);
+            assert!(locks
+                .acquire("sess-1", ConcurrentResumePolicy::Reject)
+                .await
+                .is_ok());
+        });
+    }
+
+    #[test]
+    fn resolve_max_message_limit_from_env_value() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            resolve_max_message_limit(None, &mut warnings),
+            MAX_MESSAGE_LIMIT
+        );
+        assert_eq!(
+            resolve_max_message_limit(Some(" 200000 ".into()), &mut warnings),
+            200000
+        );
+        assert!(warnings.is_empty());
+
+        for invalid in ["0", "-5", "lots"] {
+            assert_eq!(
+                resolve_max_message_limit(Some(invalid.into()), &mut warnings),
+                MAX_MESSAGE_LIMIT
+            );
+        }
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].contains("CODEX_MAX_MESSAGE_LIMIT"));
+    }
+
+    #[test]
+    fn resolve_message_limit_clamps_to_max() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            resolve_message_limit(None, MAX_MESSAGE_LIMIT, &mut warnings),
+            DEFAULT_MESSAGE_LIMIT
+        );
+        assert_eq!(resolve_message_limit(None, 500, &mut warnings), 500);
+        assert_eq!(
+            resolve_message_limit(Some(42), MAX_MESSAGE_LIMIT, &mut warnings),
+            42
+        );
+        assert_eq!(
+            resolve_message_limit(Some(MAX_MESSAGE_LIMIT), MAX_MESSAGE_LIMIT, &mut warnings),
+            MAX_MESSAGE_LIMIT
+        );
+        assert!(warnings.is_empty());
+
+        assert_eq!(
+            resolve_message_limit(Some(usize::MAX), MAX_MESSAGE_LIMIT, &mut warnings),
+            MAX_MESSAGE_LIMIT
+        );
+        assert!(warnings[0].contains("capping to maximum"));
+        assert_eq!(
+            resolve_message_limit(Some(0), MAX_MESSAGE_LIMIT, &mut warnings),
+            DEFAULT_MESSAGE_LIMIT
+        );
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn resolve_relative_cd_per_policy() {
+        let relative = Path::new("sub/dir");
+        let roots = vec![PathBuf::from("/work/a"), PathBuf::from("/work/b")];
+        let mut warnings = Vec::new();
+
+        // cwd: left for canonicalize to resolve against the server's cwd
+        for r in [&roots[..], &[]] {
+            assert_eq!(
+                resolve_relative_cd(relative, r, RelativeCdPolicy::Cwd, &mut warnings),
+                Ok(PathBuf::from("sub/dir"))
+            );
+        }
+
+        // root and reject both use the first root when there is one
+        for policy in [RelativeCdPolicy::Root, RelativeCdPolicy::Reject] {
+            assert_eq!(
+                resolve_relative_cd(relative, &roots, policy, &mut warnings),
+                Ok(PathBuf::from("/work/a/sub/dir"))
+            );
+        }
+        assert!(warnings.is_empty());
+
+        // Without roots, root falls back to the cwd with a warning and reject fails
+        assert_eq!(
+            resolve_relative_cd(relative, &[], RelativeCdPolicy::Root, &mut warnings),
+            Ok(PathBuf::from("sub/dir"))
+        );
+        assert_eq!(warnings.len(), 1);
+        let err = resolve_relative_cd(relative, &[], RelativeCdPolicy::Reject, &mut warnings)
+            .unwrap_err();
+        assert!(err.contains("absolute path"));
+        assert!(err.contains("sub/dir"));
+
+        // Absolute paths are never rewritten
+        for policy in [RelativeCdPolicy::Root, RelativeCdPolicy::Reject] {
+            assert_eq!(
+                resolve_relative_cd(Path::new("/abs"), &[], policy, &mut warnings),
+                Ok(PathBuf::from("/abs"))
+            );
+        }
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn resolve_env_bool_returns_none_for_empty() {
+        let mut warnings = Vec::new();
+        assert_eq!(resolve_env_bool("K", Some("".into()), &mut warnings), None);
+        assert_eq!(resolve_env_bool("K", None, &mut warnings), None);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn merge_warnings_combines_security_and_result() {
+        let combined = merge_warnings(vec!["security".into()], Some("result".into())).unwrap();
+        assert!(combined.contains("security"));
+        assert!(combined.contains("result"));
+    }
+
+    #[test]
+    fn merge_warning_list_keeps_individual_messages() {
+        let list = merge_warning_list(
+            vec!["security".into()],
+            Some("model fallback\nsandbox retry".into()),
+        )
+        .unwrap();
+        assert_eq!(list, vec!["security", "model fallback", "sandbox retry"]);
+        assert!(merge_warning_list(vec![], None).is_none());
+    }
+
+    #[test]
+    fn build_codex_output_warnings_list_matches_joined() {
+        let result = model_test_result(true, None);
+        let warnings = merge_warning_list(vec!["a".into(), "b".into()], Some("c".into()));
+        let output = build_codex_output(&result, false, warnings);
+        let list = output.warnings_list.unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(output.warnings.unwrap(), list.join("\n"));
+
+        let output = build_codex_output(&result, false, None);
+        let json = serde_json::to_value(&output).unwrap();
+        assert!(json.get("warnings").is_none());
+        assert!(json.get("warnings_list").is_none());
+    }
+
+    #[test]
+    fn test_apply_security_restrictions_returns_warnings() {
+        let mut sandbox = SandboxPolicy::DangerFullAccess;
+        let mut yolo = true;
+        let mut skip_git = true;
+        let mut approval_policy = Some("never".to_string());
+        let security = SecurityConfig {
+            allow_danger_full_access: false,
+            allow_yolo: false,
+            allow_skip_git_check: false,
+            allow_approval_never: false,
+        };
+        let warnings = apply_security_restrictions(
+            &mut sandbox,
+            &mut yolo,
+            &mut skip_git,
+            &mut approval_policy,
+            &security,
+        );
+        assert_eq!(warnings.len(), 4);
+        assert_eq!(sandbox, SandboxPolicy::ReadOnly);
+        assert!(!yolo);
+        assert!(!skip_git);
+        assert_eq!(approval_policy.as_deref(), Some(SAFE_APPROVAL_POLICY));
+        assert!(warnings[3].contains("CODEX_ALLOW_APPROVAL_NEVER"));
+    }
+
+    #[test]
+    fn test_apply_security_restrictions_approval_policy() {
+        let mut sandbox = SandboxPolicy::ReadOnly;
+        let mut yolo = false;
+        let mut skip_git = false;
+        let mut security = SecurityConfig {
+            allow_danger_full_access: false,
+            allow_yolo: false,
+            allow_skip_git_check: false,
+            allow_approval_never: false,
+        };
+
+        // Other policies pass through untouched
+        let mut approval_policy = Some("untrusted".to_string());
+        let warnings = apply_security_restrictions(
+            &mut sandbox,
+            &mut yolo,
+            &mut skip_git,
+            &mut approval_policy,
+            &security,
+        );
+        assert!(warnings.is_empty());
+        assert_eq!(approval_policy.as_deref(), Some("untrusted"));
+
+        security.allow_approval_never = true;
+        let mut approval_policy = Some("never".to_string());
+        let warnings = apply_security_restrictions(
+            &mut sandbox,
+            &mut yolo,
+            &mut skip_git,
+            &mut approval_policy,
+            &security,
+        );
+        assert!(warnings.is_empty());
+        assert_eq!(approval_policy.as_deref(), Some("never"));
     }
 
     #[test]
@@ -1,13 +1,21 @@
 use anyhow::{Context, Result};
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::path::PathBuf;
 use std::process::Stdio;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
 use tokio::time::timeout;
 
-use crate::shared::{DEFAULT_TIMEOUT_SECS, MAX_TIMEOUT_SECS, MIN_TIMEOUT_SECS};
+use crate::shared::{
+    apply_path_prepend, env_flag, finish_stdin, parse_json_bytes, push_line_with_limit,
+    stdin_write_timeout, truncation_marker, wrap_prompt, write_stdin, MessageNormalization,
+    StdinWrite, DEFAULT_TIMEOUT_SECS, MAX_TIMEOUT_SECS, MIN_TIMEOUT_SECS,
+};
+use crate::tools::gemini_image_api::ImageApiStyle;
 
 const PROMPT_DEPRECATION_WARNING: &str = "The --prompt (-p) flag has been deprecated";
 const KEY_SESSION_ID: &str = "session_id";
@@ -16,6 +24,8 @@ const KEY_ROLE: &str = "role";
 const KEY_CONTENT: &str = "content";
 const KEY_ERROR: &str = "error";
 const KEY_MESSAGE: &str = "message";
+const KEY_CODE: &str = "code";
+const KEY_STATUS: &str = "status";
 const TYPE_MESSAGE: &str = "message";
 const ROLE_ASSISTANT: &str = "assistant";
 const ENV_DEFAULT_TIMEOUT: &str = "GEMINI_DEFAULT_TIMEOUT";
@@ -25,8 +35,25 @@ const ENV_INCLUDE_DIRS: &str = "GEMINI_INCLUDE_DIRS";
 const ENV_API_KEY: &str = "GEMINI_API_KEY";
 const ENV_IMAGE_API_KEY: &str = "GEMINI_IMAGE_API_KEY";
 const ENV_API_URL: &str = "GEMINI_API_URL";
+const ENV_PROMPT_MODE: &str = "GEMINI_PROMPT_MODE";
+const ENV_RESUME_FALLBACK: &str = "GEMINI_RESUME_FALLBACK";
+const ENV_IMAGE_MAX_COUNT: &str = "GEMINI_IMAGE_MAX_COUNT";
+const ENV_IMAGE_MAX_TOTAL_BYTES: &str = "GEMINI_IMAGE_MAX_TOTAL_BYTES";
+const ENV_PROMPT_PREFIX: &str = "GEMINI_PROMPT_PREFIX";
+const ENV_PROMPT_SUFFIX: &str = "GEMINI_PROMPT_SUFFIX";
+const ENV_INCLUDE_STDERR_WARNINGS: &str = "GEMINI_INCLUDE_STDERR_WARNINGS";
+const ENV_IMAGE_DIR: &str = "AIMCP_IMAGE_DIR";
+const ENV_PATH_PREPEND: &str = "GEMINI_PATH_PREPEND";
+const ENV_KEEP_DEPRECATION_EVENTS: &str = "GEMINI_KEEP_DEPRECATION_EVENTS";
+const ENV_IMAGE_DIR_PRIORITY: &str = "GEMINI_IMAGE_DIR_PRIORITY";
+const ENV_MAX_NON_JSON_LINES: &str = "GEMINI_MAX_NON_JSON_LINES";
+const ENV_IMAGE_API_STYLE: &str = "GEMINI_IMAGE_API_STYLE";
+const ENV_PARSE_STDERR_JSON: &str = "GEMINI_PARSE_STDERR_JSON";
+const ENV_AUTO_APPROVE: &str = "GEMINI_AUTO_APPROVE";
+const ENV_APPROVE_FLAG: &str = "GEMINI_APPROVE_FLAG";
+const DEFAULT_APPROVE_FLAG: &str = "-y";
 const MAX_MESSAGES_LIMIT: usize = 10000; // Maximum number of messages to store
-const MAX_NON_JSON_LINES: usize = 1000; // Maximum non-JSON lines to store
+const DEFAULT_MAX_NON_JSON_LINES: usize = 1000; // Maximum non-JSON lines to store
 const MAX_STDERR_BYTES: usize = 100_000; // Maximum stderr output to capture (100KB)
 
 fn parse_timeout_secs_from_env_value(value: &str) -> Option<u64> {
@@ -47,6 +74,40 @@ fn get_default_timeout() -> u64 {
         .unwrap_or(DEFAULT_TIMEOUT_SECS)
 }
 
+/// Timeout a run uses: the requested one, otherwise `GEMINI_DEFAULT_TIMEOUT` or the default
+pub fn effective_timeout_secs(requested: Option<u64>) -> u64 {
+    requested.unwrap_or_else(get_default_timeout)
+}
+
+/// How the prompt is handed to the gemini CLI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PromptMode {
+    /// Write the prompt to the child's stdin (default)
+    #[default]
+    Stdin,
+    /// Pass the prompt as a trailing positional argument, stdin is null
+    Arg,
+    /// Write the prompt to a private temp file and pass `@<path>` as the positional
+    /// argument, for prompts too large for the command line when stdin is unavailable
+    File,
+}
+
+/// Get the prompt mode from environment variable, defaulting to stdin
+pub fn get_prompt_mode() -> PromptMode {
+    match std::env::var(ENV_PROMPT_MODE) {
+        Ok(v) if v.trim().eq_ignore_ascii_case("arg") => PromptMode::Arg,
+        Ok(v) if v.trim().eq_ignore_ascii_case("file") => PromptMode::File,
+        _ => PromptMode::Stdin,
+    }
+}
+
+/// Wrap the prompt with `GEMINI_PROMPT_PREFIX` / `GEMINI_PROMPT_SUFFIX`, if set
+pub fn apply_prompt_affixes(prompt: String) -> String {
+    let prefix = std::env::var(ENV_PROMPT_PREFIX).ok();
+    let suffix = std::env::var(ENV_PROMPT_SUFFIX).ok();
+    wrap_prompt(prompt, prefix.as_deref(), suffix.as_deref())
+}
+
 /// Get the force model from environment variable, if set
 pub fn get_force_model() -> Option<String> {
     std::env::var(ENV_FORCE_MODEL)
@@ -87,6 +148,190 @@ pub fn get_api_url() -> Option<String> {
         .filter(|v| !v.is_empty())
 }
 
+/// Get the maximum number of images saved per `gemini_image` call,
+/// falling back to the default when unset, zero or invalid
+pub fn get_image_max_count() -> usize {
+    std::env::var(ENV_IMAGE_MAX_COUNT)
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(crate::tools::gemini_image_api::DEFAULT_MAX_IMAGE_COUNT)
+}
+
+/// Get the maximum total decoded bytes saved per `gemini_image` call, if set
+pub fn get_image_max_total_bytes() -> Option<usize> {
+    std::env::var(ENV_IMAGE_MAX_TOTAL_BYTES)
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|&n| n > 0)
+}
+
+/// A place `gemini_image` may save generated images to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageDirSource {
+    /// The `output_dir` argument
+    Arg,
+    /// `AIMCP_IMAGE_DIR`
+    Env,
+    /// The first MCP workspace root
+    Root,
+    /// The server's current working directory
+    Cwd,
+}
+
+/// Save directory order used when `GEMINI_IMAGE_DIR_PRIORITY` is unset or invalid
+pub const DEFAULT_IMAGE_DIR_PRIORITY: &[ImageDirSource] = &[
+    ImageDirSource::Arg,
+    ImageDirSource::Env,
+    ImageDirSource::Root,
+    ImageDirSource::Cwd,
+];
+
+/// The candidate save directories for one `gemini_image` call
+#[derive(Debug, Default, Clone)]
+pub struct ImageDirCandidates {
+    pub arg: Option<PathBuf>,
+    pub env: Option<PathBuf>,
+    pub root: Option<PathBuf>,
+    pub cwd: Option<PathBuf>,
+}
+
+impl ImageDirCandidates {
+    fn get(&self, source: ImageDirSource) -> Option<&PathBuf> {
+        match source {
+            ImageDirSource::Arg => self.arg.as_ref(),
+            ImageDirSource::Env => self.env.as_ref(),
+            ImageDirSource::Root => self.root.as_ref(),
+            ImageDirSource::Cwd => self.cwd.as_ref(),
+        }
+    }
+}
+
+/// Parse a comma-separated save directory order such as `arg,env,root,cwd`.
+/// Names are case-insensitive and repeats are ignored.
+pub fn parse_image_dir_priority(value: &str) -> Result<Vec<ImageDirSource>, String> {
+    let mut order = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let source = match name.to_ascii_lowercase().as_str() {
+            "arg" => ImageDirSource::Arg,
+            "env" => ImageDirSource::Env,
+            "root" => ImageDirSource::Root,
+            "cwd" => ImageDirSource::Cwd,
+            _ => {
+                return Err(format!(
+                    "unknown image directory source '{}'; expected arg, env, root or cwd",
+                    name
+                ))
+            }
+        };
+        if !order.contains(&source) {
+            order.push(source);
+        }
+    }
+    if order.is_empty() {
+        return Err("image directory priority is empty".to_string());
+    }
+    Ok(order)
+}
+
+/// Get the save directory order from `GEMINI_IMAGE_DIR_PRIORITY`,
+/// falling back to the default when unset or invalid
+pub fn get_image_dir_priority() -> Vec<ImageDirSource> {
+    match std::env::var(ENV_IMAGE_DIR_PRIORITY) {
+        Ok(v) if !v.trim().is_empty() => parse_image_dir_priority(&v).unwrap_or_else(|e| {
+            eprintln!("[gemini_image] Ignoring {}: {}", ENV_IMAGE_DIR_PRIORITY, e);
+            DEFAULT_IMAGE_DIR_PRIORITY.to_vec()
+        }),
+        _ => DEFAULT_IMAGE_DIR_PRIORITY.to_vec(),
+    }
+}
+
+/// Get the image API request shape from `GEMINI_IMAGE_API_STYLE`, falling back
+/// to the Gemini shape when unset or invalid
+pub fn get_image_api_style() -> ImageApiStyle {
+    match std::env::var(ENV_IMAGE_API_STYLE) {
+        Ok(v) if !v.trim().is_empty() => ImageApiStyle::parse(&v).unwrap_or_else(|| {
+            eprintln!(
+                "[gemini_image] Ignoring {}={}: expected gemini or openai",
+                ENV_IMAGE_API_STYLE, v
+            );
+            ImageApiStyle::default()
+        }),
+        _ => ImageApiStyle::default(),
+    }
+}
+
+/// Get the default image save directory from `AIMCP_IMAGE_DIR`, if set
+pub fn get_image_dir() -> Option<PathBuf> {
+    std::env::var(ENV_IMAGE_DIR)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Pick the first candidate present in `order`. Sources left out of `order` are
+/// never used; when none of the listed ones is available, `.` is returned.
+pub fn resolve_image_dir(candidates: &ImageDirCandidates, order: &[ImageDirSource]) -> PathBuf {
+    order
+        .iter()
+        .find_map(|&source| candidates.get(source))
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// How the `all_messages` event log is rendered in the tool response
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, schemars::JsonSchema, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum EventLogFormat {
+    /// Indented JSON array (human-readable)
+    #[default]
+    Pretty,
+    /// One compact JSON event per line (streaming-friendly)
+    Ndjson,
+    /// Single-line JSON array
+    Compact,
+}
+
+/// Render captured events in the requested format.
+pub fn format_event_log(events: &[Value], format: EventLogFormat) -> Option<String> {
+    match format {
+        EventLogFormat::Pretty => serde_json::to_string_pretty(events).ok(),
+        EventLogFormat::Compact => serde_json::to_string(events).ok(),
+        EventLogFormat::Ndjson => events
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .map(|lines| lines.join("\n")),
+    }
+}
+
+/// Characters of `agent_messages` kept in the final result when content was streamed to a sink
+pub const STREAMED_TAIL_CHARS: usize = 2000;
+
+/// Receives each assistant content chunk as gemini produces it
+#[derive(Clone)]
+pub struct ContentSink(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl ContentSink {
+    pub fn new(f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    fn send(&self, chunk: &str) {
+        (self.0)(chunk)
+    }
+}
+
+impl std::fmt::Debug for ContentSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ContentSink")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Options {
     pub prompt: String,
@@ -101,6 +346,9 @@ pub struct Options {
     pub api_key: Option<String>,
     /// Optional API base URL to set as GOOGLE_GEMINI_BASE_URL on the child process.
     pub api_base_url: Option<String>,
+    /// Receives assistant content as it arrives. When set, the final `agent_messages`
+    /// only keeps its last `STREAMED_TAIL_CHARS` characters.
+    pub content_sink: Option<ContentSink>,
 }
 
 #[derive(Debug)]
@@ -108,16 +356,37 @@ pub struct GeminiResult {
     pub success: bool,
     pub session_id: String,
     pub agent_messages: String,
+    /// Whether `agent_messages` was cut to its tail because the content was streamed
+    pub agent_messages_truncated: bool,
     pub all_messages: Vec<Value>,
     pub return_all_messages: bool,
     pub error: Option<String>,
+    /// `error.code` from the error event, e.g. `429`
+    pub error_code: Option<String>,
+    /// `error.type` or `error.status` from the error event, e.g. `RESOURCE_EXHAUSTED`
+    pub error_type: Option<String>,
+    /// Non-fatal notice for the caller, e.g. that a resume fell back to a new session
+    pub warning: Option<String>,
+    /// Stderr captured from a successful run, when `GEMINI_INCLUDE_STDERR_WARNINGS` is enabled
+    pub warnings: Option<String>,
 }
 
 /// Process a single JSON line from the gemini CLI output
-fn process_json_line(line_data: &Value, result: &mut GeminiResult, return_all_messages: bool) {
+/// `keep_deprecation_events` keeps the CLI's prompt deprecation notice in `all_messages`;
+/// it never reaches `agent_messages`.
+fn process_json_line(
+    line_data: &Value,
+    result: &mut GeminiResult,
+    return_all_messages: bool,
+    keep_deprecation_events: bool,
+    sink: Option<&ContentSink>,
+) {
     // Collect all messages if requested - store the raw Value to handle objects, arrays, and primitives
     // Limit the number of messages to prevent memory exhaustion
-    if return_all_messages && result.all_messages.len() < MAX_MESSAGES_LIMIT {
+    if return_all_messages
+        && result.all_messages.len() < MAX_MESSAGES_LIMIT
+        && (keep_deprecation_events || !is_deprecation_event(line_data))
+    {
         result.all_messages.push(line_data.clone());
     }
 
@@ -148,6 +417,9 @@ fn process_json_line(line_data: &Value, result: &mut GeminiResult, return_all_me
                 result.agent_messages.push('\n');
             }
             result.agent_messages.push_str(content);
+            if let Some(sink) = sink {
+                sink.send(content);
+            }
         }
     }
 
@@ -162,14 +434,295 @@ fn process_json_line(line_data: &Value, result: &mut GeminiResult, return_all_me
             if let Some(msg) = error_obj.get(KEY_MESSAGE).and_then(|v| v.as_str()) {
                 result.error = Some(format!("gemini error: {}", msg));
             }
+            if let Some(code) = error_obj.get(KEY_CODE).and_then(scalar_to_string) {
+                result.error_code = Some(code);
+            }
+            if let Some(kind) = error_obj
+                .get(KEY_TYPE)
+                .or_else(|| error_obj.get(KEY_STATUS))
+                .and_then(scalar_to_string)
+            {
+                result.error_type = Some(kind);
+            }
         } else if let Some(msg) = line_data.get(KEY_MESSAGE).and_then(|v| v.as_str()) {
             result.error = Some(format!("gemini error: {}", msg));
         }
     }
 }
 
+/// Render a string or number error field; other shapes are ignored
+fn scalar_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Stderr/error fragments the gemini CLI prints when `--resume` cannot find the session
+const RESUME_FAILURE_SIGNATURES: &[&str] = &[
+    "session not found",
+    "no previous sessions",
+    "invalid session identifier",
+    "could not find session",
+    "error resuming session",
+    "failed to resume",
+];
+
+/// Maximum accepted SESSION_ID length
+const MAX_SESSION_ID_LEN: usize = 128;
+
+/// Check that a SESSION_ID looks like something `gemini --resume` accepts
+/// (a session UUID, an index, or "latest") before spawning the CLI.
+pub fn validate_session_id(session_id: &str) -> std::result::Result<(), String> {
+    if session_id.trim().is_empty() {
+        return Err("SESSION_ID must be a non-empty string".to_string());
+    }
+    if session_id.len() > MAX_SESSION_ID_LEN {
+        return Err(format!(
+            "SESSION_ID is too long ({} characters, maximum {})",
+            session_id.len(),
+            MAX_SESSION_ID_LEN
+        ));
+    }
+    // A leading '-' would be parsed by the CLI as another flag
+    let starts_alphanumeric = session_id
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric());
+    if !starts_alphanumeric
+        || !session_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "SESSION_ID '{}' is not a valid gemini session identifier \
+             (expected letters, digits, '-' or '_', starting with a letter or digit)",
+            session_id
+        ));
+    }
+    Ok(())
+}
+
+/// Whether an error from a resumed run means the session itself could not be resumed
+pub fn is_resume_failure(error: &str) -> bool {
+    let lower = error.to_lowercase();
+    RESUME_FAILURE_SIGNATURES
+        .iter()
+        .any(|signature| lower.contains(signature))
+}
+
+/// Whether `line_data` is the CLI's own prompt deprecation notice, sent as an assistant message
+fn is_deprecation_event(line_data: &Value) -> bool {
+    line_data.get(KEY_TYPE).and_then(|v| v.as_str()) == Some(TYPE_MESSAGE)
+        && line_data.get(KEY_ROLE).and_then(|v| v.as_str()) == Some(ROLE_ASSISTANT)
+        && line_data.get(KEY_CONTENT).and_then(|v| v.as_str()) == Some(PROMPT_DEPRECATION_WARNING)
+}
+
+/// Whether the prompt deprecation event stays in `all_messages` instead of being filtered out
+fn get_keep_deprecation_events() -> bool {
+    env_flag(ENV_KEEP_DEPRECATION_EVENTS)
+}
+
+/// Whether stderr from a successful run is returned as `warnings` instead of discarded
+fn get_include_stderr_warnings() -> bool {
+    std::env::var(ENV_INCLUDE_STDERR_WARNINGS)
+        .map(|v| {
+            matches!(
+                v.trim().to_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Whether JSON object lines on stderr are processed as events, for CLI
+/// configurations that write some events there instead of stdout
+fn get_parse_stderr_json() -> bool {
+    env_flag(ENV_PARSE_STDERR_JSON)
+}
+
+/// Auto-approve flag passed to gemini: `None` when `auto_approve` is a false value,
+/// otherwise `approve_flag` or `-y` when that is unset or blank
+fn resolve_approve_flag(auto_approve: Option<&str>, approve_flag: Option<&str>) -> Option<String> {
+    let disabled = auto_approve.is_some_and(|v| {
+        matches!(
+            v.trim().to_lowercase().as_str(),
+            "0" | "false" | "no" | "off"
+        )
+    });
+    if disabled {
+        return None;
+    }
+    let flag = approve_flag
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .unwrap_or(DEFAULT_APPROVE_FLAG);
+    Some(flag.to_string())
+}
+
+/// Auto-approve flag from `GEMINI_AUTO_APPROVE` / `GEMINI_APPROVE_FLAG`. On by default,
+/// since nobody can answer gemini's approval prompts in a non-interactive MCP run.
+fn get_approve_flag() -> Option<String> {
+    resolve_approve_flag(
+        std::env::var(ENV_AUTO_APPROVE).ok().as_deref(),
+        std::env::var(ENV_APPROVE_FLAG).ok().as_deref(),
+    )
+}
+
+/// Get the maximum number of non-JSON stdout lines kept for diagnostics,
+/// falling back to the default when unset or invalid. Zero keeps none.
+fn get_max_non_json_lines() -> usize {
+    std::env::var(ENV_MAX_NON_JSON_LINES)
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_NON_JSON_LINES)
+}
+
+/// Non-JSON stdout lines kept for error reports, up to a cap; later lines are only counted
+#[derive(Debug, Default)]
+struct NonJsonLines {
+    lines: Vec<String>,
+    max_lines: usize,
+    dropped: usize,
+}
+
+impl NonJsonLines {
+    fn new(max_lines: usize) -> Self {
+        Self {
+            lines: Vec::new(),
+            max_lines,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, line: &[u8]) {
+        if self.lines.len() < self.max_lines {
+            self.lines
+                .push(String::from_utf8_lossy(line).trim().to_string());
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.dropped == 0
+    }
+
+    /// The kept lines under a header with the line count, one indented line each
+    fn report(&self, header: &str) -> String {
+        let total = self.lines.len() + self.dropped;
+        let noun = if total == 1 { "line" } else { "lines" };
+        let mut report = if self.dropped > 0 {
+            format!(
+                "{} ({} {}, {} beyond GEMINI_MAX_NON_JSON_LINES={} dropped):",
+                header, total, noun, self.dropped, self.max_lines
+            )
+        } else {
+            format!("{} ({} {}):", header, total, noun)
+        };
+        for line in &self.lines {
+            report.push_str("\n  ");
+            report.push_str(line);
+        }
+        report
+    }
+}
+
+/// Whether a failed resume should transparently start a new session instead
+fn get_resume_fallback() -> bool {
+    std::env::var(ENV_RESUME_FALLBACK)
+        .map(|v| {
+            matches!(
+                v.trim().to_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
 /// Build the gemini command with the given options
 fn build_command(opts: &Options) -> Command {
+    build_command_with_prompt_mode(opts, get_prompt_mode())
+}
+
+/// Prompt written to a file in its own private temp directory for `PromptMode::File`.
+/// The directory is removed on drop, so cleanup also happens on errors and timeouts.
+struct PromptFile {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl PromptFile {
+    /// Write `prompt` to a new, owner-only directory under `base`
+    fn create(base: &std::path::Path, prompt: &str) -> Result<Self> {
+        use std::io::Write;
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let dir = base.join(format!(
+            "aimcp-gemini-prompt-{}-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+            nanos
+        ));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&dir)
+            .with_context(|| format!("Failed to create prompt directory {}", dir.display()))?;
+
+        // From here on, drop removes the directory if writing fails
+        let prompt_file = Self {
+            path: dir.join("prompt.md"),
+            dir,
+        };
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&prompt_file.path).with_context(|| {
+            format!(
+                "Failed to create prompt file {}",
+                prompt_file.path.display()
+            )
+        })?;
+        file.write_all(prompt.as_bytes())
+            .context("Failed to write prompt file")?;
+        Ok(prompt_file)
+    }
+
+    /// `opts` with the prompt replaced by an `@<path>` reference to this file and
+    /// its directory added to the workspace so gemini may read it
+    fn options_for(&self, opts: &Options) -> Options {
+        let mut include_directories = opts.include_directories.clone();
+        include_directories.push(self.dir.clone());
+        Options {
+            prompt: format!("@{}", self.path.display()),
+            include_directories,
+            ..opts.clone()
+        }
+    }
+}
+
+impl Drop for PromptFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            eprintln!(
+                "[gemini] Failed to remove prompt file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Build the gemini command, passing the prompt according to `prompt_mode`
+fn build_command_with_prompt_mode(opts: &Options, prompt_mode: PromptMode) -> Command {
     let gemini_bin = std::env::var("GEMINI_BIN").unwrap_or_else(|_| {
         if cfg!(windows) {
             "gemini.cmd".to_string()
@@ -201,7 +754,10 @@ fn build_command(opts: &Options) -> Command {
     #[cfg(not(windows))]
     let mut cmd = Command::new(&gemini_bin);
 
-    cmd.arg("-y");
+    apply_path_prepend(&mut cmd, ENV_PATH_PREPEND);
+    if let Some(flag) = get_approve_flag() {
+        cmd.arg(flag);
+    }
     cmd.arg("-o");
     cmd.arg("stream-json");
 
@@ -253,38 +809,101 @@ fn build_command(opts: &Options) -> Command {
         cmd.env("GOOGLE_GEMINI_BASE_URL", api_base_url);
     }
 
-    // Configure process: stdin is piped so we can write the prompt
-    cmd.stdin(Stdio::piped());
+    // Configure process: stdin is piped so we can write the prompt, unless it
+    // goes on the command line for wrappers that don't forward stdin
+    match prompt_mode {
+        PromptMode::Stdin => {
+            cmd.stdin(Stdio::piped());
+        }
+        PromptMode::Arg | PromptMode::File => {
+            cmd.arg(&opts.prompt);
+            cmd.stdin(Stdio::null());
+        }
+    }
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
 
     cmd
 }
 
-/// Execute Gemini CLI with the given options and return the result
+/// Execute Gemini CLI with the given options and return the result.
+///
+/// When resuming a session that the CLI cannot find, the error explains how to
+/// start over, or with `GEMINI_RESUME_FALLBACK=1` a new session is started instead.
 pub async fn run(opts: Options) -> Result<GeminiResult> {
-    // Validate options
-    if opts.prompt.trim().is_empty() {
-        return Err(anyhow::anyhow!(
-            "Prompt must be a non-empty, non-whitespace string"
+    validate_options(&opts).map_err(|e| anyhow::anyhow!(e))?;
+    let Some(session_id) = opts.session_id.clone() else {
+        return run_once(opts).await;
+    };
+
+    let fresh_opts = Options {
+        session_id: None,
+        ..opts.clone()
+    };
+    let mut result = run_once(opts).await?;
+    let resume_failed = !result.success && result.error.as_deref().is_some_and(is_resume_failure);
+    if !resume_failed {
+        return Ok(result);
+    }
+
+    if get_resume_fallback() {
+        eprintln!(
+            "[gemini] Could not resume session '{}', starting a new session",
+            session_id
+        );
+        let mut fresh = run_once(fresh_opts).await?;
+        fresh.warning = Some(format!(
+            "Could not resume gemini session '{}'; started a new session instead.",
+            session_id
         ));
+        return Ok(fresh);
+    }
+
+    let error = result.error.take().unwrap_or_default();
+    result.error = Some(format!(
+        "{}\nCould not resume gemini session '{}'. The SESSION_ID may be invalid or expired; \
+         omit SESSION_ID to start a new session (or set {}=1 to do this automatically).",
+        error, session_id, ENV_RESUME_FALLBACK
+    ));
+    Ok(result)
+}
+
+/// Check the options `run` would reject, so callers can report them as invalid
+/// parameters before running. The error is a user-facing message.
+pub fn validate_options(opts: &Options) -> std::result::Result<(), String> {
+    if opts.prompt.trim().is_empty() {
+        return Err("Prompt must be a non-empty, non-whitespace string".to_string());
     }
 
     if let Some(timeout) = opts.timeout_secs {
         if !(MIN_TIMEOUT_SECS..=MAX_TIMEOUT_SECS).contains(&timeout) {
-            return Err(anyhow::anyhow!(
+            return Err(format!(
                 "timeout_secs must be between {} and {} seconds",
-                MIN_TIMEOUT_SECS,
-                MAX_TIMEOUT_SECS
+                MIN_TIMEOUT_SECS, MAX_TIMEOUT_SECS
             ));
         }
     }
 
-    let timeout_duration =
-        Duration::from_secs(opts.timeout_secs.unwrap_or_else(get_default_timeout));
+    if let Some(ref session_id) = opts.session_id {
+        validate_session_id(session_id)?;
+    }
+    Ok(())
+}
 
-    // Build and spawn the command with kill_on_drop enabled
-    let mut cmd = build_command(&opts);
+/// Run the gemini CLI once with options already checked by `validate_options`
+async fn run_once(opts: Options) -> Result<GeminiResult> {
+    let timeout_duration = Duration::from_secs(effective_timeout_secs(opts.timeout_secs));
+
+    // Build and spawn the command with kill_on_drop enabled. The prompt file lives
+    // until this function returns, whatever the outcome.
+    let prompt_file = match get_prompt_mode() {
+        PromptMode::File => Some(PromptFile::create(&std::env::temp_dir(), &opts.prompt)?),
+        PromptMode::Stdin | PromptMode::Arg => None,
+    };
+    let mut cmd = match prompt_file {
+        Some(ref file) => build_command(&file.options_for(&opts)),
+        None => build_command(&opts),
+    };
     cmd.kill_on_drop(true);
     let mut child = cmd.spawn().context("Failed to spawn gemini command")?;
 
@@ -303,16 +922,44 @@ pub async fn run(opts: Options) -> Result<GeminiResult> {
         j
     });
     match timeout(timeout_duration, async {
-        // Write prompt via stdin (replaces deprecated --prompt flag)
-        if let Some(mut stdin_pipe) = child.stdin.take() {
-            stdin_pipe
-                .write_all(opts.prompt.as_bytes())
-                .await
-                .context("Failed to write prompt to stdin")?;
-            drop(stdin_pipe); // Close stdin to signal EOF
+        // Write prompt via stdin (replaces deprecated --prompt flag).
+        // In arg mode stdin is null and the prompt is already on the command line.
+        let mut pending_stdin = None;
+        if let Some(stdin_pipe) = child.stdin.take() {
+            let write_timeout = stdin_write_timeout();
+            match write_stdin(stdin_pipe, opts.prompt.clone().into_bytes(), write_timeout).await {
+                StdinWrite::Done => {}
+                StdinWrite::Failed(e) => {
+                    return Err(e).context("Failed to write prompt to stdin");
+                }
+                StdinWrite::Pending(handle) => {
+                    eprintln!(
+                        "Warning: gemini has not read the prompt after {}s; \
+                         reading its output while the write continues",
+                        write_timeout.as_secs()
+                    );
+                    pending_stdin = Some(handle);
+                }
+            }
         }
 
-        run_with_child(&mut child, opts.return_all_messages).await
+        let mut result = run_with_child(
+            &mut child,
+            opts.return_all_messages,
+            opts.content_sink.as_ref(),
+            get_include_stderr_warnings(),
+            get_parse_stderr_json(),
+        )
+        .await?;
+        if let Some(handle) = pending_stdin {
+            if let Some(notice) = finish_stdin(handle, "gemini").await {
+                result.warning = Some(match result.warning.take() {
+                    Some(existing) => format!("{}\n{}", existing, notice),
+                    None => notice,
+                });
+            }
+        }
+        Ok(result)
     })
     .await
     {
@@ -337,6 +984,9 @@ pub async fn run(opts: Options) -> Result<GeminiResult> {
 async fn run_with_child(
     child: &mut tokio::process::Child,
     return_all_messages: bool,
+    content_sink: Option<&ContentSink>,
+    include_stderr_warnings: bool,
+    parse_stderr_json: bool,
 ) -> Result<GeminiResult> {
     // Read stdout and stderr
     let stdout = child.stdout.take().context("Failed to get stdout")?;
@@ -346,72 +996,101 @@ async fn run_with_child(
         success: true,
         session_id: String::new(),
         agent_messages: String::new(),
+        agent_messages_truncated: false,
         all_messages: Vec::new(),
         return_all_messages,
         error: None,
+        error_code: None,
+        error_type: None,
+        warning: None,
+        warnings: None,
     };
 
     // Read stdout and stderr concurrently
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+    // Read raw bytes so invalid UTF-8 neither aborts the read nor corrupts JSON
+    let mut stdout_reader = BufReader::new(stdout);
+    let mut stderr_reader = BufReader::new(stderr);
+    let mut stdout_buf = Vec::new();
+    let mut stderr_buf = Vec::new();
     let mut stderr_output = String::new();
     let mut stderr_truncated = false;
-    let mut non_json_lines = Vec::with_capacity(100); // Start with reasonable capacity
+    let mut non_json_lines = NonJsonLines::new(get_max_non_json_lines());
     let mut valid_json_seen = false;
+    let mut replaced_invalid_utf8 = false;
+    let keep_deprecation_events = get_keep_deprecation_events();
     let mut stdout_closed = false;
     let mut stderr_closed = false;
     while !stdout_closed || !stderr_closed {
         tokio::select! {
-            line = stdout_reader.next_line(), if !stdout_closed => {
-                let line = line.context("Failed to read from stdout")?;
-
-                match line {
-                    Some(line) => {
-                        let trimmed = line.trim();
-                        if trimmed.is_empty() {
-                            continue;
-                        }
+            read = stdout_reader.read_until(b'\n', &mut stdout_buf), if !stdout_closed => {
+                let read = read.context("Failed to read from stdout")?;
+                if read == 0 {
+                    stdout_closed = true;
+                    continue;
+                }
 
-                        // Parse JSON line
-                        let line_data: Value = match serde_json::from_str(trimmed) {
-                            Ok(data) => {
-                                valid_json_seen = true;
-                                data
-                            }
-                            Err(_) => {
-                                // Collect non-JSON lines for potential logging (with limit)
-                                if non_json_lines.len() < MAX_NON_JSON_LINES {
-                                    non_json_lines.push(trimmed.to_string());
-                                }
-                                continue;
-                            }
-                        };
+                let line = std::mem::take(&mut stdout_buf);
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
 
-                        // Process the parsed JSON line
-                        process_json_line(&line_data, &mut result, return_all_messages);
+                // Parse JSON line
+                let line_data: Value = match parse_json_bytes(&line) {
+                    Ok((data, replaced)) => {
+                        valid_json_seen = true;
+                        replaced_invalid_utf8 |= replaced;
+                        data
                     }
-                    None => stdout_closed = true,
-                }
+                    Err(_) => {
+                        // Collect non-JSON lines for potential logging (with limit)
+                        non_json_lines.push(&line);
+                        continue;
+                    }
+                };
+
+                // Process the parsed JSON line
+                process_json_line(
+                    &line_data,
+                    &mut result,
+                    return_all_messages,
+                    keep_deprecation_events,
+                    content_sink,
+                );
             }
-            line = stderr_reader.next_line(), if !stderr_closed => {
-                match line {
-                    Ok(Some(line)) => {
-                        // Only capture stderr up to the limit
-                        if stderr_output.len() < MAX_STDERR_BYTES && !stderr_truncated {
-                            if !stderr_output.is_empty() {
-                                stderr_output.push('\n');
-                            }
-                            let remaining = MAX_STDERR_BYTES - stderr_output.len();
-                            if line.len() <= remaining {
-                                stderr_output.push_str(&line);
-                            } else {
-                                stderr_output.push_str(&line[..remaining]);
-                                stderr_output.push_str("\n... (stderr truncated)");
-                                stderr_truncated = true;
+            read = stderr_reader.read_until(b'\n', &mut stderr_buf), if !stderr_closed => {
+                match read {
+                    Ok(0) => stderr_closed = true,
+                    Ok(_) => {
+                        let raw = std::mem::take(&mut stderr_buf);
+                        // Only objects count as events; other stderr stays plain text
+                        if parse_stderr_json {
+                            if let Ok((line_data @ Value::Object(_), replaced)) =
+                                parse_json_bytes(&raw)
+                            {
+                                valid_json_seen = true;
+                                replaced_invalid_utf8 |= replaced;
+                                process_json_line(
+                                    &line_data,
+                                    &mut result,
+                                    return_all_messages,
+                                    keep_deprecation_events,
+                                    content_sink,
+                                );
+                                continue;
                             }
                         }
+                        let line = String::from_utf8_lossy(&raw);
+                        let line = line.trim_end_matches(['\n', '\r']);
+                        // Only capture stderr up to the limit
+                        if !stderr_truncated {
+                            stderr_truncated = push_line_with_limit(
+                                &mut stderr_output,
+                                line,
+                                MAX_STDERR_BYTES,
+                                &truncation_marker(),
+                            );
+                        }
                     }
-                    Ok(None) => stderr_closed = true,
                     Err(e) => {
                         eprintln!("Warning: Failed to read from stderr: {}", e);
                         stderr_closed = true;
@@ -442,9 +1121,9 @@ async fn run_with_child(
         // Always include non-JSON output on failure to help with diagnosis
         if !non_json_lines.is_empty() {
             full_error = format!(
-                "{}\nNon-JSON output: {}",
+                "{}\n{}",
                 full_error,
-                non_json_lines.join("\n")
+                non_json_lines.report("Non-JSON output")
             );
         }
         result.error = Some(full_error);
@@ -452,15 +1131,52 @@ async fn run_with_child(
         // Process succeeded but no valid JSON was seen
         result.success = false;
         result.error = Some(format!(
-            "No valid JSON output received from gemini CLI.\nOutput: {}",
-            non_json_lines.join("\n")
+            "No valid JSON output received from gemini CLI.\n{}",
+            non_json_lines.report("Output")
         ));
     }
 
-    Ok(enforce_required_fields(result))
+    if result.success && include_stderr_warnings && !stderr_output.trim().is_empty() {
+        result.warnings = Some(stderr_output);
+    }
+
+    // The caller already received the full content through the sink
+    if content_sink.is_some() {
+        result.agent_messages_truncated =
+            keep_tail(&mut result.agent_messages, STREAMED_TAIL_CHARS);
+    }
+
+    if replaced_invalid_utf8 {
+        result.warning = Some(
+            "gemini output contained invalid UTF-8; invalid bytes were replaced with U+FFFD"
+                .to_string(),
+        );
+    }
+
+    let mut result = enforce_required_fields(result);
+    // The run exited cleanly but produced no usable message; stray output often explains why
+    if status.success() && valid_json_seen && !result.success && !non_json_lines.is_empty() {
+        let error = result.error.get_or_insert_with(String::new);
+        error.push('\n');
+        error.push_str(&non_json_lines.report("Non-JSON output"));
+    }
+    Ok(result)
+}
+
+/// Keep only the last `max_chars` characters of `text`. Returns whether anything was cut.
+fn keep_tail(text: &mut String, max_chars: usize) -> bool {
+    let char_count = text.chars().count();
+    if char_count <= max_chars {
+        return false;
+    }
+    if let Some((start, _)) = text.char_indices().nth(char_count - max_chars) {
+        text.drain(..start);
+    }
+    true
 }
 
 fn enforce_required_fields(mut result: GeminiResult) -> GeminiResult {
+    result.agent_messages = MessageNormalization::from_env().apply(&result.agent_messages);
     let mut errors = Vec::new();
 
     if result.session_id.is_empty() {
@@ -491,9 +1207,12 @@ fn enforce_required_fields(mut result: GeminiResult) -> GeminiResult {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    /// Held by tests that set `GEMINI_BIN` or build a command from it
+    pub(crate) static GEMINI_BIN_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
     #[test]
     fn test_options_creation() {
         let opts = Options {
@@ -506,6 +1225,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
         assert_eq!(opts.prompt, "test prompt");
@@ -524,6 +1244,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
         assert_eq!(opts.session_id, Some("test-session-123".to_string()));
@@ -532,15 +1253,60 @@ mod tests {
         assert!(opts.sandbox);
     }
 
+    fn sample_events() -> Vec<Value> {
+        vec![
+            serde_json::json!({"type": "init", "session_id": "s1"}),
+            serde_json::json!({"type": "message", "role": "assistant", "content": "hi"}),
+        ]
+    }
+
+    #[test]
+    fn test_format_event_log_pretty() {
+        let rendered = format_event_log(&sample_events(), EventLogFormat::Pretty).unwrap();
+        assert!(rendered.starts_with("[\n"));
+        let parsed: Vec<Value> = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(parsed, sample_events());
+    }
+
+    #[test]
+    fn test_format_event_log_compact() {
+        let rendered = format_event_log(&sample_events(), EventLogFormat::Compact).unwrap();
+        assert!(!rendered.contains('\n'));
+        let parsed: Vec<Value> = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(parsed, sample_events());
+    }
+
+    #[test]
+    fn test_format_event_log_ndjson() {
+        let rendered = format_event_log(&sample_events(), EventLogFormat::Ndjson).unwrap();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, expected) in lines.iter().zip(sample_events()) {
+            assert_eq!(serde_json::from_str::<Value>(line).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_event_log_format_default_is_pretty() {
+        assert_eq!(EventLogFormat::default(), EventLogFormat::Pretty);
+        let format: EventLogFormat = serde_json::from_str(r#""ndjson""#).unwrap();
+        assert_eq!(format, EventLogFormat::Ndjson);
+    }
+
     #[test]
     fn test_enforce_required_fields_requires_session_id() {
         let result = GeminiResult {
             success: true,
             session_id: String::new(),
             agent_messages: "msg".to_string(),
+            agent_messages_truncated: false,
             all_messages: Vec::new(),
             return_all_messages: false,
             error: None,
+            error_code: None,
+            error_type: None,
+            warning: None,
+            warnings: None,
         };
 
         let updated = enforce_required_fields(result);
@@ -559,9 +1325,14 @@ mod tests {
             success: true,
             session_id: "session".to_string(),
             agent_messages: String::new(),
+            agent_messages_truncated: false,
             all_messages: Vec::new(),
             return_all_messages: false,
             error: None,
+            error_code: None,
+            error_type: None,
+            warning: None,
+            warnings: None,
         };
 
         let updated = enforce_required_fields(result);
@@ -580,9 +1351,14 @@ mod tests {
             success: true,
             session_id: "session".to_string(),
             agent_messages: String::new(),
+            agent_messages_truncated: false,
             all_messages: vec![serde_json::json!({"type": "tool_use"})],
             return_all_messages: true,
             error: None,
+            error_code: None,
+            error_type: None,
+            warning: None,
+            warnings: None,
         };
 
         let updated = enforce_required_fields(result);
@@ -603,8 +1379,10 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
+        let _bin = GEMINI_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         let cmd = build_command(&opts);
         let program = cmd.as_std().get_program();
 
@@ -639,8 +1417,10 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
+        let _bin = GEMINI_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         let cmd = build_command(&opts);
         let program = cmd.as_std().get_program();
 
@@ -668,8 +1448,10 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
+        let _bin = GEMINI_BIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         let cmd = build_command(&opts);
         let program = cmd.as_std().get_program();
 
@@ -685,14 +1467,500 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate_session_id() {
+        assert!(validate_session_id("5d2c6f3e-1a2b-4c3d-9e8f-0123456789ab").is_ok());
+        assert!(validate_session_id("latest").is_ok());
+        assert!(validate_session_id("3").is_ok());
+        assert!(validate_session_id("session_1").is_ok());
+
+        assert!(validate_session_id("").is_err());
+        assert!(validate_session_id("   ").is_err());
+        assert!(validate_session_id("abc def").is_err());
+        assert!(validate_session_id("--model").is_err());
+        assert!(validate_session_id("../etc/passwd").is_err());
+        assert!(validate_session_id(&"a".repeat(MAX_SESSION_ID_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_validate_options() {
+        let opts = prompt_file_test_options();
+        assert!(validate_options(&opts).is_ok());
+
+        let blank = Options {
+            prompt: "  ".to_string(),
+            ..opts.clone()
+        };
+        assert!(validate_options(&blank).unwrap_err().contains("Prompt"));
+
+        let timeout = Options {
+            timeout_secs: Some(MAX_TIMEOUT_SECS + 1),
+            ..opts.clone()
+        };
+        assert!(validate_options(&timeout)
+            .unwrap_err()
+            .contains("timeout_secs"));
+
+        let session = Options {
+            session_id: Some("--model".to_string()),
+            ..opts
+        };
+        assert!(validate_options(&session)
+            .unwrap_err()
+            .contains("SESSION_ID"));
+    }
+
+    #[test]
+    fn test_is_resume_failure() {
+        assert!(is_resume_failure(
+            "gemini command failed with exit code: Some(1)\nStderr: Error resuming session: Session not found"
+        ));
+        assert!(is_resume_failure(
+            "No previous sessions found for this project."
+        ));
+        assert!(is_resume_failure("Invalid session identifier \"abc\""));
+        assert!(!is_resume_failure("gemini error: quota exceeded"));
+        assert!(!is_resume_failure(
+            "Failed to get `agent_messages` from the gemini session."
+        ));
+    }
+
+    #[test]
+    fn test_build_command_prompt_mode_stdin() {
+        let opts = Options {
+            prompt: "stdin prompt".to_string(),
+            sandbox: false,
+            session_id: None,
+            return_all_messages: false,
+            model: None,
+            timeout_secs: None,
+            include_directories: vec![],
+            api_key: None,
+            api_base_url: None,
+            content_sink: None,
+        };
+
+        let cmd = build_command_with_prompt_mode(&opts, PromptMode::Stdin);
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        assert!(!args.iter().any(|a| *a == "stdin prompt"));
+    }
+
+    #[test]
+    fn test_build_command_prepends_path() {
+        let _guard = EnvVarGuard::new(ENV_PATH_PREPEND);
+        let opts = Options {
+            prompt: "p".to_string(),
+            sandbox: false,
+            session_id: None,
+            return_all_messages: false,
+            model: None,
+            timeout_secs: None,
+            include_directories: vec![],
+            api_key: None,
+            api_base_url: None,
+            content_sink: None,
+        };
+        let child_path = |cmd: &Command| {
+            cmd.as_std()
+                .get_envs()
+                .find(|(key, _)| *key == "PATH")
+                .and_then(|(_, value)| value.map(|v| v.to_os_string()))
+        };
+
+        std::env::remove_var(ENV_PATH_PREPEND);
+        let cmd = build_command_with_prompt_mode(&opts, PromptMode::Stdin);
+        assert!(child_path(&cmd).is_none());
+
+        let prepend = std::env::join_paths(["/opt/gemini-next/bin", "/opt/node/bin"]).unwrap();
+        std::env::set_var(ENV_PATH_PREPEND, &prepend);
+        let cmd = build_command_with_prompt_mode(&opts, PromptMode::Stdin);
+        let path = child_path(&cmd).unwrap();
+        let dirs: Vec<PathBuf> = std::env::split_paths(&path).collect();
+        assert_eq!(dirs[0], PathBuf::from("/opt/gemini-next/bin"));
+        assert_eq!(dirs[1], PathBuf::from("/opt/node/bin"));
+        if let Some(parent) = std::env::var_os("PATH") {
+            assert_eq!(dirs.len(), 2 + std::env::split_paths(&parent).count());
+        }
+    }
+
+    #[test]
+    fn test_build_command_prompt_mode_arg() {
+        let opts = Options {
+            prompt: "arg prompt".to_string(),
+            sandbox: false,
+            session_id: Some("abc-123".to_string()),
+            return_all_messages: false,
+            model: None,
+            timeout_secs: None,
+            include_directories: vec![PathBuf::from("/tmp")],
+            api_key: None,
+            api_base_url: None,
+            content_sink: None,
+        };
+
+        let cmd = build_command_with_prompt_mode(&opts, PromptMode::Arg);
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        assert_eq!(
+            args.last().unwrap().to_string_lossy(),
+            "arg prompt",
+            "prompt should be the trailing positional argument"
+        );
+        assert_eq!(args.iter().filter(|a| **a == "arg prompt").count(), 1);
+    }
+
+    fn prompt_file_test_options() -> Options {
+        Options {
+            prompt: "a very large prompt".to_string(),
+            sandbox: false,
+            session_id: None,
+            return_all_messages: false,
+            model: None,
+            timeout_secs: None,
+            include_directories: vec![PathBuf::from("/workspace")],
+            api_key: None,
+            api_base_url: None,
+            content_sink: None,
+        }
+    }
+
+    #[test]
+    fn test_prompt_file_created_and_cleaned_up() {
+        let base = tempfile::tempdir().unwrap();
+        let file = PromptFile::create(base.path(), "line one\nline two").unwrap();
+        let dir = file.dir.clone();
+        assert!(dir.starts_with(base.path()));
+        assert_eq!(
+            std::fs::read_to_string(&file.path).unwrap(),
+            "line one\nline two"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let file_mode = std::fs::metadata(&file.path).unwrap().permissions().mode();
+            assert_eq!(file_mode & 0o777, 0o600);
+            let dir_mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(dir_mode & 0o777, 0o700);
+        }
+
+        drop(file);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_prompt_files_do_not_collide() {
+        let base = tempfile::tempdir().unwrap();
+        let first = PromptFile::create(base.path(), "one").unwrap();
+        let second = PromptFile::create(base.path(), "two").unwrap();
+        assert_ne!(first.path, second.path);
+    }
+
+    #[test]
+    fn test_prompt_file_creation_error() {
+        let base = tempfile::tempdir().unwrap();
+        let missing = base.path().join("missing");
+        assert!(PromptFile::create(&missing, "prompt").is_err());
+    }
+
+    #[test]
+    fn test_build_command_prompt_mode_file() {
+        let base = tempfile::tempdir().unwrap();
+        let opts = prompt_file_test_options();
+        let file = PromptFile::create(base.path(), &opts.prompt).unwrap();
+
+        let cmd = build_command_with_prompt_mode(&file.options_for(&opts), PromptMode::File);
+        let args: Vec<_> = cmd.as_std().get_args().collect();
+        let expected_ref = format!("@{}", file.path.display());
+        assert_eq!(args.last().unwrap().to_string_lossy(), expected_ref);
+        assert!(!args.iter().any(|a| *a == "a very large prompt"));
+        let includes: Vec<_> = args
+            .windows(2)
+            .filter(|w| w[0] == "--include-directories")
+            .map(|w| PathBuf::from(w[1]))
+            .collect();
+        assert!(includes.contains(&PathBuf::from("/workspace")));
+        assert!(includes.contains(&file.dir));
+    }
+
+    #[test]
+    fn test_get_prompt_mode_env_var() {
+        let _guard = EnvVarGuard::new(ENV_PROMPT_MODE);
+
+        std::env::remove_var(ENV_PROMPT_MODE);
+        assert_eq!(get_prompt_mode(), PromptMode::Stdin);
+
+        std::env::set_var(ENV_PROMPT_MODE, "arg");
+        assert_eq!(get_prompt_mode(), PromptMode::Arg);
+
+        std::env::set_var(ENV_PROMPT_MODE, " ARG ");
+        assert_eq!(get_prompt_mode(), PromptMode::Arg);
+
+        std::env::set_var(ENV_PROMPT_MODE, "File");
+        assert_eq!(get_prompt_mode(), PromptMode::File);
+
+        std::env::set_var(ENV_PROMPT_MODE, "stdin");
+        assert_eq!(get_prompt_mode(), PromptMode::Stdin);
+
+        std::env::set_var(ENV_PROMPT_MODE, "bogus");
+        assert_eq!(get_prompt_mode(), PromptMode::Stdin);
+    }
+
     /// RAII guard to restore environment variable on drop
-    struct EnvVarGuard {
+    /// Run a shell script as if it were the gemini CLI
+    #[cfg(unix)]
+    fn run_fake_gemini(script: &str, include_stderr_warnings: bool) -> GeminiResult {
+        run_fake_gemini_with(script, include_stderr_warnings, false)
+    }
+
+    #[cfg(unix)]
+    fn run_fake_gemini_with(
+        script: &str,
+        include_stderr_warnings: bool,
+        parse_stderr_json: bool,
+    ) -> GeminiResult {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut child = Command::new("sh")
+                .args(["-c", script])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            run_with_child(
+                &mut child,
+                false,
+                None,
+                include_stderr_warnings,
+                parse_stderr_json,
+            )
+            .await
+            .unwrap()
+        })
+    }
+
+    #[cfg(unix)]
+    const FAKE_GEMINI_SUCCESS: &str = r#"
+echo '{"type":"init","session_id":"s1"}'
+echo '{"type":"message","role":"assistant","content":"done"}'
+echo 'Loaded cached credentials.' >&2
+echo 'warning: extension foo is deprecated' >&2
+"#;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_success_stderr_becomes_warnings_when_enabled() {
+        let result = run_fake_gemini(FAKE_GEMINI_SUCCESS, true);
+        assert!(result.success, "{:?}", result.error);
+        assert_eq!(result.agent_messages, "done");
+        assert_eq!(
+            result.warnings.as_deref(),
+            Some("Loaded cached credentials.\nwarning: extension foo is deprecated")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_success_stderr_discarded_by_default() {
+        let result = run_fake_gemini(FAKE_GEMINI_SUCCESS, false);
+        assert!(result.success);
+        assert!(result.warnings.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failure_stderr_stays_in_error() {
+        let script = "echo '{\"type\":\"init\",\"session_id\":\"s1\"}'; echo boom >&2; exit 3";
+        let result = run_fake_gemini(script, true);
+        assert!(!result.success);
+        assert!(result.warnings.is_none());
+        assert!(result.error.unwrap().contains("Stderr: boom"));
+    }
+
+    #[cfg(unix)]
+    const FAKE_GEMINI_STDERR_EVENTS: &str = r#"
+echo '{"type":"init","session_id":"from-stderr"}' >&2
+echo 'Loaded cached credentials.' >&2
+echo '{"type":"message","role":"assistant","content":"done"}'
+echo '42' >&2
+"#;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stderr_json_recovers_session_id_when_enabled() {
+        let result = run_fake_gemini_with(FAKE_GEMINI_STDERR_EVENTS, true, true);
+        assert!(result.success, "{:?}", result.error);
+        assert_eq!(result.session_id, "from-stderr");
+        assert_eq!(result.agent_messages, "done");
+        // Non-JSON stderr, and JSON that is not an event object, stays as warnings
+        assert_eq!(
+            result.warnings.as_deref(),
+            Some("Loaded cached credentials.\n42")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stderr_json_ignored_by_default() {
+        let result = run_fake_gemini_with(FAKE_GEMINI_STDERR_EVENTS, true, false);
+        assert!(!result.success);
+        assert!(result.session_id.is_empty());
+        assert!(result.error.unwrap().contains("SESSION_ID"));
+    }
+
+    #[test]
+    fn test_get_parse_stderr_json() {
+        let _guard = EnvVarGuard::new(ENV_PARSE_STDERR_JSON);
+        std::env::remove_var(ENV_PARSE_STDERR_JSON);
+        assert!(!get_parse_stderr_json());
+        std::env::set_var(ENV_PARSE_STDERR_JSON, "1");
+        assert!(get_parse_stderr_json());
+        std::env::set_var(ENV_PARSE_STDERR_JSON, "no");
+        assert!(!get_parse_stderr_json());
+    }
+
+    #[test]
+    fn test_get_include_stderr_warnings() {
+        let _guard = EnvVarGuard::new(ENV_INCLUDE_STDERR_WARNINGS);
+        std::env::remove_var(ENV_INCLUDE_STDERR_WARNINGS);
+        assert!(!get_include_stderr_warnings());
+        std::env::set_var(ENV_INCLUDE_STDERR_WARNINGS, " TRUE ");
+        assert!(get_include_stderr_warnings());
+        std::env::set_var(ENV_INCLUDE_STDERR_WARNINGS, "off");
+        assert!(!get_include_stderr_warnings());
+    }
+
+    fn image_dir_candidates() -> ImageDirCandidates {
+        ImageDirCandidates {
+            arg: Some(PathBuf::from("/arg")),
+            env: Some(PathBuf::from("/env")),
+            root: Some(PathBuf::from("/root-dir")),
+            cwd: Some(PathBuf::from("/cwd")),
+        }
+    }
+
+    #[test]
+    fn test_resolve_image_dir_default_order() {
+        let mut candidates = image_dir_candidates();
+        let order = DEFAULT_IMAGE_DIR_PRIORITY;
+        assert_eq!(resolve_image_dir(&candidates, order), PathBuf::from("/arg"));
+        candidates.arg = None;
+        assert_eq!(resolve_image_dir(&candidates, order), PathBuf::from("/env"));
+        candidates.env = None;
+        assert_eq!(
+            resolve_image_dir(&candidates, order),
+            PathBuf::from("/root-dir")
+        );
+        candidates.root = None;
+        assert_eq!(resolve_image_dir(&candidates, order), PathBuf::from("/cwd"));
+        candidates.cwd = None;
+        assert_eq!(resolve_image_dir(&candidates, order), PathBuf::from("."));
+    }
+
+    #[test]
+    fn test_resolve_image_dir_custom_orders() {
+        let candidates = image_dir_candidates();
+
+        let order = parse_image_dir_priority("cwd,arg").unwrap();
+        assert_eq!(
+            resolve_image_dir(&candidates, &order),
+            PathBuf::from("/cwd")
+        );
+
+        let order = parse_image_dir_priority("root, env").unwrap();
+        assert_eq!(
+            resolve_image_dir(&candidates, &order),
+            PathBuf::from("/root-dir")
+        );
+
+        // Sources missing from the order are never consulted
+        let order = parse_image_dir_priority("env").unwrap();
+        let only_arg = ImageDirCandidates {
+            arg: Some(PathBuf::from("/arg")),
+            ..Default::default()
+        };
+        assert_eq!(resolve_image_dir(&only_arg, &order), PathBuf::from("."));
+    }
+
+    #[test]
+    fn test_parse_image_dir_priority() {
+        assert_eq!(
+            parse_image_dir_priority("ARG,cwd,arg,").unwrap(),
+            vec![ImageDirSource::Arg, ImageDirSource::Cwd]
+        );
+        assert!(parse_image_dir_priority("arg,home")
+            .unwrap_err()
+            .contains("'home'"));
+        assert!(parse_image_dir_priority(" , ").is_err());
+    }
+
+    #[test]
+    fn test_get_image_dir_priority_falls_back_on_invalid() {
+        let _guard = EnvVarGuard::new(ENV_IMAGE_DIR_PRIORITY);
+        std::env::set_var(ENV_IMAGE_DIR_PRIORITY, "cwd,root");
+        assert_eq!(
+            get_image_dir_priority(),
+            vec![ImageDirSource::Cwd, ImageDirSource::Root]
+        );
+        std::env::set_var(ENV_IMAGE_DIR_PRIORITY, "bogus");
+        assert_eq!(get_image_dir_priority(), DEFAULT_IMAGE_DIR_PRIORITY);
+    }
+
+    #[test]
+    fn test_get_image_api_style_env_var() {
+        let _guard = EnvVarGuard::new(ENV_IMAGE_API_STYLE);
+        std::env::remove_var(ENV_IMAGE_API_STYLE);
+        assert_eq!(get_image_api_style(), ImageApiStyle::Gemini);
+        std::env::set_var(ENV_IMAGE_API_STYLE, "openai");
+        assert_eq!(get_image_api_style(), ImageApiStyle::OpenAi);
+        std::env::set_var(ENV_IMAGE_API_STYLE, "bogus");
+        assert_eq!(get_image_api_style(), ImageApiStyle::Gemini);
+    }
+
+    #[test]
+    fn test_get_max_non_json_lines_env_var() {
+        let _guard = EnvVarGuard::new(ENV_MAX_NON_JSON_LINES);
+        std::env::remove_var(ENV_MAX_NON_JSON_LINES);
+        assert_eq!(get_max_non_json_lines(), DEFAULT_MAX_NON_JSON_LINES);
+        std::env::set_var(ENV_MAX_NON_JSON_LINES, " 5000 ");
+        assert_eq!(get_max_non_json_lines(), 5000);
+        std::env::set_var(ENV_MAX_NON_JSON_LINES, "0");
+        assert_eq!(get_max_non_json_lines(), 0);
+        std::env::set_var(ENV_MAX_NON_JSON_LINES, "lots");
+        assert_eq!(get_max_non_json_lines(), DEFAULT_MAX_NON_JSON_LINES);
+    }
+
+    #[test]
+    fn test_non_json_lines_drops_beyond_cap() {
+        let mut lines = NonJsonLines::new(2);
+        assert!(lines.is_empty());
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            lines.push(line.as_bytes());
+        }
+        assert_eq!(lines.lines, vec!["first", "second"]);
+        assert_eq!(lines.dropped, 2);
+        assert_eq!(
+            lines.report("Non-JSON output"),
+            "Non-JSON output (4 lines, 2 beyond GEMINI_MAX_NON_JSON_LINES=2 dropped):\n  first\n  second"
+        );
+
+        let mut lines = NonJsonLines::new(10);
+        lines.push(b"only\n");
+        assert_eq!(lines.report("Output"), "Output (1 line):\n  only");
+
+        // A zero cap keeps nothing but still records that output was seen
+        let mut lines = NonJsonLines::new(0);
+        lines.push(b"noise\n");
+        assert!(lines.lines.is_empty());
+        assert!(!lines.is_empty());
+    }
+
+    pub(crate) struct EnvVarGuard {
         key: &'static str,
         original: Option<String>,
     }
 
     impl EnvVarGuard {
-        fn new(key: &'static str) -> Self {
+        pub(crate) fn new(key: &'static str) -> Self {
             let original = std::env::var(key).ok();
             Self { key, original }
         }
@@ -707,6 +1975,67 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_resolve_approve_flag() {
+        assert_eq!(resolve_approve_flag(None, None).as_deref(), Some("-y"));
+        assert_eq!(
+            resolve_approve_flag(Some("true"), Some("  ")).as_deref(),
+            Some("-y")
+        );
+        assert_eq!(
+            resolve_approve_flag(None, Some(" --approval-mode=yolo ")).as_deref(),
+            Some("--approval-mode=yolo")
+        );
+        for off in ["0", "false", " NO ", "off"] {
+            assert_eq!(resolve_approve_flag(Some(off), Some("--yolo")), None);
+        }
+    }
+
+    // Covers all scenarios in one test since env vars are process-global state
+    #[test]
+    fn test_build_command_approve_flag_env_vars() {
+        let _auto_guard = EnvVarGuard::new(ENV_AUTO_APPROVE);
+        let _flag_guard = EnvVarGuard::new(ENV_APPROVE_FLAG);
+        let opts = Options {
+            prompt: "review this".to_string(),
+            sandbox: false,
+            session_id: None,
+            return_all_messages: false,
+            model: None,
+            timeout_secs: None,
+            include_directories: vec![],
+            api_key: None,
+            api_base_url: None,
+            content_sink: None,
+        };
+        let args = || -> Vec<String> {
+            build_command(&opts)
+                .as_std()
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        // Default: auto-approve with -y
+        std::env::remove_var(ENV_AUTO_APPROVE);
+        std::env::remove_var(ENV_APPROVE_FLAG);
+        assert!(args().iter().any(|a| a == "-y"));
+
+        // Custom flag name replaces -y
+        std::env::set_var(ENV_APPROVE_FLAG, "--yolo");
+        let custom = args();
+        assert!(custom.iter().any(|a| a == "--yolo"));
+        assert!(!custom.iter().any(|a| a == "-y"));
+
+        // Disabled: no approve flag at all, the rest of the command is unchanged
+        std::env::set_var(ENV_AUTO_APPROVE, "false");
+        let disabled = args();
+        assert!(!disabled.iter().any(|a| a == "-y" || a == "--yolo"));
+        assert!(disabled
+            .windows(2)
+            .any(|w| w[0] == "-o" && w[1] == "stream-json"));
+    }
+
     // Note: This test covers all env var scenarios in a single test to avoid
     // race conditions when tests run in parallel (env vars are process-global state)
     #[test]
@@ -721,6 +2050,8 @@ mod tests {
         // Test with valid values
         std::env::set_var(ENV_DEFAULT_TIMEOUT, "300");
         assert_eq!(get_default_timeout(), 300);
+        assert_eq!(effective_timeout_secs(None), 300);
+        assert_eq!(effective_timeout_secs(Some(42)), 42);
 
         // Test boundary: minimum valid value
         std::env::set_var(ENV_DEFAULT_TIMEOUT, "1");
@@ -792,6 +2123,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
         // We can't actually run the command, but we can verify the validation logic
@@ -817,6 +2149,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
         let runtime = tokio::runtime::Runtime::new().unwrap();
@@ -840,6 +2173,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
         // This will fail because gemini CLI doesn't exist, but it should pass validation
@@ -864,6 +2198,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
 
         let result = runtime.block_on(run(opts_max));
@@ -922,6 +2257,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
         let cmd = build_command(&opts_no_model);
         let args: Vec<_> = cmd.as_std().get_args().collect();
@@ -943,6 +2279,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
         let cmd = build_command(&opts_env_set);
         let args: Vec<_> = cmd.as_std().get_args().collect();
@@ -962,6 +2299,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
         let cmd = build_command(&opts_explicit);
         let args: Vec<_> = cmd.as_std().get_args().collect();
@@ -981,6 +2319,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
         let cmd = build_command(&opts_whitespace);
         let args: Vec<_> = cmd.as_std().get_args().collect();
@@ -1000,6 +2339,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
         let cmd = build_command(&opts_empty);
         let args: Vec<_> = cmd.as_std().get_args().collect();
@@ -1019,6 +2359,7 @@ mod tests {
             include_directories: vec![],
             api_key: None,
             api_base_url: None,
+            content_sink: None,
         };
         let cmd = build_command(&opts_with_whitespace);
         let args: Vec<_> = cmd.as_std().get_args().collect();
@@ -1027,4 +2368,159 @@ mod tests {
             "Scenario 6: Should use trimmed model from options"
         );
     }
+
+    fn error_event_result(event: Value) -> GeminiResult {
+        let mut result = GeminiResult {
+            success: true,
+            session_id: String::new(),
+            agent_messages: String::new(),
+            agent_messages_truncated: false,
+            all_messages: Vec::new(),
+            return_all_messages: false,
+            error: None,
+            error_code: None,
+            error_type: None,
+            warning: None,
+            warnings: None,
+        };
+        process_json_line(&event, &mut result, false, false, None);
+        result
+    }
+
+    #[test]
+    fn test_deprecation_event_filtered_from_all_messages() {
+        let events = [
+            serde_json::json!({"type": "init", "session_id": "s1"}),
+            serde_json::json!({"type": "message", "role": "assistant", "content": PROMPT_DEPRECATION_WARNING}),
+            serde_json::json!({"type": "message", "role": "assistant", "content": "answer"}),
+        ];
+        let collect = |keep: bool| {
+            let mut result = GeminiResult {
+                success: true,
+                session_id: String::new(),
+                agent_messages: String::new(),
+                agent_messages_truncated: false,
+                all_messages: Vec::new(),
+                return_all_messages: true,
+                error: None,
+                error_code: None,
+                error_type: None,
+                warning: None,
+                warnings: None,
+            };
+            for event in &events {
+                process_json_line(event, &mut result, true, keep, None);
+            }
+            result
+        };
+
+        let filtered = collect(false);
+        assert_eq!(filtered.agent_messages, "answer");
+        assert_eq!(filtered.all_messages.len(), 2);
+        assert!(!filtered.all_messages.iter().any(is_deprecation_event));
+
+        let kept = collect(true);
+        assert_eq!(kept.agent_messages, "answer");
+        assert_eq!(kept.all_messages.len(), 3);
+        assert!(is_deprecation_event(&kept.all_messages[1]));
+    }
+
+    #[test]
+    fn test_process_json_line_error_numeric_code() {
+        let result = error_event_result(serde_json::json!({
+            "type": "error",
+            "error": {"message": "Quota exceeded", "code": 429}
+        }));
+        assert!(!result.success);
+        assert_eq!(
+            result.error.as_deref(),
+            Some("gemini error: Quota exceeded")
+        );
+        assert_eq!(result.error_code.as_deref(), Some("429"));
+        assert!(result.error_type.is_none());
+    }
+
+    #[test]
+    fn test_process_json_line_error_type() {
+        let result = error_event_result(serde_json::json!({
+            "type": "result",
+            "error": {"message": "Invalid API key", "type": "AuthError", "code": "UNAUTHENTICATED"}
+        }));
+        assert!(!result.success);
+        assert_eq!(result.error_code.as_deref(), Some("UNAUTHENTICATED"));
+        assert_eq!(result.error_type.as_deref(), Some("AuthError"));
+    }
+
+    #[test]
+    fn test_process_json_line_error_status() {
+        let result = error_event_result(serde_json::json!({
+            "type": "error",
+            "error": {"message": "Backend unavailable", "code": 503, "status": "UNAVAILABLE"}
+        }));
+        assert_eq!(result.error_code.as_deref(), Some("503"));
+        assert_eq!(result.error_type.as_deref(), Some("UNAVAILABLE"));
+    }
+
+    #[test]
+    fn test_process_json_line_error_without_code() {
+        let result = error_event_result(serde_json::json!({
+            "type": "error",
+            "message": "something broke"
+        }));
+        assert_eq!(
+            result.error.as_deref(),
+            Some("gemini error: something broke")
+        );
+        assert!(result.error_code.is_none());
+        assert!(result.error_type.is_none());
+    }
+
+    #[test]
+    fn test_process_json_line_sends_each_assistant_chunk_to_sink() {
+        let chunks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = chunks.clone();
+        let sink = ContentSink::new(move |chunk: &str| {
+            recorder.lock().unwrap().push(chunk.to_string());
+        });
+
+        let mut result = GeminiResult {
+            success: true,
+            session_id: String::new(),
+            agent_messages: String::new(),
+            agent_messages_truncated: false,
+            all_messages: Vec::new(),
+            return_all_messages: false,
+            error: None,
+            error_code: None,
+            error_type: None,
+            warning: None,
+            warnings: None,
+        };
+        let lines = [
+            serde_json::json!({"type": "init", "session_id": "s-1"}),
+            serde_json::json!({"type": "message", "role": "assistant", "content": "Hello"}),
+            serde_json::json!({"type": "message", "role": "user", "content": "ignored"}),
+            serde_json::json!({"type": "message", "role": "assistant", "content": "world"}),
+        ];
+        for line in &lines {
+            process_json_line(line, &mut result, false, false, Some(&sink));
+        }
+
+        assert_eq!(*chunks.lock().unwrap(), vec!["Hello", "world"]);
+        assert_eq!(result.agent_messages, "Hello\nworld");
+    }
+
+    #[test]
+    fn test_keep_tail() {
+        let mut text = "abcdef".to_string();
+        assert!(!keep_tail(&mut text, 6));
+        assert_eq!(text, "abcdef");
+
+        assert!(keep_tail(&mut text, 2));
+        assert_eq!(text, "ef");
+
+        let mut text = "你好世界".to_string();
+        assert!(keep_tail(&mut text, 3));
+        assert_eq!(text, "好世界");
+    }
 }
@@ -1,7 +1,8 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
+use crate::shared::{acquire_api_token, http_client_builder, shared_http_client};
+
 /// Request body for Gemini API generateContent
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -72,7 +73,6 @@ struct InlineData {
 #[derive(Deserialize)]
 struct ApiError {
     message: Option<String>,
-    code: Option<serde_json::Value>,
 }
 
 /// Result of image generation
@@ -83,28 +83,212 @@ pub struct ImageGenerationResult {
     pub images: Vec<(String, String)>,
 }
 
-/// Generate an image using the Gemini API directly (not via CLI).
-///
-/// # Arguments
-/// * `api_url` - Base URL of the Gemini API (e.g. "https://api.ikuncode.cc")
-/// * `api_key` - API key for authentication
-/// * `model` - Model name (e.g. "gemini-3-pro-image-preview")
-/// * `prompt` - Text prompt for image generation
-pub async fn generate_image(
-    api_url: &str,
-    api_key: &str,
-    model: &str,
+/// Default maximum number of images saved per `gemini_image` call
+pub const DEFAULT_MAX_IMAGE_COUNT: usize = 8;
+
+/// Caps applied while saving the images returned by the API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLimits {
+    /// Maximum number of images to keep
+    pub max_count: usize,
+    /// Maximum total decoded bytes to keep (unlimited when `None`)
+    pub max_total_bytes: Option<usize>,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_count: DEFAULT_MAX_IMAGE_COUNT,
+            max_total_bytes: None,
+        }
+    }
+}
+
+/// An image that passed the limits, with its decoded bytes
+pub struct DecodedImage<'a> {
+    /// Base64 data as returned by the API
+    pub data: &'a str,
+    /// Mime type as returned by the API
+    pub mime_type: &'a str,
+    /// Decoded image bytes
+    pub bytes: Vec<u8>,
+}
+
+/// Images kept after applying [`ImageLimits`]
+pub struct LimitedImages<'a> {
+    /// Images to return and save, in API order
+    pub kept: Vec<DecodedImage<'a>>,
+    /// Number of images returned by the API
+    pub returned: usize,
+    /// Why images were dropped, if a limit was hit
+    pub limit_reason: Option<String>,
+}
+
+/// Decode images in order, stopping at the count cap or before the image
+/// that would push the total decoded size over the byte cap.
+/// Images that fail to decode are skipped.
+pub fn apply_image_limits(images: &[(String, String)], limits: ImageLimits) -> LimitedImages<'_> {
+    let mut kept: Vec<DecodedImage> = Vec::new();
+    let mut total_bytes = 0usize;
+    let mut limit_reason = None;
+
+    for (data, mime_type) in images {
+        if kept.len() >= limits.max_count {
+            limit_reason = Some(format!("GEMINI_IMAGE_MAX_COUNT={}", limits.max_count));
+            break;
+        }
+
+        let bytes = match base64::Engine::decode(&base64::engine::general_purpose::STANDARD, data) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("[gemini_image] Failed to decode base64: {}", e);
+                continue;
+            }
+        };
+
+        if let Some(max_total) = limits.max_total_bytes {
+            if total_bytes + bytes.len() > max_total {
+                limit_reason = Some(format!("GEMINI_IMAGE_MAX_TOTAL_BYTES={}", max_total));
+                break;
+            }
+        }
+
+        total_bytes += bytes.len();
+        kept.push(DecodedImage {
+            data,
+            mime_type,
+            bytes,
+        });
+    }
+
+    LimitedImages {
+        kept,
+        returned: images.len(),
+        limit_reason,
+    }
+}
+
+/// Size metadata returned next to each image, so clients can decide how to
+/// display or store it without decoding the base64 data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImageMetadata {
+    /// Position among the returned images, starting at 0
+    pub index: usize,
+    pub mime_type: String,
+    /// Length of the base64 data in the image content
+    pub base64_len: usize,
+    /// Size of the image once decoded
+    pub decoded_bytes: usize,
+    /// Name of the saved file, when saving succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+}
+
+impl ImageMetadata {
+    pub fn new(index: usize, image: &DecodedImage<'_>, filename: Option<String>) -> Self {
+        Self {
+            index,
+            mime_type: image.mime_type.to_string(),
+            base64_len: image.data.len(),
+            decoded_bytes: base64_decoded_len(image.data),
+            filename,
+        }
+    }
+}
+
+/// Decoded size of standard base64 `data`, computed from its length and any
+/// `=` padding; unpadded input is accepted as well
+pub fn base64_decoded_len(data: &str) -> usize {
+    let data = data.trim_end_matches('=');
+    let len = data.len();
+    let tail = match len % 4 {
+        2 => 1,
+        3 => 2,
+        _ => 0,
+    };
+    len / 4 * 3 + tail
+}
+
+/// File extension for an image detected from its leading magic bytes
+fn sniff_image_extension(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("jpg")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("gif")
+    } else {
+        None
+    }
+}
+
+/// File extension for a saved image: detected from its magic bytes, since some
+/// proxies report every image as `application/octet-stream`; otherwise taken from
+/// the mime type; otherwise `png`.
+pub fn image_extension(bytes: &[u8], mime_type: &str) -> &'static str {
+    sniff_image_extension(bytes).unwrap_or(match mime_type {
+        "image/png" => "png",
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        _ => "png",
+    })
+}
+
+/// Request and response shape spoken by the image API (`GEMINI_IMAGE_API_STYLE`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageApiStyle {
+    /// Gemini `/v1beta/models/{model}:generateContent`
+    #[default]
+    Gemini,
+    /// OpenAI-compatible `/v1/images/generations`
+    OpenAi,
+}
+
+impl ImageApiStyle {
+    /// Parse a `GEMINI_IMAGE_API_STYLE` value: "gemini" or "openai"
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "gemini" => Some(Self::Gemini),
+            "openai" => Some(Self::OpenAi),
+            _ => None,
+        }
+    }
+}
+
+/// Request body for an OpenAI-compatible images/generations endpoint
+#[derive(Serialize)]
+struct OpenAiImageRequest {
+    model: String,
+    prompt: String,
+    n: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<String>,
+}
+
+/// Response from an OpenAI-compatible images/generations endpoint
+#[derive(Deserialize)]
+struct OpenAiImageResponse {
+    data: Option<Vec<OpenAiImageData>>,
+    /// Image format of gpt-image style responses, e.g. "png"
+    output_format: Option<String>,
+    error: Option<ApiError>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiImageData {
+    b64_json: Option<String>,
+    revised_prompt: Option<String>,
+}
+
+fn gemini_request(
     prompt: &str,
     aspect_ratio: Option<&str>,
     image_size: Option<&str>,
-) -> Result<ImageGenerationResult> {
-    let url = format!(
-        "{}/v1beta/models/{}:generateContent",
-        api_url.trim_end_matches('/'),
-        model
-    );
-
-    let request_body = GenerateContentRequest {
+) -> GenerateContentRequest {
+    GenerateContentRequest {
         contents: vec![RequestContent {
             parts: vec![RequestPart::Text {
                 text: prompt.to_string(),
@@ -121,40 +305,67 @@ pub async fn generate_image(
                 None
             },
         },
+    }
+}
+
+/// The OpenAI `size` for a call: `image_size` when it already is `WIDTHxHEIGHT`,
+/// otherwise the square, landscape or portrait size matching `aspect_ratio`
+fn openai_size(aspect_ratio: Option<&str>, image_size: Option<&str>) -> Option<String> {
+    if let Some(size) = image_size {
+        let mut dims = size.trim().splitn(2, 'x');
+        let is_dims = |d: Option<&str>| d.is_some_and(|d| d.parse::<u32>().is_ok());
+        if is_dims(dims.next()) && is_dims(dims.next()) {
+            return Some(size.trim().to_string());
+        }
+    }
+    let (w, h) = aspect_ratio?.trim().split_once(':')?;
+    let (w, h) = (w.parse::<u32>().ok()?, h.parse::<u32>().ok()?);
+    let size = match w.cmp(&h) {
+        std::cmp::Ordering::Equal => "1024x1024",
+        std::cmp::Ordering::Greater => "1536x1024",
+        std::cmp::Ordering::Less => "1024x1536",
     };
+    Some(size.to_string())
+}
 
-    let client = Client::new();
-    let response = client
-        .post(&url)
-        .header("x-goog-api-key", api_key)
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
-        .await
-        .context("Failed to send request to Gemini API")?;
+fn openai_request(
+    model: &str,
+    prompt: &str,
+    aspect_ratio: Option<&str>,
+    image_size: Option<&str>,
+) -> OpenAiImageRequest {
+    OpenAiImageRequest {
+        model: model.to_string(),
+        prompt: prompt.to_string(),
+        n: 1,
+        size: openai_size(aspect_ratio, image_size),
+    }
+}
 
-    let status = response.status();
-    let body = response
-        .text()
-        .await
-        .context("Failed to read response body")?;
+fn api_error(error: ApiError) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Gemini API error: {}",
+        error.message.unwrap_or_else(|| "Unknown error".to_string())
+    )
+}
 
-    if !status.is_success() {
-        return Err(anyhow::anyhow!(
-            "Gemini API returned HTTP {}: {}",
-            status,
-            body
-        ));
+/// Append a text part to the result's text, one part per line
+fn push_text(result: &mut ImageGenerationResult, text: String) {
+    if text.is_empty() {
+        return;
     }
+    result.text = Some(match result.text.take() {
+        Some(existing) => format!("{}\n{}", existing, text),
+        None => text,
+    });
+}
 
+fn parse_gemini_response(body: &str) -> Result<ImageGenerationResult> {
     let parsed: GenerateContentResponse =
-        serde_json::from_str(&body).context("Failed to parse Gemini API response")?;
+        serde_json::from_str(body).context("Failed to parse Gemini API response")?;
 
     if let Some(error) = parsed.error {
-        return Err(anyhow::anyhow!(
-            "Gemini API error: {}",
-            error.message.unwrap_or_else(|| "Unknown error".to_string())
-        ));
+        return Err(api_error(error));
     }
 
     let mut result = ImageGenerationResult {
@@ -173,12 +384,7 @@ pub async fn generate_image(
                                 .push((inline_data.data, inline_data.mime_type));
                         }
                         if let Some(text) = part.text {
-                            if !text.is_empty() {
-                                result.text = Some(match result.text {
-                                    Some(existing) => format!("{}\n{}", existing, text),
-                                    None => text,
-                                });
-                            }
+                            push_text(&mut result, text);
                         }
                     }
                 }
@@ -186,6 +392,102 @@ pub async fn generate_image(
         }
     }
 
+    Ok(result)
+}
+
+fn parse_openai_response(body: &str) -> Result<ImageGenerationResult> {
+    let parsed: OpenAiImageResponse =
+        serde_json::from_str(body).context("Failed to parse image API response")?;
+
+    if let Some(error) = parsed.error {
+        return Err(api_error(error));
+    }
+
+    let mime_type = format!(
+        "image/{}",
+        parsed
+            .output_format
+            .as_deref()
+            .map(|f| if f == "jpg" { "jpeg" } else { f })
+            .unwrap_or("png")
+    );
+    let mut result = ImageGenerationResult {
+        text: None,
+        images: Vec::new(),
+    };
+    for entry in parsed.data.unwrap_or_default() {
+        if let Some(data) = entry.b64_json {
+            result.images.push((data, mime_type.clone()));
+        }
+        if let Some(text) = entry.revised_prompt {
+            push_text(&mut result, text);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Generate an image using the Gemini API directly (not via CLI).
+///
+/// # Arguments
+/// * `api_url` - Base URL of the Gemini API (e.g. "https://api.ikuncode.cc")
+/// * `api_key` - API key for authentication
+/// * `model` - Model name (e.g. "gemini-3-pro-image-preview")
+/// * `prompt` - Text prompt for image generation
+/// * `style` - Request shape: Gemini `generateContent` or OpenAI `images/generations`
+pub async fn generate_image(
+    api_url: &str,
+    api_key: &str,
+    model: &str,
+    prompt: &str,
+    aspect_ratio: Option<&str>,
+    image_size: Option<&str>,
+    style: ImageApiStyle,
+) -> Result<ImageGenerationResult> {
+    let base_url = api_url.trim_end_matches('/');
+    static CLIENT: std::sync::OnceLock<reqwest::Client> = std::sync::OnceLock::new();
+    let client = shared_http_client(&CLIENT, || http_client_builder().build())
+        .context("Failed to build HTTP client")?;
+    let request = match style {
+        ImageApiStyle::Gemini => client
+            .post(format!(
+                "{}/v1beta/models/{}:generateContent",
+                base_url, model
+            ))
+            .header("x-goog-api-key", api_key)
+            .json(&gemini_request(prompt, aspect_ratio, image_size)),
+        ImageApiStyle::OpenAi => client
+            .post(format!("{}/v1/images/generations", base_url))
+            .bearer_auth(api_key)
+            .json(&openai_request(model, prompt, aspect_ratio, image_size)),
+    };
+
+    acquire_api_token().await;
+    let response = request
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .context("Failed to send request to Gemini API")?;
+
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .context("Failed to read response body")?;
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "Gemini API returned HTTP {}: {}",
+            status,
+            body
+        ));
+    }
+
+    let result = match style {
+        ImageApiStyle::Gemini => parse_gemini_response(&body)?,
+        ImageApiStyle::OpenAi => parse_openai_response(&body)?,
+    };
+
     if result.images.is_empty() && result.text.is_none() {
         return Err(anyhow::anyhow!(
             "Gemini API returned no content (no images or text)"
@@ -194,3 +496,248 @@ pub async fn generate_image(
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(len: usize) -> (String, String) {
+        let data =
+            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, vec![0u8; len]);
+        (data, "image/png".to_string())
+    }
+
+    #[test]
+    fn test_base64_decoded_len() {
+        for len in 0..10 {
+            let (data, _) = image(len);
+            assert_eq!(base64_decoded_len(&data), len, "padded, {} bytes", len);
+            assert_eq!(
+                base64_decoded_len(data.trim_end_matches('=')),
+                len,
+                "unpadded, {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn test_image_metadata_matches_images() {
+        let images = vec![image(1), image(200), image(1024)];
+        let limited = apply_image_limits(&images, ImageLimits::default());
+
+        let metadata: Vec<ImageMetadata> = limited
+            .kept
+            .iter()
+            .enumerate()
+            .map(|(idx, image)| {
+                let filename = (idx != 1).then(|| format!("gemini_image_{}.png", idx + 1));
+                ImageMetadata::new(idx, image, filename)
+            })
+            .collect();
+
+        for (meta, (image, (data, _))) in metadata.iter().zip(limited.kept.iter().zip(&images)) {
+            assert_eq!(meta.decoded_bytes, image.bytes.len());
+            assert_eq!(meta.base64_len, data.len());
+            assert_eq!(meta.mime_type, "image/png");
+        }
+        assert_eq!(
+            serde_json::to_value(&metadata[0]).unwrap(),
+            serde_json::json!({
+                "index": 0,
+                "mime_type": "image/png",
+                "base64_len": 4,
+                "decoded_bytes": 1,
+                "filename": "gemini_image_1.png",
+            })
+        );
+        assert!(serde_json::to_value(&metadata[1])
+            .unwrap()
+            .get("filename")
+            .is_none());
+        assert_eq!(metadata[2].decoded_bytes, 1024);
+    }
+
+    #[test]
+    fn test_image_api_style_parse() {
+        assert_eq!(ImageApiStyle::parse("gemini"), Some(ImageApiStyle::Gemini));
+        assert_eq!(
+            ImageApiStyle::parse(" OpenAI "),
+            Some(ImageApiStyle::OpenAi)
+        );
+        assert_eq!(ImageApiStyle::parse("dalle"), None);
+        assert_eq!(ImageApiStyle::default(), ImageApiStyle::Gemini);
+    }
+
+    #[test]
+    fn test_gemini_request_shape() {
+        let body = serde_json::to_value(gemini_request("a cat", Some("16:9"), None)).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "contents": [{"parts": [{"text": "a cat"}]}],
+                "generationConfig": {
+                    "responseModalities": ["IMAGE", "TEXT"],
+                    "imageConfig": {"aspectRatio": "16:9"},
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_openai_request_shape() {
+        let body = serde_json::to_value(openai_request("gpt-image-1", "a cat", Some("16:9"), None))
+            .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "model": "gpt-image-1",
+                "prompt": "a cat",
+                "n": 1,
+                "size": "1536x1024",
+            })
+        );
+
+        let body = serde_json::to_value(openai_request("m", "p", None, None)).unwrap();
+        assert!(body.get("size").is_none());
+    }
+
+    #[test]
+    fn test_openai_size() {
+        assert_eq!(openai_size(Some("1:1"), None).as_deref(), Some("1024x1024"));
+        assert_eq!(
+            openai_size(Some("9:16"), None).as_deref(),
+            Some("1024x1536")
+        );
+        assert_eq!(
+            openai_size(Some("1:1"), Some("512x512")).as_deref(),
+            Some("512x512")
+        );
+        // Gemini-style sizes do not translate
+        assert_eq!(openai_size(None, Some("2K")), None);
+        assert_eq!(openai_size(Some("wide"), None), None);
+    }
+
+    #[test]
+    fn test_parse_gemini_response() {
+        let body = r#"{"candidates":[{"content":{"parts":[
+            {"text":"Here it is"},
+            {"inlineData":{"mimeType":"image/png","data":"aW1n"}}
+        ]}}]}"#;
+        let result = parse_gemini_response(body).unwrap();
+        assert_eq!(result.text.as_deref(), Some("Here it is"));
+        assert_eq!(
+            result.images,
+            vec![("aW1n".to_string(), "image/png".to_string())]
+        );
+
+        let err = parse_gemini_response(r#"{"error":{"code":400,"message":"bad prompt"}}"#)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Gemini API error: bad prompt");
+    }
+
+    #[test]
+    fn test_parse_openai_response() {
+        let body = r#"{"created":1,"output_format":"jpeg","data":[
+            {"b64_json":"b25l","revised_prompt":"a cute cat"},
+            {"b64_json":"dHdv"},
+            {"url":"https://example.com/x.png"}
+        ]}"#;
+        let result = parse_openai_response(body).unwrap();
+        assert_eq!(
+            result.images,
+            vec![
+                ("b25l".to_string(), "image/jpeg".to_string()),
+                ("dHdv".to_string(), "image/jpeg".to_string()),
+            ]
+        );
+        assert_eq!(result.text.as_deref(), Some("a cute cat"));
+
+        let result = parse_openai_response(r#"{"data":[{"b64_json":"b25l"}]}"#).unwrap();
+        assert_eq!(result.images[0].1, "image/png");
+
+        let err = parse_openai_response(r#"{"error":{"message":"quota"}}"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("quota"));
+    }
+
+    #[test]
+    fn test_image_extension_from_magic_bytes() {
+        let octet = "application/octet-stream";
+        assert_eq!(image_extension(b"\x89PNG\r\n\x1a\n\0\0", octet), "png");
+        assert_eq!(
+            image_extension(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10], octet),
+            "jpg"
+        );
+        assert_eq!(image_extension(b"RIFF\x24\0\0\0WEBPVP8 ", octet), "webp");
+        assert_eq!(image_extension(b"GIF89a\x01\0", octet), "gif");
+        assert_eq!(image_extension(b"GIF87a\x01\0", octet), "gif");
+        // Magic bytes win over a mislabelled mime type
+        assert_eq!(
+            image_extension(&[0xFF, 0xD8, 0xFF, 0xDB], "image/png"),
+            "jpg"
+        );
+    }
+
+    #[test]
+    fn test_image_extension_falls_back_to_mime_type() {
+        let unknown = b"not an image";
+        assert_eq!(image_extension(unknown, "image/jpeg"), "jpg");
+        assert_eq!(image_extension(unknown, "image/webp"), "webp");
+        assert_eq!(image_extension(unknown, "image/gif"), "gif");
+        assert_eq!(image_extension(unknown, "application/octet-stream"), "png");
+        // Too short to be a WebP header
+        assert_eq!(image_extension(b"RIFF", "image/gif"), "gif");
+    }
+
+    #[test]
+    fn test_apply_image_limits_count_cap() {
+        let images: Vec<_> = (0..5).map(|_| image(10)).collect();
+        let limits = ImageLimits {
+            max_count: 3,
+            max_total_bytes: None,
+        };
+
+        let limited = apply_image_limits(&images, limits);
+        assert_eq!(limited.kept.len(), 3);
+        assert_eq!(limited.returned, 5);
+        assert_eq!(
+            limited.limit_reason.as_deref(),
+            Some("GEMINI_IMAGE_MAX_COUNT=3")
+        );
+    }
+
+    #[test]
+    fn test_apply_image_limits_total_bytes_cap() {
+        let images = vec![image(40), image(40), image(40)];
+        let limits = ImageLimits {
+            max_count: 8,
+            max_total_bytes: Some(100),
+        };
+
+        let limited = apply_image_limits(&images, limits);
+        assert_eq!(limited.kept.len(), 2);
+        assert_eq!(
+            limited.kept.iter().map(|i| i.bytes.len()).sum::<usize>(),
+            80
+        );
+        assert_eq!(limited.returned, 3);
+        assert_eq!(
+            limited.limit_reason.as_deref(),
+            Some("GEMINI_IMAGE_MAX_TOTAL_BYTES=100")
+        );
+    }
+
+    #[test]
+    fn test_apply_image_limits_within_caps() {
+        let mut images = vec![image(10), image(10)];
+        images.insert(1, ("not base64!".to_string(), "image/png".to_string()));
+
+        let limited = apply_image_limits(&images, ImageLimits::default());
+        assert_eq!(limited.kept.len(), 2);
+        assert_eq!(limited.returned, 3);
+        assert!(limited.limit_reason.is_none());
+    }
+}
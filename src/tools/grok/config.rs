@@ -1,8 +1,14 @@
 use std::sync::Mutex;
 use std::sync::OnceLock;
 
+use super::prompts::OutputLanguage;
+
 const DEFAULT_MODEL: &str = "grok-4.20-beta";
 
+/// Default and upper bound for GROK_CONNECTION_TEST_TIMEOUT (seconds)
+const DEFAULT_CONNECTION_TEST_TIMEOUT: u64 = 10;
+const MAX_CONNECTION_TEST_TIMEOUT: u64 = 60;
+
 static CONFIG: OnceLock<Mutex<Config>> = OnceLock::new();
 
 pub struct Config {
@@ -59,6 +65,31 @@ impl Config {
             .unwrap_or(false)
     }
 
+    /// Include the raw provider content and HTTP status in web_search/web_fetch
+    /// output, for debugging malformed results without GROK_DEBUG logging
+    pub fn debug_return_raw() -> bool {
+        std::env::var("GROK_DEBUG_RETURN_RAW")
+            .map(|v| matches!(v.to_lowercase().as_str(), "true" | "1" | "yes"))
+            .unwrap_or(false)
+    }
+
+    /// Return web_search output as the raw model text instead of the structured
+    /// `{results, status, message}` object, for integrations built on the old format
+    pub fn legacy_search_output() -> bool {
+        std::env::var("GROK_SEARCH_LEGACY_OUTPUT")
+            .map(|v| matches!(v.to_lowercase().as_str(), "true" | "1" | "yes"))
+            .unwrap_or(false)
+    }
+
+    /// Language for the fixed web_fetch instructions (GROK_OUTPUT_LANGUAGE).
+    /// Unset or unrecognized values keep the original Chinese instructions.
+    pub fn output_language() -> OutputLanguage {
+        std::env::var("GROK_OUTPUT_LANGUAGE")
+            .ok()
+            .and_then(|v| OutputLanguage::parse(&v))
+            .unwrap_or_default()
+    }
+
     /// Maximum retry attempts
     pub fn retry_max_attempts() -> u32 {
         std::env::var("GROK_RETRY_MAX_ATTEMPTS")
@@ -83,6 +114,58 @@ impl Config {
             .unwrap_or(10)
     }
 
+    /// Disable retry jitter so backoff waits are exactly `multiplier * 2^attempt`
+    /// (capped at the max wait), for reproducible retry timing
+    pub fn retry_no_jitter() -> bool {
+        std::env::var("GROK_RETRY_NO_JITTER")
+            .map(|v| matches!(v.to_lowercase().as_str(), "true" | "1" | "yes"))
+            .unwrap_or(false)
+    }
+
+    /// Fraction of `min_results` still requested when the search is restricted to
+    /// specific platforms, clamped to 0.0-1.0 (default 0.5; 1.0 disables the relaxation)
+    pub fn platform_min_results_factor() -> f64 {
+        std::env::var("GROK_PLATFORM_MIN_RESULTS_FACTOR")
+            .ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|v| v.is_finite())
+            .map(|v| v.clamp(0.0, 1.0))
+            .unwrap_or(0.5)
+    }
+
+    /// Content size (bytes) below which a stream that breaks mid-response is retried
+    /// instead of returning the partial content
+    pub fn stream_retry_min_bytes() -> usize {
+        std::env::var("GROK_STREAM_RETRY_MIN_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(512)
+    }
+
+    /// Consecutive failed calls that open the Grok circuit breaker (default 5; 0 disables it)
+    pub fn circuit_failure_threshold() -> u32 {
+        std::env::var("GROK_CIRCUIT_FAILURE_THRESHOLD")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5)
+    }
+
+    /// Window (seconds) within which failures must occur to count as consecutive
+    pub fn circuit_window() -> u64 {
+        std::env::var("GROK_CIRCUIT_WINDOW")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60)
+    }
+
+    /// How long (seconds) an open circuit fails fast before a probe call is let through
+    pub fn circuit_cooldown() -> u64 {
+        std::env::var("GROK_CIRCUIT_COOLDOWN")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30)
+    }
+
     /// Overall timeout for a single streaming response (seconds).
     /// Guards against server keeping connection alive but not completing.
     pub fn stream_timeout() -> u64 {
@@ -100,6 +183,36 @@ impl Config {
             .unwrap_or(300)
     }
 
+    /// Content size (bytes) after which raw SSE body lines stop being retained.
+    /// Raw lines only feed the non-streaming fallback and debug output, so
+    /// dropping them bounds memory for very large fetches.
+    pub fn sse_retain_limit() -> usize {
+        std::env::var("GROK_SSE_RETAIN_LIMIT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1024 * 1024)
+    }
+
+    /// Upper bound (bytes) on the answer accumulated from one streamed response;
+    /// longer answers are cut there and marked truncated (default 16 MiB)
+    pub fn max_response_bytes() -> usize {
+        std::env::var("GROK_MAX_RESPONSE_BYTES")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(16 * 1024 * 1024)
+    }
+
+    /// Timeout (seconds) for the /models call made by the connection test in
+    /// get_config_info, clamped to 1-60 (default 10)
+    pub fn connection_test_timeout() -> u64 {
+        std::env::var("GROK_CONNECTION_TEST_TIMEOUT")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(|v| v.clamp(1, MAX_CONNECTION_TEST_TIMEOUT))
+            .unwrap_or(DEFAULT_CONNECTION_TEST_TIMEOUT)
+    }
+
     /// Max idle time between chunks before aborting (seconds).
     /// Prevents hang when server sends keep-alive but no real data.
     pub fn idle_timeout() -> u64 {
@@ -165,4 +278,32 @@ mod tests {
         assert!((Config::retry_multiplier() - 1.0).abs() < f64::EPSILON);
         assert_eq!(Config::retry_max_wait(), 10);
     }
+
+    #[test]
+    fn test_platform_min_results_factor() {
+        std::env::remove_var("GROK_PLATFORM_MIN_RESULTS_FACTOR");
+        assert!((Config::platform_min_results_factor() - 0.5).abs() < f64::EPSILON);
+        std::env::set_var("GROK_PLATFORM_MIN_RESULTS_FACTOR", "0.25");
+        assert!((Config::platform_min_results_factor() - 0.25).abs() < f64::EPSILON);
+        std::env::set_var("GROK_PLATFORM_MIN_RESULTS_FACTOR", "3");
+        assert!((Config::platform_min_results_factor() - 1.0).abs() < f64::EPSILON);
+        std::env::set_var("GROK_PLATFORM_MIN_RESULTS_FACTOR", "bogus");
+        assert!((Config::platform_min_results_factor() - 0.5).abs() < f64::EPSILON);
+        std::env::remove_var("GROK_PLATFORM_MIN_RESULTS_FACTOR");
+    }
+
+    #[test]
+    fn test_connection_test_timeout() {
+        std::env::remove_var("GROK_CONNECTION_TEST_TIMEOUT");
+        assert_eq!(Config::connection_test_timeout(), 10);
+        std::env::set_var("GROK_CONNECTION_TEST_TIMEOUT", " 3 ");
+        assert_eq!(Config::connection_test_timeout(), 3);
+        std::env::set_var("GROK_CONNECTION_TEST_TIMEOUT", "0");
+        assert_eq!(Config::connection_test_timeout(), 1);
+        std::env::set_var("GROK_CONNECTION_TEST_TIMEOUT", "600");
+        assert_eq!(Config::connection_test_timeout(), 60);
+        std::env::set_var("GROK_CONNECTION_TEST_TIMEOUT", "soon");
+        assert_eq!(Config::connection_test_timeout(), 10);
+        std::env::remove_var("GROK_CONNECTION_TEST_TIMEOUT");
+    }
 }
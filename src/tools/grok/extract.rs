@@ -0,0 +1,344 @@
+use scraper::{ElementRef, Html, Node};
+
+/// Elements whose content is never part of the readable text
+const SKIPPED_TAGS: &[&str] = &[
+    "script", "style", "noscript", "template", "textarea", "svg", "iframe", "canvas", "head",
+    "nav", "footer", "aside", "form", "button", "select", "dialog",
+];
+
+/// Elements that are site chrome at page level but hold the title inside
+/// `<main>`/`<article>`, so they are only skipped outside those
+const PAGE_LEVEL_TAGS: &[&str] = &["header"];
+
+/// Elements rendered as separate paragraphs
+const BLOCK_TAGS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "header",
+    "blockquote",
+    "pre",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "table",
+    "tr",
+    "figure",
+    "figcaption",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+];
+
+/// `class`/`id` fragments marking navigation, ads and other boilerplate
+const BOILERPLATE_MARKERS: &[&str] = &[
+    "nav",
+    "menu",
+    "sidebar",
+    "footer",
+    "header",
+    "advert",
+    "ads",
+    "banner",
+    "cookie",
+    "promo",
+    "social",
+    "share",
+    "related",
+    "comment",
+    "breadcrumb",
+    "popup",
+    "newsletter",
+    "subscribe",
+];
+
+/// ARIA roles marking boilerplate regions
+const BOILERPLATE_ROLES: &[&str] = &[
+    "navigation",
+    "banner",
+    "contentinfo",
+    "complementary",
+    "search",
+];
+
+/// Extract the readable text of an HTML page: navigation, ads, scripts and other
+/// boilerplate are dropped, and when the page has `<main>` or `<article>` only their
+/// content is kept. The `<title>` is emitted as a leading `# ` heading.
+/// Deterministic and offline; no model call is involved.
+pub fn extract_main_content(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let root = document.root_element();
+    let title = root
+        .descendent_elements()
+        .find(|e| e.value().name() == "title")
+        .map(|e| collapse_whitespace(&e.text().collect::<String>()))
+        .unwrap_or_default();
+    let has_main = root
+        .descendent_elements()
+        .any(|e| is_main(e.value().name()));
+
+    let mut out = TextBuilder::default();
+    render_children(root, false, has_main, &mut out);
+
+    let body = out.finish();
+    match (title.is_empty(), body.is_empty()) {
+        (true, _) => body,
+        (false, true) => format!("# {}", title),
+        (false, false) => format!("# {}\n\n{}", title, body),
+    }
+}
+
+fn is_main(name: &str) -> bool {
+    name == "main" || name == "article"
+}
+
+fn render_children(element: ElementRef, in_main: bool, has_main: bool, out: &mut TextBuilder) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) if !has_main || in_main => out.push_text(text),
+            Node::Element(_) => {
+                if let Some(child) = ElementRef::wrap(child) {
+                    render_element(child, in_main, has_main, out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn render_element(element: ElementRef, in_main: bool, has_main: bool, out: &mut TextBuilder) {
+    if is_boilerplate(element, in_main) {
+        return;
+    }
+    let name = element.value().name();
+    let in_main = in_main || is_main(name);
+    let block = BLOCK_TAGS.contains(&name);
+
+    if !has_main || in_main {
+        match name {
+            "br" => out.line_break(),
+            "li" => {
+                out.line_break();
+                out.push_marker("-");
+            }
+            _ if block => {
+                out.paragraph_break();
+                if let Some(level) = heading_level(name) {
+                    out.push_marker(&"#".repeat(level));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    render_children(element, in_main, has_main, out);
+    if block {
+        out.paragraph_break();
+    }
+}
+
+fn is_boilerplate(element: ElementRef, in_main: bool) -> bool {
+    let el = element.value();
+    // Inside <main>/<article>, a `header` tag or class is the article's own header
+    let kept_in_main = |word: &str| in_main && PAGE_LEVEL_TAGS.contains(&word);
+    if SKIPPED_TAGS.contains(&el.name()) || (PAGE_LEVEL_TAGS.contains(&el.name()) && !in_main) {
+        return true;
+    }
+    if el
+        .attr("role")
+        .is_some_and(|role| BOILERPLATE_ROLES.contains(&role.trim().to_ascii_lowercase().as_str()))
+    {
+        return true;
+    }
+    [el.attr("class"), el.attr("id")]
+        .into_iter()
+        .flatten()
+        .flat_map(|value| value.split(|c: char| c.is_whitespace() || c == '-' || c == '_'))
+        .map(str::to_ascii_lowercase)
+        .any(|word| BOILERPLATE_MARKERS.contains(&word.as_str()) && !kept_in_main(&word))
+}
+
+/// Accumulates text, collapsing whitespace and honouring pending line/paragraph breaks
+#[derive(Default)]
+struct TextBuilder {
+    text: String,
+    /// Newlines to insert before the next word (1 = line break, 2 = paragraph)
+    pending_newlines: usize,
+    /// Whitespace was seen since the last word; text split only by inline tags is glued
+    pending_space: bool,
+}
+
+impl TextBuilder {
+    fn push_word(&mut self, word: &str) {
+        if !self.text.is_empty() {
+            if self.pending_newlines > 0 {
+                self.text.push_str(&"\n".repeat(self.pending_newlines));
+            } else if self.pending_space {
+                self.text.push(' ');
+            }
+        }
+        self.text.push_str(word);
+        self.pending_newlines = 0;
+        self.pending_space = false;
+    }
+
+    /// Push a list bullet or heading marker, always followed by a space
+    fn push_marker(&mut self, marker: &str) {
+        self.push_word(marker);
+        self.pending_space = true;
+    }
+
+    fn push_text(&mut self, text: &str) {
+        for (i, word) in text.split_whitespace().enumerate() {
+            if i > 0 || text.starts_with(char::is_whitespace) {
+                self.pending_space = true;
+            }
+            self.push_word(word);
+        }
+        if text.ends_with(char::is_whitespace) {
+            self.pending_space = true;
+        }
+    }
+
+    fn line_break(&mut self) {
+        self.pending_newlines = self.pending_newlines.max(1);
+    }
+
+    fn paragraph_break(&mut self) {
+        self.pending_newlines = 2;
+    }
+
+    fn finish(self) -> String {
+        self.text
+    }
+}
+
+fn heading_level(name: &str) -> Option<usize> {
+    match name.as_bytes() {
+        [b'h', level @ b'1'..=b'6'] => Some(usize::from(level - b'0')),
+        _ => None,
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARTICLE_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Rust 2024 Edition &amp; You</title>
+  <style>body { color: red; }</style>
+  <script>var tracking = "<p>not content</p>";</script>
+</head>
+<body>
+  <header class="site-header"><a href="/">Home</a> | <a href="/blog">Blog</a></header>
+  <nav><ul><li>Docs</li><li>Community</li></ul></nav>
+  <div class="ad-banner">Buy now! 50% off</div>
+  <main>
+    <article>
+      <h1>What's new in the 2024 edition</h1>
+      <p>The 2024 edition stabilizes <code>async</code> closures and
+         tightens <em>temporary</em> lifetimes.</p>
+      <div class="share-buttons">Share on Twitter</div>
+      <ul>
+        <li>RPIT lifetime capture rules</li>
+        <li>Reserved <b>gen</b> keyword</li>
+      </ul>
+      <p>Upgrade with <code>cargo fix --edition</code> &mdash; it's mostly automatic.</p>
+      <!-- <p>commented out</p> -->
+    </article>
+    <aside>Related posts: Rust 2021</aside>
+  </main>
+  <div id="cookie-consent">We use cookies.</div>
+  <footer>&copy; 2024 Rust Blog</footer>
+</body>
+</html>"#;
+
+    #[test]
+    fn test_extract_keeps_main_content() {
+        let text = extract_main_content(ARTICLE_PAGE);
+        assert_eq!(
+            text,
+            "# Rust 2024 Edition & You\n\n\
+             # What's new in the 2024 edition\n\n\
+             The 2024 edition stabilizes async closures and tightens temporary lifetimes.\n\n\
+             - RPIT lifetime capture rules\n\
+             - Reserved gen keyword\n\n\
+             Upgrade with cargo fix --edition \u{2014} it's mostly automatic."
+        );
+    }
+
+    #[test]
+    fn test_extract_removes_boilerplate() {
+        let text = extract_main_content(ARTICLE_PAGE);
+        for boilerplate in [
+            "Home",
+            "Docs",
+            "Buy now",
+            "Share on Twitter",
+            "Related posts",
+            "cookies",
+            "2024 Rust Blog",
+            "tracking",
+            "color: red",
+            "commented out",
+        ] {
+            assert!(!text.contains(boilerplate), "{boilerplate:?} in {text:?}");
+        }
+    }
+
+    #[test]
+    fn test_extract_without_main_keeps_body_text() {
+        let html = "<html><body><nav>Menu</nav><h2>Notes</h2><p>First<br>Second</p>\
+                    <p>a &lt; b &#38; c &#x3E; d</p><footer>Footer</footer></body></html>";
+        assert_eq!(
+            extract_main_content(html),
+            "## Notes\n\nFirst\nSecond\n\na < b & c > d"
+        );
+    }
+
+    #[test]
+    fn test_extract_inline_tags_do_not_split_words() {
+        let html = "<p>un<b>break</b>able, <i>spaced</i> words</p>";
+        assert_eq!(extract_main_content(html), "unbreakable, spaced words");
+    }
+
+    #[test]
+    fn test_extract_plain_text_and_stray_angle_bracket() {
+        assert_eq!(extract_main_content("just  text"), "just text");
+        assert_eq!(extract_main_content("<p>1 < 2</p>"), "1 < 2");
+        assert_eq!(extract_main_content(""), "");
+    }
+
+    #[test]
+    fn test_extract_keeps_article_header() {
+        let html = "<body><header class=\"site-header\">Site</header><article>\
+                    <header class=\"entry-header\"><h1>Post title</h1><p>By Ann</p></header>\
+                    <p>Body text.</p></article></body>";
+        assert_eq!(
+            extract_main_content(html),
+            "# Post title\n\nBy Ann\n\nBody text."
+        );
+    }
+
+    #[test]
+    fn test_extract_boilerplate_attributes() {
+        let html =
+            "<p>kept</p><div CLASS=\"Social-Links\">x</div><div role=\" Navigation \">y</div>\
+                    <div id=main_menu>z</div>";
+        assert_eq!(extract_main_content(html), "kept");
+    }
+}
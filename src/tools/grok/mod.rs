@@ -1,4 +1,5 @@
 pub mod config;
+pub mod extract;
 pub mod prompts;
 pub mod provider;
 pub mod tools;
@@ -43,6 +43,97 @@ pub fn format_search_results(results: &[SearchResult]) -> String {
     formatted.join("\n\n---\n\n")
 }
 
+/// Language used for the fixed fetch instructions around the page content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputLanguage {
+    #[default]
+    Chinese,
+    English,
+}
+
+impl OutputLanguage {
+    /// Parse a `GROK_OUTPUT_LANGUAGE` value such as "en", "english", "zh" or "chinese"
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        match value.split(['-', '_']).next().unwrap_or_default() {
+            "en" | "english" => Some(Self::English),
+            "zh" | "chinese" | "cn" => Some(Self::Chinese),
+            _ => None,
+        }
+    }
+
+    fn prompt_label(self) -> &'static str {
+        match self {
+            Self::Chinese => "中文",
+            Self::English => "English",
+        }
+    }
+}
+
+/// System prompt for web_fetch with the instruction language set to `language`
+pub fn fetch_system_prompt(language: OutputLanguage) -> String {
+    FETCH_PROMPT.replacen(
+        &format!("- **Language**: {}", OutputLanguage::Chinese.prompt_label()),
+        &format!("- **Language**: {}", language.prompt_label()),
+        1,
+    )
+}
+
+/// User message asking the model to fetch `url`, phrased in `language`
+pub fn fetch_user_content(url: &str, language: OutputLanguage) -> String {
+    match language {
+        OutputLanguage::Chinese => format!("{}\n获取该网页内容并返回其结构化Markdown格式", url),
+        OutputLanguage::English => format!(
+            "{}\nFetch the content of this web page and return it as structured Markdown",
+            url
+        ),
+    }
+}
+
+/// User message asking the model for only the metadata of `url`, phrased in `language`
+pub fn fetch_metadata_user_content(url: &str, language: OutputLanguage) -> String {
+    match language {
+        OutputLanguage::Chinese => format!("{}\n仅获取该网页的元数据并以JSON对象返回", url),
+        OutputLanguage::English => format!(
+            "{}\nFetch only the metadata of this web page and return it as a JSON object",
+            url
+        ),
+    }
+}
+
+/// Fields requested by [`FETCH_METADATA_PROMPT`], in output order
+pub const FETCH_METADATA_FIELDS: [&str; 5] = [
+    "title",
+    "description",
+    "author",
+    "published_date",
+    "canonical_url",
+];
+
+pub const FETCH_METADATA_PROMPT: &str = r#"
+# Profile: Web Metadata Extractor
+
+- **Role**: Fetch the given URL and report only the page's metadata. Do not extract, summarize or convert the page body.
+
+## Output
+
+Return exactly one JSON object and nothing else (no Markdown, no code fences, no commentary):
+
+{
+  "title": "<page title>",
+  "description": "<meta description or og:description>",
+  "author": "<author name>",
+  "published_date": "<ISO 8601 date of publication>",
+  "canonical_url": "<rel=canonical or og:url, otherwise the fetched URL>"
+}
+
+## Rules
+
+- Read the values from the page's <title>, <meta>, Open Graph, JSON-LD and <link rel="canonical"> tags before falling back to the visible page.
+- Use null for any field the page does not provide; never guess.
+- Keep values as they appear on the page, without translation.
+"#;
+
 pub const FETCH_PROMPT: &str = r#"
 # Profile: Web Content Fetcher
 
@@ -262,6 +353,71 @@ pub const SEARCH_PROMPT: &str = r#"
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_output_language_parse() {
+        assert_eq!(OutputLanguage::parse("en"), Some(OutputLanguage::English));
+        assert_eq!(
+            OutputLanguage::parse(" English "),
+            Some(OutputLanguage::English)
+        );
+        assert_eq!(
+            OutputLanguage::parse("en-US"),
+            Some(OutputLanguage::English)
+        );
+        assert_eq!(
+            OutputLanguage::parse("zh_CN"),
+            Some(OutputLanguage::Chinese)
+        );
+        assert_eq!(OutputLanguage::parse("klingon"), None);
+        assert_eq!(OutputLanguage::default(), OutputLanguage::Chinese);
+    }
+
+    #[test]
+    fn test_fetch_templates_default_to_chinese() {
+        let prompt = fetch_system_prompt(OutputLanguage::Chinese);
+        assert_eq!(prompt, FETCH_PROMPT);
+        let content = fetch_user_content("https://example.com", OutputLanguage::Chinese);
+        assert_eq!(
+            content,
+            "https://example.com\n获取该网页内容并返回其结构化Markdown格式"
+        );
+    }
+
+    #[test]
+    fn test_fetch_templates_english() {
+        let prompt = fetch_system_prompt(OutputLanguage::English);
+        assert!(prompt.contains("- **Language**: English"));
+        assert!(!prompt.contains("- **Language**: 中文"));
+
+        let content = fetch_user_content("https://example.com", OutputLanguage::English);
+        assert!(content.starts_with("https://example.com\n"));
+        assert!(content.contains("structured Markdown"));
+        assert!(!content.contains("获取"));
+    }
+
+    #[test]
+    fn test_fetch_metadata_templates() {
+        for field in FETCH_METADATA_FIELDS {
+            assert!(
+                FETCH_METADATA_PROMPT.contains(&format!("\"{}\"", field)),
+                "prompt is missing {}",
+                field
+            );
+        }
+        assert!(FETCH_METADATA_PROMPT.contains("Do not extract"));
+
+        let content = fetch_metadata_user_content("https://example.com", OutputLanguage::English);
+        assert!(content.starts_with("https://example.com\n"));
+        assert!(content.contains("only the metadata"));
+        assert!(!content.contains("Markdown"));
+
+        let content = fetch_metadata_user_content("https://example.com", OutputLanguage::Chinese);
+        assert_eq!(
+            content,
+            "https://example.com\n仅获取该网页的元数据并以JSON对象返回"
+        );
+    }
+
     #[test]
     fn test_format_search_results_empty() {
         let results: Vec<SearchResult> = vec![];
@@ -5,7 +5,14 @@ use reqwest::Client;
 use std::time::Duration;
 
 use super::config::Config;
-use super::prompts::{FETCH_PROMPT, SEARCH_PROMPT};
+use super::prompts::{
+    fetch_metadata_user_content, fetch_system_prompt, fetch_user_content, OutputLanguage,
+    FETCH_METADATA_PROMPT, SEARCH_PROMPT,
+};
+use crate::shared::{
+    acquire_api_token, http_client_builder, shared_http_client, truncate_str, truncate_with_marker,
+    truncation_marker, MAX_TIMEOUT_SECS, MIN_TIMEOUT_SECS,
+};
 
 /// Chinese time-related keywords
 const CN_TIME_KEYWORDS: &[&str] = &[
@@ -83,6 +90,12 @@ pub fn needs_time_context(query: &str) -> bool {
 }
 
 /// Get local time info string for injection into queries
+/// Whether to prepend the current time to a search: `time_context` when given,
+/// otherwise keyword detection via [`needs_time_context`]
+fn should_inject_time_context(query: &str, time_context: Option<bool>) -> bool {
+    time_context.unwrap_or_else(|| needs_time_context(query))
+}
+
 fn get_local_time_info() -> String {
     let now = Local::now();
     let weekdays_cn = [
@@ -105,6 +118,75 @@ fn get_local_time_info() -> String {
     )
 }
 
+/// Split the `platform` argument into individual platform names.
+/// Accepts a single platform or a comma-separated list ("GitHub,Reddit").
+fn parse_platforms(platform: &str) -> Vec<String> {
+    platform
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Build the platform clause appended to the search prompt.
+/// The literal "all" explicitly broadens the search to every platform.
+fn build_platform_prompt(platform: &str) -> String {
+    let platforms = parse_platforms(platform);
+    if platforms.is_empty() {
+        return String::new();
+    }
+
+    if platforms.iter().any(|p| p.eq_ignore_ascii_case("all")) {
+        return "\n\nYou should search the web broadly across all platforms and sources, without restricting to any particular platform.".to_string();
+    }
+
+    if platforms.len() == 1 {
+        format!(
+            "\n\nYou should search the web for the information you need, and focus on these platform: {}",
+            platforms[0]
+        )
+    } else {
+        format!(
+            "\n\nYou should search the web for the information you need, and focus on these platforms: {}. Cover each of them in the results.",
+            platforms.join(", ")
+        )
+    }
+}
+
+/// Whether `platform` restricts the search to specific platforms (anything but empty or "all")
+fn is_platform_constrained(platform: &str) -> bool {
+    let platforms = parse_platforms(platform);
+    !platforms.is_empty() && !platforms.iter().any(|p| p.eq_ignore_ascii_case("all"))
+}
+
+/// Build the result-count clause appended to the search prompt.
+/// Platform-restricted searches have fewer genuine hits, so the minimum is scaled
+/// by `platform_factor` and the model is told to return fewer rather than invent results.
+fn build_return_prompt(
+    min_results: i32,
+    max_results: i32,
+    platform_constrained: bool,
+    platform_factor: f64,
+) -> String {
+    if max_results <= 0 {
+        return String::new();
+    }
+
+    let relaxed_min = (f64::from(min_results.max(0)) * platform_factor).ceil() as i32;
+    if !platform_constrained || relaxed_min >= min_results {
+        return format!(
+            "\n\nYou should return the results in a JSON format, and the results should at least be {} and at most be {} results.",
+            min_results, max_results
+        );
+    }
+
+    format!(
+        "\n\nYou should return the results in a JSON format, and the results should at least be {} and at most be {} results. Because the search is restricted to specific platforms, the minimum is relaxed from {}: if fewer genuine results exist, return fewer rather than inventing results.",
+        relaxed_min, max_results, min_results
+    )
+}
+
 /// Check if an HTTP status code is retryable
 fn is_retryable_status(status: u16) -> bool {
     RETRYABLE_STATUS_CODES.contains(&status)
@@ -129,66 +211,671 @@ fn parse_retry_after(header_value: &str) -> Option<f64> {
     None
 }
 
-/// Calculate exponential backoff with jitter
+/// Calculate exponential backoff with random jitter, or none when GROK_RETRY_NO_JITTER is set
 fn exponential_backoff_with_jitter(attempt: u32, multiplier: f64, max_wait: u64) -> f64 {
-    let mut rng = rand::thread_rng();
+    let jitter_fraction = if Config::retry_no_jitter() {
+        0.0
+    } else {
+        rand::thread_rng().gen_range(0.0..1.0)
+    };
+    exponential_backoff(attempt, multiplier, max_wait, jitter_fraction)
+}
+
+/// Backoff of `multiplier * 2^attempt` plus `jitter_fraction` (0..1) of that base,
+/// capped at `max_wait` seconds
+fn exponential_backoff(attempt: u32, multiplier: f64, max_wait: u64, jitter_fraction: f64) -> f64 {
     let base = multiplier * (2.0_f64.powi(attempt as i32));
-    let jitter = rng.gen_range(0.0..base);
-    let wait = base + jitter;
+    let wait = base + base * jitter_fraction;
     wait.min(max_wait as f64)
 }
 
+/// A source the provider attached to its answer
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Citation {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// Collect the citations carried by a chat completion chunk or response: a
+/// top-level `citations` array (HTTP(S) URL strings or objects with a `url`)
+/// and the `annotations` of the first choice's delta or message (`url_citation`
+/// entries). URLs already in `citations` are skipped, only filling in a
+/// missing title, since some providers repeat the full list in every chunk.
+fn collect_citations(data: &serde_json::Value, citations: &mut Vec<Citation>) {
+    let first_choice = data
+        .get("choices")
+        .and_then(|c| c.as_array())
+        .and_then(|c| c.first());
+    let annotations = first_choice.into_iter().flat_map(|choice| {
+        ["delta", "message"]
+            .into_iter()
+            .filter_map(move |key| choice.get(key)?.get("annotations")?.as_array())
+    });
+    let entries = data
+        .get("citations")
+        .and_then(|c| c.as_array())
+        .into_iter()
+        .chain(annotations)
+        .flatten();
+
+    for entry in entries {
+        let (url, title) = match entry {
+            serde_json::Value::String(url) if url.starts_with("http") => (url.as_str(), None),
+            serde_json::Value::Object(obj) => {
+                let inner = obj.get("url_citation").unwrap_or(entry);
+                let Some(url) = inner.get("url").and_then(|u| u.as_str()) else {
+                    continue;
+                };
+                (url, inner.get("title").and_then(|t| t.as_str()))
+            }
+            _ => continue,
+        };
+        if url.is_empty() {
+            continue;
+        }
+        match citations.iter_mut().find(|c| c.url == url) {
+            Some(existing) => {
+                if existing.title.is_none() {
+                    existing.title = title.map(str::to_string);
+                }
+            }
+            None => citations.push(Citation {
+                url: url.to_string(),
+                title: title.map(str::to_string),
+            }),
+        }
+    }
+}
+
+/// Longest SSE line buffered while waiting for its newline; longer lines are
+/// dropped so a body without newlines cannot grow memory without bound
+const MAX_SSE_LINE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Splits the raw SSE byte stream into lines, keeping bytes (not chars) until a
+/// line is complete so multi-byte characters split across chunks decode intact.
+/// Lines longer than `max_line` are dropped, including the rest of the line that
+/// arrives after the limit is hit.
+struct SseLineBuffer {
+    buf: Vec<u8>,
+    max_line: usize,
+    /// Skipping the remainder of an over-long line until its newline
+    discarding: bool,
+    dropped_lines: usize,
+}
+
+impl SseLineBuffer {
+    fn new(max_line: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_line,
+            discarding: false,
+            dropped_lines: 0,
+        }
+    }
+
+    fn push(&mut self, mut chunk: &[u8]) {
+        if self.discarding {
+            match chunk.iter().position(|&b| b == b'\n') {
+                Some(pos) => {
+                    self.discarding = false;
+                    chunk = &chunk[pos + 1..];
+                }
+                None => return,
+            }
+        }
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Next complete line, including its newline
+    fn next_line(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.buf.iter().position(|&b| b == b'\n') {
+                Some(pos) if pos > self.max_line => {
+                    self.buf.drain(..=pos);
+                    self.dropped_lines += 1;
+                }
+                Some(pos) => return Some(self.buf.drain(..=pos).collect()),
+                None => {
+                    if self.buf.len() > self.max_line {
+                        self.buf = Vec::new();
+                        self.discarding = true;
+                        self.dropped_lines += 1;
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Last line if the stream ended without a trailing newline
+    fn finish(self) -> Option<Vec<u8>> {
+        (!self.discarding && !self.buf.is_empty()).then_some(self.buf)
+    }
+}
+
+/// Incremental SSE line processor used by `parse_streaming_response`.
+///
+/// Raw body lines are only needed for the non-streaming fallback and debug
+/// output, so once `content` grows past `retain_limit` bytes they are dropped
+/// and no longer collected; `content` keeps accumulating regardless, up to
+/// `max_content` bytes, where it is cut, marked and `finished` is set.
+struct SseAccumulator {
+    content: String,
+    full_body_lines: Vec<String>,
+    citations: Vec<Citation>,
+    lines_received: usize,
+    retain_limit: usize,
+    max_content: usize,
+    truncated: bool,
+    finished: bool,
+}
+
+impl SseAccumulator {
+    fn new(retain_limit: usize) -> Self {
+        Self {
+            content: String::new(),
+            full_body_lines: Vec::new(),
+            citations: Vec::new(),
+            lines_received: 0,
+            retain_limit,
+            max_content: usize::MAX,
+            truncated: false,
+            finished: false,
+        }
+    }
+
+    /// Stop accumulating once `content` reaches `max_content` bytes
+    fn with_max_content(mut self, max_content: usize) -> Self {
+        self.max_content = max_content;
+        self
+    }
+
+    /// Append a delta, cutting it at `max_content` and finishing the stream there
+    fn push_content(&mut self, delta: &str) {
+        let room = self.max_content.saturating_sub(self.content.len());
+        if delta.len() <= room {
+            self.content.push_str(delta);
+            return;
+        }
+        self.content.push_str(truncate_str(delta, room));
+        self.content.push_str(&truncation_marker());
+        self.truncated = true;
+        self.finished = true;
+    }
+
+    /// Process a single SSE line, appending any delta content.
+    /// Sets `finished` on `data: [DONE]` or a non-null `finish_reason`.
+    fn push_line(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+
+        self.lines_received += 1;
+        if self.content.len() <= self.retain_limit {
+            self.full_body_lines.push(line.to_string());
+        } else if !self.full_body_lines.is_empty() {
+            self.full_body_lines = Vec::new();
+        }
+
+        // Handle SSE "data: {...}" and "data:{...}" formats
+        let Some(data_str) = line.strip_prefix("data:") else {
+            return;
+        };
+        let data_str = data_str.trim();
+        if data_str == "[DONE]" {
+            self.finished = true;
+            return;
+        }
+
+        if let Ok(data) = serde_json::from_str::<serde_json::Value>(data_str) {
+            collect_citations(&data, &mut self.citations);
+            if let Some(first) = data
+                .get("choices")
+                .and_then(|c| c.as_array())
+                .and_then(|c| c.first())
+            {
+                if let Some(delta_content) = first
+                    .get("delta")
+                    .and_then(|d| d.get("content"))
+                    .and_then(|c| c.as_str())
+                {
+                    self.push_content(delta_content);
+                    if self.truncated {
+                        return;
+                    }
+                }
+                // Check for finish_reason (some proxies don't send [DONE])
+                if first
+                    .get("finish_reason")
+                    .and_then(|v| v.as_str())
+                    .is_some()
+                {
+                    self.finished = true;
+                }
+            }
+        }
+    }
+}
+
+/// Bytes of an HTTP error body quoted in error messages
+const ERROR_BODY_PREVIEW_BYTES: usize = 100;
+
+/// Upper bound on the body returned by a raw fetch
+const MAX_RAW_FETCH_BYTES: usize = 10 * 1024 * 1024;
+
+/// Maximum redirects followed by the HTTP client
+const MAX_REDIRECTS: usize = 10;
+
+/// HTTP client settings shared by Grok API calls and raw fetches
+fn http_builder(redirect: reqwest::redirect::Policy) -> reqwest::ClientBuilder {
+    http_client_builder()
+        .connect_timeout(Duration::from_secs(6))
+        .read_timeout(Duration::from_secs(120))
+        .redirect(redirect)
+}
+
+#[cfg(test)]
+fn build_http_client(redirect: reqwest::redirect::Policy) -> Client {
+    http_builder(redirect).build().unwrap()
+}
+
+/// Client for Grok API calls, shared by every provider
+fn api_http_client() -> reqwest::Result<Client> {
+    static CLIENT: std::sync::OnceLock<Client> = std::sync::OnceLock::new();
+    shared_http_client(&CLIENT, || {
+        http_builder(reqwest::redirect::Policy::limited(MAX_REDIRECTS)).build()
+    })
+}
+
+/// Reject URLs a raw fetch must not reach: non-HTTP(S) schemes, `localhost`,
+/// and loopback, private, link-local or unspecified IP literals.
+/// Hostnames are checked after resolution by [`PublicAddressResolver`].
+pub fn check_fetch_url(url: &reqwest::Url) -> Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!(
+            "Only http and https URLs can be fetched, got '{}'",
+            url.scheme()
+        );
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("URL has no host: {}", url))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let blocked = match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => is_local_ip(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_ascii_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+    };
+    if blocked {
+        anyhow::bail!("Refusing to fetch local or private address: {}", url);
+    }
+    Ok(())
+}
+
+fn is_local_ip(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(ip) => is_local_ipv4(ip),
+        std::net::IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || ip.to_ipv4_mapped().is_some_and(is_local_ipv4)
+        }
+    }
+}
+
+fn is_local_ipv4(ip: std::net::Ipv4Addr) -> bool {
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+}
+
+/// DNS resolver for direct fetches that refuses names resolving to any local or
+/// private address, so a public hostname pointing at 127.0.0.1 or a metadata
+/// endpoint cannot bypass [`check_fetch_url`]. Used for every connection the
+/// client makes, redirect targets included.
+struct PublicAddressResolver;
+
+impl reqwest::dns::Resolve for PublicAddressResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<std::net::SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| is_local_ip(addr.ip())) {
+                return Err(format!(
+                    "Refusing to fetch {}: it resolves to local or private address {}",
+                    host,
+                    addr.ip()
+                )
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Result of a HEAD check on a URL
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct HeadInfo {
+    /// URL after following redirects
+    pub url: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_length: Option<u64>,
+    /// Whether `content_type` names a binary (non-text) format
+    pub binary: bool,
+}
+
+/// Whether a Content-Type names a binary format. Text types, JSON, XML, JavaScript
+/// and feed formats are not binary; a missing type is treated as unknown, not binary.
+pub fn is_binary_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if mime.is_empty() || mime.starts_with("text/") {
+        return false;
+    }
+    let textual = [
+        "application/json",
+        "application/xml",
+        "application/javascript",
+        "application/ecmascript",
+        "application/x-www-form-urlencoded",
+        "image/svg+xml",
+    ];
+    if textual.contains(&mime.as_str()) {
+        return false;
+    }
+    !(mime.ends_with("+json") || mime.ends_with("+xml"))
+}
+
+/// Client for direct fetches: every redirect target must also pass [`check_fetch_url`],
+/// and every resolved address must pass [`PublicAddressResolver`]
+fn checked_http_client() -> reqwest::Result<Client> {
+    static CLIENT: std::sync::OnceLock<Client> = std::sync::OnceLock::new();
+    shared_http_client(&CLIENT, || {
+        let redirect = reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match check_fetch_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e.to_string()),
+            }
+        });
+        http_builder(redirect)
+            .dns_resolver(std::sync::Arc::new(PublicAddressResolver))
+            .build()
+    })
+}
+
+/// Send a HEAD request and report status, content type and length, without
+/// downloading the body. Subject to the same URL and resolved-address checks as
+/// [`fetch_raw`], on the first request and on every redirect.
+pub async fn fetch_head(url: &str, total_timeout_secs: u64) -> Result<HeadInfo> {
+    let url = reqwest::Url::parse(url).with_context(|| format!("Invalid URL: {}", url))?;
+    check_fetch_url(&url)?;
+    let client = checked_http_client().context("Failed to build HTTP client")?;
+    fetch_head_with_client(&client, url, total_timeout_secs).await
+}
+
+async fn fetch_head_with_client(
+    client: &Client,
+    url: reqwest::Url,
+    total_timeout_secs: u64,
+) -> Result<HeadInfo> {
+    let request = client.head(url.clone()).send();
+    let response = tokio::time::timeout(Duration::from_secs(total_timeout_secs), request)
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "HEAD request timed out after {} seconds",
+                total_timeout_secs
+            )
+        })?
+        .with_context(|| format!("Failed to send HEAD request to {}", url))?;
+
+    let header = |name: reqwest::header::HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let content_type = header(reqwest::header::CONTENT_TYPE);
+    let content_length =
+        header(reqwest::header::CONTENT_LENGTH).and_then(|v| v.trim().parse::<u64>().ok());
+
+    Ok(HeadInfo {
+        url: response.url().to_string(),
+        status: response.status().as_u16(),
+        binary: content_type.as_deref().is_some_and(is_binary_content_type),
+        content_type,
+        content_length,
+    })
+}
+
+/// Fetch a URL directly and return its body unmodified, without going through the model.
+/// The URL and every redirect target must pass [`check_fetch_url`].
+pub async fn fetch_raw(url: &str, total_timeout_secs: u64) -> Result<String> {
+    let url = reqwest::Url::parse(url).with_context(|| format!("Invalid URL: {}", url))?;
+    check_fetch_url(&url)?;
+
+    let client = checked_http_client().context("Failed to build HTTP client")?;
+    fetch_raw_with_client(&client, url, total_timeout_secs).await
+}
+
+async fn fetch_raw_with_client(
+    client: &Client,
+    url: reqwest::Url,
+    total_timeout_secs: u64,
+) -> Result<String> {
+    let request = async {
+        let response = client
+            .get(url.clone())
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch {}", url))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("HTTP {} fetching {}", status, url);
+        }
+        if response
+            .content_length()
+            .is_some_and(|len| len > MAX_RAW_FETCH_BYTES as u64)
+        {
+            anyhow::bail!("Response exceeds {} byte limit", MAX_RAW_FETCH_BYTES);
+        }
+
+        // Content-Length may be absent, so enforce the cap while reading
+        let mut response = response;
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .context("Failed to read response body")?
+        {
+            if body.len() + chunk.len() > MAX_RAW_FETCH_BYTES {
+                anyhow::bail!("Response exceeds {} byte limit", MAX_RAW_FETCH_BYTES);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    };
+
+    tokio::time::timeout(Duration::from_secs(total_timeout_secs), request)
+        .await
+        .map_err(|_| anyhow::anyhow!("Fetch timed out after {} seconds", total_timeout_secs))?
+}
+
+/// Upper bound for a per-call retry override, so one call cannot retry indefinitely
+pub const MAX_RETRIES_LIMIT: u32 = 10;
+
+/// Model ids from an OpenAI-style /models response (`{"data": [{"id": ...}, ...]}`)
+fn parse_model_ids(body: &serde_json::Value) -> Vec<String> {
+    body.get("data")
+        .and_then(|d| d.as_array())
+        .map(|data| {
+            data.iter()
+                .filter_map(|m| m.get("id").and_then(|id| id.as_str()))
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A streaming response that broke before enough content arrived to be worth returning.
+/// Retried by `execute_stream_with_retry` while attempts remain.
+#[derive(Debug, thiserror::Error)]
+#[error("stream interrupted after {} bytes of content: {reason}", partial.len())]
+struct StreamInterrupted {
+    reason: String,
+    /// Content received before the break, returned if no retries remain
+    partial: String,
+}
+
+/// The API answered with a non-success HTTP status
+#[derive(Debug, thiserror::Error)]
+#[error("API request failed with HTTP {status}: {body}")]
+struct ApiStatusError {
+    status: u16,
+    body: String,
+}
+
+/// The whole request, retries included, ran past the total timeout
+#[derive(Debug, thiserror::Error)]
+#[error("Total operation timeout ({0}s) exceeded. The API server may be unresponsive.")]
+struct TotalTimeout(u64);
+
+/// Whether a failed Grok call says the backend itself is unhealthy: a transport
+/// error, a timeout, an interrupted stream or a 5xx. Errors the caller can cause,
+/// such as an invalid URL or a 4xx, return false.
+pub fn is_backend_failure(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<ApiStatusError>() {
+            return e.status >= 500;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(status) => status.is_server_error(),
+                None => !e.is_builder(),
+            };
+        }
+        cause.is::<TotalTimeout>() || cause.is::<StreamInterrupted>()
+    })
+}
+
+/// Called with the content accumulated so far each time a stream delivers more.
+/// A retried request starts over, so the content may shrink between calls.
+pub type ContentProgress = std::sync::Arc<dyn Fn(&str) + Send + Sync>;
+
 pub struct GrokSearchProvider {
     client: Client,
     api_url: String,
     api_key: String,
     model: String,
+    total_timeout_secs: u64,
+    max_retries: u32,
+    /// Partial content at or above this size is returned when the stream breaks
+    stream_retry_min_bytes: usize,
+    content_progress: Option<ContentProgress>,
+    /// HTTP status of the most recent chat completion response (0 before any)
+    last_status: std::sync::atomic::AtomicU16,
+    /// Citations of the most recent successfully parsed response
+    last_citations: std::sync::Mutex<Vec<Citation>>,
 }
 
 impl GrokSearchProvider {
-    pub fn new(api_url: String, api_key: String, model: String) -> Self {
-        let client = Client::builder()
-            .connect_timeout(Duration::from_secs(6))
-            .read_timeout(Duration::from_secs(120))
-            .redirect(reqwest::redirect::Policy::limited(10))
-            .build()
-            .unwrap_or_default();
+    pub fn new(api_url: String, api_key: String, model: String) -> Result<Self> {
+        let client = api_http_client().context("Failed to build HTTP client")?;
 
-        Self {
+        Ok(Self {
             client,
             api_url,
             api_key,
             model,
+            total_timeout_secs: Config::total_timeout(),
+            max_retries: Config::retry_max_attempts(),
+            stream_retry_min_bytes: Config::stream_retry_min_bytes(),
+            content_progress: None,
+            last_status: std::sync::atomic::AtomicU16::new(0),
+            last_citations: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+
+    /// HTTP status of the most recent chat completion response, if one was received
+    pub fn last_http_status(&self) -> Option<u16> {
+        match self.last_status.load(std::sync::atomic::Ordering::Relaxed) {
+            0 => None,
+            status => Some(status),
         }
     }
 
+    /// Citations the provider attached to the most recent response
+    pub fn last_citations(&self) -> Vec<Citation> {
+        self.last_citations.lock().unwrap().clone()
+    }
+
+    /// Report streamed content to `progress` as it arrives
+    pub fn with_content_progress(mut self, progress: Option<ContentProgress>) -> Self {
+        self.content_progress = progress;
+        self
+    }
+
+    /// Override the total operation timeout (GROK_TOTAL_TIMEOUT) for this provider,
+    /// clamped to [`MIN_TIMEOUT_SECS`]..=[`MAX_TIMEOUT_SECS`]
+    pub fn with_total_timeout(mut self, timeout_secs: Option<u64>) -> Self {
+        if let Some(secs) = timeout_secs {
+            self.total_timeout_secs = secs.clamp(MIN_TIMEOUT_SECS, MAX_TIMEOUT_SECS);
+        }
+        self
+    }
+
+    /// Override the maximum retry attempts (GROK_RETRY_MAX_ATTEMPTS) for this provider,
+    /// capped at [`MAX_RETRIES_LIMIT`]
+    pub fn with_max_retries(mut self, max_retries: Option<u32>) -> Self {
+        if let Some(retries) = max_retries {
+            self.max_retries = retries.min(MAX_RETRIES_LIMIT);
+        }
+        self
+    }
+
     /// Perform a web search via the Grok API
+    /// `time_context` forces the current-time preamble on or off; `None` detects
+    /// time-sensitive queries by keyword.
     pub async fn search(
         &self,
         query: &str,
         platform: &str,
         min_results: i32,
         max_results: i32,
+        time_context: Option<bool>,
     ) -> Result<String> {
-        let mut platform_prompt = String::new();
-        let mut return_prompt = String::new();
-
-        if !platform.is_empty() {
-            platform_prompt = format!(
-                "\n\nYou should search the web for the information you need, and focus on these platform: {}",
-                platform
-            );
-        }
-
-        if max_results > 0 {
-            return_prompt = format!(
-                "\n\nYou should return the results in a JSON format, and the results should at least be {} and at most be {} results.",
-                min_results, max_results
-            );
-        }
+        let platform_prompt = build_platform_prompt(platform);
+        let return_prompt = build_return_prompt(
+            min_results,
+            max_results,
+            is_platform_constrained(platform),
+            Config::platform_min_results_factor(),
+        );
 
-        // Inject time context only when query contains time-related keywords
-        let time_context = if needs_time_context(query) {
+        // Inject time context when forced, or when the query contains time-related keywords
+        let time_context = if should_inject_time_context(query, time_context) {
             get_local_time_info() + "\n"
         } else {
             String::new()
@@ -222,15 +909,37 @@ impl GrokSearchProvider {
     }
 
     /// Fetch a URL's content via the Grok API
-    pub async fn fetch(&self, url: &str) -> Result<String> {
-        let user_content = format!("{}\n获取该网页内容并返回其结构化Markdown格式", url);
+    pub async fn fetch(&self, url: &str, metadata_only: bool) -> Result<String> {
+        let payload = self.fetch_payload(url, metadata_only, Config::output_language());
+        self.execute_stream_with_retry(&payload).await
+    }
 
-        let payload = serde_json::json!({
+    /// Chat payload for a fetch; `metadata_only` swaps the Markdown extraction
+    /// prompt for one asking only for the page's metadata as JSON
+    fn fetch_payload(
+        &self,
+        url: &str,
+        metadata_only: bool,
+        language: OutputLanguage,
+    ) -> serde_json::Value {
+        let (system_prompt, user_content) = if metadata_only {
+            (
+                FETCH_METADATA_PROMPT.to_string(),
+                fetch_metadata_user_content(url, language),
+            )
+        } else {
+            (
+                fetch_system_prompt(language),
+                fetch_user_content(url, language),
+            )
+        };
+
+        serde_json::json!({
             "model": self.model,
             "messages": [
                 {
                     "role": "system",
-                    "content": FETCH_PROMPT,
+                    "content": system_prompt,
                 },
                 {
                     "role": "user",
@@ -238,25 +947,54 @@ impl GrokSearchProvider {
                 },
             ],
             "stream": true,
-        });
-
-        self.execute_stream_with_retry(&payload).await
+        })
     }
 
-    /// Test API connection by calling /models endpoint
-    pub async fn test_connection(&self) -> Result<serde_json::Value> {
+    /// Request for the /models endpoint
+    fn models_request(&self, timeout: Duration) -> reqwest::RequestBuilder {
         let models_url = format!("{}/models", self.api_url.trim_end_matches('/'));
-        let start = std::time::Instant::now();
-
-        let response = self
-            .client
+        self.client
             .get(&models_url)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .timeout(Duration::from_secs(10))
+            .timeout(timeout)
+    }
+
+    /// GET the /models endpoint
+    async fn get_models(&self, timeout: Duration) -> Result<reqwest::Response> {
+        acquire_api_token().await;
+        self.models_request(timeout)
             .send()
             .await
-            .context("Failed to connect to API")?;
+            .context("Failed to connect to API")
+    }
+
+    /// List the model ids offered by the provider's /models endpoint
+    pub async fn list_models(&self) -> Result<Vec<String>> {
+        let response = self.get_models(Duration::from_secs(10)).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!(
+                "Failed to list models: HTTP {}: {}",
+                status.as_u16(),
+                truncate_with_marker(&body, ERROR_BODY_PREVIEW_BYTES, &truncation_marker())
+            );
+        }
+
+        let body: serde_json::Value = response
+            .json()
+            .await
+            .context("Failed to parse /models response")?;
+        Ok(parse_model_ids(&body))
+    }
+
+    /// Test API connection by calling /models endpoint
+    pub async fn test_connection(&self) -> Result<serde_json::Value> {
+        let start = std::time::Instant::now();
+
+        let timeout = Duration::from_secs(Config::connection_test_timeout());
+        let response = self.get_models(timeout).await?;
 
         let response_time = start.elapsed().as_millis();
         let status = response.status();
@@ -276,10 +1014,7 @@ impl GrokSearchProvider {
                     status.as_u16(),
                     model_count
                 ));
-                let model_names: Vec<&str> = data
-                    .iter()
-                    .filter_map(|m| m.get("id").and_then(|id| id.as_str()))
-                    .collect();
+                let model_names = parse_model_ids(&body);
                 if !model_names.is_empty() {
                     result["available_models"] = serde_json::json!(model_names);
                 }
@@ -290,7 +1025,11 @@ impl GrokSearchProvider {
             let body = response.text().await.unwrap_or_default();
             Ok(serde_json::json!({
                 "status": "⚠️ Connection error",
-                "message": format!("HTTP {}: {}", status.as_u16(), &body[..body.len().min(100)]),
+                "message": format!(
+                    "HTTP {}: {}",
+                    status.as_u16(),
+                    truncate_with_marker(&body, ERROR_BODY_PREVIEW_BYTES, &truncation_marker())
+                ),
                 "response_time_ms": response_time,
             }))
         }
@@ -299,11 +1038,12 @@ impl GrokSearchProvider {
     /// Parse SSE streaming response, extracting content from delta chunks.
     /// Uses `response.chunk()` to read incrementally, avoiding hangs on keep-alive connections.
     /// Terminates on `data: [DONE]`, `finish_reason` != null, idle timeout, or connection close.
+    /// A read error or idle timeout before `stream_retry_min_bytes` of content has arrived
+    /// fails with [`StreamInterrupted`] so the request can be retried.
     async fn parse_streaming_response(&self, response: reqwest::Response) -> Result<String> {
-        let mut content = String::new();
-        let mut line_buf = String::new();
-        let mut full_body_lines: Vec<String> = Vec::new();
-        let mut finished = false;
+        let mut acc = SseAccumulator::new(Config::sse_retain_limit())
+            .with_max_content(Config::max_response_bytes());
+        let mut line_buf = SseLineBuffer::new(MAX_SSE_LINE_BYTES);
         let idle_timeout_secs = Config::idle_timeout();
         let stream_timeout_secs = Config::stream_timeout();
         let stream_start = tokio::time::Instant::now();
@@ -323,7 +1063,7 @@ impl GrokSearchProvider {
                 eprintln!(
                     "[grok] Stream timeout ({}s) exceeded, aborting. Content so far: {} bytes",
                     stream_timeout_secs,
-                    content.len()
+                    acc.content.len()
                 );
                 break;
             }
@@ -343,113 +1083,93 @@ impl GrokSearchProvider {
                 }
                 Ok(Err(e)) => {
                     // Network/read error
-                    if !content.is_empty() {
-                        eprintln!("[grok] Read error after receiving {} bytes of content, using partial result: {}", content.len(), e);
+                    if !acc.content.is_empty() && acc.content.len() >= self.stream_retry_min_bytes {
+                        eprintln!("[grok] Read error after receiving {} bytes of content, using partial result: {}", acc.content.len(), e);
                         break;
                     }
-                    return Err(e).context("Failed to read SSE chunk");
+                    return Err(StreamInterrupted {
+                        reason: format!("failed to read SSE chunk: {}", e),
+                        partial: acc.content,
+                    }
+                    .into());
                 }
                 Err(_) => {
                     // Idle timeout — no chunk received within idle_timeout_secs
                     eprintln!(
                         "[grok] Idle timeout ({}s) — no data received. Content so far: {} bytes",
                         idle_timeout_secs,
-                        content.len()
+                        acc.content.len()
                     );
-                    if !content.is_empty() {
-                        // We have partial content, use it
+                    if !acc.content.is_empty() && acc.content.len() >= self.stream_retry_min_bytes {
+                        // We have substantial partial content, use it
                         break;
                     }
-                    anyhow::bail!(
-                        "Stream idle timeout: no data received for {}s",
-                        idle_timeout_secs
-                    );
+                    return Err(StreamInterrupted {
+                        reason: format!(
+                            "stream idle timeout: no data received for {}s",
+                            idle_timeout_secs
+                        ),
+                        partial: acc.content,
+                    }
+                    .into());
                 }
             };
 
-            let chunk_str = String::from_utf8_lossy(&chunk);
-            line_buf.push_str(&chunk_str);
+            line_buf.push(&chunk);
 
             // Process complete lines from the buffer
-            while let Some(newline_pos) = line_buf.find('\n') {
-                let line = line_buf[..newline_pos].trim().to_string();
-                line_buf = line_buf[newline_pos + 1..].to_string();
-
-                if line.is_empty() {
-                    continue;
+            let content_len = acc.content.len();
+            while let Some(line_bytes) = line_buf.next_line() {
+                acc.push_line(&String::from_utf8_lossy(&line_bytes));
+                if acc.finished {
+                    break;
                 }
-
-                full_body_lines.push(line.clone());
-
-                // Handle SSE "data: {...}" and "data:{...}" formats
-                if let Some(data_str) = line.strip_prefix("data:") {
-                    let data_str = data_str.trim();
-                    if data_str == "[DONE]" {
-                        finished = true;
-                        break;
-                    }
-
-                    if let Ok(data) = serde_json::from_str::<serde_json::Value>(data_str) {
-                        if let Some(choices) = data.get("choices").and_then(|c| c.as_array()) {
-                            if let Some(first) = choices.first() {
-                                if let Some(delta_content) = first
-                                    .get("delta")
-                                    .and_then(|d| d.get("content"))
-                                    .and_then(|c| c.as_str())
-                                {
-                                    content.push_str(delta_content);
-                                }
-                                // Check for finish_reason (some proxies don't send [DONE])
-                                if first
-                                    .get("finish_reason")
-                                    .and_then(|v| v.as_str())
-                                    .is_some()
-                                {
-                                    finished = true;
-                                    break;
-                                }
-                            }
-                        }
-                    }
+            }
+            if acc.content.len() > content_len {
+                if let Some(ref progress) = self.content_progress {
+                    progress(&acc.content);
                 }
             }
 
-            if finished {
+            if acc.finished {
                 break;
             }
         }
 
+        if line_buf.dropped_lines > 0 {
+            eprintln!(
+                "[grok] Dropped {} SSE line(s) longer than {} bytes",
+                line_buf.dropped_lines, MAX_SSE_LINE_BYTES
+            );
+        }
+        if acc.truncated {
+            eprintln!(
+                "[grok] Response exceeded {} bytes, stopped reading and truncated it",
+                acc.max_content
+            );
+        }
+
         // Process any residual data in line_buf (last line without trailing newline)
-        if !finished && !line_buf.is_empty() {
-            let line = line_buf.trim().to_string();
-            if !line.is_empty() {
-                full_body_lines.push(line.clone());
-                if let Some(data_str) = line.strip_prefix("data:") {
-                    let data_str = data_str.trim();
-                    if data_str != "[DONE]" {
-                        if let Ok(data) = serde_json::from_str::<serde_json::Value>(data_str) {
-                            if let Some(choices) = data.get("choices").and_then(|c| c.as_array())
-                            {
-                                if let Some(first) = choices.first() {
-                                    if let Some(delta_content) = first
-                                        .get("delta")
-                                        .and_then(|d| d.get("content"))
-                                        .and_then(|c| c.as_str())
-                                    {
-                                        content.push_str(delta_content);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+        if !acc.finished {
+            if let Some(rest) = line_buf.finish() {
+                acc.push_line(&String::from_utf8_lossy(&rest));
             }
         }
 
+        let SseAccumulator {
+            mut content,
+            full_body_lines,
+            mut citations,
+            lines_received,
+            finished,
+            ..
+        } = acc;
+
         // Fallback: try parsing the entire body as non-streaming JSON
         if content.is_empty() && !full_body_lines.is_empty() {
             let full_text: String = full_body_lines.join("");
             if let Ok(data) = serde_json::from_str::<serde_json::Value>(&full_text) {
+                collect_citations(&data, &mut citations);
                 if let Some(choices) = data.get("choices").and_then(|c| c.as_array()) {
                     if let Some(first) = choices.first() {
                         if let Some(msg_content) = first
@@ -480,12 +1200,12 @@ impl GrokSearchProvider {
                 "[grok] stream ended (finished={}, elapsed={:.1}s), lines: {}, content length: {}",
                 finished,
                 elapsed.as_secs_f64(),
-                full_body_lines.len(),
+                lines_received,
                 content.len()
             );
             if content.is_empty() && !full_body_lines.is_empty() {
                 for (i, l) in full_body_lines.iter().take(5).enumerate() {
-                    eprintln!("[grok] body line {}: {}", i, &l[..l.len().min(200)]);
+                    eprintln!("[grok] body line {}: {}", i, truncate_str(l, 200));
                 }
             }
         }
@@ -494,17 +1214,18 @@ impl GrokSearchProvider {
             anyhow::bail!(
                 "Empty response from API after {:.1}s ({} lines received)",
                 elapsed.as_secs_f64(),
-                full_body_lines.len()
+                lines_received
             );
         }
 
+        *self.last_citations.lock().unwrap() = citations;
         Ok(content)
     }
 
     /// Execute a streaming HTTP request with retry logic.
     /// Wrapped in a total timeout to prevent indefinite blocking.
     async fn execute_stream_with_retry(&self, payload: &serde_json::Value) -> Result<String> {
-        let total_timeout_secs = Config::total_timeout();
+        let total_timeout_secs = self.total_timeout_secs;
 
         match tokio::time::timeout(
             Duration::from_secs(total_timeout_secs),
@@ -513,12 +1234,7 @@ impl GrokSearchProvider {
         .await
         {
             Ok(result) => result,
-            Err(_) => {
-                anyhow::bail!(
-                    "Total operation timeout ({}s) exceeded. The API server may be unresponsive.",
-                    total_timeout_secs
-                );
-            }
+            Err(_) => Err(TotalTimeout(total_timeout_secs).into()),
         }
     }
 
@@ -526,7 +1242,7 @@ impl GrokSearchProvider {
         &self,
         payload: &serde_json::Value,
     ) -> Result<String> {
-        let max_attempts = Config::retry_max_attempts();
+        let max_attempts = self.max_retries;
         let multiplier = Config::retry_multiplier();
         let max_wait = Config::retry_max_wait();
         let url = format!("{}/chat/completions", self.api_url.trim_end_matches('/'));
@@ -544,6 +1260,7 @@ impl GrokSearchProvider {
                 );
             }
 
+            acquire_api_token().await;
             match self
                 .client
                 .post(&url)
@@ -555,17 +1272,47 @@ impl GrokSearchProvider {
             {
                 Ok(response) => {
                     let status = response.status();
+                    self.last_status
+                        .store(status.as_u16(), std::sync::atomic::Ordering::Relaxed);
                     if Config::debug_enabled() {
                         eprintln!("[grok] HTTP {} from {}", status.as_u16(), &url);
                     }
                     if status.is_success() {
-                        return self.parse_streaming_response(response).await;
+                        match self.parse_streaming_response(response).await {
+                            Err(e) if e.is::<StreamInterrupted>() => {
+                                if attempt == max_attempts {
+                                    let partial = e
+                                        .downcast_ref::<StreamInterrupted>()
+                                        .map(|s| s.partial.clone())
+                                        .unwrap_or_default();
+                                    if !partial.is_empty() {
+                                        eprintln!(
+                                            "[grok] {}; no retries left, using partial result",
+                                            e
+                                        );
+                                        return Ok(partial);
+                                    }
+                                    return Err(e);
+                                }
+                                let wait_secs =
+                                    exponential_backoff_with_jitter(attempt, multiplier, max_wait);
+                                eprintln!("[grok] {}, waiting {:.1}s", e, wait_secs);
+                                tokio::time::sleep(Duration::from_secs_f64(wait_secs)).await;
+                                last_error = Some(e);
+                                continue;
+                            }
+                            result => return result,
+                        }
                     }
 
                     let status_code = status.as_u16();
                     if !is_retryable_status(status_code) || attempt == max_attempts {
                         let body = response.text().await.unwrap_or_default();
-                        anyhow::bail!("API request failed with HTTP {}: {}", status_code, body);
+                        return Err(ApiStatusError {
+                            status: status_code,
+                            body,
+                        }
+                        .into());
                     }
 
                     // Check for Retry-After header on 429
@@ -630,6 +1377,26 @@ mod tests {
         assert!(!needs_time_context("rust programming tutorial"));
     }
 
+    #[test]
+    fn test_should_inject_time_context() {
+        // Auto: keyword detection
+        assert!(should_inject_time_context("latest rust release", None));
+        assert!(!should_inject_time_context(
+            "rust programming tutorial",
+            None
+        ));
+        // Forced off despite a keyword match (e.g. a proper noun)
+        assert!(!should_inject_time_context(
+            "The Latest Show cast",
+            Some(false)
+        ));
+        // Forced on without any keyword
+        assert!(should_inject_time_context(
+            "rust programming tutorial",
+            Some(true)
+        ));
+    }
+
     #[test]
     fn test_needs_time_context_mixed() {
         assert!(needs_time_context("最新 Rust release"));
@@ -647,6 +1414,87 @@ mod tests {
         assert!(info.contains("星期"));
     }
 
+    #[test]
+    fn test_build_platform_prompt_single() {
+        assert_eq!(
+            build_platform_prompt("GitHub"),
+            "\n\nYou should search the web for the information you need, and focus on these platform: GitHub"
+        );
+    }
+
+    #[test]
+    fn test_build_platform_prompt_multiple() {
+        let prompt = build_platform_prompt("GitHub, Reddit,,Twitter");
+        assert!(prompt.contains("focus on these platforms: GitHub, Reddit, Twitter."));
+    }
+
+    #[test]
+    fn test_build_platform_prompt_empty() {
+        assert_eq!(build_platform_prompt(""), "");
+        assert_eq!(build_platform_prompt(" , "), "");
+    }
+
+    #[test]
+    fn test_build_platform_prompt_all() {
+        let prompt = build_platform_prompt("all");
+        assert!(prompt.contains("across all platforms"));
+        assert_eq!(build_platform_prompt("GitHub,ALL"), prompt);
+    }
+
+    #[test]
+    fn test_build_return_prompt_without_platform() {
+        let prompt = build_return_prompt(3, 10, false, 0.5);
+        assert_eq!(
+            prompt,
+            "\n\nYou should return the results in a JSON format, and the results should at least be 3 and at most be 10 results."
+        );
+        assert_eq!(build_return_prompt(3, 0, true, 0.5), "");
+    }
+
+    #[test]
+    fn test_build_return_prompt_relaxed_for_platform() {
+        let prompt = build_return_prompt(5, 10, true, 0.5);
+        assert!(prompt.contains("at least be 3 and at most be 10 results"));
+        assert!(prompt.contains("relaxed from 5"));
+        assert!(prompt.contains("return fewer rather than inventing results"));
+
+        let prompt = build_return_prompt(3, 10, true, 0.0);
+        assert!(prompt.contains("at least be 0 and at most be 10 results"));
+    }
+
+    #[test]
+    fn test_build_return_prompt_factor_one_keeps_minimum() {
+        let prompt = build_return_prompt(3, 10, true, 1.0);
+        assert_eq!(prompt, build_return_prompt(3, 10, false, 0.5));
+    }
+
+    #[test]
+    fn test_is_platform_constrained() {
+        assert!(is_platform_constrained("GitHub"));
+        assert!(is_platform_constrained("GitHub, Reddit"));
+        assert!(!is_platform_constrained(""));
+        assert!(!is_platform_constrained(" , "));
+        assert!(!is_platform_constrained("all"));
+        assert!(!is_platform_constrained("GitHub,ALL"));
+    }
+
+    #[test]
+    fn test_is_backend_failure() {
+        let status = |status: u16| {
+            anyhow::Error::from(ApiStatusError {
+                status,
+                body: String::new(),
+            })
+        };
+        assert!(is_backend_failure(&status(502)));
+        assert!(!is_backend_failure(&status(400)));
+        assert!(!is_backend_failure(&status(401)));
+        assert!(is_backend_failure(
+            &anyhow::Error::from(TotalTimeout(60)).context("web_search failed")
+        ));
+        assert!(!is_backend_failure(&anyhow::anyhow!("Invalid URL: x")));
+    }
+
     #[test]
     fn test_is_retryable_status() {
         assert!(is_retryable_status(429));
@@ -686,6 +1534,25 @@ mod tests {
         assert!(wait <= 10.0);
     }
 
+    #[test]
+    fn test_exponential_backoff_without_jitter_is_exact() {
+        assert_eq!(exponential_backoff(0, 1.0, 10, 0.0), 1.0);
+        assert_eq!(exponential_backoff(2, 1.0, 10, 0.0), 4.0);
+        assert_eq!(exponential_backoff(3, 0.5, 10, 0.0), 4.0);
+        assert_eq!(exponential_backoff(4, 1.0, 10, 0.0), 10.0);
+        assert_eq!(exponential_backoff(2, 1.0, 10, 0.5), 6.0);
+    }
+
+    #[test]
+    fn test_exponential_backoff_no_jitter_env() {
+        std::env::set_var("GROK_RETRY_NO_JITTER", "1");
+        for attempt in 0..5 {
+            let expected = (2.0_f64.powi(attempt as i32)).min(10.0);
+            assert_eq!(exponential_backoff_with_jitter(attempt, 1.0, 10), expected);
+        }
+        std::env::remove_var("GROK_RETRY_NO_JITTER");
+    }
+
     #[test]
     fn test_exponential_backoff_respects_max() {
         for attempt in 0..10 {
@@ -699,15 +1566,704 @@ mod tests {
         }
     }
 
+    fn delta_line(text: &str) -> String {
+        format!(
+            "data: {}",
+            serde_json::json!({"choices": [{"delta": {"content": text}, "finish_reason": null}]})
+        )
+    }
+
+    #[test]
+    fn test_sse_accumulator_collects_delta_content() {
+        let mut acc = SseAccumulator::new(1024);
+        acc.push_line(&delta_line("Hello, "));
+        acc.push_line("");
+        acc.push_line(&delta_line("world"));
+        acc.push_line("data: [DONE]");
+        assert_eq!(acc.content, "Hello, world");
+        assert!(acc.finished);
+        assert_eq!(acc.lines_received, 3);
+    }
+
+    #[test]
+    fn test_sse_accumulator_finish_reason_terminates() {
+        let mut acc = SseAccumulator::new(1024);
+        acc.push_line(r#"data:{"choices":[{"delta":{"content":"x"},"finish_reason":"stop"}]}"#);
+        assert_eq!(acc.content, "x");
+        assert!(acc.finished);
+    }
+
+    #[test]
+    fn test_sse_accumulator_stops_retaining_lines_past_threshold() {
+        let mut acc = SseAccumulator::new(10);
+        acc.push_line(&delta_line("0123456789"));
+        acc.push_line(&delta_line("abc"));
+        // content is now past the threshold; retained lines are dropped
+        acc.push_line(&delta_line("def"));
+        assert!(acc.full_body_lines.is_empty());
+
+        for _ in 0..100 {
+            acc.push_line(&delta_line("more"));
+        }
+        assert!(acc.full_body_lines.is_empty());
+        assert_eq!(acc.content.len(), 10 + 3 + 3 + 400);
+        assert_eq!(acc.lines_received, 103);
+    }
+
+    #[test]
+    fn test_sse_accumulator_caps_total_content() {
+        let mut acc = SseAccumulator::new(1024).with_max_content(8);
+        acc.push_line(&delta_line("12345"));
+        assert!(!acc.finished);
+        acc.push_line(&delta_line("678910"));
+        assert!(acc.truncated);
+        assert!(acc.finished);
+        assert_eq!(acc.content, format!("12345678{}", truncation_marker()));
+
+        // A cut inside a multi-byte character keeps the string valid
+        let mut acc = SseAccumulator::new(1024).with_max_content(4);
+        acc.push_line(&delta_line("中文"));
+        assert_eq!(acc.content, format!("中{}", truncation_marker()));
+    }
+
+    #[test]
+    fn test_sse_line_buffer_splits_lines_across_chunks() {
+        let mut lines = SseLineBuffer::new(64);
+        lines.push("data: 中".as_bytes());
+        assert!(lines.next_line().is_none());
+        lines.push("文\ndata: b\nrest".as_bytes());
+        assert_eq!(lines.next_line().unwrap(), "data: 中文\n".as_bytes());
+        assert_eq!(lines.next_line().unwrap(), b"data: b\n");
+        assert!(lines.next_line().is_none());
+        assert_eq!(lines.finish().unwrap(), b"rest");
+    }
+
+    #[test]
+    fn test_sse_line_buffer_drops_over_long_lines() {
+        let mut lines = SseLineBuffer::new(8);
+        // No newline yet: the buffer stops growing once the line passes the limit
+        lines.push(b"0123456789");
+        assert!(lines.next_line().is_none());
+        assert!(lines.buf.is_empty());
+        lines.push(b"still the same line");
+        assert!(lines.buf.is_empty());
+        lines.push(b"end\nok\n");
+        assert_eq!(lines.next_line().unwrap(), b"ok\n");
+
+        // A complete over-long line in one chunk is dropped as well
+        lines.push(b"0123456789abc\nfine\n");
+        assert_eq!(lines.next_line().unwrap(), b"fine\n");
+        assert_eq!(lines.dropped_lines, 2);
+        assert!(lines.finish().is_none());
+    }
+
+    #[test]
+    fn test_sse_accumulator_retains_lines_for_non_streaming_fallback() {
+        let mut acc = SseAccumulator::new(1024);
+        acc.push_line(r#"{"choices":[{"message":"#);
+        acc.push_line(r#"{"content":"hi"}}]}"#);
+        assert!(acc.content.is_empty());
+        assert_eq!(acc.full_body_lines.len(), 2);
+    }
+
+    #[test]
+    fn test_sse_accumulator_collects_citations() {
+        let mut acc = SseAccumulator::new(1024);
+        // Top-level citations, repeated in full on every chunk
+        acc.push_line(
+            r#"data: {"citations":["https://a.example","https://b.example"],"choices":[{"delta":{"content":"x"}}]}"#,
+        );
+        acc.push_line(
+            r#"data: {"citations":["https://a.example","https://b.example"],"choices":[{"delta":{"content":"y"}}]}"#,
+        );
+        // OpenAI-style url_citation annotations on the delta
+        acc.push_line(
+            r#"data: {"choices":[{"delta":{"annotations":[{"type":"url_citation","url_citation":{"url":"https://a.example","title":"A"}},{"type":"url_citation","url_citation":{"url":"https://c.example","title":"C"}}]}}]}"#,
+        );
+        acc.push_line(r#"data: {"choices":[{"delta":{"annotations":[{"type":"file"},"bogus"]}}]}"#);
+        acc.push_line("data: [DONE]");
+
+        assert_eq!(acc.content, "xy");
+        assert_eq!(
+            acc.citations,
+            vec![
+                Citation {
+                    url: "https://a.example".to_string(),
+                    title: Some("A".to_string()),
+                },
+                Citation {
+                    url: "https://b.example".to_string(),
+                    title: None,
+                },
+                Citation {
+                    url: "https://c.example".to_string(),
+                    title: Some("C".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_citations_from_non_streaming_message() {
+        let data = serde_json::json!({
+            "choices": [{"message": {
+                "content": "answer",
+                "annotations": [{"url": "https://d.example", "title": "D"}],
+            }}],
+        });
+        let mut citations = Vec::new();
+        collect_citations(&data, &mut citations);
+        assert_eq!(citations.len(), 1);
+        assert_eq!(citations[0].url, "https://d.example");
+        assert_eq!(citations[0].title.as_deref(), Some("D"));
+    }
+
     #[test]
     fn test_grok_provider_new() {
         let provider = GrokSearchProvider::new(
             "https://api.x.ai/v1".to_string(),
             "test-key".to_string(),
             "grok-4.20-beta".to_string(),
-        );
+        )
+        .unwrap();
         assert_eq!(provider.api_url, "https://api.x.ai/v1");
         assert_eq!(provider.api_key, "test-key");
         assert_eq!(provider.model, "grok-4.20-beta");
     }
+
+    #[test]
+    fn test_fetch_payload_metadata_only() {
+        let provider = GrokSearchProvider::new(
+            "https://api.x.ai/v1".to_string(),
+            "test-key".to_string(),
+            "grok-4.20-beta".to_string(),
+        )
+        .unwrap();
+
+        let payload = provider.fetch_payload("https://example.com", true, OutputLanguage::English);
+        assert_eq!(payload["messages"][0]["content"], FETCH_METADATA_PROMPT);
+        let user = payload["messages"][1]["content"].as_str().unwrap();
+        assert!(user.contains("only the metadata"));
+
+        let payload = provider.fetch_payload("https://example.com", false, OutputLanguage::English);
+        let system = payload["messages"][0]["content"].as_str().unwrap();
+        assert!(system.contains("Web Content Fetcher"));
+        let user = payload["messages"][1]["content"].as_str().unwrap();
+        assert!(user.contains("structured Markdown"));
+    }
+
+    #[test]
+    fn test_grok_provider_per_call_overrides_env() {
+        // Env vars are left alone: other tests assert on their defaults in parallel
+        let provider = GrokSearchProvider::new(
+            "https://api.x.ai/v1".to_string(),
+            "test-key".to_string(),
+            "grok-4.20-beta".to_string(),
+        )
+        .unwrap();
+        let env_timeout = provider.total_timeout_secs;
+        let env_retries = provider.max_retries;
+
+        // None keeps the env defaults
+        let provider = provider.with_total_timeout(None).with_max_retries(None);
+        assert_eq!(provider.total_timeout_secs, env_timeout);
+        assert_eq!(provider.max_retries, env_retries);
+
+        let provider = provider
+            .with_total_timeout(Some(7))
+            .with_max_retries(Some(0));
+        assert_eq!(provider.total_timeout_secs, 7);
+        assert_eq!(provider.max_retries, 0);
+
+        let provider = provider
+            .with_max_retries(Some(1000))
+            .with_total_timeout(Some(86_400));
+        assert_eq!(provider.max_retries, MAX_RETRIES_LIMIT);
+        assert_eq!(provider.total_timeout_secs, MAX_TIMEOUT_SECS);
+
+        let provider = provider.with_total_timeout(Some(0));
+        assert_eq!(provider.total_timeout_secs, MIN_TIMEOUT_SECS);
+    }
+
+    #[test]
+    fn test_check_fetch_url() {
+        let check = |url: &str| check_fetch_url(&reqwest::Url::parse(url).unwrap());
+        assert!(check("https://example.com/page").is_ok());
+        assert!(check("http://93.184.216.34/").is_ok());
+        assert!(check("file:///etc/passwd").is_err());
+        assert!(check("http://localhost:8080/").is_err());
+        assert!(check("http://127.0.0.1/").is_err());
+        assert!(check("http://10.0.0.5/").is_err());
+        assert!(check("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check("http://[::1]/").is_err());
+        assert!(check("http://[::ffff:192.168.1.1]/").is_err());
+    }
+
+    /// Serve one canned HTTP response on a loopback port and return the URL
+    async fn serve_once(response: String) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/page", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    /// Serve canned HTTP responses on successive connections of a loopback port,
+    /// reading each full request first. Returns the base URL and a count of
+    /// connections served.
+    async fn serve_sequence(
+        responses: Vec<String>,
+    ) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = served.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some(header_end) = text.find("\r\n\r\n") {
+                        let length = text[..header_end]
+                            .lines()
+                            .find_map(|l| {
+                                l.to_ascii_lowercase()
+                                    .strip_prefix("content-length:")
+                                    .map(|v| v.trim().parse::<usize>().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if request.len() >= header_end + 4 + length {
+                            break;
+                        }
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                }
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                socket.write_all(response.as_bytes()).await.unwrap();
+                // Dropping the socket closes the connection, cutting off unterminated bodies
+            }
+        });
+        (url, served)
+    }
+
+    /// A chunked SSE response carrying `text` as one delta. When `complete` is false
+    /// the body is cut off without the terminating chunk, so the read fails.
+    fn sse_response(text: &str, complete: bool) -> String {
+        let mut body = format!("{}\n\n", delta_line(text));
+        if complete {
+            body.push_str("data: [DONE]\n\n");
+        }
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n{}\r\n",
+            body.len(),
+            body
+        );
+        if complete {
+            response.push_str("0\r\n\r\n");
+        }
+        response
+    }
+
+    #[test]
+    fn test_content_progress_sees_each_chunk() {
+        let pieces = [
+            r#"[{"title":"A","url":"https://a"}"#,
+            r#",{"title":"B","#,
+            r#""url":"https://b"}]"#,
+        ];
+        let mut response = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n".to_string();
+        for piece in pieces {
+            let body = format!("{}\n\n", delta_line(piece));
+            response.push_str(&format!("{:x}\r\n{}\r\n", body.len(), body));
+        }
+        let done = "data: [DONE]\n\n";
+        response.push_str(&format!("{:x}\r\n{}\r\n0\r\n\r\n", done.len(), done));
+
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let content = rt.block_on(async {
+            let (url, _) = serve_sequence(vec![response]).await;
+            let seen = seen.clone();
+            let provider =
+                stream_test_provider(url).with_content_progress(Some(std::sync::Arc::new(
+                    move |content: &str| seen.lock().unwrap().push(content.to_string()),
+                )));
+            provider
+                .execute_stream_with_retry(&serde_json::json!({"stream": true}))
+                .await
+                .unwrap()
+        });
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.last().unwrap(), &content);
+        assert_eq!(content, pieces.concat());
+        // Every call saw a growing prefix of the final content
+        assert!(seen.windows(2).all(|w| w[1].starts_with(&w[0])));
+        assert!(!seen.is_empty());
+    }
+
+    #[test]
+    fn test_last_http_status_records_final_response() {
+        let rate_limited = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbusy".to_string();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (url, _) = serve_sequence(vec![rate_limited, sse_response("done", true)]).await;
+            let provider = stream_test_provider(url);
+            assert_eq!(provider.last_http_status(), None);
+            provider
+                .execute_stream_with_retry(&serde_json::json!({"stream": true}))
+                .await
+                .unwrap();
+            assert_eq!(provider.last_http_status(), Some(200));
+        });
+    }
+
+    #[test]
+    fn test_last_citations_attached_to_stream_result() {
+        let chunk = r#"data: {"citations":["https://a.example"],"choices":[{"delta":{"content":"answer"}}]}"#;
+        let body = format!("{}\n\ndata: [DONE]\n\n", chunk);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (url, _) = serve_sequence(vec![response]).await;
+            let provider = stream_test_provider(url);
+            assert!(provider.last_citations().is_empty());
+            let content = provider
+                .execute_stream_with_retry(&serde_json::json!({"stream": true}))
+                .await
+                .unwrap();
+            assert_eq!(content, "answer");
+            assert_eq!(
+                provider.last_citations(),
+                vec![Citation {
+                    url: "https://a.example".to_string(),
+                    title: None,
+                }]
+            );
+        });
+    }
+
+    fn stream_test_provider(url: String) -> GrokSearchProvider {
+        let mut provider =
+            GrokSearchProvider::new(url, "test-key".to_string(), "test-model".to_string())
+                .unwrap()
+                .with_total_timeout(Some(30))
+                .with_max_retries(Some(1));
+        provider.stream_retry_min_bytes = 20;
+        provider
+    }
+
+    /// Count the requests made for `max_retries` when every attempt is rate limited
+    fn attempts_with_max_retries(max_retries: Option<u32>) -> usize {
+        let rate_limited = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbusy".to_string();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (url, served) = serve_sequence(vec![rate_limited; 12]).await;
+            let provider =
+                GrokSearchProvider::new(url, "test-key".to_string(), "test-model".to_string())
+                    .unwrap()
+                    .with_total_timeout(Some(30))
+                    .with_max_retries(max_retries);
+            let err = provider
+                .execute_stream_with_retry(&serde_json::json!({"stream": true}))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("HTTP 429"), "{}", err);
+            served.load(std::sync::atomic::Ordering::SeqCst)
+        })
+    }
+
+    #[test]
+    fn test_max_retries_override_changes_attempts() {
+        assert_eq!(attempts_with_max_retries(Some(0)), 1);
+        assert_eq!(attempts_with_max_retries(Some(2)), 3);
+    }
+
+    #[test]
+    fn test_stream_broken_early_is_retried() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (result, served) = rt.block_on(async {
+            let (url, served) = serve_sequence(vec![
+                sse_response("Par", false),
+                sse_response("The complete answer text", true),
+            ])
+            .await;
+            let provider = stream_test_provider(url);
+            let result = provider
+                .execute_stream_with_retry(&serde_json::json!({"stream": true}))
+                .await;
+            (result, served)
+        });
+
+        assert_eq!(result.unwrap(), "The complete answer text");
+        assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_stream_broken_late_returns_partial_content() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (result, served) = rt.block_on(async {
+            let (url, served) = serve_sequence(vec![
+                sse_response("A long enough partial answer", false),
+                sse_response("unused", true),
+            ])
+            .await;
+            let provider = stream_test_provider(url);
+            let result = provider
+                .execute_stream_with_retry(&serde_json::json!({"stream": true}))
+                .await;
+            (result, served)
+        });
+
+        assert_eq!(result.unwrap(), "A long enough partial answer");
+        assert_eq!(served.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_stream_broken_early_without_retries_returns_partial() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let result = rt.block_on(async {
+            let (url, _) = serve_sequence(vec![sse_response("Par", false)]).await;
+            let provider = stream_test_provider(url).with_max_retries(Some(0));
+            provider
+                .execute_stream_with_retry(&serde_json::json!({"stream": true}))
+                .await
+        });
+
+        assert_eq!(result.unwrap(), "Par");
+    }
+
+    #[test]
+    fn test_list_models_parses_ids() {
+        let body = r#"{"object":"list","data":[{"id":"grok-4-fast","object":"model"},{"id":"grok-3"},{"object":"model"}]}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let models = rt
+            .block_on(async {
+                let (url, _) = serve_sequence(vec![response]).await;
+                GrokSearchProvider::new(url, "test-key".to_string(), "test-model".to_string())
+                    .unwrap()
+                    .list_models()
+                    .await
+            })
+            .unwrap();
+        assert_eq!(models, vec!["grok-4-fast", "grok-3"]);
+    }
+
+    #[test]
+    fn test_list_models_reports_http_error() {
+        let response = "HTTP/1.1 401 Unauthorized\r\nContent-Length: 13\r\nConnection: close\r\n\r\ninvalid token".to_string();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let err = rt
+            .block_on(async {
+                let (url, _) = serve_sequence(vec![response]).await;
+                GrokSearchProvider::new(url, "test-key".to_string(), "test-model".to_string())
+                    .unwrap()
+                    .list_models()
+                    .await
+            })
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("HTTP 401"), "{}", err);
+        assert!(err.contains("invalid token"), "{}", err);
+    }
+
+    #[test]
+    fn test_models_request_applies_timeout() {
+        let provider = GrokSearchProvider::new(
+            "http://127.0.0.1:1/v1/".to_string(),
+            "test-key".to_string(),
+            "test-model".to_string(),
+        )
+        .unwrap();
+        let request = provider
+            .models_request(Duration::from_secs(4))
+            .build()
+            .unwrap();
+        assert_eq!(request.url().as_str(), "http://127.0.0.1:1/v1/models");
+        assert_eq!(request.timeout(), Some(&Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_list_models_error_with_chinese_body() {
+        // 3-byte chars, so a 100-byte cut would land mid-char
+        let body = "服务暂时不可用".repeat(50);
+        let response = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let err = rt
+            .block_on(async {
+                let (url, _) = serve_sequence(vec![response]).await;
+                GrokSearchProvider::new(url, "test-key".to_string(), "test-model".to_string())
+                    .unwrap()
+                    .list_models()
+                    .await
+            })
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("HTTP 503"), "{}", err);
+        assert!(err.contains(&body[..99]), "{}", err);
+        assert!(
+            err.ends_with(&crate::shared::truncation_marker()),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_fetch_raw_returns_body_unmodified() {
+        let body = "<html><body><h1>Title</h1>\n<p>text</p></body></html>";
+        let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let url = serve_once(format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ))
+            .await;
+
+            // The public entry point refuses loopback addresses
+            assert!(fetch_raw(&url, 5).await.is_err());
+
+            let client = build_http_client(reqwest::redirect::Policy::none());
+            fetch_raw_with_client(&client, reqwest::Url::parse(&url).unwrap(), 5).await
+        });
+
+        assert_eq!(result.unwrap(), body);
+    }
+
+    #[test]
+    fn test_fetch_raw_caps_body_without_content_length() {
+        let body = "a".repeat(MAX_RAW_FETCH_BYTES + 1);
+        let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let url = serve_once(format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n{}",
+                body
+            ))
+            .await;
+            let client = build_http_client(reqwest::redirect::Policy::none());
+            fetch_raw_with_client(&client, reqwest::Url::parse(&url).unwrap(), 30).await
+        });
+
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("byte limit"), "{}", err);
+    }
+
+    #[test]
+    fn test_public_address_resolver_rejects_local_names() {
+        use reqwest::dns::Resolve;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let resolve = |host: &str| {
+            rt.block_on(PublicAddressResolver.resolve(host.parse().unwrap()))
+                .map(|addrs| addrs.count())
+        };
+        let err = resolve("localhost").err().unwrap().to_string();
+        assert!(err.contains("local or private address"), "{}", err);
+        assert!(resolve("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_is_binary_content_type() {
+        assert!(!is_binary_content_type("text/html; charset=utf-8"));
+        assert!(!is_binary_content_type("application/json"));
+        assert!(!is_binary_content_type("application/rss+xml"));
+        assert!(!is_binary_content_type("image/svg+xml"));
+        assert!(!is_binary_content_type(""));
+        assert!(is_binary_content_type("image/png"));
+        assert!(is_binary_content_type("application/pdf"));
+        assert!(is_binary_content_type("Application/Octet-Stream"));
+        assert!(is_binary_content_type("video/mp4"));
+    }
+
+    #[test]
+    fn test_fetch_head_refuses_redirect_to_local_name() {
+        let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let url = serve_once(
+                "HTTP/1.1 302 Found\r\nLocation: http://localhost/admin\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            )
+            .await;
+            fetch_head_with_client(
+                &checked_http_client().unwrap(),
+                reqwest::Url::parse(&url).unwrap(),
+                5,
+            )
+                .await
+        });
+
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("local or private address"), "{}", err);
+    }
+
+    #[test]
+    fn test_fetch_head_reports_headers() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let client = build_http_client(reqwest::redirect::Policy::none());
+
+        let info = rt
+            .block_on(async {
+                let url = serve_once(
+                    "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: 52428800\r\nConnection: close\r\n\r\n"
+                        .to_string(),
+                )
+                .await;
+                assert!(fetch_head(&url, 5).await.is_err());
+                fetch_head_with_client(&client, reqwest::Url::parse(&url).unwrap(), 5).await
+            })
+            .unwrap();
+        assert_eq!(info.status, 200);
+        assert_eq!(info.content_type.as_deref(), Some("image/png"));
+        assert_eq!(info.content_length, Some(52_428_800));
+        assert!(info.binary);
+
+        let info = rt
+            .block_on(async {
+                let url = serve_once(
+                    "HTTP/1.1 404 Not Found\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string(),
+                )
+                .await;
+                fetch_head_with_client(&client, reqwest::Url::parse(&url).unwrap(), 5).await
+            })
+            .unwrap();
+        assert_eq!(info.status, 404);
+        assert!(!info.binary);
+
+        let info = rt
+            .block_on(async {
+                let url = serve_once(
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                )
+                .await;
+                fetch_head_with_client(&client, reqwest::Url::parse(&url).unwrap(), 5).await
+            })
+            .unwrap();
+        assert!(info.content_type.is_none());
+        assert!(!info.binary);
+    }
 }
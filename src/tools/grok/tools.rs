@@ -1,20 +1,118 @@
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use super::config::{self, Config};
-use super::provider::GrokSearchProvider;
+use super::provider::{self, GrokSearchProvider};
+
+/// API key passed with a single call; its `Debug` output never shows the value
+#[derive(Clone, Default, PartialEq, Eq, Deserialize, schemars::JsonSchema)]
+#[serde(transparent)]
+pub struct ApiKey(pub String);
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
+}
+
+/// Gateway for a single call. Unset fields fall back to GROK_API_URL / GROK_API_KEY;
+/// the global configuration itself is never changed.
+#[derive(Debug, Clone, Default)]
+pub struct ApiOverride {
+    pub api_url: Option<String>,
+    pub api_key: Option<ApiKey>,
+}
+
+impl ApiOverride {
+    /// Whether this call goes somewhere other than the configured gateway
+    pub fn is_set(&self) -> bool {
+        self.api_url.is_some() || self.api_key.is_some()
+    }
+
+    /// Whether the call carries its own URL and key, so it needs no environment configuration
+    pub fn is_complete(&self) -> bool {
+        self.api_url.is_some() && self.api_key.is_some()
+    }
+
+    /// Check the given overrides. Messages never include the key.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ref api_url) = self.api_url {
+            let valid = reqwest::Url::parse(api_url.trim())
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+            if !valid {
+                return Err(format!("api_url must be an http(s) URL, got '{}'", api_url));
+            }
+            // The configured key must never be sent to a caller-chosen gateway
+            if self.api_key.is_none() {
+                return Err("api_url requires api_key".to_string());
+            }
+        }
+        if let Some(ApiKey(ref api_key)) = self.api_key {
+            if api_key.is_empty() || api_key.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(
+                    "api_key must be a non-empty string without whitespace or control characters"
+                        .to_string(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The URL and key to use, taking each override before its environment value.
+    /// An overridden URL is only ever paired with an overridden key.
+    fn resolve(
+        &self,
+        env_url: Result<String, String>,
+        env_key: Result<String, String>,
+    ) -> Result<(String, String)> {
+        let api_key = match self.api_key {
+            Some(ApiKey(ref key)) => key.clone(),
+            None if self.api_url.is_some() => anyhow::bail!("api_url requires api_key"),
+            None => env_key.map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?,
+        };
+        let api_url = match self.api_url {
+            Some(ref url) => url.trim().to_string(),
+            None => env_url.map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?,
+        };
+        Ok((api_url, api_key))
+    }
+
+    fn credentials(&self) -> Result<(String, String)> {
+        self.resolve(Config::grok_api_url(), Config::grok_api_key())
+    }
+}
+
+/// Run `call` through the global circuit breaker, unless the call goes to a
+/// per-call gateway whose failures say nothing about the configured one
+async fn guarded<T>(api: &ApiOverride, call: impl Future<Output = Result<T>>) -> Result<T> {
+    if api.is_set() {
+        call.await
+    } else {
+        CircuitBreaker::global().call(call).await
+    }
+}
 
 /// Execute a web search via the Grok API
+#[allow(clippy::too_many_arguments)]
 pub async fn web_search(
     query: &str,
     platform: &str,
     min_results: i32,
     max_results: i32,
     model_override: Option<String>,
+    timeout_secs: Option<u64>,
+    max_retries: Option<u32>,
+    time_context: Option<bool>,
+    on_result: Option<SearchResultSink>,
+    api: &ApiOverride,
 ) -> Result<String> {
-    let api_url =
-        Config::grok_api_url().map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?;
-    let api_key =
-        Config::grok_api_key().map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?;
+    let (api_url, api_key) = api.credentials()?;
     let model = model_override
         .filter(|m| !m.trim().is_empty())
         .unwrap_or_else(|| {
@@ -23,23 +121,471 @@ pub async fn web_search(
             cfg.grok_model()
         });
 
-    let provider = GrokSearchProvider::new(api_url, api_key, model);
+    let provider = GrokSearchProvider::new(api_url, api_key, model)?
+        .with_total_timeout(timeout_secs)
+        .with_max_retries(max_retries)
+        .with_content_progress(on_result.map(result_progress));
 
     eprintln!("[grok] Begin Search: {}", query);
-    let result = provider
-        .search(query, platform, min_results, max_results)
-        .await?;
+    let result = guarded(
+        api,
+        provider.search(query, platform, min_results, max_results, time_context),
+    )
+    .await?;
     eprintln!("[grok] Search Finished!");
 
-    Ok(result)
+    let debug = Config::debug_return_raw().then(|| provider.last_http_status());
+    format_search_output(
+        &result,
+        Config::legacy_search_output(),
+        debug,
+        provider.last_citations(),
+    )
+}
+
+/// Render the model's search output as the web_search result. `debug` carries the
+/// HTTP status when GROK_DEBUG_RETURN_RAW is set, and adds the raw output as well.
+/// `citations` from the provider are attached to the structured output only.
+fn format_search_output(
+    raw: &str,
+    legacy: bool,
+    debug: Option<Option<u16>>,
+    citations: Vec<provider::Citation>,
+) -> Result<String> {
+    if legacy {
+        return Ok(match debug {
+            Some(status) => append_raw_debug(raw.to_string(), status, Some(raw)),
+            None => raw.to_string(),
+        });
+    }
+    let mut response = build_search_response(raw);
+    response.citations = citations;
+    if let Some(status) = debug {
+        response.raw = Some(raw.to_string());
+        response.http_status = status;
+    }
+    serde_json::to_string_pretty(&response)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize search results: {}", e))
+}
+
+/// Append a GROK_DEBUG_RETURN_RAW section with the HTTP status and, if given,
+/// the raw provider content to a text tool result
+fn append_raw_debug(mut output: String, http_status: Option<u16>, raw: Option<&str>) -> String {
+    let status = http_status.map_or_else(|| "unknown".to_string(), |s| s.to_string());
+    output.push_str(&format!("\n\n---\n[debug] HTTP status: {}", status));
+    if let Some(raw) = raw {
+        output.push_str(&format!("\n[debug] raw provider content:\n{}", raw));
+    }
+    output
+}
+
+/// Receives each search result object as soon as the streamed output contains it in full
+pub type SearchResultSink = std::sync::Arc<dyn Fn(&Value) + Send + Sync>;
+
+/// Adapt a result sink to streamed search output. The accumulated content is
+/// scanned incrementally, and only results beyond those already sent are
+/// forwarded, so a retried stream does not repeat them.
+fn result_progress(sink: SearchResultSink) -> provider::ContentProgress {
+    // The scanner and the number of results sent so far
+    let state = Mutex::new((ArrayObjectScanner::default(), 0usize));
+    std::sync::Arc::new(move |content: &str| {
+        let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+        let (scanner, emitted) = &mut *state;
+        let objects = scanner.feed(content);
+        let first = scanner.found - objects.len();
+        for (index, result) in (first..).zip(&objects) {
+            if index >= *emitted {
+                sink(result);
+            }
+        }
+        *emitted = (*emitted).max(scanner.found);
+    })
+}
+
+/// The objects of a JSON array that are already complete in `partial`, a prefix
+/// of the model's search output (optionally inside a ```json fence). Elements
+/// still being streamed, and anything that is not an object, are left out.
+pub fn complete_array_objects(partial: &str) -> Vec<Value> {
+    ArrayObjectScanner::default().feed(partial)
+}
+
+/// Bytes before the scan offset remembered to recognise a stream that started over
+const SCAN_TAIL_BYTES: usize = 32;
+
+/// Finds the complete objects of a streamed JSON array, scanning each byte once
+/// as the content grows. Content that no longer extends what was scanned (a
+/// retried stream) restarts the scan.
+#[derive(Debug, Default)]
+struct ArrayObjectScanner {
+    /// Offset up to which the content has been scanned
+    scanned: usize,
+    /// The last bytes before `scanned`, to check the next content extends it
+    tail: Vec<u8>,
+    /// Offset just past the array's opening `[`, once found
+    body_start: Option<usize>,
+    /// The content is not a JSON array, or the array has ended
+    done: bool,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Offset of the `{` opening the current top-level element
+    start: Option<usize>,
+    /// Objects found since the scan (re)started
+    found: usize,
+}
+
+enum ArrayStart {
+    /// Only whitespace or part of an opening fence so far
+    Pending,
+    /// The array body starts at this offset
+    Body(usize),
+    NotArray,
+}
+
+impl ArrayObjectScanner {
+    /// Scan the part of `content` not seen yet and return the objects it completes
+    fn feed(&mut self, content: &str) -> Vec<Value> {
+        if !self.extended_by(content) {
+            *self = Self::default();
+        }
+        if self.body_start.is_none() && !self.done {
+            match array_start(content) {
+                ArrayStart::Pending => return Vec::new(),
+                ArrayStart::NotArray => self.done = true,
+                ArrayStart::Body(offset) => {
+                    self.body_start = Some(offset);
+                    self.scanned = offset;
+                }
+            }
+        }
+
+        let mut objects = Vec::new();
+        if !self.done {
+            // Structural characters are ASCII, so byte offsets stay on char boundaries
+            for (i, byte) in content.bytes().enumerate().skip(self.scanned) {
+                if let Some(object) = self.scan_byte(content, i, byte) {
+                    objects.push(object);
+                }
+                if self.done {
+                    break;
+                }
+            }
+        }
+        self.found += objects.len();
+        self.scanned = content.len();
+        let tail_from = content.len().saturating_sub(SCAN_TAIL_BYTES);
+        self.tail = content.as_bytes()[tail_from..].to_vec();
+        objects
+    }
+
+    /// Whether `content` continues the content scanned so far
+    fn extended_by(&self, content: &str) -> bool {
+        content.len() >= self.scanned
+            && content.as_bytes()[self.scanned - self.tail.len()..self.scanned] == self.tail[..]
+    }
+
+    fn scan_byte(&mut self, content: &str, i: usize, byte: u8) -> Option<Value> {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+            }
+            return None;
+        }
+        match byte {
+            b'"' => self.in_string = true,
+            b'{' | b'[' => {
+                if self.depth == 0 && byte == b'{' {
+                    self.start = Some(i);
+                }
+                self.depth += 1;
+            }
+            b'}' | b']' => {
+                if self.depth == 0 {
+                    // End of the top-level array
+                    self.done = true;
+                    return None;
+                }
+                self.depth -= 1;
+                if self.depth == 0 {
+                    let from = self.start.take()?;
+                    if let Ok(object @ Value::Object(_)) = serde_json::from_str(&content[from..=i])
+                    {
+                        return Some(object);
+                    }
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Where the array body starts in streamed search output, which may open with
+/// a ```json fence
+fn array_start(content: &str) -> ArrayStart {
+    let text = content.trim_start();
+    let text = match text.strip_prefix("```") {
+        Some(rest) if rest.len() < 4 && "json".starts_with(rest) => return ArrayStart::Pending,
+        Some(rest) => rest.strip_prefix("json").unwrap_or(rest).trim_start(),
+        None if "```".starts_with(text) => return ArrayStart::Pending,
+        None => text,
+    };
+    match text.strip_prefix('[') {
+        Some(body) => ArrayStart::Body(content.len() - body.len()),
+        None if text.is_empty() => ArrayStart::Pending,
+        None => ArrayStart::NotArray,
+    }
+}
+
+/// Fails Grok calls fast after repeated failures, so an unreachable API does not
+/// cost every caller the full retry/backoff cycle. Only backend failures count
+/// (see [`provider::is_backend_failure`]), so one caller's bad input cannot open
+/// the circuit for everyone.
+///
+/// Closed: calls pass through. `failure_threshold` consecutive failures within
+/// `window` open the circuit; open calls fail immediately until `cooldown` has
+/// passed, then one probe call is let through (half-open). A successful probe
+/// closes the circuit, a failed one reopens it for another cooldown.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    first_failure_at: Option<Instant>,
+    opened_at: Option<Instant>,
+    /// When the half-open probe started; a probe that never reports back
+    /// (e.g. cancelled) is replaced after another cooldown
+    probe_started_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// A breaker that opens after `failure_threshold` failures (0 disables it)
+    pub fn new(failure_threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            window,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Process-wide breaker for the Grok API, configured from the environment
+    pub fn global() -> &'static CircuitBreaker {
+        static BREAKER: OnceLock<CircuitBreaker> = OnceLock::new();
+        BREAKER.get_or_init(|| {
+            CircuitBreaker::new(
+                Config::circuit_failure_threshold(),
+                Duration::from_secs(Config::circuit_window()),
+                Duration::from_secs(Config::circuit_cooldown()),
+            )
+        })
+    }
+
+    /// Run `call` unless the circuit is open, recording its outcome.
+    /// Errors that are not backend failures are neither successes nor failures.
+    pub async fn call<T>(&self, call: impl Future<Output = Result<T>>) -> Result<T> {
+        self.try_acquire(Instant::now())?;
+        let result = call.await;
+        match &result {
+            Ok(_) => self.record_success(),
+            Err(e) if provider::is_backend_failure(e) => self.record_failure(Instant::now()),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Whether a call may proceed at `now`; errors while the circuit is open
+    fn try_acquire(&self, now: Instant) -> Result<()> {
+        if self.failure_threshold == 0 {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(opened_at) = state.opened_at else {
+            return Ok(());
+        };
+
+        let probe_due = match state.probe_started_at {
+            Some(started) => now.duration_since(started) >= self.cooldown,
+            None => now.duration_since(opened_at) >= self.cooldown,
+        };
+        if probe_due {
+            eprintln!("[grok] Circuit half-open, probing the API");
+            state.probe_started_at = Some(now);
+            return Ok(());
+        }
+
+        let reopen_in = self
+            .cooldown
+            .saturating_sub(now.duration_since(state.probe_started_at.unwrap_or(opened_at)));
+        anyhow::bail!(
+            "Grok backend temporarily unavailable: {} consecutive failures, retrying in {}s",
+            state.consecutive_failures,
+            reopen_in.as_secs().max(1)
+        )
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.opened_at.is_some() {
+            eprintln!("[grok] Circuit closed, API call succeeded");
+        }
+        *state = BreakerState::default();
+    }
+
+    fn record_failure(&self, now: Instant) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.opened_at.is_some() {
+            // Failed probe: stay open for another cooldown
+            state.consecutive_failures += 1;
+            state.opened_at = Some(now);
+            state.probe_started_at = None;
+            return;
+        }
+
+        match state.first_failure_at {
+            Some(first) if now.duration_since(first) <= self.window => {
+                state.consecutive_failures += 1;
+            }
+            _ => {
+                state.first_failure_at = Some(now);
+                state.consecutive_failures = 1;
+            }
+        }
+        if state.consecutive_failures >= self.failure_threshold {
+            eprintln!(
+                "[grok] Circuit opened after {} consecutive failures, failing fast for {}s",
+                state.consecutive_failures,
+                self.cooldown.as_secs()
+            );
+            state.opened_at = Some(now);
+        }
+    }
+}
+
+/// Outcome of a web search, so callers can tell "nothing found" from a formatting failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchStatus {
+    Ok,
+    Empty,
+    Error,
+}
+
+/// Structured web_search output
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    pub results: Vec<Value>,
+    pub status: SearchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Sources the provider attached to its answer, outside the model's text
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<provider::Citation>,
+    /// Unparsed model output, present when it could not be interpreted or
+    /// GROK_DEBUG_RETURN_RAW is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+    /// HTTP status of the provider response, only with GROK_DEBUG_RETURN_RAW
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+}
+
+impl SearchResponse {
+    fn results(results: Vec<Value>) -> Self {
+        let status = if results.is_empty() {
+            SearchStatus::Empty
+        } else {
+            SearchStatus::Ok
+        };
+        let message = results.is_empty().then(|| "No results found.".to_string());
+        Self {
+            results,
+            status,
+            message,
+            citations: Vec::new(),
+            raw: None,
+            http_status: None,
+        }
+    }
+
+    fn error(message: String, raw: Option<String>) -> Self {
+        Self {
+            results: Vec::new(),
+            status: SearchStatus::Error,
+            message: Some(message),
+            citations: Vec::new(),
+            raw,
+            http_status: None,
+        }
+    }
+}
+
+/// Interpret the model's search output as a JSON result list
+pub fn build_search_response(raw: &str) -> SearchResponse {
+    let text = strip_code_fence(raw.trim());
+    if text.is_empty()
+        || text
+            .trim_end_matches('.')
+            .eq_ignore_ascii_case("No results found")
+    {
+        return SearchResponse::results(Vec::new());
+    }
+
+    match serde_json::from_str::<Value>(text) {
+        Ok(Value::Array(results)) => SearchResponse::results(results),
+        Ok(Value::Object(mut obj)) => {
+            // The search prompt asks for {"error": "...", "results": []} on failure
+            if let Some(error) = obj.get("error").and_then(Value::as_str) {
+                if !error.is_empty() {
+                    return SearchResponse::error(error.to_string(), None);
+                }
+            }
+            match obj.remove("results") {
+                Some(Value::Array(results)) => SearchResponse::results(results),
+                _ => SearchResponse::error(
+                    "Search output is a JSON object without a results array".to_string(),
+                    Some(raw.to_string()),
+                ),
+            }
+        }
+        _ => SearchResponse::error(
+            "Search output could not be parsed as a JSON result list".to_string(),
+            Some(raw.to_string()),
+        ),
+    }
+}
+
+/// Remove a surrounding ```json ... ``` fence if the model added one anyway
+fn strip_code_fence(text: &str) -> &str {
+    let Some(rest) = text.strip_prefix("```") else {
+        return text;
+    };
+    let rest = rest.strip_prefix("json").unwrap_or(rest);
+    rest.strip_suffix("```").unwrap_or(rest).trim()
 }
 
 /// Fetch and extract content from a URL via the Grok API
-pub async fn web_fetch(url: &str, model_override: Option<String>) -> Result<String> {
-    let api_url =
-        Config::grok_api_url().map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?;
-    let api_key =
-        Config::grok_api_key().map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?;
+pub async fn web_fetch(
+    url: &str,
+    model_override: Option<String>,
+    max_retries: Option<u32>,
+    metadata_only: bool,
+    api: &ApiOverride,
+) -> Result<String> {
+    let (api_url, api_key) = api.credentials()?;
     let model = model_override
         .filter(|m| !m.trim().is_empty())
         .unwrap_or_else(|| {
@@ -48,17 +594,143 @@ pub async fn web_fetch(url: &str, model_override: Option<String>) -> Result<Stri
             cfg.grok_model()
         });
 
-    let provider = GrokSearchProvider::new(api_url, api_key, model);
+    let provider = GrokSearchProvider::new(api_url, api_key, model)?.with_max_retries(max_retries);
 
     eprintln!("[grok] Begin Fetch: {}", url);
-    let result = provider.fetch(url).await?;
+    let result = guarded(api, provider.fetch(url, metadata_only)).await?;
     eprintln!("[grok] Fetch Finished!");
 
+    // The fetch result already is the raw provider content, so only the status is added
+    if Config::debug_return_raw() {
+        return Ok(append_raw_debug(result, provider.last_http_status(), None));
+    }
+    Ok(result)
+}
+
+/// A fetched page split into its front-matter metadata and Markdown body
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FetchDocument {
+    /// Fields of the leading `---` header (`source`, `title`, `fetched_at`, ...)
+    pub metadata: serde_json::Map<String, Value>,
+    /// The Markdown after the header, or the whole output when there is no header
+    pub content: String,
+}
+
+/// Split the fetch output's leading `---` ... `---` header into metadata fields.
+/// Output without a complete header yields empty metadata and the full body.
+pub fn parse_fetch_output(raw: &str) -> FetchDocument {
+    let fallback = || FetchDocument {
+        metadata: serde_json::Map::new(),
+        content: raw.to_string(),
+    };
+
+    let text = raw.trim_start();
+    let mut lines = text.split_inclusive('\n');
+    let Some(opening) = lines.next().filter(|l| l.trim_end() == "---") else {
+        return fallback();
+    };
+
+    let mut metadata = serde_json::Map::new();
+    let mut consumed = opening.len();
+    for line in lines {
+        consumed += line.len();
+        let line = line.trim();
+        if line == "---" {
+            return FetchDocument {
+                metadata,
+                content: text[consumed..]
+                    .trim_start_matches(['\r', '\n'])
+                    .to_string(),
+            };
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            let key = key.trim();
+            if !key.is_empty() {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                metadata.insert(key.to_string(), Value::String(value.to_string()));
+            }
+        }
+    }
+    fallback()
+}
+
+/// How `web_fetch` produces its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FetchMode {
+    /// Extract the page to Markdown via the Grok API (default)
+    #[default]
+    Markdown,
+    /// Fetch the URL directly and return the unmodified HTML/text
+    Raw,
+    /// Fetch the URL directly and return its main text with boilerplate removed
+    Extract,
+}
+
+impl FetchMode {
+    /// Parse a mode name case-insensitively; unset or empty means Markdown
+    pub fn parse(value: Option<&str>) -> Option<Self> {
+        match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("markdown") => Some(Self::Markdown),
+            Some("raw") => Some(Self::Raw),
+            Some("extract") => Some(Self::Extract),
+            _ => None,
+        }
+    }
+}
+
+/// Fetch a URL directly, bypassing the model. Does not require Grok configuration.
+pub async fn web_fetch_raw(url: &str) -> Result<String> {
+    eprintln!("[grok] Begin Raw Fetch: {}", url);
+    let result = provider::fetch_raw(url, Config::total_timeout()).await?;
+    eprintln!("[grok] Raw Fetch Finished!");
+
     Ok(result)
 }
 
+/// Fetch a URL directly and strip navigation, ads and other boilerplate locally,
+/// bypassing the model. Does not require Grok configuration.
+pub async fn web_fetch_extract(url: &str) -> Result<String> {
+    eprintln!("[grok] Begin Extract Fetch: {}", url);
+    let html = provider::fetch_raw(url, Config::total_timeout()).await?;
+    eprintln!("[grok] Extract Fetch Finished!");
+
+    Ok(super::extract::extract_main_content(&html))
+}
+
+/// Check a URL with a HEAD request: status, content type and length. Does not require Grok configuration.
+pub async fn web_fetch_head(url: &str) -> Result<provider::HeadInfo> {
+    provider::fetch_head(url, Config::total_timeout()).await
+}
+
+/// List the model ids available from the configured Grok provider
+pub async fn list_models() -> Result<Vec<String>> {
+    let api_url =
+        Config::grok_api_url().map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?;
+    let api_key =
+        Config::grok_api_key().map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?;
+    let model = {
+        let cfg = Config::global();
+        let mut cfg = cfg.lock().unwrap();
+        cfg.grok_model()
+    };
+
+    GrokSearchProvider::new(api_url, api_key, model)?
+        .list_models()
+        .await
+}
+
 /// Get current configuration info with connection test
 pub async fn get_config_info() -> Result<String> {
+    serde_json::to_string_pretty(&config_report().await)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize config info: {}", e))
+}
+
+/// Configuration report as JSON. The connection test is skipped when Grok is not configured.
+pub async fn config_report() -> Value {
     let mut config_info = config::get_config_info();
 
     // Test connection
@@ -69,8 +741,11 @@ pub async fn get_config_info() -> Result<String> {
                 let mut cfg = cfg.lock().unwrap();
                 cfg.grok_model()
             };
-            let provider = GrokSearchProvider::new(api_url, api_key, model);
-            match provider.test_connection().await {
+            let result = match GrokSearchProvider::new(api_url, api_key, model) {
+                Ok(provider) => provider.test_connection().await,
+                Err(e) => Err(e),
+            };
+            match result {
                 Ok(result) => result,
                 Err(e) => serde_json::json!({
                     "status": "❌ Connection failed",
@@ -79,15 +754,13 @@ pub async fn get_config_info() -> Result<String> {
             }
         }
         _ => serde_json::json!({
-            "status": "❌ Configuration error",
-            "message": "GROK_API_URL or GROK_API_KEY not set",
+            "status": "not configured",
+            "message": "Connection test skipped: GROK_API_URL or GROK_API_KEY not set",
         }),
     };
 
     config_info["connection_test"] = test_result;
-
-    serde_json::to_string_pretty(&config_info)
-        .map_err(|e| anyhow::anyhow!("Failed to serialize config info: {}", e))
+    config_info
 }
 
 #[cfg(test)]
@@ -100,24 +773,337 @@ mod tests {
         std::env::remove_var("GROK_API_KEY");
 
         let rt = tokio::runtime::Runtime::new().unwrap();
-        let result = rt.block_on(web_search("test", "", 3, 10, None));
+        let result = rt.block_on(web_search(
+            "test",
+            "",
+            3,
+            10,
+            None,
+            None,
+            None,
+            None,
+            None,
+            &ApiOverride::default(),
+        ));
         assert!(result.is_err());
         let err = result.unwrap_err().to_string();
         assert!(err.contains("Configuration error"));
     }
 
+    #[test]
+    fn test_complete_array_objects_only_returns_closed_objects() {
+        assert!(complete_array_objects("").is_empty());
+        assert!(complete_array_objects("[").is_empty());
+        assert!(complete_array_objects(r#"[{"title":"A","url":"#).is_empty());
+
+        let partial = r#"```json
+[{"title":"A } ] {","url":"https://a","nested":{"x":[1,2]}},
+ {"title":"B \"quoted\"","url":"https://b"},
+ {"title":"C","url":"ht"#;
+        let objects = complete_array_objects(partial);
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0]["title"], "A } ] {");
+        assert_eq!(objects[1]["title"], "B \"quoted\"");
+
+        let done = r#"[{"title":"A"}, "stray", {"title":"B"}] trailing {"title":"C"}"#;
+        assert_eq!(complete_array_objects(done).len(), 2);
+        assert!(complete_array_objects(r#"{"results": [{"title":"A"}]}"#).is_empty());
+    }
+
+    #[test]
+    fn test_result_progress_emits_each_result_once() {
+        let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let sink_seen = seen.clone();
+        let progress = result_progress(std::sync::Arc::new(move |v: &Value| {
+            sink_seen
+                .lock()
+                .unwrap()
+                .push(v["title"].as_str().unwrap().to_string())
+        }));
+
+        let full = r#"[{"title":"A","url":"https://a"},{"title":"B","url":"https://b"},{"title":"C","url":"https://c"}]"#;
+        // Feed the stream one character at a time, then once more as a retried attempt
+        for end in 1..=full.len() {
+            progress(&full[..end]);
+        }
+        progress(&full[..40]);
+        progress(full);
+
+        assert_eq!(*seen.lock().unwrap(), vec!["A", "B", "C"]);
+    }
+
+    #[test]
+    fn test_array_object_scanner_is_incremental() {
+        let mut scanner = ArrayObjectScanner::default();
+        let full = "```json\n[{\"title\":\"A\"},{\"title\":\"B\"}]\n```";
+        let mut titles = Vec::new();
+        for end in 1..=full.len() {
+            for object in scanner.feed(&full[..end]) {
+                titles.push(object["title"].as_str().unwrap().to_string());
+            }
+            // Once the array has opened, each call only scans the new bytes
+            if scanner.body_start.is_some() {
+                assert_eq!(scanner.scanned, end);
+            }
+        }
+        assert_eq!(titles, vec!["A", "B"]);
+
+        // A restarted stream that is no longer a prefix is scanned from the start
+        let objects = scanner.feed("[{\"title\":\"C\"}, {\"title\":\"D\"}] and more text");
+        assert_eq!(objects.len(), 2);
+        assert_eq!(scanner.found, 2);
+
+        let mut scanner = ArrayObjectScanner::default();
+        assert!(scanner.feed("Here are").is_empty());
+        assert!(scanner
+            .feed("Here are the results: [{\"title\":\"A\"}]")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_format_search_output_includes_raw_only_in_debug() {
+        let raw = r#"[{"title":"A","url":"https://a"}]"#;
+
+        let json: Value =
+            serde_json::from_str(&format_search_output(raw, false, None, Vec::new()).unwrap())
+                .unwrap();
+        assert_eq!(json["status"], "ok");
+        assert!(json.get("raw").is_none());
+        assert!(json.get("http_status").is_none());
+        assert!(json.get("citations").is_none());
+
+        let json: Value = serde_json::from_str(
+            &format_search_output(raw, false, Some(Some(200)), Vec::new()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["status"], "ok");
+        assert_eq!(json["raw"], raw);
+        assert_eq!(json["http_status"], 200);
+        assert_eq!(json["results"][0]["title"], "A");
+
+        assert_eq!(
+            format_search_output(raw, true, None, Vec::new()).unwrap(),
+            raw
+        );
+        let legacy = format_search_output(raw, true, Some(None), Vec::new()).unwrap();
+        assert!(legacy.starts_with(raw));
+        assert!(legacy.contains("[debug] HTTP status: unknown"));
+        assert!(legacy.ends_with(&format!("raw provider content:\n{}", raw)));
+    }
+
+    #[test]
+    fn test_format_search_output_attaches_citations() {
+        let raw = r#"[{"title":"A","url":"https://a"}]"#;
+        let citations = vec![provider::Citation {
+            url: "https://source.example".to_string(),
+            title: Some("Source".to_string()),
+        }];
+
+        let json: Value = serde_json::from_str(
+            &format_search_output(raw, false, None, citations.clone()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            json["citations"],
+            serde_json::json!([{"url": "https://source.example", "title": "Source"}])
+        );
+        assert_eq!(json["results"][0]["title"], "A");
+
+        // The legacy output stays the raw model text
+        assert_eq!(
+            format_search_output(raw, true, None, citations).unwrap(),
+            raw
+        );
+    }
+
+    #[test]
+    fn test_build_search_response_ok() {
+        let raw = r#"[{"title": "Rust", "url": "https://rust-lang.org", "description": "Rust"}]"#;
+        let response = build_search_response(raw);
+        assert_eq!(response.status, SearchStatus::Ok);
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(response.results[0]["url"], "https://rust-lang.org");
+        assert!(response.message.is_none());
+
+        let fenced = format!("```json\n{}\n```", raw);
+        let response = build_search_response(&fenced);
+        assert_eq!(response.status, SearchStatus::Ok);
+        assert_eq!(response.results.len(), 1);
+    }
+
+    #[test]
+    fn test_build_search_response_empty() {
+        for raw in [
+            "[]",
+            "No results found.",
+            "no results found",
+            "  ",
+            r#"{"results": []}"#,
+        ] {
+            let response = build_search_response(raw);
+            assert_eq!(response.status, SearchStatus::Empty, "raw: {raw:?}");
+            assert!(response.results.is_empty());
+            assert!(response.raw.is_none());
+        }
+    }
+
+    #[test]
+    fn test_build_search_response_error() {
+        let response = build_search_response(r#"{"error": "rate limited", "results": []}"#);
+        assert_eq!(response.status, SearchStatus::Error);
+        assert_eq!(response.message.as_deref(), Some("rate limited"));
+
+        let response = build_search_response("Here are some links I found: ...");
+        assert_eq!(response.status, SearchStatus::Error);
+        assert_eq!(
+            response.raw.as_deref(),
+            Some("Here are some links I found: ...")
+        );
+
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["status"], "error");
+        assert_eq!(json["results"], serde_json::json!([]));
+    }
+
     #[test]
     fn test_web_fetch_requires_config() {
         std::env::remove_var("GROK_API_URL");
         std::env::remove_var("GROK_API_KEY");
 
         let rt = tokio::runtime::Runtime::new().unwrap();
-        let result = rt.block_on(web_fetch("https://example.com", None));
+        let result = rt.block_on(web_fetch(
+            "https://example.com",
+            None,
+            None,
+            false,
+            &ApiOverride::default(),
+        ));
         assert!(result.is_err());
         let err = result.unwrap_err().to_string();
         assert!(err.contains("Configuration error"));
     }
 
+    fn api_override(url: Option<&str>, key: Option<&str>) -> ApiOverride {
+        ApiOverride {
+            api_url: url.map(String::from),
+            api_key: key.map(|k| ApiKey(k.to_string())),
+        }
+    }
+
+    #[test]
+    fn test_api_override_takes_precedence_per_field() {
+        let env = || -> (Result<String, String>, Result<String, String>) {
+            (Ok("https://env.example/v1".into()), Ok("env-key".into()))
+        };
+
+        let (url, key) = env();
+        let resolved = ApiOverride::default().resolve(url, key).unwrap();
+        assert_eq!(
+            resolved,
+            ("https://env.example/v1".into(), "env-key".into())
+        );
+
+        let (url, key) = env();
+        let api = api_override(None, Some("tenant-key"));
+        let resolved = api.resolve(url, key).unwrap();
+        assert_eq!(
+            resolved,
+            ("https://env.example/v1".into(), "tenant-key".into())
+        );
+
+        // Both overrides work without any environment configuration
+        let api = api_override(Some(" https://tenant.example/v1 "), Some("tenant-key"));
+        let resolved = api
+            .resolve(Err("unset".into()), Err("unset".into()))
+            .unwrap();
+        assert_eq!(
+            resolved,
+            ("https://tenant.example/v1".into(), "tenant-key".into())
+        );
+
+        let err = api_override(None, Some("tenant-key"))
+            .resolve(Err("GROK_API_URL not set".into()), Ok("env-key".into()))
+            .unwrap_err();
+        assert!(err.to_string().contains("Configuration error"));
+    }
+
+    #[test]
+    fn test_api_override_url_never_gets_env_key() {
+        let api = api_override(Some("https://tenant.example/v1"), None);
+        assert!(!api.is_complete());
+        assert_eq!(api.validate().unwrap_err(), "api_url requires api_key");
+
+        let err = api
+            .resolve(Ok("https://env.example/v1".into()), Ok("env-key".into()))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("api_url requires api_key"));
+        assert!(!err.contains("env-key"));
+    }
+
+    #[test]
+    fn test_api_override_validation_hides_key() {
+        assert!(ApiOverride::default().validate().is_ok());
+        assert!(api_override(Some("https://gw.example/v1"), Some("sk-1"))
+            .validate()
+            .is_ok());
+
+        for url in ["gw.example", "ftp://gw.example", "http://"] {
+            let err = api_override(Some(url), None).validate().unwrap_err();
+            assert!(err.contains("api_url"), "{url}: {err}");
+        }
+        for key in ["", "sk 1", "sk-1\r\nX-Injected: 1"] {
+            let err = api_override(None, Some(key)).validate().unwrap_err();
+            assert!(err.contains("api_key"));
+            assert!(key.is_empty() || !err.contains(key));
+        }
+
+        let api = api_override(Some("https://gw.example"), Some("sk-secret"));
+        assert!(!format!("{:?}", api).contains("sk-secret"));
+    }
+
+    #[test]
+    fn test_web_fetch_override_reaches_provider() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/v1", listener.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !String::from_utf8_lossy(&request).contains("\"stream\":true") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let delta = serde_json::json!({"choices": [{"delta": {"content": "# Page"}}]});
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", delta);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                String::from_utf8_lossy(&request).into_owned()
+            });
+
+            let api = api_override(Some(&url), Some("tenant-key"));
+            let result = web_fetch("https://example.com", None, Some(0), false, &api)
+                .await
+                .unwrap();
+            assert!(result.contains("# Page"));
+
+            let request = server.await.unwrap();
+            assert!(request.starts_with("POST /v1/"), "{request}");
+            assert!(request.contains("Bearer tenant-key"));
+        });
+    }
+
     #[test]
     fn test_get_config_info_without_env() {
         std::env::remove_var("GROK_API_URL");
@@ -130,4 +1116,187 @@ mod tests {
         assert!(info.contains("config_status"));
         assert!(info.contains("connection_test"));
     }
+
+    fn test_breaker() -> CircuitBreaker {
+        CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(30))
+    }
+
+    #[test]
+    fn test_circuit_breaker_trips_and_fails_fast() {
+        let breaker = test_breaker();
+        let start = Instant::now();
+        for i in 0..3 {
+            breaker.try_acquire(start).unwrap();
+            breaker.record_failure(start + Duration::from_secs(i));
+        }
+
+        let err = breaker
+            .try_acquire(start + Duration::from_secs(10))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("temporarily unavailable"), "{}", err);
+        assert!(err.contains("3 consecutive failures"), "{}", err);
+    }
+
+    #[test]
+    fn test_circuit_breaker_recovers_after_probe() {
+        let breaker = test_breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure(start);
+        }
+
+        // Half-open after the cooldown: one probe passes, others still fail fast
+        let probe_at = start + Duration::from_secs(31);
+        breaker.try_acquire(probe_at).unwrap();
+        assert!(breaker.try_acquire(probe_at).is_err());
+
+        breaker.record_success();
+        breaker.try_acquire(probe_at).unwrap();
+        breaker.try_acquire(probe_at).unwrap();
+    }
+
+    #[test]
+    fn test_circuit_breaker_failed_probe_reopens() {
+        let breaker = test_breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure(start);
+        }
+
+        let probe_at = start + Duration::from_secs(31);
+        breaker.try_acquire(probe_at).unwrap();
+        breaker.record_failure(probe_at);
+        assert!(breaker
+            .try_acquire(probe_at + Duration::from_secs(29))
+            .is_err());
+        breaker
+            .try_acquire(probe_at + Duration::from_secs(30))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_circuit_breaker_window_and_success_reset_count() {
+        let breaker = test_breaker();
+        let start = Instant::now();
+        breaker.record_failure(start);
+        breaker.record_failure(start);
+        // Outside the window the count restarts
+        breaker.record_failure(start + Duration::from_secs(61));
+        breaker
+            .try_acquire(start + Duration::from_secs(61))
+            .unwrap();
+
+        breaker.record_failure(start + Duration::from_secs(62));
+        breaker.record_success();
+        breaker.record_failure(start + Duration::from_secs(63));
+        breaker
+            .try_acquire(start + Duration::from_secs(63))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_circuit_breaker_ignores_caller_errors() {
+        let breaker = test_breaker();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let invalid_url = || async {
+            let request = reqwest::Client::new().get("not a url").build();
+            request.map(|_| ()).map_err(anyhow::Error::from)
+        };
+        for _ in 0..5 {
+            assert!(rt.block_on(breaker.call(invalid_url())).is_err());
+        }
+        breaker.try_acquire(Instant::now()).unwrap();
+
+        let refused = || async {
+            // Port 1 on loopback refuses the connection: a transport error
+            let request = reqwest::Client::new().get("http://127.0.0.1:1/").send();
+            request.await.map(|_| ()).map_err(anyhow::Error::from)
+        };
+        for _ in 0..3 {
+            assert!(rt.block_on(breaker.call(refused())).is_err());
+        }
+        assert!(breaker.try_acquire(Instant::now()).is_err());
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled_with_zero_threshold() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60), Duration::from_secs(30));
+        let start = Instant::now();
+        for _ in 0..10 {
+            breaker.record_failure(start);
+        }
+        breaker.try_acquire(start).unwrap();
+    }
+
+    #[test]
+    fn test_circuit_breaker_call_records_outcomes() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(30));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            for _ in 0..2 {
+                let refused = reqwest::Client::new().get("http://127.0.0.1:1/").send();
+                let result = breaker
+                    .call(async { refused.await.map_err(anyhow::Error::from) })
+                    .await;
+                assert!(result.is_err());
+            }
+            let called = std::sync::atomic::AtomicBool::new(false);
+            let result = breaker
+                .call(async {
+                    called.store(true, std::sync::atomic::Ordering::SeqCst);
+                    Ok(())
+                })
+                .await;
+            assert!(result.is_err());
+            assert!(!called.load(std::sync::atomic::Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_fetch_mode_parse() {
+        assert_eq!(FetchMode::parse(None), Some(FetchMode::Markdown));
+        assert_eq!(FetchMode::parse(Some("")), Some(FetchMode::Markdown));
+        assert_eq!(
+            FetchMode::parse(Some("Markdown")),
+            Some(FetchMode::Markdown)
+        );
+        assert_eq!(FetchMode::parse(Some(" raw ")), Some(FetchMode::Raw));
+        assert_eq!(FetchMode::parse(Some("Extract")), Some(FetchMode::Extract));
+        assert_eq!(FetchMode::parse(Some("screenshot")), None);
+    }
+
+    #[test]
+    fn test_parse_fetch_output_header() {
+        let raw = "---\nsource: https://example.com/post\ntitle: \"Hello: World\"\nfetched_at: 2025-01-02T03:04:05Z\n---\n\n# Hello\n\nBody text.\n";
+        let doc = parse_fetch_output(raw);
+        assert_eq!(doc.metadata["source"], "https://example.com/post");
+        assert_eq!(doc.metadata["title"], "Hello: World");
+        assert_eq!(doc.metadata["fetched_at"], "2025-01-02T03:04:05Z");
+        assert_eq!(doc.content, "# Hello\n\nBody text.\n");
+    }
+
+    #[test]
+    fn test_parse_fetch_output_crlf_and_leading_whitespace() {
+        let raw = "\n---\r\ntitle: Page\r\n---\r\nBody";
+        let doc = parse_fetch_output(raw);
+        assert_eq!(doc.metadata["title"], "Page");
+        assert_eq!(doc.content, "Body");
+    }
+
+    #[test]
+    fn test_parse_fetch_output_without_header() {
+        let raw = "# Title\n\n---\n\nMore text";
+        let doc = parse_fetch_output(raw);
+        assert!(doc.metadata.is_empty());
+        assert_eq!(doc.content, raw);
+    }
+
+    #[test]
+    fn test_parse_fetch_output_unterminated_header() {
+        let raw = "---\ntitle: Page\n# Body without closing marker";
+        let doc = parse_fetch_output(raw);
+        assert!(doc.metadata.is_empty());
+        assert_eq!(doc.content, raw);
+    }
 }
@@ -2,3 +2,4 @@ pub mod codex;
 pub mod gemini;
 pub mod gemini_image_api;
 pub mod grok;
+pub mod smoke;
@@ -0,0 +1,204 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::{codex, gemini};
+
+/// Canned prompt sent to each backend
+pub const SMOKE_PROMPT: &str = "Reply with exactly: OK";
+
+/// Default hard timeout per backend in seconds
+pub const DEFAULT_SMOKE_TIMEOUT_SECS: u64 = 30;
+
+/// Longest `first_message` reported, in characters
+const MAX_FIRST_MESSAGE_CHARS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmokeStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+/// Per-backend smoke test outcome
+#[derive(Debug, Serialize)]
+pub struct BackendSmokeReport {
+    pub backend: &'static str,
+    pub status: SmokeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a backend run produced, reduced to the fields the report needs
+#[derive(Debug)]
+pub struct SmokeRun {
+    pub success: bool,
+    pub agent_messages: String,
+    pub error: Option<String>,
+}
+
+/// A finished backend run and how long it took
+pub type TimedRun = (Duration, anyhow::Result<SmokeRun>);
+
+/// Build the report for one backend; `None` means the backend was not available.
+pub fn assemble_report(backend: &'static str, run: Option<TimedRun>) -> BackendSmokeReport {
+    let Some((elapsed, outcome)) = run else {
+        return BackendSmokeReport {
+            backend,
+            status: SmokeStatus::Skipped,
+            latency_ms: None,
+            first_message: None,
+            error: Some(format!("{} CLI not available", backend)),
+        };
+    };
+
+    let latency_ms = Some(elapsed.as_millis() as u64);
+    match outcome {
+        Ok(run) => {
+            let first_message = run
+                .agent_messages
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .map(|line| line.chars().take(MAX_FIRST_MESSAGE_CHARS).collect());
+            BackendSmokeReport {
+                backend,
+                status: if run.success {
+                    SmokeStatus::Ok
+                } else {
+                    SmokeStatus::Failed
+                },
+                latency_ms,
+                first_message,
+                error: run.error,
+            }
+        }
+        Err(e) => BackendSmokeReport {
+            backend,
+            status: SmokeStatus::Failed,
+            latency_ms,
+            first_message: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Run the canned prompt through the gemini CLI
+pub async fn run_gemini(timeout_secs: u64, include_directories: Vec<PathBuf>) -> TimedRun {
+    let opts = gemini::Options {
+        prompt: SMOKE_PROMPT.to_string(),
+        sandbox: false,
+        session_id: None,
+        return_all_messages: false,
+        model: None,
+        timeout_secs: Some(timeout_secs),
+        include_directories,
+        api_key: gemini::get_api_key(),
+        api_base_url: gemini::get_api_url(),
+        content_sink: None,
+    };
+
+    let start = Instant::now();
+    let outcome = gemini::run(opts).await.map(|r| SmokeRun {
+        success: r.success,
+        agent_messages: r.agent_messages,
+        error: r.error,
+    });
+    (start.elapsed(), outcome)
+}
+
+/// Run the canned prompt through the codex CLI in a read-only sandbox
+pub async fn run_codex(timeout_secs: u64, working_dir: PathBuf) -> TimedRun {
+    let security = codex::get_security_config(&mut Vec::new());
+    let opts = codex::Options {
+        prompt: SMOKE_PROMPT.to_string(),
+        working_dir,
+        sandbox: codex::SandboxPolicy::ReadOnly,
+        session_id: None,
+        skip_git_repo_check: security.allow_skip_git_check,
+        skip_git_repo_check_denied: false,
+        return_all_messages: false,
+        return_all_messages_limit: None,
+        return_turns: false,
+        include_reasoning_summary: false,
+        image_paths: Vec::new(),
+        model: None,
+        yolo: false,
+        profile: None,
+        timeout_secs: Some(timeout_secs),
+        force_stdin: false,
+        session_registry: None,
+        log_file: None,
+        reasoning_effort: None,
+        verbosity: None,
+        approval_policy: None,
+        developer_instructions: None,
+    };
+
+    let start = Instant::now();
+    let outcome = codex::run(opts).await.map(|r| SmokeRun {
+        success: r.success,
+        agent_messages: r.agent_messages,
+        error: r.error,
+    });
+    (start.elapsed(), outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_report_success() {
+        let run = SmokeRun {
+            success: true,
+            agent_messages: "\nOK\nextra".to_string(),
+            error: None,
+        };
+        let report = assemble_report("gemini", Some((Duration::from_millis(1500), Ok(run))));
+        assert_eq!(report.status, SmokeStatus::Ok);
+        assert_eq!(report.latency_ms, Some(1500));
+        assert_eq!(report.first_message.as_deref(), Some("OK"));
+        assert!(report.error.is_none());
+    }
+
+    #[test]
+    fn test_assemble_report_failure_and_error() {
+        let run = SmokeRun {
+            success: false,
+            agent_messages: String::new(),
+            error: Some("Codex execution timed out after 30 seconds".to_string()),
+        };
+        let report = assemble_report("codex", Some((Duration::from_secs(30), Ok(run))));
+        assert_eq!(report.status, SmokeStatus::Failed);
+        assert!(report.first_message.is_none());
+        assert!(report.error.unwrap().contains("timed out"));
+
+        let report = assemble_report(
+            "codex",
+            Some((
+                Duration::ZERO,
+                Err(anyhow::anyhow!("Failed to spawn codex command")),
+            )),
+        );
+        assert_eq!(report.status, SmokeStatus::Failed);
+        assert_eq!(report.latency_ms, Some(0));
+        assert!(report.error.unwrap().contains("spawn"));
+    }
+
+    #[test]
+    fn test_assemble_report_skipped() {
+        let report = assemble_report("gemini", None);
+        assert_eq!(report.status, SmokeStatus::Skipped);
+        assert!(report.latency_ms.is_none());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["status"], "skipped");
+        assert!(json.get("first_message").is_none());
+    }
+}
@@ -13,9 +13,11 @@ use std::sync::Arc;
 use futures::{SinkExt, StreamExt};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
 use tokio_util::bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
 
 /// Detected message framing format
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -27,6 +29,23 @@ pub enum FramingFormat {
     Lsp,
 }
 
+/// Format detected from incoming messages, shared between the reader and writer
+/// codecs and with anything that reports it (e.g. the server's diagnostics)
+pub type SharedFramingFormat = Arc<RwLock<Option<FramingFormat>>>;
+
+/// Describe the detected format for diagnostics: `JsonLines`, `Lsp`, or
+/// `not yet detected` before the first message has arrived
+pub fn describe_framing_format(shared: &SharedFramingFormat) -> String {
+    match shared.try_read() {
+        Ok(guard) => match *guard {
+            Some(format) => format!("{:?}", format),
+            None => "not yet detected".to_string(),
+        },
+        // Only held while the first message is being detected
+        Err(_) => "not yet detected".to_string(),
+    }
+}
+
 /// Adaptive codec that handles both JSONL and LSP-style message framing.
 ///
 /// The codec auto-detects the incoming format and responds using the same format.
@@ -38,7 +57,7 @@ pub struct AdaptiveCodec<T> {
     /// Detected format for incoming messages (also used for outgoing)
     detected_format: Option<FramingFormat>,
     /// Shared format state between reader and writer (if provided)
-    shared_format: Option<Arc<RwLock<Option<FramingFormat>>>>,
+    shared_format: Option<SharedFramingFormat>,
     /// Buffer state for JSONL parsing
     next_index: usize,
     max_length: usize,
@@ -67,7 +86,7 @@ impl<T> AdaptiveCodec<T> {
     }
 
     /// Create a codec with shared format state
-    pub fn with_shared_format(shared: Arc<RwLock<Option<FramingFormat>>>) -> Self {
+    pub fn with_shared_format(shared: SharedFramingFormat) -> Self {
         Self {
             _marker: PhantomData,
             detected_format: None,
@@ -79,12 +98,25 @@ impl<T> AdaptiveCodec<T> {
         }
     }
 
+    /// Limit the size of a single message, for both JSONL lines and LSP bodies
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_length = max_message_size;
+        self
+    }
+
     pub fn detected_format(&self) -> Option<FramingFormat> {
         self.detected_format
     }
 
     /// Detect format by peeking at buffer contents
     fn detect_format(buf: &[u8]) -> Option<FramingFormat> {
+        // Skip a UTF-8 BOM the same way as whitespace
+        let buf = match buf.strip_prefix(UTF8_BOM) {
+            Some(rest) => rest,
+            None if UTF8_BOM.starts_with(buf) => return None, // Partial BOM, need more data
+            None => buf,
+        };
+
         // Skip any leading whitespace
         let trimmed = buf.iter().position(|&b| !b.is_ascii_whitespace());
         let start = trimmed.unwrap_or(0);
@@ -95,15 +127,14 @@ impl<T> AdaptiveCodec<T> {
 
         let first_byte = buf[start];
 
-        // LSP-style starts with 'C' from "Content-Length:"
-        if first_byte == b'C' {
-            if buf[start..].starts_with(b"Content-Length:") {
-                return Some(FramingFormat::Lsp);
-            }
-            // Could be partial "Content-Length", need more data
-            if buf.len() - start < 15 {
-                return None;
-            }
+        // LSP-style starts with "Content-Length:"
+        let rest = &buf[start..];
+        if rest.starts_with(LSP_HEADER_PREFIX) {
+            return Some(FramingFormat::Lsp);
+        }
+        // A strict prefix of "Content-Length:" could still become LSP, need more data
+        if LSP_HEADER_PREFIX.starts_with(rest) {
+            return None;
         }
 
         // JSONL starts with '{'
@@ -138,18 +169,32 @@ impl<T> AdaptiveCodec<T> {
     }
 }
 
-fn without_carriage_return(s: &[u8]) -> &[u8] {
-    if let Some(&b'\r') = s.last() {
-        &s[..s.len() - 1]
-    } else {
-        s
-    }
+/// UTF-8 byte order mark, emitted by some Windows tooling before the first message
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Default upper bound on a single incoming message (64 MiB)
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Header that introduces an LSP-style frame
+const LSP_HEADER_PREFIX: &[u8] = b"Content-Length:";
+
+/// Strip trailing whitespace (including `\r` from CRLF line endings)
+fn trim_trailing_whitespace(s: &[u8]) -> &[u8] {
+    let end = s
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &s[..end]
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum AdaptiveCodecError {
     #[error("max line length exceeded")]
     MaxLineLengthExceeded,
+    #[error("content length {length} exceeds max message size {max}")]
+    MaxContentLengthExceeded { length: usize, max: usize },
+    #[error("stream ended after {received} of {expected} LSP body bytes")]
+    TruncatedLspBody { expected: usize, received: usize },
     #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
     #[error("io error: {0}")]
@@ -176,6 +221,10 @@ impl<T: DeserializeOwned> Decoder for AdaptiveCodec<T> {
 
         // Auto-detect format if not yet determined
         if self.detected_format.is_none() {
+            // Drop a leading BOM so neither framing parser sees it
+            if buf.starts_with(UTF8_BOM) {
+                buf.advance(UTF8_BOM.len());
+            }
             match Self::detect_format(buf) {
                 Some(fmt) => {
                     self.detected_format = Some(fmt);
@@ -203,6 +252,26 @@ impl<T: DeserializeOwned> Decoder for AdaptiveCodec<T> {
             FramingFormat::JsonLines => self.decode_jsonl(buf),
         }
     }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(item) = self.decode(buf)? {
+            return Ok(Some(item));
+        }
+        // The peer closed mid-body: report it instead of dropping the partial message
+        if let Some(expected) = self.expected_content_length.take() {
+            let received = buf.len();
+            buf.clear();
+            return Err(AdaptiveCodecError::TruncatedLspBody { expected, received });
+        }
+        if self.detected_format == Some(FramingFormat::JsonLines) {
+            return self.decode_jsonl_eof(buf);
+        }
+        if buf.is_empty() {
+            Ok(None)
+        } else {
+            Err(std::io::Error::other("bytes remaining on stream").into())
+        }
+    }
 }
 
 impl<T: DeserializeOwned> AdaptiveCodec<T> {
@@ -223,6 +292,13 @@ impl<T: DeserializeOwned> AdaptiveCodec<T> {
             Some((content_length, body_start)) => {
                 // Consume headers
                 buf.advance(body_start);
+                // Refuse to buffer a body larger than the configured limit
+                if content_length > self.max_length {
+                    return Err(AdaptiveCodecError::MaxContentLengthExceeded {
+                        length: content_length,
+                        max: self.max_length,
+                    });
+                }
                 self.expected_content_length = Some(content_length);
                 // Recursively try to read body
                 self.decode_lsp(buf)
@@ -231,6 +307,21 @@ impl<T: DeserializeOwned> AdaptiveCodec<T> {
         }
     }
 
+    /// Parse a final line the peer closed the stream without terminating
+    fn decode_jsonl_eof(&mut self, buf: &mut BytesMut) -> Result<Option<T>, AdaptiveCodecError> {
+        let rest = buf.split();
+        self.next_index = 0;
+        if std::mem::take(&mut self.is_discarding) {
+            return Ok(None);
+        }
+        let line = trim_trailing_whitespace(&rest);
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let item: T = serde_json::from_slice(line)?;
+        Ok(Some(item))
+    }
+
     fn decode_jsonl(&mut self, buf: &mut BytesMut) -> Result<Option<T>, AdaptiveCodecError> {
         loop {
             let read_to = std::cmp::min(self.max_length.saturating_add(1), buf.len());
@@ -256,8 +347,7 @@ impl<T: DeserializeOwned> AdaptiveCodec<T> {
                     let newline_index = offset + self.next_index;
                     self.next_index = 0;
                     let line = buf.split_to(newline_index + 1);
-                    let line = &line[..line.len() - 1];
-                    let line = without_carriage_return(line);
+                    let line = trim_trailing_whitespace(&line[..line.len() - 1]);
 
                     // Skip empty lines
                     if line.is_empty() {
@@ -315,36 +405,157 @@ impl<T: Serialize> Encoder<T> for AdaptiveCodec<T> {
 /// Type alias for the framed writer with adaptive codec
 type AdaptiveWriter<W, T> = FramedWrite<W, AdaptiveCodec<T>>;
 
+/// A queued outgoing message and the channel to report its write result on
+type WriteRequest<T> = (T, oneshot::Sender<std::io::Result<()>>);
+
+/// Whether a write error means the peer has gone away for good
+fn is_disconnect(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::NotConnected
+            | std::io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Drain queued messages into the writer until all senders are dropped.
+/// Stops early and cancels `disconnected` when the peer has gone away, so later
+/// sends fail fast and the read side stops waiting for more messages.
+async fn write_loop<W, T>(
+    mut writer: AdaptiveWriter<W, T>,
+    mut write_rx: mpsc::UnboundedReceiver<WriteRequest<T>>,
+    disconnected: CancellationToken,
+) where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    while let Some((item, ack)) = write_rx.recv().await {
+        let result: std::io::Result<()> = writer.send(item).await.map_err(Into::into);
+        let peer_gone = result.as_ref().is_err_and(is_disconnect);
+        let _ = ack.send(result);
+        if peer_gone {
+            eprintln!("[transport] Peer disconnected, closing transport");
+            disconnected.cancel();
+            return;
+        }
+    }
+    let _ = writer.close().await;
+}
+
 /// Adaptive transport that wraps AsyncRead/AsyncWrite with format auto-detection.
 ///
 /// This transport uses `AdaptiveCodec` to handle both JSONL and LSP-style framing,
-/// auto-detecting the format from incoming messages.
+/// auto-detecting the format from incoming messages. Outgoing messages are handed
+/// to a dedicated writer task over a channel, so concurrent sends are written in
+/// queue order without contending on a lock around the writer.
 pub struct AdaptiveTransport<R, W, Tx, Rx>
 where
     R: AsyncRead,
     W: AsyncWrite,
 {
     read: FramedRead<R, AdaptiveCodec<Rx>>,
-    write: Arc<Mutex<Option<AdaptiveWriter<W, Tx>>>>,
+    write_tx: Option<mpsc::UnboundedSender<WriteRequest<Tx>>>,
+    writer_task: Option<JoinHandle<()>>,
+    /// Cancelled by the writer task when a write fails because the peer disconnected
+    disconnected: CancellationToken,
+    _writer: PhantomData<fn() -> W>,
 }
 
 impl<R, W, Tx, Rx> AdaptiveTransport<R, W, Tx, Rx>
 where
     R: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
+    W: AsyncWrite + Send + Unpin + 'static,
+    Tx: Serialize + Send + 'static,
 {
+    /// Create a transport over the given read/write halves.
+    /// Must be called from within a Tokio runtime (spawns the writer task).
     pub fn new(read: R, write: W) -> Self {
-        // Create shared format state so reader and writer use the same detected format
-        let shared_format = Arc::new(RwLock::new(None));
+        Self::with_max_message_size(read, write, DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    /// Create a transport that rejects incoming messages larger than `max_message_size`.
+    pub fn with_max_message_size(read: R, write: W, max_message_size: usize) -> Self {
+        Self::with_shared_format(read, write, max_message_size, Arc::new(RwLock::new(None)))
+    }
+
+    /// Create a transport that records the detected format in `shared_format`,
+    /// which the reader and writer codecs also use to agree on the format.
+    pub fn with_shared_format(
+        read: R,
+        write: W,
+        max_message_size: usize,
+        shared_format: SharedFramingFormat,
+    ) -> Self {
         let read = FramedRead::new(
             read,
-            AdaptiveCodec::<Rx>::with_shared_format(shared_format.clone()),
+            AdaptiveCodec::<Rx>::with_shared_format(shared_format.clone())
+                .with_max_message_size(max_message_size),
         );
-        let write = Arc::new(Mutex::new(Some(FramedWrite::new(
+        let writer = FramedWrite::new(
             write,
             AdaptiveCodec::<Tx>::with_shared_format(shared_format),
-        ))));
-        Self { read, write }
+        );
+        // Each send waits for its write to finish, so the queue never holds more
+        // than one message per in-flight send
+        let (write_tx, write_rx) = mpsc::unbounded_channel();
+        let disconnected = CancellationToken::new();
+        let writer_task = tokio::spawn(write_loop(writer, write_rx, disconnected.clone()));
+        Self {
+            read,
+            write_tx: Some(write_tx),
+            writer_task: Some(writer_task),
+            disconnected,
+            _writer: PhantomData,
+        }
+    }
+
+    /// Read the next message. Ends the stream once the peer has disconnected, even if
+    /// the read half is still open, so the service loop shuts down instead of spinning.
+    async fn next_message(&mut self) -> Option<Rx>
+    where
+        Rx: DeserializeOwned,
+    {
+        let disconnected = self.disconnected.clone();
+        tokio::select! {
+            biased;
+            _ = disconnected.cancelled() => None,
+            next = self.read.next() => next.and_then(|result| {
+                result
+                    .inspect_err(|e| {
+                        eprintln!("[transport] Error reading message: {}", e);
+                    })
+                    .ok()
+            }),
+        }
+    }
+
+    /// Queue a message for the writer task and wait until it has been written.
+    fn enqueue(
+        &self,
+        item: Tx,
+    ) -> impl std::future::Future<Output = Result<(), std::io::Error>> + Send + 'static {
+        // Enqueue eagerly so messages are written in call order, not poll order
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let queued = self
+            .write_tx
+            .as_ref()
+            .is_some_and(|write_tx| write_tx.send((item, ack_tx)).is_ok());
+        async move {
+            let closed =
+                || std::io::Error::new(std::io::ErrorKind::NotConnected, "Transport is closed");
+            if !queued {
+                return Err(closed());
+            }
+            ack_rx.await.map_err(|_| closed())?
+        }
+    }
+
+    /// Stop accepting messages and wait for queued ones to be flushed.
+    async fn shutdown_writer(&mut self) {
+        drop(self.write_tx.take());
+        if let Some(task) = self.writer_task.take() {
+            let _ = task.await;
+        }
     }
 }
 
@@ -360,7 +571,7 @@ where
     R: AsyncRead + Send + Unpin,
     W: AsyncWrite + Send + Unpin + 'static,
     RxJsonRpcMessage<Role>: DeserializeOwned,
-    TxJsonRpcMessage<Role>: Serialize,
+    TxJsonRpcMessage<Role>: Serialize + Send + 'static,
 {
     type Error = std::io::Error;
 
@@ -368,46 +579,68 @@ where
         &mut self,
         item: TxJsonRpcMessage<Role>,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send + 'static {
-        let lock = self.write.clone();
-        async move {
-            let mut guard = lock.lock().await;
-            if let Some(ref mut writer) = *guard {
-                writer.send(item).await.map_err(Into::into)
-            } else {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::NotConnected,
-                    "Transport is closed",
-                ))
-            }
-        }
+        self.enqueue(item)
     }
 
     fn receive(
         &mut self,
     ) -> impl std::future::Future<Output = Option<RxJsonRpcMessage<Role>>> + Send {
-        let next = self.read.next();
-        async {
-            next.await.and_then(|result| {
-                result
-                    .inspect_err(|e| {
-                        eprintln!("[transport] Error reading message: {}", e);
-                    })
-                    .ok()
-            })
-        }
+        self.next_message()
     }
 
     async fn close(&mut self) -> Result<(), Self::Error> {
-        let mut guard = self.write.lock().await;
-        drop(guard.take());
+        self.shutdown_writer().await;
         Ok(())
     }
 }
 
+/// Adaptive transport over an arbitrary read/write pair, such as an in-memory
+/// `tokio::io::duplex` pipe when embedding the server or driving it from tests.
+pub struct AdaptiveIo<R, W> {
+    read: R,
+    write: W,
+    shared_format: SharedFramingFormat,
+}
+
+impl<R, W> AdaptiveIo<R, W> {
+    pub fn new(read: R, write: W) -> Self {
+        Self {
+            read,
+            write,
+            shared_format: SharedFramingFormat::default(),
+        }
+    }
+
+    /// Record the detected framing format in `shared_format`
+    pub fn with_shared_format(mut self, shared_format: SharedFramingFormat) -> Self {
+        self.shared_format = shared_format;
+        self
+    }
+}
+
+impl<Role, R, W> IntoTransport<Role, std::io::Error, AdaptiveTransportAdapter> for AdaptiveIo<R, W>
+where
+    Role: ServiceRole,
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+    RxJsonRpcMessage<Role>: DeserializeOwned,
+    TxJsonRpcMessage<Role>: Serialize + Send + 'static,
+{
+    fn into_transport(self) -> impl Transport<Role, Error = std::io::Error> + 'static {
+        AdaptiveTransport::<R, W, TxJsonRpcMessage<Role>, RxJsonRpcMessage<Role>>::with_shared_format(
+            self.read,
+            self.write,
+            DEFAULT_MAX_MESSAGE_SIZE,
+            self.shared_format,
+        )
+    }
+}
+
 /// Wrapper to force using AdaptiveTransport instead of rmcp's default.
 pub struct AdaptiveStdio {
     stdin: tokio::io::Stdin,
     stdout: tokio::io::Stdout,
+    shared_format: SharedFramingFormat,
 }
 
 impl AdaptiveStdio {
@@ -415,8 +648,15 @@ impl AdaptiveStdio {
         Self {
             stdin: tokio::io::stdin(),
             stdout: tokio::io::stdout(),
+            shared_format: SharedFramingFormat::default(),
         }
     }
+
+    /// Record the detected framing format in `shared_format`
+    pub fn with_shared_format(mut self, shared_format: SharedFramingFormat) -> Self {
+        self.shared_format = shared_format;
+        self
+    }
 }
 
 impl Default for AdaptiveStdio {
@@ -429,7 +669,7 @@ impl<Role> IntoTransport<Role, std::io::Error, AdaptiveTransportAdapter> for Ada
 where
     Role: ServiceRole,
     RxJsonRpcMessage<Role>: DeserializeOwned,
-    TxJsonRpcMessage<Role>: Serialize,
+    TxJsonRpcMessage<Role>: Serialize + Send + 'static,
 {
     fn into_transport(self) -> impl Transport<Role, Error = std::io::Error> + 'static {
         AdaptiveTransport::<
@@ -437,7 +677,12 @@ where
             tokio::io::Stdout,
             TxJsonRpcMessage<Role>,
             RxJsonRpcMessage<Role>,
-        >::new(self.stdin, self.stdout)
+        >::with_shared_format(
+            self.stdin,
+            self.stdout,
+            DEFAULT_MAX_MESSAGE_SIZE,
+            self.shared_format,
+        )
     }
 }
 
@@ -472,6 +717,189 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_detect_partial_lsp_header_waits() {
+        let header = b"Content-Length:";
+        for len in 1..header.len() {
+            assert_eq!(
+                AdaptiveCodec::<()>::detect_format(&header[..len]),
+                None,
+                "prefix of length {len} should need more data"
+            );
+        }
+        assert_eq!(AdaptiveCodec::<()>::detect_format(b"  Content-Len"), None);
+        assert_eq!(
+            AdaptiveCodec::<()>::detect_format(header),
+            Some(FramingFormat::Lsp)
+        );
+    }
+
+    #[test]
+    fn test_detect_non_lsp_c_prefix_is_jsonl() {
+        assert_eq!(
+            AdaptiveCodec::<()>::detect_format(b"Cx"),
+            Some(FramingFormat::JsonLines)
+        );
+        assert_eq!(
+            AdaptiveCodec::<()>::detect_format(b"Content-Type: x"),
+            Some(FramingFormat::JsonLines)
+        );
+    }
+
+    #[test]
+    fn test_lsp_content_length_over_max_is_rejected() {
+        let mut codec = AdaptiveCodec::<serde_json::Value>::new().with_max_message_size(1024);
+        let mut buf = BytesMut::from(&b"Content-Length: 9999999999\r\n\r\n{"[..]);
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            AdaptiveCodecError::MaxContentLengthExceeded {
+                length: 9_999_999_999,
+                max: 1024
+            }
+        ));
+        // Headers consumed, nothing reserved for the declared body
+        assert_eq!(&buf[..], b"{");
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn test_lsp_content_length_at_max_is_accepted() {
+        let mut codec = AdaptiveCodec::<serde_json::Value>::new().with_max_message_size(2);
+        let mut buf = BytesMut::from(&b"Content-Length: 2\r\n\r\n{}"[..]);
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg, serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_truncated_lsp_body_errors_at_eof() {
+        let mut codec = AdaptiveCodec::<serde_json::Value>::new();
+        let mut buf = BytesMut::from(&b"Content-Length: 24\r\n\r\n{\"jsonrpc\":"[..]);
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        let err = codec.decode_eof(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            AdaptiveCodecError::TruncatedLspBody {
+                expected: 24,
+                received: 11
+            }
+        ));
+        assert!(buf.is_empty());
+        assert!(codec.expected_content_length.is_none());
+
+        // Headers alone, with the stream closing before any body bytes
+        let mut buf = BytesMut::from(&b"Content-Length: 2\r\n\r\n"[..]);
+        let err = codec.decode_eof(&mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "stream ended after 0 of 2 LSP body bytes");
+    }
+
+    #[test]
+    fn test_truncated_lsp_body_surfaces_from_framed_read() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let input: &[u8] = b"Content-Length: 2\r\n\r\n{}Content-Length: 24\r\n\r\n{";
+            let mut read = FramedRead::new(input, AdaptiveCodec::<serde_json::Value>::new());
+
+            let first = read.next().await.unwrap().unwrap();
+            assert_eq!(first, serde_json::json!({}));
+            let err = read.next().await.unwrap().unwrap_err();
+            assert!(matches!(err, AdaptiveCodecError::TruncatedLspBody { .. }));
+            assert!(read.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_detect_skips_bom() {
+        let buf = b"\xEF\xBB\xBF{\"jsonrpc\":\"2.0\"}";
+        assert_eq!(
+            AdaptiveCodec::<()>::detect_format(buf),
+            Some(FramingFormat::JsonLines)
+        );
+        let buf = b"\xEF\xBB\xBFContent-Length: 2\r\n\r\n{}";
+        assert_eq!(
+            AdaptiveCodec::<()>::detect_format(buf),
+            Some(FramingFormat::Lsp)
+        );
+        assert_eq!(AdaptiveCodec::<()>::detect_format(b"\xEF\xBB"), None);
+    }
+
+    #[test]
+    fn test_decode_jsonl_with_bom() {
+        let mut codec = AdaptiveCodec::<serde_json::Value>::new();
+        let mut buf = BytesMut::from(&b"\xEF\xBB\xBF{\"jsonrpc\":\"2.0\",\"id\":1}\n"[..]);
+
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(codec.detected_format(), Some(FramingFormat::JsonLines));
+        assert_eq!(msg["id"], 1);
+    }
+
+    #[test]
+    fn test_decode_lsp_with_bom() {
+        let mut codec = AdaptiveCodec::<serde_json::Value>::new();
+        let json = r#"{"jsonrpc":"2.0","id":1}"#;
+        let msg = format!("\u{FEFF}Content-Length: {}\r\n\r\n{}", json.len(), json);
+        let mut buf = BytesMut::from(msg.as_bytes());
+
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(codec.detected_format(), Some(FramingFormat::Lsp));
+        assert_eq!(msg["id"], 1);
+    }
+
+    #[test]
+    fn test_decode_jsonl_trailing_whitespace() {
+        let mut codec = AdaptiveCodec::<serde_json::Value>::new();
+        let mut buf = BytesMut::from(&b"{\"id\":1} \t\r\n   \r\n{\"id\":2}\n"[..]);
+
+        let msg1 = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg1["id"], 1);
+        let msg2 = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg2["id"], 2);
+    }
+
+    #[test]
+    fn test_decode_jsonl_unterminated_final_line_at_eof() {
+        let mut codec = AdaptiveCodec::<serde_json::Value>::new();
+        let mut buf = BytesMut::from(&b"{\"id\":1}\n{\"id\":2} \r"[..]);
+
+        let msg1 = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg1["id"], 1);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        let msg2 = codec.decode_eof(&mut buf).unwrap().unwrap();
+        assert_eq!(msg2["id"], 2);
+        assert!(buf.is_empty());
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+
+        // Trailing blank lines are not a message
+        let mut buf = BytesMut::from(&b"  \r\n \t"[..]);
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_jsonl_without_trailing_newline_read_at_eof() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let input: &[u8] = br#"{"jsonrpc":"2.0","id":7}"#;
+            let mut read = FramedRead::new(input, AdaptiveCodec::<serde_json::Value>::new());
+
+            let msg = read.next().await.unwrap().unwrap();
+            assert_eq!(msg["id"], 7);
+            assert!(read.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_describe_framing_format_after_first_message() {
+        let shared = SharedFramingFormat::default();
+        assert_eq!(describe_framing_format(&shared), "not yet detected");
+
+        let mut codec = AdaptiveCodec::<serde_json::Value>::with_shared_format(shared.clone());
+        let mut buf = BytesMut::from(&b"{\"jsonrpc\":\"2.0\",\"id\":1}\n"[..]);
+        codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(describe_framing_format(&shared), "JsonLines");
+    }
+
     #[test]
     fn test_parse_lsp_headers() {
         let buf = b"Content-Length: 18\r\n\r\n{\"jsonrpc\":\"2.0\"}";
@@ -587,4 +1015,90 @@ mod tests {
         let msg2 = codec.decode(&mut buf).unwrap().unwrap();
         assert_eq!(msg2["id"], 2);
     }
+
+    #[test]
+    fn test_concurrent_sends_delivered_in_order() {
+        use tokio::io::AsyncBufReadExt;
+
+        const COUNT: usize = 200;
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            // Small pipe buffer forces the writer to wait on the reader
+            let (client, server) = tokio::io::duplex(64);
+            let (server_read, server_write) = tokio::io::split(server);
+            let mut transport =
+                AdaptiveTransport::<_, _, serde_json::Value, serde_json::Value>::new(
+                    server_read,
+                    server_write,
+                );
+
+            let reader = tokio::spawn(async move {
+                let mut lines = tokio::io::BufReader::new(client).lines();
+                let mut ids = Vec::new();
+                while ids.len() < COUNT {
+                    let line = lines.next_line().await.unwrap().unwrap();
+                    let msg: serde_json::Value = serde_json::from_str(&line).unwrap();
+                    ids.push(msg["id"].as_u64().unwrap() as usize);
+                }
+                ids
+            });
+
+            let sends: Vec<_> = (0..COUNT)
+                .map(|i| transport.enqueue(serde_json::json!({"jsonrpc": "2.0", "id": i})))
+                .collect();
+            futures::future::try_join_all(sends).await.unwrap();
+
+            let ids = reader.await.unwrap();
+            assert_eq!(ids, (0..COUNT).collect::<Vec<_>>());
+
+            transport.shutdown_writer().await;
+            let err = transport
+                .enqueue(serde_json::json!({"id": "late"}))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+        });
+    }
+
+    #[test]
+    fn test_broken_pipe_closes_transport() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            // Keep the read side open so only the write failure can end the stream
+            let (_client_write, server_read) = tokio::io::duplex(64);
+            let (client_read, server_write) = tokio::io::duplex(64);
+            drop(client_read);
+            let mut transport =
+                AdaptiveTransport::<_, _, serde_json::Value, serde_json::Value>::new(
+                    server_read,
+                    server_write,
+                );
+
+            let err = transport
+                .enqueue(serde_json::json!({"jsonrpc": "2.0", "id": 1}))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+            for id in 2..5 {
+                let err = transport
+                    .enqueue(serde_json::json!({"jsonrpc": "2.0", "id": id}))
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+                assert_eq!(err.to_string(), "Transport is closed");
+            }
+
+            let next =
+                tokio::time::timeout(std::time::Duration::from_secs(5), transport.next_message())
+                    .await
+                    .expect("receive did not end after the peer disconnected");
+            assert!(next.is_none());
+            let writer_task = transport.writer_task.take().unwrap();
+            tokio::time::timeout(std::time::Duration::from_secs(5), writer_task)
+                .await
+                .expect("writer task kept running after the peer disconnected")
+                .unwrap();
+        });
+    }
 }
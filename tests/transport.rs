@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+
+use ikuncode_aimcp::detection::Capabilities;
+use ikuncode_aimcp::server::UnifiedServer;
+use ikuncode_aimcp::transport::AdaptiveIo;
+use rmcp::ServiceExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+const INITIALIZE: &str = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2024-11-05","capabilities":{},"clientInfo":{"name":"duplex-test","version":"0.0.0"}}}"#;
+
+fn test_capabilities() -> Capabilities {
+    Capabilities {
+        gemini_available: false,
+        gemini_path: None,
+        gemini_found_not_executable: false,
+        codex_available: true,
+        codex_path: Some(PathBuf::from("/usr/bin/codex")),
+        codex_found_not_executable: false,
+        grok_available: false,
+    }
+}
+
+fn assert_initialize_response(response: &serde_json::Value) {
+    assert_eq!(response["id"], 1);
+    assert_eq!(response["result"]["protocolVersion"], "2024-11-05");
+    assert!(response["result"]["serverInfo"]["name"].is_string());
+    assert!(response["result"]["capabilities"]["tools"].is_object());
+}
+
+#[test]
+fn test_initialize_over_duplex_jsonl() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_read, server_write) = tokio::io::split(server);
+        let serving = tokio::spawn(
+            UnifiedServer::new(test_capabilities())
+                .serve(AdaptiveIo::new(server_read, server_write)),
+        );
+
+        let (client_read, mut client_write) = tokio::io::split(client);
+        client_write
+            .write_all(format!("{}\n", INITIALIZE).as_bytes())
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(client_read).lines();
+        let line = tokio::time::timeout(std::time::Duration::from_secs(10), lines.next_line())
+            .await
+            .expect("timed out waiting for initialize response")
+            .unwrap()
+            .expect("server closed the pipe");
+        let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_initialize_response(&response);
+
+        drop(client_write);
+        drop(lines);
+        serving.abort();
+    });
+}
+
+#[test]
+fn test_initialize_over_duplex_lsp() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let (client, server) = tokio::io::duplex(4096);
+        let (server_read, server_write) = tokio::io::split(server);
+        let serving = tokio::spawn(
+            UnifiedServer::new(test_capabilities())
+                .serve(AdaptiveIo::new(server_read, server_write)),
+        );
+
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let frame = format!("Content-Length: {}\r\n\r\n{}", INITIALIZE.len(), INITIALIZE);
+        client_write.write_all(frame.as_bytes()).await.unwrap();
+
+        let mut reader = BufReader::new(client_read);
+        let read_response = async {
+            let mut header = String::new();
+            reader.read_line(&mut header).await.unwrap();
+            let length: usize = header
+                .trim()
+                .strip_prefix("Content-Length:")
+                .expect("response should use LSP framing")
+                .trim()
+                .parse()
+                .unwrap();
+            let mut blank = String::new();
+            reader.read_line(&mut blank).await.unwrap();
+            assert_eq!(blank, "\r\n");
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).await.unwrap();
+            body
+        };
+        let body = tokio::time::timeout(std::time::Duration::from_secs(10), read_response)
+            .await
+            .expect("timed out waiting for initialize response");
+        let response: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_initialize_response(&response);
+
+        drop(client_write);
+        serving.abort();
+    });
+}